@@ -126,10 +126,26 @@ pub struct SysConfig {
     pub pccs_url: Option<String>,
     pub docker_registry: Option<String>,
     pub host_api_url: String,
+    /// URL of the guest callback API, the host API keeps serving guests without it
+    #[serde(default)]
+    pub guest_callback_url: Option<String>,
     // JSON serialized VmConfig
     pub vm_config: String,
+    /// Token to authenticate to the VMM guest API as this VM
+    #[serde(default)]
+    pub guest_api_token: Option<String>,
+    /// Hostname to set in the guest, from the VM network config
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// The data disk key is the boot secret [`BOOT_SECRET_DISK_KEY`] instead of the key from
+    /// the key provider
+    #[serde(default)]
+    pub disk_key_from_boot_secret: bool,
 }
 
+/// Boot secret holding the LUKS key of the data disk, for VMs whose host provides it.
+pub const BOOT_SECRET_DISK_KEY: &str = "dstack.disk_crypt_key";
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct VmConfig {
     pub spec_version: u32,
@@ -6,11 +6,12 @@ use crate::utils::{deserialize_json_file, sha256, SysConfig};
 use anyhow::{anyhow, bail, Context, Result};
 use dstack_types::shared_filenames::{HOST_SHARED_DIR, SYS_CONFIG};
 use host_api::{
-    client::{new_client, DefaultClient},
-    Notification,
+    client::{new_callback_client, new_client, CallbackClient, DefaultClient},
+    FetchBootSecretRequest, GuestCallbackRequest, Notification,
 };
 use ra_tls::attestation::validate_tcb;
 use sodiumbox::{generate_keypair, open_sealed_box, PUBLICKEYBYTES};
+use std::collections::BTreeMap;
 use tracing::warn;
 
 pub(crate) struct KeyProvision {
@@ -21,6 +22,8 @@ pub(crate) struct KeyProvision {
 pub(crate) struct HostApi {
     client: DefaultClient,
     pccs_url: Option<String>,
+    /// The guest callback API and the guest token to call it with, if the host serves it
+    callback: Option<(CallbackClient, String)>,
 }
 
 impl Default for HostApi {
@@ -34,9 +37,21 @@ impl HostApi {
         Self {
             client: new_client(base_url),
             pccs_url,
+            callback: None,
         }
     }
 
+    /// Send heartbeats, readiness and boot secret fetches to the guest callback API of the
+    /// sys-config, if it has one.
+    pub fn with_callback(mut self, sys_config: &SysConfig) -> Self {
+        if let (Some(url), Some(token)) =
+            (&sys_config.guest_callback_url, &sys_config.guest_api_token)
+        {
+            self.callback = Some((new_callback_client(url.clone()), token.clone()));
+        }
+        self
+    }
+
     pub fn load_or_default(url: Option<String>) -> Result<Self> {
         let api = match url {
             Some(url) => Self::new(url, None),
@@ -47,12 +62,23 @@ impl HostApi {
                     local_config.host_api_url.clone(),
                     local_config.pccs_url.clone(),
                 )
+                .with_callback(&local_config)
             }
         };
         Ok(api)
     }
 
     pub async fn notify(&self, event: &str, payload: &str) -> Result<()> {
+        if let Some((callback, token)) = &self.callback {
+            let request = GuestCallbackRequest {
+                token: token.clone(),
+            };
+            match (event, payload) {
+                ("heartbeat", _) => return Ok(callback.heartbeat(request).await?),
+                ("boot.progress", "done") => return Ok(callback.ready(request).await?),
+                _ => {}
+            }
+        }
         self.client
             .notify(Notification {
                 event: event.to_string(),
@@ -68,6 +94,19 @@ impl HostApi {
         }
     }
 
+    /// Fetch the secrets the host provisioned for this boot.
+    pub async fn fetch_boot_secrets(&self, token: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let request = FetchBootSecretRequest {
+            token: token.to_string(),
+        };
+        let response = match &self.callback {
+            Some((callback, _)) => callback.fetch_boot_secret(request).await,
+            None => self.client.fetch_boot_secret(request).await,
+        }
+        .map_err(|err| anyhow!("Failed to fetch boot secrets: {err:?}"))?;
+        Ok(response.secrets.into_iter().collect())
+    }
+
     pub async fn get_sealing_key(&self) -> Result<KeyProvision> {
         let (pk, sk) = generate_keypair();
         let mut report_data = [0u8; 64];
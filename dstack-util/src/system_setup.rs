@@ -15,7 +15,7 @@ use dstack_types::{
         APP_COMPOSE, APP_KEYS, DECRYPTED_ENV, DECRYPTED_ENV_JSON, ENCRYPTED_ENV,
         HOST_SHARED_DIR_NAME, INSTANCE_INFO, SYS_CONFIG, USER_CONFIG,
     },
-    KeyProvider, KeyProviderInfo,
+    KeyProvider, KeyProviderInfo, BOOT_SECRET_DISK_KEY,
 };
 use fs_err as fs;
 use luks2::{
@@ -285,7 +285,8 @@ impl<'a> Stage0<'a> {
         let host_api = HostApi::new(
             host_shared.sys_config.host_api_url.clone(),
             host_shared.sys_config.pccs_url.clone(),
-        );
+        )
+        .with_callback(&host_shared.sys_config);
         Ok(Self {
             args,
             shared: host_shared,
@@ -591,6 +592,23 @@ impl<'a> Stage0<'a> {
         Ok(())
     }
 
+    /// The data disk key the host provides as a boot secret, hex encoded like the key from the
+    /// key provider.
+    async fn fetch_host_disk_key(&self) -> Result<String> {
+        info!("Fetching the data disk key from the host");
+        let token = self
+            .shared
+            .sys_config
+            .guest_api_token
+            .as_deref()
+            .context("No guest API token to fetch the disk key with")?;
+        let secrets = self.vmm.fetch_boot_secrets(token).await?;
+        let key = secrets
+            .get(BOOT_SECRET_DISK_KEY)
+            .context("The host did not provide the disk key")?;
+        Ok(hex::encode(key))
+    }
+
     async fn setup_fs(self) -> Result<Stage1<'a>> {
         let is_initialized = self.shared.instance_info.is_initialized();
         let app_info = self
@@ -615,7 +633,12 @@ impl<'a> Stage0<'a> {
         fs::write(self.app_keys_file(), keys_json).context("Failed to write app keys")?;
 
         self.vmm.notify_q("boot.progress", "unsealing env").await;
-        self.mount_data_disk(is_initialized, &hex::encode(&app_keys.disk_crypt_key))
+        let disk_crypt_key = if self.shared.sys_config.disk_key_from_boot_secret {
+            self.fetch_host_disk_key().await?
+        } else {
+            hex::encode(&app_keys.disk_crypt_key)
+        };
+        self.mount_data_disk(is_initialized, &disk_crypt_key)
             .await?;
         self.vmm
             .notify_q(
@@ -699,6 +722,7 @@ impl Stage1<'_> {
     async fn setup(&self) -> Result<()> {
         let envs = self.unseal_env_vars()?;
         self.link_files()?;
+        self.setup_hostname()?;
         self.setup_guest_agent_config()?;
         self.vmm
             .notify_q("boot.progress", "setting up dstack-gateway")
@@ -869,6 +893,25 @@ impl Stage1<'_> {
         Ok(())
     }
 
+    fn setup_hostname(&self) -> Result<()> {
+        let Some(hostname) = &self.shared.sys_config.hostname else {
+            return Ok(());
+        };
+        // The sys-config comes from the untrusted host
+        if hostname.is_empty()
+            || hostname.len() > 253
+            || !hostname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            bail!("Invalid hostname in sys-config");
+        }
+        info!("Setting hostname: {hostname}");
+        fs::write("/etc/hostname", format!("{hostname}\n"))?;
+        cmd!(hostname $hostname)?;
+        Ok(())
+    }
+
     fn setup_guest_agent_config(&self) -> Result<()> {
         info!("Setting up guest agent config");
         let data_disks = ["/".as_ref() as &Path, self.args.mount_point.as_ref()];
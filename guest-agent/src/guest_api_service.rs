@@ -16,7 +16,7 @@ use guest_api::{
     Container, DiskInfo, Gateway, GuestInfo, Interface, IpAddress, ListContainersResponse,
     NetworkInformation, SystemInfo,
 };
-use host_api::Notification;
+use host_api::{GuestCallbackRequest, Notification};
 use ra_rpc::{CallContext, RpcCall};
 use tracing::error;
 
@@ -208,6 +208,16 @@ pub async fn notify_host(event: &str, payload: &str) -> Result<()> {
     let local_config: SysConfig = serde_json::from_str(&fs::read_to_string(format!(
         "{HOST_SHARED_DIR}/{SYS_CONFIG}"
     ))?)?;
+    if event == "heartbeat" {
+        if let (Some(url), Some(token)) = (
+            local_config.guest_callback_url,
+            local_config.guest_api_token,
+        ) {
+            let callback = host_api::client::new_callback_client(url);
+            callback.heartbeat(GuestCallbackRequest { token }).await?;
+            return Ok(());
+        }
+    }
     let nc = host_api::client::new_client(local_config.host_api_url);
     nc.notify(Notification {
         event: event.to_string(),
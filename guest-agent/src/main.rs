@@ -18,7 +18,7 @@ use rpc_service::{AppState, ExternalRpcHandler, InternalRpcHandler, InternalRpcH
 use sd_notify::{notify as sd_notify, NotifyState};
 use std::time::Duration;
 use tokio::sync::oneshot;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 mod config;
 mod guest_api_service;
@@ -158,6 +158,18 @@ async fn run_guest_api(state: AppState, figment: Figment) -> Result<()> {
     Ok(())
 }
 
+/// Tell the host the guest is alive, so it can detect hung guests.
+async fn run_heartbeat() {
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = guest_api_service::notify_host("heartbeat", "").await {
+            debug!("Failed to send heartbeat to host: {err:?}");
+        }
+    }
+}
+
 async fn run_watchdog(port: u16) {
     let mut watchdog_usec = 0;
     let enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
@@ -229,11 +241,14 @@ async fn main() -> Result<()> {
         _ = async {
             let _ = tappd_ready_rx.await;
             let _ = sock_ready_rx.await;
-            if args.watchdog {
-                run_watchdog(bind_addr.port).await;
-            } else {
-                pending::<()>().await;
-            }
+            let watchdog = async {
+                if args.watchdog {
+                    run_watchdog(bind_addr.port).await;
+                } else {
+                    pending::<()>().await;
+                }
+            };
+            tokio::join!(watchdog, run_heartbeat());
         } => {}
     );
     Ok(())
@@ -2,11 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::guest_callback_client::GuestCallbackClient;
 use crate::host_api_client::HostApiClient;
 use http_client::prpc::PrpcClient;
 
 pub type DefaultClient = HostApiClient<PrpcClient>;
+pub type CallbackClient = GuestCallbackClient<PrpcClient>;
 
 pub fn new_client(base_url: String) -> DefaultClient {
     DefaultClient::new(PrpcClient::new(base_url))
 }
+
+pub fn new_callback_client(base_url: String) -> CallbackClient {
+    CallbackClient::new(PrpcClient::new(base_url))
+}
@@ -2,12 +2,29 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
 use figment::{
-    providers::{Data, Format, Json, Toml},
+    providers::{Data, Format, Json, Serialized, Toml},
     Figment,
 };
 use tracing::info;
 
+/// Top-level key listing config files to merge before the file itself.
+///
+/// Included files are merged in the listed order, each after its own includes, so a file
+/// overrides everything it includes and later includes override earlier ones. Relative paths
+/// are resolved against the directory of the including file.
+pub const INCLUDE_KEY: &str = "include";
+
+/// Top-level key holding named config profiles, e.g. `[profiles.dev]`.
+///
+/// A selected profile is merged over the fully loaded config: tables are merged key by key,
+/// while scalars and arrays replace the base value. A profile may override any key except
+/// [`INCLUDE_KEY`] and [`PROFILES_KEY`] itself.
+pub const PROFILES_KEY: &str = "profiles";
+
 trait MaybeNested {
     fn maybe_nested(self, nested: bool) -> Self;
 }
@@ -32,6 +49,51 @@ fn load_config_file(path: &str, nested: bool, figment: Figment) -> Figment {
     figment.merge(Json::file(path).maybe_nested(nested))
 }
 
+fn file_includes(path: &str) -> Result<Vec<String>> {
+    let file = load_config_file(path, false, Figment::new());
+    if file.find_value(INCLUDE_KEY).is_err() {
+        return Ok(vec![]);
+    }
+    file.extract_inner(INCLUDE_KEY)
+        .with_context(|| format!("Invalid `{INCLUDE_KEY}` in config file {path}"))
+}
+
+fn load_config_tree(
+    path: &Path,
+    nested: bool,
+    figment: Figment,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Figment> {
+    let canonical = path.canonicalize().with_context(|| match stack.last() {
+        Some(parent) => format!(
+            "Config file {} included from {} not found",
+            path.display(),
+            parent.display()
+        ),
+        None => format!("Config file {} not found", path.display()),
+    })?;
+    if let Some(pos) = stack.iter().position(|p| *p == canonical) {
+        let chain = stack[pos..]
+            .iter()
+            .chain([&canonical])
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        bail!("Circular config include: {chain}");
+    }
+    let path_str = canonical.to_string_lossy().to_string();
+    let includes = file_includes(&path_str)?;
+    let base_dir = canonical.parent().unwrap_or(Path::new("/")).to_path_buf();
+    stack.push(canonical);
+    let mut figment = figment;
+    for include in includes {
+        figment = load_config_tree(&base_dir.join(include), nested, figment, stack)?;
+    }
+    stack.pop();
+    info!("Loading config file: {path_str}");
+    Ok(load_config_file(&path_str, nested, figment))
+}
+
 fn load_config_in_dir(name: &str, path: &str, nested: bool, mut figment: Figment) -> Figment {
     for ext in ["toml", "json"] {
         let filename = format!("{}/{}.{}", path, name, ext);
@@ -71,6 +133,64 @@ pub fn load_config(
     search_load_config(name, &[&etc_path, "."], default_toml, leaf_config, nested)
 }
 
+fn search_load_config_with_includes(
+    name: &str,
+    search_paths: &[&str],
+    default_toml: &str,
+    leaf_config: Option<&str>,
+    nested: bool,
+) -> Result<Figment> {
+    let mut figment = Figment::from(rocket::Config::default())
+        .merge(Toml::string(default_toml).maybe_nested(nested));
+    for path in search_paths {
+        for ext in ["toml", "json"] {
+            let filename = Path::new(path).join(format!("{name}.{ext}"));
+            if filename.exists() {
+                figment = load_config_tree(&filename, nested, figment, &mut vec![])?;
+            }
+        }
+    }
+    match leaf_config {
+        Some(path) => load_config_tree(Path::new(path), nested, figment, &mut vec![]),
+        None => Ok(figment),
+    }
+}
+
+/// Like [`load_config`], also merging the files listed under [`INCLUDE_KEY`].
+///
+/// Fails if an included file is missing or includes itself, directly or indirectly.
+pub fn load_config_with_includes(
+    name: &str,
+    default_toml: &str,
+    leaf_config: Option<&str>,
+    nested: bool,
+) -> Result<Figment> {
+    let etc_path = format!("/etc/{name}");
+    search_load_config_with_includes(name, &[&etc_path, "."], default_toml, leaf_config, nested)
+}
+
+/// Merge the config profile `name` from [`PROFILES_KEY`] over `figment`.
+pub fn apply_profile(figment: Figment, name: &str) -> Result<Figment> {
+    if name.is_empty() || name.contains('.') {
+        bail!("Invalid config profile name: {name:?}");
+    }
+    let key = format!("{PROFILES_KEY}.{name}");
+    let profile = figment
+        .find_value(&key)
+        .ok()
+        .with_context(|| format!("Config profile {name} not found"))?
+        .into_dict()
+        .with_context(|| format!("Config profile {name} must be a table"))?;
+    for reserved in [INCLUDE_KEY, PROFILES_KEY] {
+        if profile.contains_key(reserved) {
+            bail!("Config profile {name} may not set `{reserved}`");
+        }
+    }
+    info!("Applying config profile: {name}");
+    let selected = figment.profile().clone();
+    Ok(figment.merge(Serialized::from(profile, selected)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -309,4 +429,101 @@ override = "default"
             "nested_from_toml"
         );
     }
+
+    #[test]
+    fn test_load_config_includes() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("fragments")).unwrap();
+        fs::write(
+            temp_dir.path().join("fragments/a.toml"),
+            r#"
+include = ["b.json"]
+from_a = "a"
+common = "a"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            temp_dir.path().join("fragments/b.json"),
+            r#"{"from_b": "b", "common": "b"}"#,
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("fragments/c.toml"), r#"common = "c""#).unwrap();
+        let leaf_config = temp_dir.path().join("leaf.toml");
+        fs::write(
+            &leaf_config,
+            r#"
+include = ["fragments/a.toml", "fragments/c.toml"]
+leaf_only = "leaf"
+"#,
+        )
+        .unwrap();
+
+        let result = search_load_config_with_includes(
+            "app",
+            &[],
+            "common = \"default\"",
+            Some(leaf_config.to_str().unwrap()),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(result.extract_inner::<String>("from_a").unwrap(), "a");
+        assert_eq!(result.extract_inner::<String>("from_b").unwrap(), "b");
+        assert_eq!(result.extract_inner::<String>("leaf_only").unwrap(), "leaf");
+        assert_eq!(result.extract_inner::<String>("common").unwrap(), "c");
+    }
+
+    #[test]
+    fn test_load_config_include_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.toml");
+        fs::write(&a, r#"include = ["b.toml"]"#).unwrap();
+        fs::write(temp_dir.path().join("b.toml"), r#"include = ["a.toml"]"#).unwrap();
+        let missing = temp_dir.path().join("missing.toml");
+        fs::write(&missing, r#"include = ["nowhere.toml"]"#).unwrap();
+
+        let err = search_load_config_with_includes("app", &[], "", a.to_str(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Circular config include"), "{err}");
+
+        let err = search_load_config_with_includes("app", &[], "", missing.to_str(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("nowhere.toml"), "{err}");
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let figment = Figment::new().merge(Toml::string(
+            r#"
+log_level = "debug"
+ports = [1, 2]
+
+[cvm]
+qemu = "qemu"
+memory = 1024
+
+[profiles.prod]
+log_level = "info"
+ports = [3]
+cvm = { memory = 2048 }
+
+[profiles.bad]
+include = ["other.toml"]
+"#,
+        ));
+
+        let result = apply_profile(figment.clone(), "prod").unwrap();
+        assert_eq!(result.extract_inner::<String>("log_level").unwrap(), "info");
+        assert_eq!(result.extract_inner::<Vec<u32>>("ports").unwrap(), [3]);
+        assert_eq!(result.extract_inner::<String>("cvm.qemu").unwrap(), "qemu");
+        assert_eq!(result.extract_inner::<u32>("cvm.memory").unwrap(), 2048);
+
+        let err = apply_profile(figment.clone(), "staging").unwrap_err();
+        assert!(err.to_string().contains("not found"), "{err}");
+        let err = apply_profile(figment, "bad").unwrap_err();
+        assert!(err.to_string().contains("may not set `include`"), "{err}");
+    }
 }
@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bearer tokens confined to read-only methods, methods confined to admin tokens and methods
+//! disabled altogether, checked before every prpc handler.
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+
+/// Observer tokens of a server and the methods they may call, managed as rocket state.
+///
+/// A request presenting one of these tokens as `Authorization: Bearer <token>` is refused any
+/// other method, whatever else the server would accept it for.
+#[derive(Debug, Clone, Default)]
+pub struct ObserverTokens {
+    tokens: BTreeSet<String>,
+    methods: BTreeSet<String>,
+}
+
+impl ObserverTokens {
+    /// `methods` are names without the service prefix (e.g. `Status`).
+    pub fn new(
+        tokens: impl IntoIterator<Item = String>,
+        methods: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            tokens: tokens.into_iter().filter(|t| !t.is_empty()).collect(),
+            methods: methods.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn is_observer(&self, token: &str) -> bool {
+        self.tokens.contains(token)
+    }
+
+    /// Refuse a call of `method` made with an observer token, unless it is read-only.
+    pub fn check(&self, method: &str, token: Option<&str>) -> Result<(), ObserverDenied> {
+        if !token.is_some_and(|t| self.is_observer(t)) {
+            return Ok(());
+        }
+        let name = method.rsplit_once('.').map_or(method, |(_, name)| name);
+        if self.methods.contains(name) {
+            return Ok(());
+        }
+        Err(ObserverDenied {
+            method: method.to_string(),
+        })
+    }
+}
+
+/// A mutating call refused to an observer token.
+#[derive(Debug, Error)]
+#[error("observer tokens can not call {method}")]
+pub struct ObserverDenied {
+    pub method: String,
+}
+
+impl ObserverDenied {
+    /// HTTP status reported for the error.
+    pub fn status_code(&self) -> u16 {
+        403
+    }
+}
+
+/// Admin tokens of a server and the methods only they may call, managed as rocket state.
+///
+/// A call of one of these methods is refused unless it presents an admin token as
+/// `Authorization: Bearer <token>`. Without admin tokens the methods can not be called at all.
+#[derive(Debug, Clone, Default)]
+pub struct AdminTokens {
+    tokens: BTreeSet<String>,
+    methods: BTreeSet<String>,
+}
+
+impl AdminTokens {
+    /// `methods` are names without the service prefix (e.g. `Status`).
+    pub fn new(
+        tokens: impl IntoIterator<Item = String>,
+        methods: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            tokens: tokens.into_iter().filter(|t| !t.is_empty()).collect(),
+            methods: methods.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Refuse a call of an admin-only `method` made without an admin token.
+    pub fn check(&self, method: &str, token: Option<&str>) -> Result<(), AdminRequired> {
+        let name = method.rsplit_once('.').map_or(method, |(_, name)| name);
+        if !self.methods.contains(name) || token.is_some_and(|t| self.tokens.contains(t)) {
+            return Ok(());
+        }
+        Err(AdminRequired {
+            method: method.to_string(),
+        })
+    }
+}
+
+/// An admin-only call made without an admin token.
+#[derive(Debug, Error)]
+#[error("{method} requires an admin token")]
+pub struct AdminRequired {
+    pub method: String,
+}
+
+impl AdminRequired {
+    /// HTTP status reported for the error.
+    pub fn status_code(&self) -> u16 {
+        403
+    }
+}
+
+/// Methods a server refuses to everyone, whatever token is presented, managed as rocket state.
+#[derive(Debug, Clone, Default)]
+pub struct DisabledMethods {
+    methods: BTreeSet<String>,
+}
+
+impl DisabledMethods {
+    /// `methods` are names without the service prefix (e.g. `QmpCommand`).
+    pub fn new(methods: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            methods: methods.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Refuse a call of `method` if it is disabled.
+    pub fn check(&self, method: &str) -> Result<(), MethodDisabled> {
+        let name = method.rsplit_once('.').map_or(method, |(_, name)| name);
+        if !self.methods.contains(name) {
+            return Ok(());
+        }
+        Err(MethodDisabled {
+            method: method.to_string(),
+        })
+    }
+}
+
+/// A call of a method disabled on the server.
+#[derive(Debug, Error)]
+#[error("{method} is disabled on this server")]
+pub struct MethodDisabled {
+    pub method: String,
+}
+
+impl MethodDisabled {
+    /// HTTP status reported for the error.
+    pub fn status_code(&self) -> u16 {
+        403
+    }
+}
@@ -12,6 +12,9 @@ use tracing::{error, info};
 
 pub use ra_tls::attestation::{Attestation, VerifiedAttestation};
 
+pub mod access;
+pub mod limits;
+
 #[cfg(feature = "rocket")]
 pub mod rocket_helper;
 
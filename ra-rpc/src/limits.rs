@@ -0,0 +1,305 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-method timeouts, request size limits and concurrency limits, enforced around every prpc
+//! handler.
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits of a method. Zero means not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodLimits {
+    /// Seconds the handler may run before the call fails, for the methods the server lets be
+    /// interrupted
+    #[serde(default)]
+    pub timeout_secs: u64,
+    /// Largest accepted request payload in bytes
+    #[serde(default)]
+    pub max_request_size: u64,
+    /// Calls of the method handled at the same time
+    #[serde(default)]
+    pub max_concurrent: u64,
+    /// Seconds a call beyond `max_concurrent` waits for a slot before it is rejected as busy.
+    /// Such calls are rejected at once if not set
+    #[serde(default)]
+    pub queue_secs: u64,
+}
+
+impl MethodLimits {
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+
+    pub fn max_request_size(&self) -> Option<u64> {
+        (self.max_request_size > 0).then_some(self.max_request_size)
+    }
+
+    pub fn max_concurrent(&self) -> Option<usize> {
+        (self.max_concurrent > 0).then_some(self.max_concurrent as usize)
+    }
+
+    /// Fill the limits not set here from `fallback`.
+    fn or(self, fallback: MethodLimits) -> MethodLimits {
+        MethodLimits {
+            timeout_secs: match self.timeout_secs {
+                0 => fallback.timeout_secs,
+                t => t,
+            },
+            max_request_size: match self.max_request_size {
+                0 => fallback.max_request_size,
+                s => s,
+            },
+            max_concurrent: match self.max_concurrent {
+                0 => fallback.max_concurrent,
+                n => n,
+            },
+            queue_secs: match self.queue_secs {
+                0 => fallback.queue_secs,
+                t => t,
+            },
+        }
+    }
+}
+
+/// Central limits of the prpc methods of a server, managed as rocket state.
+///
+/// Without this state, or for limits not set, requests are bounded by the rocket `limits`
+/// entry named after the method (10 MiB if absent) and handlers run without timeout.
+///
+/// A timed out handler is dropped wherever it is, so only the methods in `interruptible` are
+/// timed out. Handlers of the other methods may leave partial changes behind when dropped and
+/// always run to completion.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RpcLimits {
+    /// Limits of the methods without an entry in `methods`, or not set in it
+    #[serde(default)]
+    pub default: MethodLimits,
+    /// Limits by method name, after the routes trimmed their prefix (e.g. `CreateVm`)
+    #[serde(default)]
+    pub methods: BTreeMap<String, MethodLimits>,
+    /// Methods whose handler can be dropped midway, the ones that change no state
+    #[serde(skip)]
+    pub interruptible: BTreeSet<String>,
+}
+
+impl RpcLimits {
+    pub fn for_method(&self, method: &str) -> MethodLimits {
+        self.methods
+            .get(method)
+            .copied()
+            .unwrap_or_default()
+            .or(self.default)
+    }
+
+    /// Let the timeouts apply to `methods`, names without the service prefix.
+    pub fn with_interruptible(
+        mut self,
+        methods: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.interruptible = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Timeout of `method`, `None` if it has none or must not be interrupted.
+    pub fn timeout(&self, method: &str) -> Option<Duration> {
+        let name = method.rsplit_once('.').map_or(method, |(_, name)| name);
+        if !self.interruptible.contains(name) {
+            return None;
+        }
+        self.for_method(method).timeout()
+    }
+
+    /// Run the handler `call` of `method`, failing after the timeout of the method if it has
+    /// one.
+    pub async fn run<F: Future>(&self, method: &str, call: F) -> Result<F::Output, RpcLimitError> {
+        let Some(timeout) = self.timeout(method) else {
+            return Ok(call.await);
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .map_err(|_| RpcLimitError::Timeout {
+                method: method.to_string(),
+                timeout,
+            })
+    }
+}
+
+/// Calls in progress of the methods with `max_concurrent`, managed as rocket state.
+///
+/// Each method has its own slots, a `max_concurrent` in the default limits applies to every
+/// method separately. Clones share the slots.
+#[derive(Debug, Clone, Default)]
+pub struct RpcConcurrency {
+    limits: RpcLimits,
+    slots: Arc<Mutex<BTreeMap<String, MethodSlots>>>,
+}
+
+#[derive(Debug)]
+struct MethodSlots {
+    semaphore: Arc<Semaphore>,
+    limit: usize,
+}
+
+/// Calls in progress of a method with a concurrency limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlight {
+    pub method: String,
+    pub calls: usize,
+    pub limit: usize,
+}
+
+impl RpcConcurrency {
+    pub fn new(limits: RpcLimits) -> Self {
+        Self {
+            limits,
+            slots: Default::default(),
+        }
+    }
+
+    /// Take a slot of `method`, held until the permit is dropped. `None` if the method has no
+    /// concurrency limit.
+    pub async fn acquire(
+        &self,
+        method: &str,
+    ) -> Result<Option<OwnedSemaphorePermit>, RpcLimitError> {
+        let limits = self.limits.for_method(method);
+        let Some(limit) = limits.max_concurrent() else {
+            return Ok(None);
+        };
+        let semaphore = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_insert_with(|| MethodSlots {
+                semaphore: Arc::new(Semaphore::new(limit)),
+                limit,
+            })
+            .semaphore
+            .clone();
+        let busy = || RpcLimitError::Busy {
+            method: method.to_string(),
+            limit,
+        };
+        let permit = match limits.queue_secs {
+            0 => semaphore.try_acquire_owned().map_err(|_| busy())?,
+            secs => tokio::time::timeout(Duration::from_secs(secs), semaphore.acquire_owned())
+                .await
+                .map_err(|_| busy())?
+                .map_err(|_| busy())?,
+        };
+        Ok(Some(permit))
+    }
+
+    /// Calls in progress of each method called so far that has a concurrency limit.
+    pub fn in_flight(&self) -> Vec<InFlight> {
+        self.slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, slots)| InFlight {
+                method: method.clone(),
+                calls: slots.limit - slots.semaphore.available_permits(),
+                limit: slots.limit,
+            })
+            .collect()
+    }
+}
+
+/// A call rejected or aborted for exceeding the limits of its method.
+#[derive(Debug, Error)]
+pub enum RpcLimitError {
+    #[error("request to {method} exceeds the limit of {limit} bytes")]
+    RequestTooLarge { method: String, limit: u64 },
+    #[error("{method} did not complete within {timeout:?}")]
+    Timeout { method: String, timeout: Duration },
+    #[error("{method} is busy, {limit} calls are in progress")]
+    Busy { method: String, limit: usize },
+}
+
+impl RpcLimitError {
+    /// HTTP status reported for the error.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            RpcLimitError::RequestTooLarge { .. } => 413,
+            RpcLimitError::Timeout { .. } => 504,
+            RpcLimitError::Busy { .. } => 429,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RpcLimits {
+        RpcLimits {
+            default: MethodLimits {
+                timeout_secs: 1,
+                ..Default::default()
+            },
+            methods: [(
+                "Slow".to_string(),
+                MethodLimits {
+                    timeout_secs: 30,
+                    max_request_size: 1024,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+        .with_interruptible(["Status", "Slow"])
+    }
+
+    #[test]
+    fn method_limits_fall_back_to_default() {
+        let limits = limits();
+        assert_eq!(limits.for_method("Slow").timeout_secs, 30);
+        assert_eq!(limits.for_method("Slow").max_request_size(), Some(1024));
+        assert_eq!(limits.for_method("Status").timeout_secs, 1);
+        assert_eq!(limits.for_method("Status").max_request_size(), None);
+    }
+
+    #[test]
+    fn only_interruptible_methods_time_out() {
+        let limits = limits();
+        assert_eq!(limits.timeout("Status"), Some(Duration::from_secs(1)));
+        assert_eq!(
+            limits.timeout("Teepod.Status"),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(limits.timeout("Slow"), Some(Duration::from_secs(30)));
+        assert_eq!(limits.timeout("CreateVm"), None);
+        assert_eq!(RpcLimits::default().timeout("Status"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_fails_an_interruptible_call_at_the_timeout() {
+        let result = limits()
+            .run("Status", tokio::time::sleep(Duration::from_secs(5)))
+            .await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, RpcLimitError::Timeout { .. }));
+        assert_eq!(err.status_code(), 504);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_completes_other_calls_past_the_timeout() {
+        let limits = limits();
+        let call = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "done"
+        };
+        assert_eq!(limits.run("CreateVm", call).await.unwrap(), "done");
+        let quick = limits.run("Status", async { "quick" }).await;
+        assert_eq!(quick.unwrap(), "quick");
+    }
+}
@@ -18,6 +18,10 @@ use rocket::{
 use rocket_vsock_listener::VsockEndpoint;
 use tracing::warn;
 
+use crate::access::{
+    AdminRequired, AdminTokens, DisabledMethods, MethodDisabled, ObserverDenied, ObserverTokens,
+};
+use crate::limits::{RpcConcurrency, RpcLimitError, RpcLimits};
 use crate::{encode_error, CallContext, RemoteEndpoint, RpcCall};
 
 pub struct RpcResponse {
@@ -180,16 +184,33 @@ impl QuoteVerifier {
     }
 }
 
-async fn read_data(data: Data<'_>, limit: ByteUnit) -> Result<Vec<u8>> {
+async fn read_data(data: Data<'_>, method: &str, limit: ByteUnit) -> Result<Vec<u8>> {
     let stream = data.open(limit);
     let data = stream.into_bytes().await.context("failed to read data")?;
     if !data.is_complete() {
-        anyhow::bail!("payload too large");
+        return Err(RpcLimitError::RequestTooLarge {
+            method: method.to_string(),
+            limit: limit.as_u64(),
+        }
+        .into());
     }
     Ok(data.into_inner())
 }
 
-fn limit_for_method(method: &str, limits: &Limits) -> ByteUnit {
+fn check_request_size(method: &str, len: u64, limit: ByteUnit) -> Result<(), RpcLimitError> {
+    if len > limit.as_u64() {
+        return Err(RpcLimitError::RequestTooLarge {
+            method: method.to_string(),
+            limit: limit.as_u64(),
+        });
+    }
+    Ok(())
+}
+
+fn limit_for_method(method: &str, limits: &Limits, rpc_limits: Option<&RpcLimits>) -> ByteUnit {
+    if let Some(v) = rpc_limits.and_then(|l| l.for_method(method).max_request_size()) {
+        return v.bytes();
+    }
     if let Some(v) = limits.get(method) {
         return v;
     }
@@ -211,6 +232,12 @@ pub struct RpcRequest<'r> {
     quote_verifier: Option<&'r QuoteVerifier>,
     origin: &'r Origin<'r>,
     limits: &'r Limits,
+    rpc_limits: Option<&'r RpcLimits>,
+    concurrency: Option<&'r RpcConcurrency>,
+    observers: Option<&'r ObserverTokens>,
+    admins: Option<&'r AdminTokens>,
+    disabled: Option<&'r DisabledMethods>,
+    bearer_token: Option<&'r str>,
     content_type: Option<&'r ContentType>,
     json: bool,
     is_get: bool,
@@ -227,6 +254,15 @@ impl<'r> FromRequest<'r> for RpcRequest<'r> {
             quote_verifier: from_request!(request),
             origin: from_request!(request),
             limits: from_request!(request),
+            rpc_limits: rocket::State::<RpcLimits>::get(request.rocket()),
+            concurrency: rocket::State::<RpcConcurrency>::get(request.rocket()),
+            observers: rocket::State::<ObserverTokens>::get(request.rocket()),
+            admins: rocket::State::<AdminTokens>::get(request.rocket()),
+            disabled: rocket::State::<DisabledMethods>::get(request.rocket()),
+            bearer_token: request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|v| v.strip_prefix("Bearer ")),
             content_type: from_request!(request),
             json: request.method() == Method::Get || query_field_get_bool(request, "json"),
             is_get: request.method() == Method::Get,
@@ -243,10 +279,21 @@ impl<S> PrpcHandler<'_, '_, S> {
             Err(e) => {
                 let estr = format!("{e:?}");
                 warn!("error handling prpc: {estr}");
+                let status = if let Some(e) = e.downcast_ref::<RpcLimitError>() {
+                    Status::new(e.status_code())
+                } else if let Some(e) = e.downcast_ref::<ObserverDenied>() {
+                    Status::new(e.status_code())
+                } else if let Some(e) = e.downcast_ref::<AdminRequired>() {
+                    Status::new(e.status_code())
+                } else if let Some(e) = e.downcast_ref::<MethodDisabled>() {
+                    Status::new(e.status_code())
+                } else {
+                    Status::BadRequest
+                };
                 let body = encode_error(json, estr);
                 RpcResponse {
                     is_json: json,
-                    status: Status::BadRequest,
+                    status,
                     body,
                 }
             }
@@ -285,6 +332,15 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         data,
     } = args;
     let method = method.trim_start_matches(method_trim_prefix.unwrap_or_default());
+    if let Some(disabled) = request.disabled {
+        disabled.check(method)?;
+    }
+    if let Some(observers) = request.observers {
+        observers.check(method, request.bearer_token)?;
+    }
+    if let Some(admins) = request.admins {
+        admins.check(method, request.bearer_token)?;
+    }
     let remote_app_id = request
         .certificate
         .as_ref()
@@ -313,17 +369,17 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         }
         _ => None,
     };
+    let limit = limit_for_method(method, request.limits, request.rpc_limits);
     let payload = match data {
-        Some(data) => {
-            let limit = limit_for_method(method, request.limits);
-            read_data(data, limit)
-                .await
-                .context("failed to read data")?
+        Some(data) => read_data(data, method, limit).await?,
+        None => {
+            let query = request
+                .origin
+                .query()
+                .map_or(vec![], |q| q.as_bytes().to_vec());
+            check_request_size(method, query.len() as u64, limit)?;
+            query
         }
-        None => request
-            .origin
-            .query()
-            .map_or(vec![], |q| q.as_bytes().to_vec()),
     };
     let is_json = request.json || request.content_type.map(|t| t.is_json()).unwrap_or(false);
     let context = CallContext {
@@ -333,9 +389,15 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         remote_app_id,
     };
     let call = Call::construct(context).context("failed to construct call")?;
-    let (status_code, output) = call
-        .call(method.to_string(), payload, is_json, request.is_get)
-        .await;
+    let _permit = match request.concurrency {
+        Some(concurrency) => concurrency.acquire(method).await?,
+        None => None,
+    };
+    let call = call.call(method.to_string(), payload, is_json, request.is_get);
+    let (status_code, output) = match request.rpc_limits {
+        Some(rpc_limits) => rpc_limits.run(method, call).await?,
+        None => call.await,
+    };
     Ok(RpcResponse {
         is_json,
         status: Status::new(status_code),
@@ -356,3 +418,47 @@ impl CertExt for RocketCertificate<'_> {
         Ok(Some(ext.value.to_vec()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::MethodLimits;
+
+    #[test]
+    fn request_size_limit_of_method() {
+        let limits = Limits::default().limit("Upload", 2.mebibytes());
+        assert_eq!(limit_for_method("Status", &limits, None), 10.mebibytes());
+        assert_eq!(limit_for_method("Upload", &limits, None), 2.mebibytes());
+
+        let rpc_limits = RpcLimits {
+            methods: [(
+                "Upload".to_string(),
+                MethodLimits {
+                    max_request_size: 4096,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            limit_for_method("Upload", &limits, Some(&rpc_limits)),
+            4096.bytes()
+        );
+        assert_eq!(
+            limit_for_method("Status", &limits, Some(&rpc_limits)),
+            10.mebibytes()
+        );
+    }
+
+    #[test]
+    fn request_over_the_limit_is_rejected() {
+        assert!(check_request_size("Status", 1024, 1024.bytes()).is_ok());
+        let err = check_request_size("Status", 1025, 1024.bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            RpcLimitError::RequestTooLarge { limit: 1024, .. }
+        ));
+        assert_eq!(err.status_code(), 413);
+    }
+}
@@ -8,12 +8,17 @@ use rocket::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use rocket::{Ignite, Rocket};
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::{io, task};
 use tokio_vsock as vsock;
 
 use serde::{de, Deserialize, Deserializer};
 use thiserror::Error;
 
+pub use stats::{ConnectionGuard, VsockConnectionStats, VsockStats, VsockStatsSnapshot};
+
+mod stats;
+
 #[derive(Debug, Error)]
 pub enum VsockError {
     #[error("IO error: {0}")]
@@ -35,6 +40,7 @@ pub enum VsockError {
 pub struct VsockListener {
     listener: vsock::VsockListener,
     endpoint: VsockEndpoint,
+    stats: Arc<VsockStats>,
 }
 pub struct VsockAccept;
 
@@ -43,6 +49,7 @@ pub struct VsockConnection {
     #[pin]
     stream: vsock::VsockStream,
     addr: vsock::VsockAddr,
+    guard: ConnectionGuard,
 }
 
 #[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
@@ -126,7 +133,13 @@ impl AsyncRead for VsockConnection {
         cx: &mut task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> task::Poll<io::Result<()>> {
-        self.project().stream.poll_read(cx, buf)
+        let this = self.project();
+        let filled = buf.filled().len();
+        let result = this.stream.poll_read(cx, buf);
+        if let task::Poll::Ready(Ok(())) = &result {
+            this.guard.record_read(buf.filled().len() - filled);
+        }
+        result
     }
 }
 
@@ -136,7 +149,12 @@ impl AsyncWrite for VsockConnection {
         cx: &mut task::Context<'_>,
         buf: &[u8],
     ) -> task::Poll<io::Result<usize>> {
-        self.project().stream.poll_write(cx, buf)
+        let this = self.project();
+        let result = this.stream.poll_write(cx, buf);
+        if let task::Poll::Ready(Ok(n)) = &result {
+            this.guard.record_write(*n);
+        }
+        result
     }
 
     fn poll_flush(
@@ -158,7 +176,12 @@ impl AsyncWrite for VsockConnection {
         cx: &mut task::Context<'_>,
         bufs: &[io::IoSlice<'_>],
     ) -> task::Poll<Result<usize, io::Error>> {
-        self.project().stream.poll_write_vectored(cx, bufs)
+        let this = self.project();
+        let result = this.stream.poll_write_vectored(cx, bufs);
+        if let task::Poll::Ready(Ok(n)) = &result {
+            this.guard.record_write(*n);
+        }
+        result
     }
 
     fn is_write_vectored(&self) -> bool {
@@ -167,18 +190,35 @@ impl AsyncWrite for VsockConnection {
 }
 
 impl Listener for VsockListener {
-    type Accept = (vsock::VsockStream, vsock::VsockAddr);
+    type Accept = (vsock::VsockStream, vsock::VsockAddr, ConnectionGuard);
 
     type Connection = VsockConnection;
 
     async fn accept(&self) -> io::Result<Self::Accept> {
-        let (stream, addr) = self.listener.accept().await?;
-        Ok((stream, addr))
+        loop {
+            let (stream, addr) = self.listener.accept().await.inspect_err(|_| {
+                self.stats.record_error();
+            })?;
+            let peer = VsockEndpoint {
+                cid: addr.cid(),
+                port: addr.port(),
+            };
+            // Registered here rather than in `connect` so that the limits hold while
+            // rocket is still handing out accepted streams.
+            match self.stats.open_connection(peer) {
+                Some(guard) => return Ok((stream, addr, guard)),
+                None => drop(stream),
+            }
+        }
     }
 
     async fn connect(&self, accept: Self::Accept) -> io::Result<Self::Connection> {
-        let (stream, addr) = accept;
-        Ok(VsockConnection { stream, addr })
+        let (stream, addr, guard) = accept;
+        Ok(VsockConnection {
+            stream,
+            addr,
+            guard,
+        })
     }
 
     fn endpoint(&self) -> io::Result<Endpoint> {
@@ -190,12 +230,36 @@ impl VsockListener {
     pub fn bind(endpoint: &VsockEndpoint) -> Result<Self, VsockError> {
         let addr = vsock::VsockAddr::new(endpoint.cid, endpoint.port);
         let listener = vsock::VsockListener::bind(addr)?;
+        let stats = Arc::new(VsockStats::new());
+        stats.set_endpoint(*endpoint);
         Ok(Self {
             listener,
             endpoint: *endpoint,
+            stats,
         })
     }
 
+    /// Record the connection stats into `stats`, which can be kept by the caller.
+    pub fn with_stats(mut self, stats: Arc<VsockStats>) -> Self {
+        stats.set_endpoint(self.endpoint);
+        self.stats = stats;
+        self
+    }
+
+    /// Close connections right after accept while `max_connections` are open, or
+    /// `max_connections_per_cid` are open from the same peer CID. 0 means unlimited.
+    ///
+    /// The limits are stored in the stats, so call this after [`Self::with_stats`].
+    pub fn with_limits(self, max_connections: usize, max_connections_per_cid: usize) -> Self {
+        self.stats
+            .set_limits(max_connections, max_connections_per_cid);
+        self
+    }
+
+    pub fn stats(&self) -> Arc<VsockStats> {
+        self.stats.clone()
+    }
+
     pub fn extract_endpoint(rocket: &Rocket<Ignite>) -> Result<VsockEndpoint, VsockError> {
         let figment = rocket.figment();
         let endpoint = figment
@@ -267,6 +331,66 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_stats_snapshot() {
+        let stats = Arc::new(VsockStats::new());
+        stats.set_endpoint(VsockEndpoint {
+            cid: 2,
+            port: 10000,
+        });
+        let peer = VsockEndpoint {
+            cid: 1000,
+            port: 1234,
+        };
+        let guard = stats.open_connection(peer).unwrap();
+        guard.record_read(10);
+        guard.record_write(20);
+        stats.record_error();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.endpoint,
+            Some(VsockEndpoint {
+                cid: 2,
+                port: 10000
+            })
+        );
+        assert_eq!(snapshot.accepted, 1);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.bytes_read, 10);
+        assert_eq!(snapshot.bytes_written, 20);
+        assert_eq!(snapshot.connections.len(), 1);
+        assert_eq!(snapshot.connections[0].peer, peer);
+        assert_eq!(snapshot.connections[0].bytes_written, 20);
+
+        drop(guard);
+        let snapshot = stats.snapshot();
+        assert!(snapshot.connections.is_empty());
+        assert_eq!(snapshot.bytes_read, 10);
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let stats = Arc::new(VsockStats::new());
+        stats.set_limits(3, 2);
+        let peer = |cid, port| VsockEndpoint { cid, port };
+
+        let a1 = stats.open_connection(peer(3, 1)).unwrap();
+        let _a2 = stats.open_connection(peer(3, 2)).unwrap();
+        assert!(stats.open_connection(peer(3, 3)).is_none());
+        let _b1 = stats.open_connection(peer(4, 1)).unwrap();
+        assert!(stats.open_connection(peer(5, 1)).is_none());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted, 3);
+        assert_eq!(snapshot.rejected, 2);
+        assert_eq!(snapshot.connections_by_cid().get(&3), Some(&2));
+        assert_eq!(snapshot.connections_by_cid().get(&4), Some(&1));
+
+        drop(a1);
+        assert!(stats.open_connection(peer(3, 4)).is_some());
+    }
+
     #[test]
     fn test_display_format() {
         let endpoint = VsockEndpoint { cid: 1, port: 5000 };
@@ -0,0 +1,183 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::VsockEndpoint;
+
+/// Connection counters of a [`crate::VsockListener`].
+///
+/// The stats can be shared with the application before the listener is moved into rocket.
+#[derive(Debug, Default)]
+pub struct VsockStats {
+    endpoint: OnceLock<VsockEndpoint>,
+    next_id: AtomicU64,
+    accepted: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    max_connections: AtomicUsize,
+    max_connections_per_cid: AtomicUsize,
+    connections: Mutex<BTreeMap<u64, Arc<ConnectionCounters>>>,
+}
+
+#[derive(Debug)]
+struct ConnectionCounters {
+    peer: VsockEndpoint,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+/// Point-in-time copy of [`VsockStats`].
+#[derive(Debug, Clone, Default)]
+pub struct VsockStatsSnapshot {
+    /// The bound endpoint, `None` if the listener has not been bound yet
+    pub endpoint: Option<VsockEndpoint>,
+    pub accepted: u64,
+    pub errors: u64,
+    /// Connections closed right after accept for exceeding a limit
+    pub rejected: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Limit on the open connections, 0 if unlimited
+    pub max_connections: usize,
+    /// Limit on the open connections from a single peer CID, 0 if unlimited
+    pub max_connections_per_cid: usize,
+    /// Currently open connections
+    pub connections: Vec<VsockConnectionStats>,
+}
+
+impl VsockStatsSnapshot {
+    /// Number of open connections per peer CID.
+    pub fn connections_by_cid(&self) -> BTreeMap<u32, usize> {
+        let mut counts = BTreeMap::new();
+        for conn in &self.connections {
+            *counts.entry(conn.peer.cid).or_default() += 1;
+        }
+        counts
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VsockConnectionStats {
+    pub peer: VsockEndpoint,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl VsockStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn set_endpoint(&self, endpoint: VsockEndpoint) {
+        let _ = self.endpoint.set(endpoint);
+    }
+
+    pub(crate) fn set_limits(&self, max_connections: usize, max_connections_per_cid: usize) {
+        self.max_connections
+            .store(max_connections, Ordering::Relaxed);
+        self.max_connections_per_cid
+            .store(max_connections_per_cid, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Register a connection from `peer`, or count it as rejected and return `None` if
+    /// that would exceed the global or per-CID limit.
+    pub(crate) fn open_connection(
+        self: &Arc<Self>,
+        peer: VsockEndpoint,
+    ) -> Option<ConnectionGuard> {
+        let max_connections = self.max_connections.load(Ordering::Relaxed);
+        let max_per_cid = self.max_connections_per_cid.load(Ordering::Relaxed);
+        let mut connections = self.connections.lock().unwrap();
+        let over_global = max_connections > 0 && connections.len() >= max_connections;
+        let over_per_cid = max_per_cid > 0
+            && connections
+                .values()
+                .filter(|c| c.peer.cid == peer.cid)
+                .count()
+                >= max_per_cid;
+        if over_global || over_per_cid {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let counters = Arc::new(ConnectionCounters {
+            peer,
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        });
+        connections.insert(id, counters.clone());
+        Some(ConnectionGuard {
+            stats: self.clone(),
+            counters,
+            id,
+        })
+    }
+
+    pub fn snapshot(&self) -> VsockStatsSnapshot {
+        let connections = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| VsockConnectionStats {
+                peer: c.peer,
+                bytes_read: c.bytes_read.load(Ordering::Relaxed),
+                bytes_written: c.bytes_written.load(Ordering::Relaxed),
+            })
+            .collect();
+        VsockStatsSnapshot {
+            endpoint: self.endpoint.get().copied(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            max_connections: self.max_connections.load(Ordering::Relaxed),
+            max_connections_per_cid: self.max_connections_per_cid.load(Ordering::Relaxed),
+            connections,
+        }
+    }
+}
+
+/// Tracks a single connection, unregistering it from the stats when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    stats: Arc<VsockStats>,
+    counters: Arc<ConnectionCounters>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn record_read(&self, n: usize) {
+        self.counters
+            .bytes_read
+            .fetch_add(n as u64, Ordering::Relaxed);
+        self.stats.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write(&self, n: usize) {
+        self.counters
+            .bytes_written
+            .fetch_add(n as u64, Ordering::Relaxed);
+        self.stats
+            .bytes_written
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.connections.lock().unwrap().remove(&self.id);
+    }
+}
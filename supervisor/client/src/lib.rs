@@ -2,7 +2,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{path::Path, sync::Arc, time::Duration};
+use std::{
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
 use http_client::http_request;
@@ -11,18 +16,57 @@ use supervisor::{ProcessConfig, ProcessInfo, Response};
 
 pub use supervisor;
 
+/// A supervisor call did not complete within the timeout set by [`SupervisorClient::with_timeout`].
+///
+/// Returned inside `anyhow::Error`, use `downcast_ref` to tell it apart from connection errors.
+#[derive(Debug, Clone)]
+pub struct SupervisorTimeout {
+    pub method: String,
+    pub path: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for SupervisorTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Supervisor call {} {} timed out after {:?}",
+            self.method, self.path, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for SupervisorTimeout {}
+
+/// How long a supervisor started by [`SupervisorClient::start_and_connect_uds`] has to come up.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct SupervisorClient {
     base_url: Arc<String>,
+    timeout: Option<Duration>,
 }
 
 impl SupervisorClient {
     pub fn new(base_url: &str) -> Self {
         SupervisorClient {
             base_url: Arc::new(base_url.to_string()),
+            timeout: None,
         }
     }
 
+    /// Fail calls that take longer than `timeout` with [`SupervisorTimeout`]. Zero disables it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = (!timeout.is_zero()).then_some(timeout);
+        self
+    }
+
+    /// Connect to the supervisor listening on `uds`, starting it if `auto_start` is set and
+    /// it does not answer.
+    ///
+    /// A supervisor started by someone else may come up after us, so it is probed with
+    /// backoff for up to `connect_timeout` before it is started or given up on. Zero probes
+    /// once.
     pub async fn start_and_connect_uds(
         supervisor_path: impl AsRef<Path>,
         uds: impl AsRef<Path>,
@@ -30,10 +74,11 @@ impl SupervisorClient {
         log_file: impl AsRef<Path>,
         detached: bool,
         auto_start: bool,
+        connect_timeout: Duration,
     ) -> Result<Self> {
         let uri = format!("unix:{}", uds.as_ref().display());
         let client = Self::new(&uri);
-        if client.probe(Duration::from_millis(100)).await.is_ok() {
+        if client.wait_ready(connect_timeout).await {
             info!("Connected to supervisor at {uri}");
             return Ok(client);
         }
@@ -75,18 +120,39 @@ impl SupervisorClient {
                 );
             }
         });
-        // wait while ping returns pong
-        for i in 1..=10 {
-            if client.probe(Duration::from_millis(100)).await.is_ok() {
-                info!("connected to supervisor at {uri}");
-                return Ok(client);
-            }
-            info!("waiting for supervisor at {uri} to start, attempt {i}");
-            tokio::time::sleep(Duration::from_millis(100 * i)).await;
+        if client
+            .wait_ready(connect_timeout.max(STARTUP_TIMEOUT))
+            .await
+        {
+            info!("connected to supervisor at {uri}");
+            return Ok(client);
         }
         anyhow::bail!("failed to connect to supervisor at {uri}");
     }
 
+    /// Probe the supervisor until it answers or `timeout` elapses, backing off between
+    /// attempts. Probes at least once.
+    async fn wait_ready(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut delay = Duration::from_millis(100);
+        for attempt in 1.. {
+            if self.probe(Duration::from_millis(100)).await.is_ok() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            info!(
+                "waiting for supervisor at {}, attempt {attempt} failed",
+                self.base_url
+            );
+            tokio::time::sleep(delay.min(deadline - now)).await;
+            delay = (delay * 2).min(Duration::from_secs(2));
+        }
+        false
+    }
+
     async fn http_request<T: serde::de::DeserializeOwned, B: serde::Serialize>(
         &self,
         method: &str,
@@ -97,8 +163,18 @@ impl SupervisorClient {
             "POST" | "PUT" | "PATCH" => serde_json::to_vec(&body)?,
             _ => vec![],
         };
+        let request = http_request(method, &self.base_url, path, &body_bytes);
         let (status, response_bytes) =
-            http_request(method, &self.base_url, path, &body_bytes).await?;
+            match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, request).await.map_err(|_| {
+                    SupervisorTimeout {
+                        method: method.to_string(),
+                        path: path.to_string(),
+                        timeout,
+                    }
+                })??,
+                None => request.await?,
+            };
         if status != 200 {
             anyhow::bail!("Server returned error: {}", status);
         }
@@ -2,9 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::path::PathBuf;
+
 fn main() {
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     prpc_build::configure()
-        .out_dir(std::env::var_os("OUT_DIR").unwrap())
+        .out_dir(&out_dir)
+        .file_descriptor_set_path(out_dir.join("file_descriptor_set.bin"))
         .mod_prefix("super::")
         .build_scale_ext(false)
         .disable_package_emission()
@@ -0,0 +1,62 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The compiled definitions of the services, for clients generated at runtime.
+use prost::Message;
+
+/// Serialized `google.protobuf.FileDescriptorSet` of the proto files of this crate and their
+/// imports, written by the build script from the same definitions the services are generated
+/// from.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/file_descriptor_set.bin"));
+
+// The parts of `google/protobuf/descriptor.proto` needed to list the services, other fields
+// are skipped when decoding.
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorSet {
+    #[prost(message, repeated, tag = "1")]
+    file: Vec<FileDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorProto {
+    #[prost(string, tag = "2")]
+    package: String,
+    #[prost(message, repeated, tag = "6")]
+    service: Vec<ServiceDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServiceDescriptorProto {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(message, repeated, tag = "2")]
+    method: Vec<MethodDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MethodDescriptorProto {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+/// Full names of the services in [`FILE_DESCRIPTOR_SET`] with the names of their methods.
+pub fn service_methods() -> Result<Vec<(String, Vec<String>)>, prost::DecodeError> {
+    let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET)?;
+    Ok(set
+        .file
+        .into_iter()
+        .flat_map(|file| {
+            let package = file.package;
+            file.service.into_iter().map(move |service| {
+                let name = if package.is_empty() {
+                    service.name
+                } else {
+                    format!("{package}.{}", service.name)
+                };
+                (name, service.method.into_iter().map(|m| m.name).collect())
+            })
+        })
+        .collect())
+}
@@ -4,6 +4,8 @@
 
 extern crate alloc;
 
+pub use descriptor::{service_methods, FILE_DESCRIPTOR_SET};
 pub use generated::*;
 
+mod descriptor;
 mod generated;
@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::config::{Config, ProcessAnnotation, Protocol};
+use crate::metrics::RpcLatency;
+use crate::webhook::Webhooks;
 
 use anyhow::{bail, Context, Result};
 use bon::Builder;
@@ -15,21 +17,138 @@ use fs_err as fs;
 use guest_api::client::DefaultClient as GuestClient;
 use id_pool::IdPool;
 use ra_rpc::client::RaClient;
+use ra_rpc::limits::RpcConcurrency;
+use rocket::figment::Figment;
+use rocket_vsock_listener::VsockStats;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use supervisor_client::SupervisorClient;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+pub use adopt::AdoptSource;
+use attestation::{AttestationCheck, READY_PROGRESS};
+use boot_secret::BootSecrets;
+pub use capabilities::{CapabilityCache, HostCapabilities};
+pub use config_signature::{
+    upgrade_signed_message, verify_config_signature, vm_config_signed_message,
+};
+pub use cpu::{resolve_cpu, CpuConfig};
+pub use disk::{probe_qemu_aio, resolve_disks, DiskAio, DiskConfig, IoThrottle};
+pub use disk_source::DiskSource;
+pub use disk_space::check_free_space;
+pub use display::{allocate_display, resolve_display, DisplayConfig, DisplayEndpoint};
+use drain::DrainState;
+pub use error::VmmError;
+pub use events::EventBuffer;
+pub use guest_token::token_fingerprint;
+pub use hooks::{resolve_hooks, LifecycleHooks};
+use image::check_image_name;
 pub use image::{Image, ImageInfo};
-pub use qemu::{VmConfig, VmWorkDir};
+use listing::{sort_vms, ListSnapshots, Selector, SortKey};
+use mac::check_unique_macs;
+pub use mac::parse_mac;
+pub use machine::{resolve_machine, MachineConfig, DEFAULT_MACHINE_TYPE};
+pub use memory::{check_memlock, MemoryOptions};
+use network_group::check_network_isolation;
+pub use network_group::validate_network_group;
+pub use nic::{resolve_nics, NicConfig};
+pub use pci::{devices_not_bound_to_vfio, resolve_pci_devices};
+pub use platform_certs::PlatformCertCache;
+use ports::{vmm_ports, HostPort, PortRegistry};
+pub use provenance::VmOrigin;
+pub use qemu::{truncate_line, MrConfigInputs, SideFile, VmConfig, VmWorkDir};
+pub use qmp::{QmpClient, QMP_STARTUP_WINDOW};
+pub use qmp_events::{resolve_qmp_events, QmpEventsConfig};
+pub use readconfig::{argv_bytes, uses_readconfig};
+pub use reconcile::ReconcileReport;
+use reservation::Reservation;
+use restart::RestartState;
+pub use restart::{AutoRestartParams, ExitCodes, RestartPolicy};
+pub use scheduling::{resolve_scheduling, SchedulingHints};
+pub use test_determinism::{resolve_test_determinism, TestDeterminism};
+pub use usage::UsageSampler;
+pub use user_net::{resolve_user_net, UserNetAddressing};
+pub use validate::{validation_error, validation_warning};
+pub use watchdog::{WatchdogAction, WatchdogPolicy};
+pub use watchdog_device::{resolve_watchdog_device, WatchdogDevice};
 
+mod adopt;
+mod attestation;
+mod balloon;
+mod base_image;
+mod boot_disk;
+mod boot_secret;
+mod capabilities;
+mod checkpoint;
+mod config_diff;
+mod config_drive;
+mod config_signature;
+mod cpu;
+mod defunct;
+mod diagnostics;
+mod disk;
+mod disk_snapshot;
+mod disk_source;
+mod disk_space;
+mod display;
+mod drain;
+mod error;
+mod events;
+mod guest_agent;
+mod guest_reboot;
+mod guest_token;
+mod hmp;
+mod hooks;
 mod id_pool;
 mod image;
+mod inventory;
+mod launch_digest;
+mod lifetime;
+mod listing;
+mod mac;
+mod machine;
+mod measurement;
+mod memory;
+mod migration;
+mod net_devices;
+mod net_link;
+mod net_stats;
+mod network_group;
+mod nic;
+mod pci;
+mod platform_certs;
+mod ports;
+mod probe;
+mod provenance;
 mod qemu;
+mod qmp;
+mod qmp_events;
+mod readconfig;
+mod reconcile;
+mod reliability;
+mod replace;
+mod reservation;
+mod restart;
+mod revalidate;
+mod runtime_params;
+mod scheduling;
+mod serial_log;
+mod stderr_events;
+mod stop;
+mod test_determinism;
+mod time_sync;
+mod usage;
+mod user_net;
+mod validate;
+mod warmup;
+mod watchdog;
+mod watchdog_device;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PortMapping {
@@ -54,12 +173,211 @@ pub struct Manifest {
     pub hugepages: bool,
     #[serde(default)]
     pub pin_numa: bool,
+    /// Attach a QEMU guest agent channel, if the host allows it
+    #[serde(default)]
+    pub guest_agent: bool,
+    /// Preallocation and locking of guest memory, lazily allocated and swappable if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_options: Option<MemoryOptions>,
+    /// QEMU machine type, the newest `q35` of the host QEMU if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine: Option<MachineConfig>,
+    /// QMP events forwarded as VM events and the guest panic action, defaults if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qmp_events: Option<QmpEventsConfig>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub gpus: Option<GpuConfig>,
     #[serde(default)]
     pub kms_urls: Vec<String>,
     #[serde(default)]
     pub gateway_urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[builder(default)]
+    pub disks: Vec<DiskConfig>,
+    /// Kernel for direct kernel boot, overriding the one of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    /// Initrd for direct kernel boot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<String>,
+    /// Kernel cmdline, overriding the one of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    /// The effective cmdline must equal this exactly for the VM to launch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_cmdline: Option<String>,
+    /// QEMU `-name`, the VM name if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qemu_name: Option<String>,
+    /// Host PCI devices passed through with VFIO
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[builder(default)]
+    pub pci_devices: Vec<String>,
+    /// RTC settings, QEMU defaults (UTC, host clock) if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtc: Option<RtcConfig>,
+    /// Network settings. DNS is only supported with user-mode networking
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<VmNetworkConfig>,
+    /// VNC/SPICE display output, none if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<DisplayConfig>,
+    /// Host CPU affinity and weight
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuConfig>,
+    /// When the VM is restarted after exiting, `unless-stopped` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    /// Exit codes counted as a clean exit or a failure by `on-failure`, only 0 is clean if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_codes: Option<ExitCodes>,
+    /// Action on missed guest heartbeats, unwatched if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<WatchdogPolicy>,
+    /// Emulated hardware watchdog, none if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog_device: Option<WatchdogDevice>,
+    /// Seconds the guest may take to report ready before diagnostics are captured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_timeout: Option<u64>,
+    /// Seconds the VM may run after QEMU is launched before it is stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lifetime: Option<u64>,
+    /// Network isolation group, VMs of different groups never share an L2 domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_group: Option<String>,
+    /// NICs attached after the one of `cvm.networking`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[builder(default)]
+    pub nics: Vec<NicConfig>,
+    /// Name of the signing key that verified the VM definition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+    /// Host commands run around the lifecycle of the VM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<LifecycleHooks>,
+    /// Placement hints for external schedulers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<SchedulingHints>,
+    /// Fixed RNG seed and RTC start for reproducible test runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_determinism: Option<TestDeterminism>,
+    /// Image the boot disk was last swapped from, the one `SwapBootDisk` reverts to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_image: Option<String>,
+    /// Where the VM definition came from, the API if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VmOrigin>,
+}
+
+#[cfg(test)]
+impl Manifest {
+    /// A minimal manifest of VM `id`, for tests.
+    pub(crate) fn for_test(id: &str) -> Self {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": id,
+            "app_id": "0000000000000000000000000000000000000000",
+            "vcpu": 2,
+            "memory": 2048,
+            "disk_size": 20,
+            "image": "dstack-0.5.0",
+            "port_map": [],
+            "created_at_ms": 0,
+        }))
+        .expect("valid test manifest")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct VmNetworkConfig {
+    /// Guest-visible address of the built-in DNS server, at most one IPv4 and one IPv6
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// Hostname of the guest, also handed out by DHCP with user-mode networking
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// MAC address of the NIC, derived from the VM id if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// User-mode network, gateway and guest address, the host-wide ones if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addressing: Option<UserNetAddressing>,
+}
+
+impl VmNetworkConfig {
+    /// Options appended to the `-netdev user` argument.
+    pub fn slirp_opts(&self) -> String {
+        let mut opts = String::new();
+        for dns in &self.dns {
+            match dns {
+                IpAddr::V4(addr) => opts.push_str(&format!(",dns={addr}")),
+                IpAddr::V6(addr) => opts.push_str(&format!(",ipv6-dns={addr}")),
+            }
+        }
+        if let Some(hostname) = &self.hostname {
+            opts.push_str(&format!(",hostname={hostname}"));
+        }
+        opts
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcBase {
+    #[default]
+    Utc,
+    Localtime,
+}
+
+impl RtcBase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtcBase::Utc => "utc",
+            RtcBase::Localtime => "localtime",
+        }
+    }
+}
+
+/// The clock driving the guest RTC.
+///
+/// In a CVM the RTC is emulated by the untrusted host whatever the clock is, so guests that
+/// need trustworthy time must not rely on it (see `secure_time` in the app compose).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcClock {
+    /// Host system time, keeps running while the VM is paused
+    #[default]
+    Host,
+    /// Guest virtual clock, stops while the VM is paused
+    Vm,
+    /// Host monotonic clock, not affected by host time adjustments
+    Rt,
+}
+
+impl RtcClock {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtcClock::Host => "host",
+            RtcClock::Vm => "vm",
+            RtcClock::Rt => "rt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct RtcConfig {
+    #[serde(default)]
+    pub base: RtcBase,
+    #[serde(default)]
+    pub clock: RtcClock,
+}
+
+impl RtcConfig {
+    /// Value of the QEMU `-rtc` option.
+    pub fn qemu_opts(&self) -> String {
+        format!("base={},clock={}", self.base.as_str(), self.clock.as_str())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -112,7 +430,23 @@ pub struct GpuSpec {
 #[derive(Clone)]
 pub struct App {
     pub config: Arc<Config>,
+    /// The layered config sources `config` was extracted from
+    pub figment: Arc<Figment>,
     pub supervisor: SupervisorClient,
+    /// Connection stats of the host API vsock listener
+    pub vsock_stats: Arc<VsockStats>,
+    pub webhooks: Arc<Webhooks>,
+    pub capabilities: Arc<CapabilityCache>,
+    /// Attestation certificate chain of the TEE platform
+    pub platform_certs: Arc<PlatformCertCache>,
+    /// Recent lifecycle events
+    pub events: Arc<EventBuffer>,
+    /// Calls in progress of the RPC methods with a concurrency limit
+    pub rpc_concurrency: RpcConcurrency,
+    /// Latency of the external RPC calls
+    pub rpc_latency: Arc<RpcLatency>,
+    /// External source of the VMs of this host, if configured
+    inventory: Option<Arc<Inventory>>,
     state: Arc<Mutex<AppState>>,
 }
 
@@ -129,17 +463,46 @@ impl App {
         VmWorkDir::new(self.config.run_path.join(id))
     }
 
-    pub fn new(config: Config, supervisor: SupervisorClient) -> Self {
+    pub fn new(config: Config, figment: Figment, supervisor: SupervisorClient) -> Self {
         let cid_start = config.cvm.cid_start;
         let cid_end = cid_start.saturating_add(config.cvm.cid_pool_size);
         let cid_pool = IdPool::new(cid_start, cid_end);
+        let mut ports = PortRegistry::default();
+        if let Err(err) = ports.claim_all(vmm_ports(&config, &figment)) {
+            error!("Failed to register the ports of the VMM: {err:?}");
+        }
         Self {
             supervisor: supervisor.clone(),
+            vsock_stats: Arc::new(VsockStats::new()),
+            webhooks: Arc::new(Webhooks::new(config.webhook.clone())),
+            events: Arc::new(EventBuffer::new(config.events.clone())),
+            rpc_concurrency: RpcConcurrency::new(config.rpc_limits.clone()),
+            rpc_latency: Arc::new(RpcLatency::default()),
+            inventory: Inventory::from_config(&config.inventory).map(Arc::new),
+            capabilities: Arc::new(CapabilityCache::new(
+                config.cvm.qemu_path.clone(),
+                match config.cvm.capabilities_refresh {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+            )),
+            platform_certs: Arc::new(PlatformCertCache::new(config.platform_certs.clone())),
             state: Arc::new(Mutex::new(AppState {
                 cid_pool,
                 vms: HashMap::new(),
+                drain: DrainState::default(),
+                maintenance_mode: config.cvm.auto_restart.maintenance_mode,
+                auto_restart_params: AutoRestartParams::from_config(&config.cvm.auto_restart),
+                boot_secrets: HashMap::new(),
+                reservations: HashMap::new(),
+                ports,
+                warmed_images: HashMap::new(),
+                list_snapshots: ListSnapshots::default(),
+                last_reconcile: ReconcileReport::default(),
+                qmp_watchers: HashSet::new(),
             })),
             config: Arc::new(config),
+            figment: Arc::new(figment),
         }
     }
 
@@ -150,24 +513,44 @@ impl App {
         auto_start: bool,
     ) -> Result<()> {
         let vm_work_dir = VmWorkDir::new(work_dir.as_ref());
+        let manifest_mtime = provenance::manifest_mtime(&vm_work_dir);
         let manifest = vm_work_dir.manifest().context("Failed to read manifest")?;
-        if manifest.image.len() > 64
-            || manifest.image.contains("..")
-            || !manifest
-                .image
-                .chars()
-                .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
-        {
-            bail!("Invalid image name");
-        }
+        check_image_name(&manifest.image)?;
         let image_path = self.config.image_path.join(&manifest.image);
         let image = Image::load(&image_path).context("Failed to load image")?;
         let vm_id = manifest.id.clone();
+        let start_always = manifest.restart_policy == Some(RestartPolicy::Always);
         let app_compose = vm_work_dir
             .app_compose()
             .context("Failed to read compose file")?;
+        let guest_token = vm_work_dir
+            .guest_api_token()
+            .context("Failed to load guest token")?;
         {
             let mut states = self.lock();
+            for addr in &manifest.pci_devices {
+                if let Some(owner) = states.pci_device_owner(addr, &vm_id) {
+                    bail!("PCI device {addr} is already claimed by VM {owner}");
+                }
+            }
+            if let Some(port) = manifest.display.map(|d| d.port).filter(|p| *p != 0) {
+                if let Some(owner) = states.display_port_owner(port, &vm_id) {
+                    bail!("Display port {port} is already claimed by VM {owner}");
+                }
+            }
+            if let Some(addressing) = manifest.network.as_ref().and_then(|n| n.addressing) {
+                if let Some(owner) = states.user_net_owner(&addressing, &vm_id) {
+                    bail!(
+                        "Network {} overlaps the network of VM {owner}",
+                        addressing.net
+                    );
+                }
+            }
+            for ifname in manifest.nics.iter().filter_map(|n| n.ifname.as_ref()) {
+                if let Some(owner) = states.tap_owner(ifname, &vm_id) {
+                    bail!("Tap interface {ifname} is already used by VM {owner}");
+                }
+            }
             let cid = states
                 .get(&vm_id)
                 .map(|vm| vm.config.cid)
@@ -184,29 +567,111 @@ impl App {
             match states.get_mut(&vm_id) {
                 Some(vm) => {
                     vm.config = vm_config.into();
+                    vm.guest_token = guest_token;
+                    vm.loaded_at = SystemTime::now();
+                    vm.manifest_mtime = manifest_mtime;
                 }
                 None => {
-                    states.add(VmState::new(vm_config));
+                    let mut vm = VmState::new(vm_config, guest_token);
+                    vm.manifest_mtime = manifest_mtime;
+                    states.add(vm);
                 }
             }
         };
-        if auto_start && vm_work_dir.started().unwrap_or_default() {
+        if auto_start && (start_always || vm_work_dir.started().unwrap_or_default()) {
             self.start_vm(&vm_id).await?;
         }
         Ok(())
     }
 
+    /// Write the work dir of a new VM from its manifest and config and load it, starting it
+    /// if `start`. The work dir is removed if the VM fails to load or start.
+    pub(crate) async fn create_vm(
+        &self,
+        manifest: Manifest,
+        config: &VmConfiguration,
+        start: bool,
+    ) -> Result<()> {
+        self.ensure_mac_unused(&manifest)?;
+        let id = manifest.id.clone();
+        let vm_work_dir = self.work_dir(&id);
+        vm_work_dir
+            .put_manifest(&manifest)
+            .context("Failed to write manifest")?;
+        let work_dir = self.prepare_work_dir(&id, config, &manifest.app_id)?;
+        if let Err(err) = vm_work_dir.set_started(start) {
+            warn!("Failed to set started: {}", err);
+        }
+
+        let result = self
+            .load_vm(&work_dir, &Default::default(), false)
+            .await
+            .context("Failed to load VM");
+        let result = match result {
+            Ok(()) => {
+                if start {
+                    self.start_vm(&id).await
+                } else {
+                    Ok(())
+                }
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            if let Err(err) = fs::remove_dir_all(&work_dir) {
+                warn!("Failed to remove work dir: {}", err);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
     pub async fn start_vm(&self, id: &str) -> Result<()> {
+        self.launch_vm(id, None).await
+    }
+
+    /// Start a VM, with QEMU waiting for a migration stream on `incoming` if set.
+    pub(crate) async fn launch_vm(&self, id: &str, incoming: Option<&str>) -> Result<()> {
         self.sync_dynamic_config(id)?;
         let is_running = self
             .supervisor
             .info(id)
             .await?
             .is_some_and(|info| info.state.status.is_running());
+        if !is_running {
+            self.preflight().await?;
+            self.check_machine(id).await?;
+            self.ensure_vm_capacity(id).await?;
+            self.run_pre_start_hook(id)
+                .await
+                .context("Launch aborted by pre_start hook")?;
+            self.provision_disk_key(id)?;
+            let image = self
+                .lock()
+                .get(id)
+                .map(|vm| vm.config.manifest.image.clone());
+            if let Some(image) = image {
+                self.warm_image_for_launch(&image).await;
+            }
+        }
         self.set_started(id, true)?;
+        let stderr_len = fs::metadata(self.work_dir(id).stderr_file()).map_or(0, |m| m.len());
         let vm_config = {
             let mut state = self.lock();
             let vm_state = state.get_mut(id).context("VM not found")?;
+            // Per-launch state is reset for a new QEMU, one already running is watched for its exit
+            if !is_running {
+                vm_state.boot_guest_token = None;
+                vm_state.state.balloon_target = None;
+                vm_state.state.network_disabled = false;
+                vm_state.state.attestation = None;
+                // What the new QEMU writes is scanned from its first line
+                vm_state.state.stderr_offset = Some(stderr_len);
+                vm_state.state.lifetime_extension = Duration::ZERO;
+                vm_state.state.lifetime_expired = false;
+            } else {
+                vm_state.state.post_stop_pending = true;
+            }
             // Older images does not support for progress reporting
             if vm_state.config.image.info.shared_ro {
                 vm_state.state.start(is_running);
@@ -222,28 +687,162 @@ impl App {
             }
 
             let work_dir = self.work_dir(id);
-            for path in [work_dir.serial_pty(), work_dir.qmp_socket()] {
+            for path in [
+                work_dir.serial_pty(),
+                work_dir.qmp_socket(),
+                work_dir.hmp_socket(),
+            ] {
                 if path.symlink_metadata().is_ok() {
                     fs::remove_file(path)?;
                 }
             }
 
+            adopt::clear_adoption(&work_dir);
+            self.prepare_network_group(&vm_config.manifest)?;
             let devices = self.try_allocate_gpus(&vm_config.manifest)?;
-            let processes = vm_config.config_qemu(&work_dir, &self.config.cvm, &devices)?;
-            for process in processes {
-                self.supervisor
-                    .deploy(&process)
-                    .await
-                    .with_context(|| format!("Failed to start process {}", process.id))?;
+            let display = self.try_allocate_display(&vm_config.manifest)?;
+            let host_ports = vm_config
+                .manifest
+                .port_map
+                .iter()
+                .map(|pm| {
+                    let port = HostPort {
+                        protocol: pm.protocol,
+                        address: pm.address,
+                        port: pm.from,
+                    };
+                    (port, "port mapping")
+                })
+                .chain(
+                    display
+                        .iter()
+                        .map(|d| (HostPort::tcp(d.address, d.port), "display")),
+                )
+                .collect::<Vec<_>>();
+            self.lock().claim_vm_ports(id, host_ports)?;
+            // Any failure from here on releases the ports and stops what was deployed
+            let mut deployed = vec![];
+            let result = async {
+                let mut processes = vm_config.config_qemu(
+                    &work_dir,
+                    &self.config.cvm,
+                    &devices,
+                    display.as_ref(),
+                )?;
+                if let Some(incoming) = incoming {
+                    let qemu = processes
+                        .iter_mut()
+                        .find(|p| p.id == id)
+                        .context("QEMU process not found")?;
+                    qemu.args.extend(["-incoming".into(), incoming.into()]);
+                }
+                for process in processes {
+                    self.supervisor
+                        .deploy(&process)
+                        .await
+                        .with_context(|| format!("Failed to start process {}", process.id))?;
+                    deployed.push(process.id);
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(err) = result {
+                for process_id in deployed.iter().rev() {
+                    if let Err(stop_err) = self.supervisor.stop(process_id).await {
+                        warn!("Failed to stop process {process_id} of failed launch: {stop_err:?}");
+                    }
+                }
+                self.release_vm_ports(id);
+                return Err(err);
             }
 
-            let mut state = self.lock();
-            let vm_state = state.get_mut(id).context("VM not found")?;
-            vm_state.state.devices = devices;
+            {
+                let mut state = self.lock();
+                let vm_state = state.get_mut(id).context("VM not found")?;
+                vm_state.state.devices = devices;
+                vm_state.state.display = display;
+                vm_state.state.qmp_expected = self.config.cvm.qmp_socket;
+                vm_state.state.post_stop_pending = true;
+            }
+            self.emit_event(
+                "vm.start",
+                Some(id),
+                json!({ "name": vm_config.manifest.name }),
+            );
+            self.spawn_post_start_hook(&vm_config);
+        }
+        Ok(())
+    }
+
+    /// Reject the launch if one more running VM would exceed `cvm.max_vms`.
+    async fn ensure_vm_capacity(&self, id: &str) -> Result<()> {
+        let max_vms = self.config.cvm.max_vms;
+        if max_vms == 0 {
+            return Ok(());
+        }
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.config.id != id && p.state.status.is_running())
+            .filter(|p| {
+                serde_json::from_str::<ProcessAnnotation>(&p.config.note)
+                    .unwrap_or_default()
+                    .is_cvm()
+            })
+            .count();
+        if running >= max_vms as usize {
+            error!("VM cap reached ({running}/{max_vms} running), refusing to start VM {id}");
+            return Err(VmmError::ResourceExhausted(format!(
+                "at most {max_vms} VMs can be running on this host"
+            ))
+            .into());
         }
         Ok(())
     }
 
+    async fn is_running(&self, id: &str) -> Result<bool> {
+        Ok(self
+            .supervisor
+            .info(id)
+            .await?
+            .is_some_and(|info| info.state.status.is_running()))
+    }
+
+    pub(crate) async fn qmp(&self, id: &str) -> Result<QmpClient> {
+        if !self.config.cvm.qmp_socket {
+            bail!("QMP socket is disabled");
+        }
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        // QEMU opens the socket shortly after launch, retry until the startup window ends
+        let socket = self.work_dir(id).qmp_socket();
+        let launched_at = self
+            .supervisor
+            .info(id)
+            .await?
+            .and_then(|info| info.state.started_at);
+        let deadline = launched_at.map(|t| t + QMP_STARTUP_WINDOW);
+        let mut delay = Duration::from_millis(100);
+        loop {
+            let err = match QmpClient::connect(&socket).await {
+                Ok(qmp) => return Ok(qmp),
+                Err(err) => err,
+            };
+            if !deadline.is_some_and(|d| SystemTime::now() < d) {
+                if !socket.exists() {
+                    return Err(err.context(VmmError::QmpUnavailable(id.to_string())));
+                }
+                return Err(err);
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(1));
+        }
+    }
+
     fn set_started(&self, id: &str, started: bool) -> Result<()> {
         let work_dir = self.work_dir(id);
         work_dir
@@ -253,7 +852,9 @@ impl App {
 
     pub async fn stop_vm(&self, id: &str) -> Result<()> {
         self.set_started(id, false)?;
+        self.quit_adopted(id).await;
         self.supervisor.stop(id).await?;
+        self.release_vm_ports(id);
         Ok(())
     }
 
@@ -286,6 +887,9 @@ impl App {
             if let Some(vm_state) = state.remove(id) {
                 state.cid_pool.free(vm_state.config.cid);
             }
+            state.ports.release(id, None);
+            state.boot_secrets.remove(id);
+            self.events.forget_vm(id);
         }
 
         let vm_path = self.work_dir(id);
@@ -312,30 +916,85 @@ impl App {
             }
         }
         if vm_path.exists() {
+            let mut vm_dirs = vec![];
             for entry in fs::read_dir(vm_path).context("Failed to read VM directory")? {
                 let entry = entry.context("Failed to read directory entry")?;
                 let vm_path = entry.path();
                 if vm_path.is_dir() {
-                    if let Err(err) = self.load_vm(vm_path, &occupied_cids, true).await {
-                        error!("Failed to load VM: {err:?}");
-                    }
+                    vm_dirs.push(vm_path);
                 }
             }
+            let manifests = vm_dirs
+                .iter()
+                .filter_map(|dir| VmWorkDir::new(dir).manifest().ok())
+                .collect::<Vec<_>>();
+            let groups = manifests
+                .iter()
+                .flat_map(|m| m.network_groups())
+                .collect::<BTreeSet<_>>();
+            check_network_isolation(&self.config.cvm.networking, &groups)
+                .context("The host can not isolate the network groups of the VMs")?;
+            check_unique_macs(&manifests).context("Invalid VM MAC addresses")?;
+            for vm_path in vm_dirs {
+                if let Err(err) = self.load_vm(vm_path, &occupied_cids, true).await {
+                    error!("Failed to load VM: {err:?}");
+                }
+            }
+        }
+        if let Err(err) = self.reconcile_inventory().await {
+            warn!("Failed to reconcile VMs with the inventory: {err:?}");
         }
         Ok(())
     }
 
+    /// VMs matching the filters of `request`, sorted and paged. With `limit` set the order is
+    /// fixed by the first page and later pages are requested with `page_token`.
     pub async fn list_vms(&self, request: StatusRequest) -> Result<StatusResponse> {
         let vms = self
-            .supervisor
-            .list()
-            .await
-            .context("Failed to list VMs")?
+            .list_processes()
+            .await?
             .into_iter()
             .map(|p| (p.config.id.clone(), p))
             .collect::<HashMap<_, _>>();
 
-        let mut infos = self
+        if !request.page_token.is_empty() {
+            let (ids, total, next_page_token) = self
+                .lock()
+                .list_snapshots
+                .page(&request.page_token, request.limit as usize)?;
+            let states = {
+                let state = self.lock();
+                ids.iter()
+                    .filter_map(|id| state.get(id).cloned())
+                    .collect::<Vec<_>>()
+            };
+            let vms = states
+                .iter()
+                .map(|vm| {
+                    vm.merged_info(
+                        vms.get(&vm.config.manifest.id),
+                        &self.work_dir(&vm.config.manifest.id),
+                        self.config.attestation.gate_readiness,
+                    )
+                })
+                .map(|info| {
+                    info.to_pb(
+                        &self.config.gateway,
+                        &self.config.cvm.networking,
+                        request.brief,
+                    )
+                })
+                .collect();
+            return Ok(StatusResponse {
+                vms,
+                port_mapping_enabled: self.config.cvm.port_mapping.enabled,
+                total,
+                next_page_token,
+            });
+        }
+        let sort_key = request.sort_by.parse::<SortKey>()?;
+        let selector = request.selector.parse::<Selector>()?;
+        let states = self
             .lock()
             .iter_vms()
             .filter(|vm| {
@@ -353,27 +1012,44 @@ impl App {
             })
             .cloned()
             .collect::<Vec<_>>();
-        infos.sort_by(|a, b| {
-            a.config
-                .manifest
-                .created_at_ms
-                .cmp(&b.config.manifest.created_at_ms)
-        });
-
-        let total = infos.len() as u32;
-        let vms = paginate(infos, request.page, request.page_size)
+        let mut infos = states
+            .iter()
             .map(|vm| {
                 vm.merged_info(
                     vms.get(&vm.config.manifest.id),
                     &self.work_dir(&vm.config.manifest.id),
+                    self.config.attestation.gate_readiness,
+                )
+            })
+            .filter(|info| {
+                request.statuses.is_empty() || request.statuses.iter().any(|s| s == info.status)
+            })
+            .filter(|info| selector.matches(info))
+            .collect::<Vec<_>>();
+        sort_vms(&mut infos, sort_key, request.descending);
+
+        let total = infos.len() as u32;
+        let mut next_page_token = String::new();
+        let limit = request.limit as usize;
+        if limit > 0 && infos.len() > limit {
+            let ids = infos.iter().map(|info| info.manifest.id.clone()).collect();
+            next_page_token = self.lock().list_snapshots.insert(ids, limit);
+            infos.truncate(limit);
+        }
+        let vms = paginate(infos, request.page, request.page_size)
+            .map(|info| {
+                info.to_pb(
+                    &self.config.gateway,
+                    &self.config.cvm.networking,
+                    request.brief,
                 )
             })
-            .map(|info| info.to_pb(&self.config.gateway, request.brief))
             .collect::<Vec<_>>();
         Ok(StatusResponse {
             vms,
             port_mapping_enabled: self.config.cvm.port_mapping.enabled,
             total,
+            next_page_token,
         })
     }
 
@@ -390,18 +1066,101 @@ impl App {
     }
 
     pub async fn vm_info(&self, id: &str) -> Result<Option<pb::VmInfo>> {
-        let proc_state = self.supervisor.info(id).await?;
+        let mut proc_state = self.supervisor.info(id).await?;
+        if let Some(info) = &mut proc_state {
+            self.reconcile_process(info).await;
+        }
         let state = self.lock();
         let Some(vm_state) = state.get(id) else {
             return Ok(None);
         };
         let info = vm_state
-            .merged_info(proc_state.as_ref(), &self.work_dir(id))
-            .to_pb(&self.config.gateway, false);
+            .merged_info(
+                proc_state.as_ref(),
+                &self.work_dir(id),
+                self.config.attestation.gate_readiness,
+            )
+            .to_pb(&self.config.gateway, &self.config.cvm.networking, false);
         Ok(Some(info))
     }
 
+    /// The last `lines` lines of the QEMU stderr of a VM.
+    pub fn vm_stderr(&self, id: &str, lines: usize) -> Result<Vec<String>> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        self.work_dir(id)
+            .stderr_tail(lines, &self.config.log_limits)
+            .context("Failed to read QEMU stderr")
+    }
+
+    /// Dump the whole fleet as a versioned document. The VM state lock is held while the
+    /// document is built so that it reflects a single point in time.
+    pub async fn export_fleet(&self) -> Result<pb::FleetExport> {
+        let processes = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .map(|p| (p.config.id.clone(), p))
+            .collect::<HashMap<_, _>>();
+        let exported_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let state = self.lock();
+        let mut vms = state.iter_vms().collect::<Vec<_>>();
+        vms.sort_by_key(|vm| vm.config.manifest.created_at_ms);
+        let vms = vms
+            .into_iter()
+            .map(|vm| {
+                let id = &vm.config.manifest.id;
+                let process = processes.get(id);
+                let mut info = vm
+                    .merged_info(
+                        process,
+                        &self.work_dir(id),
+                        self.config.attestation.gate_readiness,
+                    )
+                    .to_pb(&self.config.gateway, &self.config.cvm.networking, false);
+                if let Some(configuration) = info.configuration.as_mut() {
+                    redact_vm_configuration(configuration);
+                }
+                let launch_command = process
+                    .map(|p| {
+                        let mut command = vec![p.config.command.clone()];
+                        command.extend(p.config.args.iter().cloned());
+                        command
+                    })
+                    .unwrap_or_default();
+                pb::FleetVm {
+                    info: Some(info),
+                    launch_command,
+                }
+            })
+            .collect();
+        Ok(pb::FleetExport {
+            version: FLEET_EXPORT_VERSION,
+            exported_at_ms,
+            vmm_version: crate::CARGO_PKG_VERSION.to_string(),
+            vms,
+        })
+    }
+
     pub(crate) fn vm_event_report(&self, cid: u32, event: &str, body: String) -> Result<()> {
+        if event == "heartbeat" {
+            let mut state = self.lock();
+            let Some(vm) = state.vms.values_mut().find(|vm| vm.config.cid == cid) else {
+                bail!("VM not found");
+            };
+            vm.state.last_heartbeat = Some(Instant::now());
+            if vm.state.unresponsive_since.take().is_some() {
+                info!(cid, "VM is sending heartbeats again");
+            }
+            return Ok(());
+        }
         info!(cid, event, "VM event");
         if body.len() > 1024 * 4 {
             error!("Event body too large, skipping");
@@ -413,6 +1172,9 @@ impl App {
         };
         match event {
             "boot.progress" => {
+                if READY_PROGRESS.contains(&body.as_str()) {
+                    self.verify_attestation_gate(&vm.config.manifest.id);
+                }
                 vm.state.boot_progress = body;
             }
             "boot.error" => {
@@ -525,7 +1287,14 @@ impl App {
                 "pccs_url": cfg.cvm.pccs_url,
                 "docker_registry": cfg.cvm.docker_registry,
                 "host_api_url": format!("vsock://2:{}/api", cfg.host_api.port),
+                "guest_callback_url": cfg
+                    .guest_callback
+                    .enabled
+                    .then(|| format!("vsock://2:{}/api", cfg.guest_callback.port)),
                 "vm_config": vm_config,
+                "guest_api_token": work_dir.guest_api_token()?,
+                "hostname": manifest.network.as_ref().and_then(|n| n.hostname.clone()),
+                "disk_key_from_boot_secret": self.disk_key_source(&manifest.name).is_some(),
             })
         } else if img_ver >= (0, 4, 2) {
             json!({
@@ -612,6 +1381,13 @@ impl App {
         Ok(KmsClient::new(prpc_client))
     }
 
+    /// Resolve a guest API token to the id of the VM it was issued to.
+    pub(crate) fn vm_id_by_guest_token(&self, token: &str) -> Option<String> {
+        self.lock()
+            .find_by_guest_token(token)
+            .map(|vm| vm.config.manifest.id.clone())
+    }
+
     pub(crate) fn guest_agent_client(&self, id: &str) -> Result<GuestClient> {
         let cid = self.lock().get(id).context("vm not found")?.config.cid;
         Ok(guest_api::client::new_client(format!(
@@ -645,32 +1421,19 @@ impl App {
             .collect();
         Ok(gpus)
     }
+}
 
-    pub(crate) async fn try_restart_exited_vms(&self) -> Result<()> {
-        let running_vms = self
-            .supervisor
-            .list()
-            .await
-            .context("Failed to list VMs")?
-            .iter()
-            .filter(|v| v.state.status.is_running())
-            .map(|v| v.config.id.clone())
-            .collect::<BTreeSet<_>>();
-        let exited_vms = self
-            .lock()
-            .iter_vms()
-            .filter(|vm| {
-                let workdir = self.work_dir(&vm.config.manifest.id);
-                let started = workdir.started().unwrap_or(false);
-                started && !running_vms.contains(&vm.config.manifest.id)
-            })
-            .map(|vm| vm.config.manifest.id.clone())
-            .collect::<Vec<_>>();
-        for id in exited_vms {
-            info!("Restarting VM {id}");
-            self.start_vm(&id).await?;
-        }
-        Ok(())
+/// Version of the document returned by [`App::export_fleet`].
+pub const FLEET_EXPORT_VERSION: u32 = 1;
+
+/// Placeholder for values withheld from exported documents.
+pub const REDACTED: &str = "<redacted>";
+
+/// Strip secrets from a VM configuration before it leaves the VMM.
+pub fn redact_vm_configuration(config: &mut VmConfiguration) {
+    config.encrypted_env.clear();
+    if !config.user_config.is_empty() {
+        config.user_config = REDACTED.to_string();
     }
 }
 
@@ -693,6 +1456,13 @@ fn paginate<T>(items: Vec<T>, page: u32, page_size: u32) -> impl Iterator<Item =
 pub struct VmState {
     pub(crate) config: Arc<VmConfig>,
     state: VmStateMut,
+    guest_token: String,
+    /// Token the running guest booted with, still accepted after a rotation until it restarts
+    boot_guest_token: Option<String>,
+    /// When the manifest was last loaded
+    loaded_at: SystemTime,
+    /// Modification time of the manifest when it was last loaded
+    manifest_mtime: Option<SystemTime>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -701,6 +1471,35 @@ struct VmStateMut {
     boot_error: String,
     shutdown_progress: String,
     devices: GpuConfig,
+    restart: RestartState,
+    display: Option<DisplayEndpoint>,
+    last_heartbeat: Option<Instant>,
+    /// Time of the last heartbeat if the watchdog fired
+    unresponsive_since: Option<Instant>,
+    /// When QEMU was launched, `None` if the boot was not observed
+    boot_started: Option<Instant>,
+    /// Boot diagnostics were captured for the current boot
+    boot_diagnosed: bool,
+    /// QEMU was launched with a QMP socket by this VMM
+    qmp_expected: bool,
+    /// QEMU was seen running and the `post_stop` hook has not run for its exit yet
+    post_stop_pending: bool,
+    /// Port reserved for the incoming migration the VM is waiting for
+    incoming_migration: Option<u16>,
+    /// Balloon target in MB set since QEMU was launched, `None` if the guest has all its memory
+    balloon_target: Option<u32>,
+    /// The NIC link was set down with `SetVmNetworkEnabled` since QEMU was launched
+    network_disabled: bool,
+    /// Problems found by the last re-validation of the stored config
+    config_problems: Vec<String>,
+    /// Last attestation verification since QEMU was launched
+    attestation: Option<AttestationCheck>,
+    /// Bytes of the QEMU stderr scanned for events, `None` until the first scan
+    stderr_offset: Option<u64>,
+    /// Time granted with `ExtendVmLifetime` since QEMU was launched
+    lifetime_extension: Duration,
+    /// The VM is being stopped for exceeding its max lifetime
+    lifetime_expired: bool,
 }
 
 impl VmStateMut {
@@ -712,20 +1511,33 @@ impl VmStateMut {
         };
         self.boot_error.clear();
         self.shutdown_progress.clear();
+        self.last_heartbeat = None;
+        self.unresponsive_since = None;
+        self.boot_started = (!already_running).then(Instant::now);
+        self.boot_diagnosed = false;
     }
 
     pub fn reset_na(&mut self) {
         self.boot_progress = "N/A".to_string();
         self.shutdown_progress = "N/A".to_string();
         self.boot_error.clear();
+        self.last_heartbeat = None;
+        self.unresponsive_since = None;
+        // Images without progress reporting never report ready
+        self.boot_started = None;
+        self.boot_diagnosed = false;
     }
 }
 
 impl VmState {
-    pub fn new(config: VmConfig) -> Self {
+    pub fn new(config: VmConfig, guest_token: String) -> Self {
         Self {
             config: Arc::new(config),
             state: VmStateMut::default(),
+            guest_token,
+            boot_guest_token: None,
+            loaded_at: SystemTime::now(),
+            manifest_mtime: None,
         }
     }
 }
@@ -733,6 +1545,25 @@ impl VmState {
 pub(crate) struct AppState {
     cid_pool: IdPool<u32>,
     vms: HashMap<String, VmState>,
+    drain: DrainState,
+    /// Auto-restart is paused
+    maintenance_mode: bool,
+    /// Auto-restart pacing, the config values unless changed at runtime
+    auto_restart_params: AutoRestartParams,
+    /// Secrets awaiting delivery, keyed by VM id
+    boot_secrets: HashMap<String, BootSecrets>,
+    /// Reserved VM ids not committed yet
+    reservations: HashMap<String, Reservation>,
+    /// Host ports claimed by VMs and the VMM
+    ports: PortRegistry,
+    /// When each image was last read into the page cache
+    warmed_images: HashMap<String, Instant>,
+    /// Ordered ids of the paged VM listings in progress
+    list_snapshots: ListSnapshots,
+    /// Outcome of the last reconciliation with the supervisor
+    last_reconcile: ReconcileReport,
+    /// VMs whose QMP events are being forwarded
+    qmp_watchers: HashSet<String>,
 }
 
 impl AppState {
@@ -755,4 +1586,16 @@ impl AppState {
     pub fn iter_vms(&self) -> impl Iterator<Item = &VmState> {
         self.vms.values()
     }
+
+    /// Find the VM the guest token was issued to.
+    pub fn find_by_guest_token(&self, token: &str) -> Option<&VmState> {
+        if token.is_empty() {
+            return None;
+        }
+        // Constant time, so the tokens can not be guessed byte by byte from response times
+        let issued = |issued: &str| bool::from(issued.as_bytes().ct_eq(token.as_bytes()));
+        self.vms.values().find(|vm| {
+            issued(&vm.guest_token) || vm.boot_guest_token.as_deref().is_some_and(issued)
+        })
+    }
 }
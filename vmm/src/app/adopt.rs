@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Adoption of QEMU processes launched outside the VMM.
+//!
+//! An adopted QEMU is not a child of the supervisor, so the supervisor runs a watcher in its
+//! place that exits when the QEMU does. Status, events and auto-restart follow the watcher like
+//! any other VM process. Restarts launch a QEMU from the manifest, the adopted one is gone.
+use std::collections::HashMap;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc::VmConfiguration;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use supervisor_client::supervisor::ProcessConfig;
+use tracing::{info, warn};
+
+use super::{defunct::defunct_reason, App, Manifest, QmpClient, VmOrigin, VmWorkDir};
+use crate::config::ProcessAnnotation;
+
+/// The external QEMU of an adopted VM, written to its work dir until the VM is relaunched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Adoption {
+    pub pid: u32,
+    pub qmp_socket: PathBuf,
+}
+
+/// Sockets and logs of the QEMU to adopt.
+pub struct AdoptSource {
+    pub pid: u32,
+    /// QMP socket of the QEMU, linked as the QMP socket of the VM
+    pub qmp_socket: PathBuf,
+    /// Serial console of the QEMU (pty or socket), linked as the serial pty of the VM
+    pub serial: PathBuf,
+    /// Serial log written by the QEMU, linked as the serial log of the VM if set
+    pub serial_log: Option<PathBuf>,
+}
+
+fn adoption_path(workdir: &VmWorkDir) -> PathBuf {
+    workdir.path().join("adopted.json")
+}
+
+/// The adoption record of a VM, `None` if its QEMU was launched by the VMM.
+fn adoption(workdir: &VmWorkDir) -> Option<Adoption> {
+    let data = fs::read(adoption_path(workdir)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Forget the adopted QEMU of a VM once it is relaunched, unlinking its serial log so the new
+/// QEMU does not append to it.
+pub(super) fn clear_adoption(workdir: &VmWorkDir) {
+    let path = adoption_path(workdir);
+    if !path.exists() {
+        return;
+    }
+    let serial_log = workdir.serial_file();
+    if serial_log.is_symlink() {
+        if let Err(err) = fs::remove_file(&serial_log) {
+            warn!("Failed to unlink the serial log of the adopted QEMU: {err:?}");
+        }
+    }
+    if let Err(err) = fs::remove_file(&path) {
+        warn!("Failed to remove adoption record: {err:?}");
+    }
+}
+
+/// Check that `pid` is a live QEMU and return the vsock CID of its guest.
+fn qemu_guest_cid(pid: u32) -> Result<u32> {
+    if let Some(reason) = defunct_reason(pid) {
+        bail!("{reason}");
+    }
+    let exe = fs::read_link(format!("/proc/{pid}/exe")).context("Failed to resolve executable")?;
+    let is_qemu = exe
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("qemu-system-"));
+    if !is_qemu {
+        bail!("Process {pid} is not QEMU: {}", exe.display());
+    }
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline"))?;
+    cmdline
+        .split(|b| *b == 0)
+        .filter_map(|arg| std::str::from_utf8(arg).ok())
+        .flat_map(|arg| arg.split(','))
+        .find_map(|opt| opt.strip_prefix("guest-cid="))
+        .context("QEMU has no vhost-vsock device")?
+        .parse()
+        .context("Invalid guest CID")
+}
+
+fn link(target: &Path, path: &Path) -> Result<()> {
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path)?;
+    }
+    symlink(target, path)
+        .with_context(|| format!("Failed to link {} to {}", path.display(), target.display()))
+}
+
+/// Supervisor process standing in for the adopted QEMU, exiting when it does. The exit code
+/// of a process that is not our child is unknown, the exit is reported as a failure.
+fn watcher_process(
+    manifest: &Manifest,
+    workdir: &VmWorkDir,
+    pid: u32,
+    cid: u32,
+) -> Result<ProcessConfig> {
+    let note = ProcessAnnotation {
+        kind: "cvm".to_string(),
+        live_for: None,
+    };
+    Ok(ProcessConfig {
+        id: manifest.id.clone(),
+        name: manifest.name.clone(),
+        command: "sh".into(),
+        args: vec![
+            "-c".into(),
+            format!("while kill -0 {pid} 2>/dev/null; do sleep 1; done; exit 1"),
+        ],
+        env: Default::default(),
+        cwd: workdir.path().to_string_lossy().to_string(),
+        stdout: workdir.stdout_file().to_string_lossy().to_string(),
+        stderr: workdir.stderr_file().to_string_lossy().to_string(),
+        pidfile: Default::default(),
+        cid: Some(cid),
+        note: serde_json::to_string(&note)?,
+    })
+}
+
+impl App {
+    /// Register a running QEMU launched outside the VMM as the VM of `manifest`.
+    ///
+    /// The process must be a live QEMU with a reachable QMP socket and a vsock CID not used by
+    /// another VM. `config` is written to the work dir as for a new VM and is what the VM is
+    /// relaunched with once the adopted QEMU exits.
+    pub(crate) async fn adopt_vm(
+        &self,
+        mut manifest: Manifest,
+        config: &VmConfiguration,
+        source: AdoptSource,
+    ) -> Result<()> {
+        manifest.origin = Some(VmOrigin::Adopted { pid: source.pid });
+        let id = manifest.id.clone();
+        uuid::Uuid::parse_str(&id).context("VM id must be a UUID")?;
+        let workdir = self.work_dir(&id);
+        if self.lock().get(&id).is_some() || workdir.path().exists() {
+            bail!("VM {id} already exists");
+        }
+        let cid = qemu_guest_cid(source.pid)?;
+        let mut qmp = QmpClient::connect(&source.qmp_socket)
+            .await
+            .context("QMP of the QEMU is not reachable")?;
+        qmp.execute("query-status", None)
+            .await
+            .context("QMP of the QEMU does not answer")?;
+        self.ensure_mac_unused(&manifest)?;
+        self.lock()
+            .cid_pool
+            .occupy(cid)
+            .with_context(|| format!("CID {cid} is already used by another VM"))?;
+
+        let result = self.register_adopted(&manifest, config, &source, cid).await;
+        if let Err(err) = result {
+            {
+                let mut state = self.lock();
+                state.remove(&id);
+                state.cid_pool.free(cid);
+            }
+            if let Err(err) = fs::remove_dir_all(workdir.path()) {
+                warn!("Failed to remove work dir: {err}");
+            }
+            return Err(err);
+        }
+        info!("Adopted QEMU process {} as VM {id}", source.pid);
+        self.emit_event(
+            "vm.adopt",
+            Some(&id),
+            json!({ "name": manifest.name, "pid": source.pid }),
+        );
+        Ok(())
+    }
+
+    async fn register_adopted(
+        &self,
+        manifest: &Manifest,
+        config: &VmConfiguration,
+        source: &AdoptSource,
+        cid: u32,
+    ) -> Result<()> {
+        let id = &manifest.id;
+        let workdir = self.work_dir(id);
+        workdir
+            .put_manifest(manifest)
+            .context("Failed to write manifest")?;
+        self.prepare_work_dir(id, config, &manifest.app_id)?;
+        workdir.set_started(true)?;
+        let adoption = Adoption {
+            pid: source.pid,
+            qmp_socket: source.qmp_socket.clone(),
+        };
+        fs::write(adoption_path(&workdir), serde_json::to_vec(&adoption)?)?;
+        link(&source.qmp_socket, &workdir.qmp_socket())?;
+        link(&source.serial, &workdir.serial_pty())?;
+        if let Some(serial_log) = &source.serial_log {
+            link(serial_log, &workdir.serial_file())?;
+        }
+        let cids = HashMap::from([(id.clone(), cid)]);
+        self.load_vm(workdir.path(), &cids, false)
+            .await
+            .context("Failed to load VM")?;
+        let watcher = watcher_process(manifest, &workdir, source.pid, cid)?;
+        self.supervisor
+            .deploy(&watcher)
+            .await
+            .context("Failed to start the watcher of the QEMU")?;
+        Ok(())
+    }
+
+    /// Ask the adopted QEMU of a VM to quit, as stopping its watcher leaves it running.
+    pub(crate) async fn quit_adopted(&self, id: &str) {
+        let Some(adoption) = adoption(&self.work_dir(id)) else {
+            return;
+        };
+        let result = async {
+            let mut qmp = QmpClient::connect(&adoption.qmp_socket).await?;
+            qmp.execute("quit", None).await
+        }
+        .await;
+        if let Err(err) = result {
+            warn!(
+                "Failed to quit adopted QEMU process {} of VM {id}: {err:?}",
+                adoption.pid
+            );
+        }
+    }
+}
@@ -0,0 +1,340 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the attestation of running CVMs.
+//!
+//! The quote of a CVM is the one in the RA-TLS certificate its guest agent serves. It is
+//! verified against the collateral of the PCCS, its event log against the RTMRs and its
+//! measurements against `attestation.expected_*`. The outcome is kept per VM until QEMU is
+//! launched again and reported as the `attestation_status` of the VM. A verification that
+//! fails is reported with a `vm.attestation_failed` event, once until the VM verifies again.
+//!
+//! With `attestation.gate_readiness` a VM whose guest reported ready is only reported ready
+//! once its attestation is verified. The verification runs as soon as the guest reports ready
+//! and is retried a few times, as the guest agent may not serve its certificate right away.
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use ra_rpc::Attestation;
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::App;
+use crate::config::AttestationConfig;
+
+/// Boot progress of guests that are up, checked by the periodic verification.
+pub(super) const READY_PROGRESS: &[&str] = &["done", "running"];
+
+/// Verifications run for a gated VM whose guest reported ready, until one passes.
+const GATE_ATTEMPTS: u32 = 5;
+const GATE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestationStatus {
+    /// The last verification passed and is not older than `attestation.max_age`
+    Verified,
+    /// Never verified since QEMU was launched, or the last pass is too old or was before the
+    /// VM stopped
+    Stale,
+    Failed,
+    /// The host has no TDX or the guest serves no attestation
+    Unsupported,
+}
+
+impl AttestationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttestationStatus::Verified => "verified",
+            AttestationStatus::Stale => "stale",
+            AttestationStatus::Failed => "failed",
+            AttestationStatus::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// Whether a VM can take traffic, from the boot progress of its guest and, if gated, the
+/// verification of its attestation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+    NotRunning,
+    /// The guest has not reported ready yet
+    Booting,
+    /// The guest reported ready, its attestation is not verified yet
+    Attesting,
+    /// The guest reported ready, the verification of its attestation failed or is unsupported
+    AttestationFailed,
+    Ready,
+}
+
+impl Readiness {
+    pub fn new(
+        is_running: bool,
+        boot_progress: &str,
+        attestation: AttestationStatus,
+        gated: bool,
+    ) -> Self {
+        if !is_running {
+            return Readiness::NotRunning;
+        }
+        if !READY_PROGRESS.contains(&boot_progress) {
+            return Readiness::Booting;
+        }
+        if !gated {
+            return Readiness::Ready;
+        }
+        match attestation {
+            AttestationStatus::Verified => Readiness::Ready,
+            AttestationStatus::Stale => Readiness::Attesting,
+            AttestationStatus::Failed | AttestationStatus::Unsupported => {
+                Readiness::AttestationFailed
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Readiness::NotRunning => "not_running",
+            Readiness::Booting => "booting",
+            Readiness::Attesting => "attesting",
+            Readiness::AttestationFailed => "attestation_failed",
+            Readiness::Ready => "ready",
+        }
+    }
+}
+
+/// Measurements of a verified quote, hex encoded.
+#[derive(Debug, Clone, Default)]
+pub struct VerifiedMeasurements {
+    pub mrtd: String,
+    pub os_image_hash: String,
+    pub mr_aggregated: String,
+    pub tcb_status: String,
+}
+
+/// Outcome of the last attestation verification of a VM.
+#[derive(Debug, Clone)]
+pub struct AttestationCheck {
+    /// `Verified`, `Failed` or `Unsupported`
+    outcome: AttestationStatus,
+    error: Option<String>,
+    checked_at: SystemTime,
+    /// When a pass turns stale, `None` if it does not
+    stale_at: Option<SystemTime>,
+    measurements: VerifiedMeasurements,
+}
+
+impl AttestationCheck {
+    pub fn status(&self, is_running: bool) -> AttestationStatus {
+        match self.outcome {
+            AttestationStatus::Verified if !is_running => AttestationStatus::Stale,
+            AttestationStatus::Verified
+                if self.stale_at.is_some_and(|t| SystemTime::now() >= t) =>
+            {
+                AttestationStatus::Stale
+            }
+            outcome => outcome,
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn to_pb(&self, id: &str, is_running: bool) -> pb::AttestationReport {
+        let checked_at = self
+            .checked_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        pb::AttestationReport {
+            id: id.to_string(),
+            status: self.status(is_running).as_str().into(),
+            error: self.error.clone().unwrap_or_default(),
+            checked_at: checked_at.as_secs(),
+            mrtd: self.measurements.mrtd.clone(),
+            os_image_hash: self.measurements.os_image_hash.clone(),
+            mr_aggregated: self.measurements.mr_aggregated.clone(),
+            tcb_status: self.measurements.tcb_status.clone(),
+        }
+    }
+}
+
+/// Status of a VM from its last check, `Stale` if it has none.
+pub fn attestation_status(check: Option<&AttestationCheck>, is_running: bool) -> AttestationStatus {
+    check.map_or(AttestationStatus::Stale, |c| c.status(is_running))
+}
+
+fn check_expected(name: &str, expected: &[String], actual: &str) -> Result<()> {
+    if expected.is_empty() || expected.iter().any(|e| e.eq_ignore_ascii_case(actual)) {
+        return Ok(());
+    }
+    bail!("{name} {actual} is not one of the expected values");
+}
+
+/// Verify the attestation in the PEM certificate of a guest.
+async fn verify_app_cert(
+    app_cert: &str,
+    config: &AttestationConfig,
+    pccs_url: &str,
+) -> Result<Option<VerifiedMeasurements>> {
+    let Some(attestation) =
+        Attestation::from_pem(app_cert.as_bytes()).context("Invalid app certificate")?
+    else {
+        return Ok(None);
+    };
+    let (_, pem) =
+        x509_parser::pem::parse_x509_pem(app_cert.as_bytes()).context("Invalid app certificate")?;
+    let cert = pem.parse_x509().context("Invalid app certificate")?;
+    let pccs_url = (!pccs_url.is_empty()).then_some(pccs_url);
+    let verified = attestation
+        .verify_with_ra_pubkey(cert.public_key().raw, pccs_url)
+        .await
+        .context("Invalid quote")?;
+    let app_info = verified
+        .decode_app_info(false)
+        .context("Failed to decode the measurements")?;
+    let measurements = VerifiedMeasurements {
+        mrtd: hex::encode(app_info.mrtd),
+        os_image_hash: hex::encode(&app_info.os_image_hash),
+        mr_aggregated: hex::encode(app_info.mr_aggregated),
+        tcb_status: verified.report.status.clone(),
+    };
+    check_expected("MRTD", &config.expected_mrtd, &measurements.mrtd)?;
+    check_expected(
+        "OS image hash",
+        &config.expected_os_image_hash,
+        &measurements.os_image_hash,
+    )?;
+    Ok(Some(measurements))
+}
+
+impl App {
+    /// Fetch the quote of the running VM `id` from its guest agent and verify it.
+    pub async fn verify_attestation(&self, id: &str) -> Result<pb::AttestationReport> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM {id} is not running");
+        }
+        let config = &self.config.attestation;
+        let outcome = if self.capabilities.get().await.tdx != Some(true) {
+            Err((
+                AttestationStatus::Unsupported,
+                "The host has no TDX".to_string(),
+            ))
+        } else {
+            let verified = async {
+                let info = self.guest_agent_client(id)?.info().await?;
+                verify_app_cert(&info.app_cert, config, &self.config.cvm.pccs_url).await
+            }
+            .await;
+            match verified {
+                Ok(Some(measurements)) => Ok(measurements),
+                Ok(None) => Err((
+                    AttestationStatus::Unsupported,
+                    "The app certificate carries no attestation".to_string(),
+                )),
+                Err(err) => Err((AttestationStatus::Failed, format!("{err:#}"))),
+            }
+        };
+        let now = SystemTime::now();
+        let check = match outcome {
+            Ok(measurements) => AttestationCheck {
+                outcome: AttestationStatus::Verified,
+                error: None,
+                checked_at: now,
+                stale_at: (config.max_age > 0).then(|| now + Duration::from_secs(config.max_age)),
+                measurements,
+            },
+            Err((outcome, error)) => AttestationCheck {
+                outcome,
+                error: Some(error),
+                checked_at: now,
+                stale_at: None,
+                measurements: VerifiedMeasurements::default(),
+            },
+        };
+        let previous = {
+            let mut state = self.lock();
+            let vm = state.get_mut(id).context("VM not found")?;
+            vm.state.attestation.replace(check.clone())
+        };
+        let failed_before = previous.is_some_and(|c| c.outcome == AttestationStatus::Failed);
+        match (check.outcome, check.error()) {
+            (AttestationStatus::Failed, Some(error)) if !failed_before => {
+                warn!("Attestation of VM {id} failed: {error}");
+                self.emit_event("vm.attestation_failed", Some(id), json!({ "error": error }));
+            }
+            (AttestationStatus::Verified, _) if failed_before => {
+                info!("Attestation of VM {id} verified again");
+            }
+            _ => {}
+        }
+        Ok(check.to_pb(id, true))
+    }
+
+    /// Verify the attestation of VM `id` whose guest just reported ready, if readiness is gated
+    /// on it. Retried until it passes or the VM stops.
+    pub(crate) fn verify_attestation_gate(&self, id: &str) {
+        if !self.config.attestation.gate_readiness {
+            return;
+        }
+        let app = self.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            for attempt in 1..=GATE_ATTEMPTS {
+                if attempt > 1 {
+                    tokio::time::sleep(GATE_RETRY_DELAY).await;
+                }
+                match app.verify_attestation(&id).await {
+                    Ok(report) if report.status == AttestationStatus::Verified.as_str() => {
+                        info!("Attestation of VM {id} verified, the VM is ready");
+                        return;
+                    }
+                    Ok(report) if report.status == AttestationStatus::Unsupported.as_str() => {
+                        warn!("VM {id} can not be attested, holding it back from ready");
+                        return;
+                    }
+                    Ok(_) => {}
+                    // Not running or removed
+                    Err(err) => {
+                        warn!("Failed to verify the attestation of VM {id}: {err:?}");
+                        return;
+                    }
+                }
+            }
+            warn!(
+                "Attestation of VM {id} failed {GATE_ATTEMPTS} times, holding it back from ready"
+            );
+        });
+    }
+
+    /// Verify the attestation of every running VM whose guest is up.
+    pub(crate) async fn verify_attestations(&self) {
+        let ids = match self.list_processes().await {
+            Ok(processes) => processes
+                .into_iter()
+                .filter(|p| p.state.status.is_running())
+                .map(|p| p.config.id)
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                warn!("Failed to list VMs to verify their attestation: {err:?}");
+                return;
+            }
+        };
+        for id in ids {
+            let ready = self
+                .lock()
+                .get(&id)
+                .is_some_and(|vm| READY_PROGRESS.contains(&vm.state.boot_progress.as_str()));
+            if !ready {
+                continue;
+            }
+            if let Err(err) = self.verify_attestation(&id).await {
+                warn!("Failed to verify the attestation of VM {id}: {err:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-balloon control and the memory capacity of the host.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use super::{App, QmpClient};
+use crate::config::ProcessAnnotation;
+
+/// Device id of the balloon emitted on the QEMU command line.
+pub const BALLOON_ID: &str = "balloon0";
+
+pub(super) const MB: u64 = 1024 * 1024;
+
+/// Read a field of `/proc/meminfo` in MB.
+pub(crate) fn meminfo_mb(meminfo: &str, key: &str) -> u64 {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|rest| rest.split_whitespace().next()?.parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+        .unwrap_or_default()
+}
+
+impl App {
+    fn vm_memory(&self, id: &str) -> Result<(u32, Option<u32>)> {
+        let state = self.lock();
+        let vm = state.get(id).context("VM not found")?;
+        Ok((vm.config.manifest.memory, vm.state.balloon_target))
+    }
+
+    /// Free memory reported by the guest balloon driver, `None` until the guest reports it.
+    async fn balloon_free_memory(&self, qmp: &mut QmpClient) -> Result<Option<u64>> {
+        let path = format!("/machine/peripheral/{BALLOON_ID}");
+        let stats = qmp
+            .execute(
+                "qom-get",
+                Some(json!({ "path": path, "property": "guest-stats" })),
+            )
+            .await?;
+        let interval = self.config.cvm.balloon.stats_interval;
+        if stats.get("last-update").and_then(Value::as_u64) == Some(0) && interval > 0 {
+            // Statistics are only collected once polling is enabled
+            qmp.execute(
+                "qom-set",
+                Some(json!({
+                    "path": path,
+                    "property": "guest-stats-polling-interval",
+                    "value": interval,
+                })),
+            )
+            .await?;
+            return Ok(None);
+        }
+        Ok(stats
+            .pointer("/stats/stat-free-memory")
+            .and_then(Value::as_u64)
+            .map(|bytes| bytes / MB))
+    }
+
+    pub async fn get_balloon_info(&self, id: &str) -> Result<pb::BalloonInfo> {
+        if !self.config.cvm.balloon.enabled {
+            bail!("Balloon device is disabled");
+        }
+        let (memory, target) = self.vm_memory(id)?;
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        let mut qmp = self.qmp(id).await?;
+        let actual = qmp
+            .execute("query-balloon", None)
+            .await
+            .context("Failed to query balloon")?
+            .get("actual")
+            .and_then(Value::as_u64)
+            .context("Invalid query-balloon response")?;
+        let free_memory_mb = self
+            .balloon_free_memory(&mut qmp)
+            .await
+            .unwrap_or_else(|err| {
+                debug!("Failed to read balloon stats of {id}: {err:?}");
+                None
+            });
+        Ok(pb::BalloonInfo {
+            id: id.to_string(),
+            memory_mb: memory,
+            target_mb: target.unwrap_or(memory),
+            actual_mb: (actual / MB) as u32,
+            free_memory_mb,
+        })
+    }
+
+    pub async fn set_balloon_target(&self, id: &str, target_mb: u32) -> Result<pb::BalloonInfo> {
+        if !self.config.cvm.balloon.enabled {
+            bail!("Balloon device is disabled");
+        }
+        let (memory, _) = self.vm_memory(id)?;
+        if target_mb == 0 || target_mb > memory {
+            bail!("Balloon target must be between 1 and {memory} MB");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        let mut qmp = self.qmp(id).await?;
+        qmp.execute("balloon", Some(json!({ "value": target_mb as u64 * MB })))
+            .await
+            .context("Failed to set balloon target")?;
+        if let Some(vm) = self.lock().get_mut(id) {
+            vm.state.balloon_target = (target_mb != memory).then_some(target_mb);
+        }
+        self.get_balloon_info(id).await
+    }
+
+    /// Memory and vCPUs of the host against what running VMs use, including what their
+    /// balloons have reclaimed, and the placement hints of all VMs.
+    pub async fn host_capacity(&self) -> Result<pb::HostCapacity> {
+        let meminfo = fs_err::read_to_string("/proc/meminfo").unwrap_or_default();
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .filter(|p| {
+                serde_json::from_str::<ProcessAnnotation>(&p.config.note)
+                    .unwrap_or_default()
+                    .is_cvm()
+            })
+            .map(|p| p.config.id)
+            .collect::<Vec<_>>();
+        let cfg = &self.config.cvm;
+        let mut capacity = pb::HostCapacity {
+            total_memory_mb: meminfo_mb(&meminfo, "MemTotal"),
+            available_memory_mb: meminfo_mb(&meminfo, "MemAvailable"),
+            max_allocable_vcpu: cfg.max_allocable_vcpu,
+            max_allocable_memory_in_mb: cfg.max_allocable_memory_in_mb,
+            ..Default::default()
+        };
+        capacity.tee = self.capabilities.get().await.tdx;
+        self.add_placements(&mut capacity, &running);
+        for id in running {
+            let Some((vcpu, memory)) = self
+                .lock()
+                .get(&id)
+                .map(|vm| (vm.config.manifest.vcpu, vm.config.manifest.memory))
+            else {
+                continue;
+            };
+            capacity.allocated_vcpu += vcpu;
+            capacity.allocated_memory_mb += memory as u64;
+            if !cfg.balloon.enabled || !cfg.qmp_socket {
+                continue;
+            }
+            match self.get_balloon_info(&id).await {
+                Ok(balloon) => {
+                    capacity.ballooned_memory_mb +=
+                        balloon.memory_mb.saturating_sub(balloon.actual_mb) as u64;
+                    capacity.guest_free_memory_mb += balloon.free_memory_mb.unwrap_or_default();
+                }
+                Err(err) => debug!("Failed to query balloon of {id}: {err:?}"),
+            }
+        }
+        Ok(capacity)
+    }
+}
@@ -0,0 +1,262 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification and registration of the COW base disk (`hda`) of images.
+//!
+//! A disk in another format can be converted to qcow2 with `qemu-img convert` on the way. The
+//! checksum is that of the disk as fetched, the checks run on the converted disk.
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::info;
+
+use super::App;
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Download `url` to `dest`, failing once it gets larger than `max_bytes` unless that is 0.
+async fn download(url: &str, dest: &Path, max_bytes: u64) -> Result<()> {
+    let mut response = reqwest::get(url)
+        .await
+        .with_context(|| format!("Failed to download {url}"))?
+        .error_for_status()?;
+    let too_large = |len: u64| max_bytes > 0 && len > max_bytes;
+    if response.content_length().is_some_and(too_large) {
+        bail!("{url} is larger than {max_bytes} bytes");
+    }
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut written = 0u64;
+    while let Some(chunk) = response.chunk().await? {
+        written += chunk.len() as u64;
+        if too_large(written) {
+            bail!("Download of {url} exceeded {max_bytes} bytes");
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Format and virtual size reported by `qemu-img info`.
+async fn qemu_img_info(path: &Path) -> Result<(String, u64)> {
+    let output = Command::new("qemu-img")
+        .args(["info", "--output=json"])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img info failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let info: Value = serde_json::from_slice(&output.stdout).context("Invalid qemu-img info")?;
+    let format = info
+        .get("format")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let virtual_size = info
+        .get("virtual-size")
+        .and_then(Value::as_u64)
+        .unwrap_or_default();
+    Ok((format, virtual_size))
+}
+
+/// Formats a disk can be converted to.
+const CONVERT_TARGETS: &[&str] = &["qcow2"];
+
+/// Convert `source` in `format` to `target` in `target_format` with `qemu-img convert`.
+async fn qemu_img_convert(
+    source: &Path,
+    format: &str,
+    target: &Path,
+    target_format: &str,
+) -> Result<()> {
+    let output = Command::new("qemu-img")
+        .args(["convert", "-f", format, "-O", target_format])
+        .arg(source)
+        .arg(target)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img convert failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The problems found by `qemu-img check`, `None` if the image is consistent.
+async fn qemu_img_check(path: &Path) -> Result<Option<String>> {
+    let output = Command::new("qemu-img")
+        .arg("check")
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    match output.status.code() {
+        // 3 means leaked clusters only, which waste space but do not corrupt data
+        Some(0) | Some(3) => Ok(None),
+        _ => Ok(Some(
+            format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .trim()
+            .to_string(),
+        )),
+    }
+}
+
+impl App {
+    /// Fetch a disk from a host path or URL, convert it to `convert_to` if set, verify it and
+    /// make it the COW base of an image. VMs created afterwards use it, existing VMs keep their
+    /// current base.
+    pub async fn prepare_image(
+        &self,
+        request: pb::PrepareImageRequest,
+    ) -> Result<pb::PrepareImageResponse> {
+        let name = &request.image;
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            bail!("Invalid image name: {name}");
+        }
+        let expected = request.sha256.trim().to_lowercase();
+        if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid SHA-256: {}", request.sha256);
+        }
+        let convert_to = request.convert_to.as_str();
+        if !convert_to.is_empty() && !CONVERT_TARGETS.contains(&convert_to) {
+            bail!("Can not convert to {convert_to}, only to {CONVERT_TARGETS:?}");
+        }
+        let image_dir = self.config.image_path.join(name);
+        if !image_dir.join("metadata.json").exists() {
+            bail!("Image not found: {name}");
+        }
+        let staging = image_dir.join(format!(".prepare-{}.tmp", &expected[..16]));
+        let converted = image_dir.join(format!(".prepare-{}.convert.tmp", &expected[..16]));
+        let result = self
+            .verify_and_register(
+                &request.source,
+                &image_dir,
+                &staging,
+                &expected,
+                (!convert_to.is_empty()).then_some((convert_to, converted.as_path())),
+            )
+            .await;
+        for path in [&staging, &converted] {
+            if path.exists() {
+                fs::remove_file(path).ok();
+            }
+        }
+        result
+    }
+
+    async fn verify_and_register(
+        &self,
+        source: &str,
+        image_dir: &Path,
+        staging: &Path,
+        expected: &str,
+        convert: Option<(&str, &Path)>,
+    ) -> Result<pb::PrepareImageResponse> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            let max_bytes = self
+                .config
+                .cvm
+                .max_image_download_mb
+                .saturating_mul(1024 * 1024);
+            download(source, staging, max_bytes).await?;
+        } else {
+            self.config.cvm.check_image_root(Path::new(source))?;
+            tokio::fs::copy(source, staging)
+                .await
+                .with_context(|| format!("Failed to copy {source}"))?;
+        }
+        let size = fs::metadata(staging)?.len();
+        let sha256 = {
+            let staging = staging.to_path_buf();
+            tokio::task::spawn_blocking(move || sha256_file(&staging)).await??
+        };
+        let mut response = pb::PrepareImageResponse {
+            size,
+            sha256: sha256.clone(),
+            ..Default::default()
+        };
+        if sha256 != expected {
+            response.error = format!("Checksum mismatch, expected {expected}");
+            return Ok(response);
+        }
+        let (format, virtual_size) = qemu_img_info(staging).await?;
+        response.source_format = format.clone();
+        response.format = format;
+        response.virtual_size = virtual_size;
+        let mut disk = staging;
+        match convert {
+            Some((target_format, _)) if target_format == response.format => {
+                info!(
+                    "Disk of image {} is {target_format} already",
+                    image_dir.display()
+                );
+            }
+            Some((target_format, target)) => {
+                let started = Instant::now();
+                qemu_img_convert(staging, &response.format, target, target_format).await?;
+                response.convert_duration_ms = started.elapsed().as_millis() as u64;
+                response.converted = true;
+                response.converted_size = fs::metadata(target)?.len();
+                info!(
+                    "Converted the disk of image {} from {} to {target_format} in {} ms",
+                    image_dir.display(),
+                    response.format,
+                    response.convert_duration_ms
+                );
+                let (format, virtual_size) = qemu_img_info(target).await?;
+                response.format = format;
+                response.virtual_size = virtual_size;
+                disk = target;
+            }
+            None => {}
+        }
+        if response.format != "qcow2" {
+            response.error = format!("COW base must be qcow2, not {}", response.format);
+            return Ok(response);
+        }
+        if let Some(problems) = qemu_img_check(disk).await? {
+            response.error = format!("qemu-img check failed: {problems}");
+            return Ok(response);
+        }
+        let hda = format!("hda-{}.qcow2", &sha256[..16]);
+        fs::rename(disk, image_dir.join(&hda))?;
+        let metadata_path = image_dir.join("metadata.json");
+        let mut metadata: Value = serde_json::from_str(&fs::read_to_string(&metadata_path)?)
+            .context("Failed to parse image metadata")?;
+        metadata["hda"] = hda.clone().into();
+        safe_write::safe_write(&metadata_path, serde_json::to_string_pretty(&metadata)?)
+            .context("Failed to update image metadata")?;
+        info!(
+            "Registered {hda} as the COW base of image {}",
+            image_dir.display()
+        );
+        response.verified = true;
+        response.hda = hda;
+        Ok(response)
+    }
+}
@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Swapping the boot disk image of a VM for blue/green guest image deploys.
+//!
+//! The image a VM boots from is swapped for another prepared image, keeping the data disk. The
+//! image it was swapped from is remembered in the manifest, so a later swap can revert to it.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+use tracing::{error, info};
+
+use super::image::{check_image_name, Image};
+use super::{App, VmConfig};
+
+impl App {
+    /// Boot VM `id` from `image` from now on, or from the image it was swapped from if
+    /// `revert` is set. A running VM is restarted on the new image if `reboot` is set, and
+    /// restarted on the old one again if that fails.
+    pub async fn swap_boot_disk(
+        &self,
+        id: &str,
+        image: &str,
+        revert: bool,
+        reboot: bool,
+    ) -> Result<pb::SwapBootDiskResponse> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let work_dir = self.work_dir(id);
+        let old_manifest = work_dir.manifest().context("Failed to read manifest")?;
+        let new_image = match (revert, image) {
+            (true, "") => old_manifest
+                .previous_image
+                .clone()
+                .context("The boot disk of the VM was never swapped")?,
+            (true, _) => bail!("An image can not be given to revert"),
+            (false, "") => bail!("No image given"),
+            (false, image) => image.to_string(),
+        };
+        if new_image == old_manifest.image {
+            bail!("VM {id} already boots from image {new_image}");
+        }
+        check_image_name(&new_image)?;
+        let mut manifest = old_manifest.clone();
+        manifest.image = new_image.clone();
+        manifest.previous_image = Some(old_manifest.image.clone());
+        let vm_config = VmConfig {
+            image: Image::load(self.config.image_path.join(&new_image))
+                .with_context(|| format!("Invalid image {new_image}"))?,
+            manifest,
+            cid: 0,
+            workdir: work_dir.path().to_path_buf(),
+            gateway_enabled: false,
+        };
+        vm_config
+            .validate_boot(&self.config.cvm)
+            .with_context(|| format!("Image {new_image} can not boot the VM"))?;
+        let manifest = vm_config.manifest;
+
+        let restart = reboot && self.is_running(id).await?;
+        if restart {
+            info!("Stopping VM {id} to swap its boot disk");
+            self.stop_vm(id).await.context("Failed to stop VM")?;
+        }
+        work_dir
+            .put_manifest(&manifest)
+            .context("Failed to write manifest")?;
+        self.load_vm(work_dir.path(), &Default::default(), false)
+            .await
+            .context("Failed to load VM")?;
+        if restart {
+            if let Err(err) = self.start_vm(id).await {
+                error!("Failed to boot VM {id} from image {new_image}, swapping back: {err:?}");
+                let rollback = async {
+                    work_dir.put_manifest(&old_manifest)?;
+                    self.load_vm(work_dir.path(), &Default::default(), false)
+                        .await?;
+                    self.start_vm(id).await
+                };
+                let outcome = match rollback.await {
+                    Ok(()) => "the old image was restored",
+                    Err(err) => {
+                        error!("Failed to restore the old image of VM {id}: {err:?}");
+                        "restoring the old image failed too"
+                    }
+                };
+                return Err(err.context(format!("Failed to boot the new image, {outcome}")));
+            }
+        }
+        info!(
+            "VM {id} swapped its boot disk from image {} to {new_image}",
+            old_manifest.image
+        );
+        self.emit_event(
+            "vm.boot_disk_swap",
+            Some(id),
+            json!({
+                "from": old_manifest.image,
+                "to": new_image,
+                "rebooted": restart,
+            }),
+        );
+        Ok(pb::SwapBootDiskResponse {
+            image: new_image,
+            previous_image: old_manifest.image,
+            rebooted: restart,
+        })
+    }
+}
@@ -0,0 +1,246 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Secrets handed to a guest over the host API at boot, kept in memory only.
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_types::BOOT_SECRET_DISK_KEY;
+use fs_err as fs;
+use tracing::info;
+
+use super::App;
+use crate::config::DiskKeyConfig;
+
+pub(crate) struct BootSecrets {
+    secrets: BTreeMap<String, Vec<u8>>,
+    first_fetch: Option<Instant>,
+}
+
+/// Outcome of a fetch of boot secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fetch {
+    /// Hand out the secrets and drop them
+    Take,
+    /// Hand out the secrets and keep them until the window closes
+    Keep { first: bool },
+    /// The window closed, drop the secrets
+    Expired,
+}
+
+impl BootSecrets {
+    /// Fetch the secrets at `now`, with `window` the time they stay retrievable after the first
+    /// fetch, dropped at once if zero.
+    fn fetch(&mut self, window: Duration, now: Instant) -> Fetch {
+        match self.first_fetch {
+            None if window.is_zero() => Fetch::Take,
+            None => {
+                self.first_fetch = Some(now);
+                Fetch::Keep { first: true }
+            }
+            Some(_) if self.expired(window, now) => Fetch::Expired,
+            Some(_) => Fetch::Keep { first: false },
+        }
+    }
+
+    /// The secrets were fetched more than `window` before `now`.
+    fn expired(&self, window: Duration, now: Instant) -> bool {
+        self.first_fetch
+            .is_some_and(|first| now.saturating_duration_since(first) > window)
+    }
+}
+
+/// Read the data disk key of a VM from its configured source.
+fn read_disk_key(source: &DiskKeyConfig) -> Result<Vec<u8>> {
+    let key = match (source.key_file.is_empty(), source.key_command.is_empty()) {
+        (false, true) => fs::read(&source.key_file)?,
+        (true, false) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&source.key_command)
+                .output()
+                .context("Failed to run the key command")?;
+            if !output.status.success() {
+                bail!(
+                    "Key command failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            output.stdout
+        }
+        _ => bail!("Exactly one of key_file and key_command must be set"),
+    };
+    let key = key.trim_ascii_end().to_vec();
+    if key.is_empty() {
+        bail!("Disk key is empty");
+    }
+    Ok(key)
+}
+
+impl App {
+    /// Source of the data disk key of the VM named `name`, if the host provides it.
+    pub(crate) fn disk_key_source(&self, name: &str) -> Option<&DiskKeyConfig> {
+        self.config.cvm.disk_keys.iter().find(|k| k.vm == name)
+    }
+
+    /// Add the data disk key to the boot secrets of VM `id` if the host provides it, next to
+    /// the secrets provisioned by the operator.
+    pub(crate) fn provision_disk_key(&self, id: &str) -> Result<()> {
+        let name = self
+            .lock()
+            .get(id)
+            .map(|vm| vm.config.manifest.name.clone())
+            .context("VM not found")?;
+        let Some(source) = self.disk_key_source(&name) else {
+            return Ok(());
+        };
+        let key = read_disk_key(source)
+            .with_context(|| format!("Failed to read the disk key of VM {name}"))?;
+        let mut state = self.lock();
+        let entry = state
+            .boot_secrets
+            .entry(id.to_string())
+            .or_insert_with(|| BootSecrets {
+                secrets: BTreeMap::new(),
+                first_fetch: None,
+            });
+        entry.secrets.insert(BOOT_SECRET_DISK_KEY.to_string(), key);
+        Ok(())
+    }
+
+    /// Provision secrets for the next boot of a VM, replacing any not yet fetched except the
+    /// disk key provided by the host.
+    pub fn provision_boot_secrets(
+        &self,
+        id: &str,
+        mut secrets: BTreeMap<String, Vec<u8>>,
+    ) -> Result<()> {
+        if secrets.contains_key(BOOT_SECRET_DISK_KEY) {
+            bail!("{BOOT_SECRET_DISK_KEY} is reserved for the disk key provided by the host");
+        }
+        let mut state = self.lock();
+        if state.get(id).is_none() {
+            bail!("VM not found");
+        }
+        let disk_key = state
+            .boot_secrets
+            .get_mut(id)
+            .and_then(|entry| entry.secrets.remove(BOOT_SECRET_DISK_KEY));
+        if let Some(disk_key) = disk_key {
+            secrets.insert(BOOT_SECRET_DISK_KEY.to_string(), disk_key);
+        }
+        if secrets.is_empty() {
+            state.boot_secrets.remove(id);
+            return Ok(());
+        }
+        state.boot_secrets.insert(
+            id.to_string(),
+            BootSecrets {
+                secrets,
+                first_fetch: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Hand out the boot secrets of the VM the guest token was issued to.
+    ///
+    /// The secrets are dropped after the first fetch, or `cvm.boot_secret_window` seconds after
+    /// it if the window is non-zero.
+    pub fn fetch_boot_secrets(&self, cid: u32, token: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let window = Duration::from_secs(self.config.cvm.boot_secret_window);
+        let mut state = self.lock();
+        let id = state
+            .find_by_guest_token(token)
+            .filter(|vm| vm.config.cid == cid)
+            .map(|vm| vm.config.manifest.id.clone())
+            .context("Invalid guest token")?;
+        let Some(entry) = state.boot_secrets.get_mut(&id) else {
+            return Ok(BTreeMap::new());
+        };
+        match entry.fetch(window, Instant::now()) {
+            Fetch::Keep { first } => {
+                if first {
+                    info!("Boot secrets of VM {id} delivered, retrievable for {window:?}");
+                }
+                Ok(entry.secrets.clone())
+            }
+            Fetch::Expired => {
+                state.boot_secrets.remove(&id);
+                bail!("Boot secrets of VM {id} expired");
+            }
+            Fetch::Take => {
+                info!("Boot secrets of VM {id} delivered");
+                Ok(state
+                    .boot_secrets
+                    .remove(&id)
+                    .map(|e| e.secrets)
+                    .unwrap_or_default())
+            }
+        }
+    }
+
+    /// Drop the boot secrets whose retrieval window closed, which a guest that does not fetch
+    /// them again would otherwise leave in memory for the life of its VM.
+    pub(crate) fn purge_expired_boot_secrets(&self) {
+        let window = Duration::from_secs(self.config.cvm.boot_secret_window);
+        let now = Instant::now();
+        self.lock().boot_secrets.retain(|id, entry| {
+            let expired = entry.expired(window, now);
+            if expired {
+                info!("Dropped the expired boot secrets of VM {id}");
+            }
+            !expired
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> BootSecrets {
+        BootSecrets {
+            secrets: [("key".to_string(), b"secret".to_vec())].into(),
+            first_fetch: None,
+        }
+    }
+
+    #[test]
+    fn without_window_secrets_are_taken_once() {
+        let mut entry = secrets();
+        assert_eq!(entry.fetch(Duration::ZERO, Instant::now()), Fetch::Take);
+        assert!(!entry.expired(Duration::ZERO, Instant::now()));
+    }
+
+    #[test]
+    fn window_starts_at_first_fetch() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut entry = secrets();
+        assert!(!entry.expired(window, start + Duration::from_secs(3600)));
+        assert_eq!(entry.fetch(window, start), Fetch::Keep { first: true });
+        assert_eq!(
+            entry.fetch(window, start + Duration::from_secs(30)),
+            Fetch::Keep { first: false }
+        );
+        assert_eq!(
+            entry.fetch(window, start + window),
+            Fetch::Keep { first: false }
+        );
+    }
+
+    #[test]
+    fn secrets_expire_after_the_window() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut entry = secrets();
+        entry.fetch(window, start);
+        let later = start + window + Duration::from_secs(1);
+        assert!(entry.expired(window, later));
+        assert_eq!(entry.fetch(window, later), Fetch::Expired);
+    }
+}
@@ -0,0 +1,271 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cached probes of what the host and its QEMU support.
+//!
+//! Probes fail soft: a probe that cannot run leaves its capability unknown (`None`), and
+//! consumers only act on capabilities known to be missing.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use tracing::{info, warn};
+
+use super::machine::QemuMachines;
+use super::App;
+use crate::config::TscConfig;
+
+const TDX_PARAM: &str = "/sys/module/kvm_intel/parameters/tdx";
+
+#[derive(Debug, Clone)]
+pub struct HostCapabilities {
+    /// Version reported by `qemu --version`
+    pub qemu_version: Option<String>,
+    /// CPU models listed by `qemu -cpu help`
+    pub cpu_models: Option<Vec<String>>,
+    /// Machines listed by `qemu -machine help`
+    pub machines: Option<QemuMachines>,
+    /// `/dev/kvm` is accessible
+    pub kvm: Option<bool>,
+    /// KVM has TDX enabled
+    pub tdx: Option<bool>,
+    /// `flags` and `vmx flags` of the first host CPU in `/proc/cpuinfo`
+    pub cpu_flags: Option<Vec<String>>,
+    pub probed_at: SystemTime,
+}
+
+fn qemu_output(qemu: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new(qemu).args(args).output();
+    match output {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(output) => {
+            warn!(
+                "{} {} failed: {}",
+                qemu.display(),
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Failed to run {}: {err}", qemu.display());
+            None
+        }
+    }
+}
+
+fn parse_qemu_version(output: &str) -> Option<String> {
+    let mut words = output.lines().next()?.split_whitespace();
+    words.find(|w| *w == "version")?;
+    words.next().map(|v| v.trim_end_matches(',').to_string())
+}
+
+/// Model names from `-cpu help`, whose entries look like `x86 Skylake-Server  (alias ...)`.
+fn parse_cpu_models(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("x86"), Some(model)) => Some(model.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn probe_kvm() -> Option<bool> {
+    match fs_err::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+    {
+        Ok(_) => Some(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(false),
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => Some(false),
+        Err(err) => {
+            warn!("Failed to probe KVM: {err}");
+            None
+        }
+    }
+}
+
+fn probe_tdx() -> Option<bool> {
+    match fs_err::read_to_string(TDX_PARAM) {
+        Ok(value) => Some(matches!(value.trim(), "Y" | "1")),
+        // kvm_intel is not loaded or too old to know about TDX
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(false),
+        Err(err) => {
+            warn!("Failed to probe TDX: {err}");
+            None
+        }
+    }
+}
+
+fn probe_cpu_flags() -> Option<Vec<String>> {
+    let cpuinfo = match fs_err::read_to_string("/proc/cpuinfo") {
+        Ok(cpuinfo) => cpuinfo,
+        Err(err) => {
+            warn!("Failed to probe CPU flags: {err}");
+            return None;
+        }
+    };
+    let flags = cpuinfo
+        .lines()
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| matches!(key.trim(), "flags" | "vmx flags"))
+        .flat_map(|(_, flags)| flags.split_whitespace().map(String::from))
+        .collect();
+    Some(flags)
+}
+
+impl HostCapabilities {
+    /// Run all probes. Spawns QEMU, so call it off the async runtime.
+    pub fn probe(qemu: &Path) -> Self {
+        Self {
+            qemu_version: qemu_output(qemu, &["--version"])
+                .as_deref()
+                .and_then(parse_qemu_version),
+            cpu_models: qemu_output(qemu, &["-cpu", "help"])
+                .as_deref()
+                .map(parse_cpu_models),
+            machines: qemu_output(qemu, &["-machine", "help"])
+                .as_deref()
+                .map(QemuMachines::parse),
+            kvm: probe_kvm(),
+            tdx: probe_tdx(),
+            cpu_flags: probe_cpu_flags(),
+            probed_at: SystemTime::now(),
+        }
+    }
+
+    /// Capabilities known to be missing that prevent launching CVMs.
+    pub fn missing(&self) -> Vec<&'static str> {
+        let mut missing = vec![];
+        if self.kvm == Some(false) {
+            missing.push("KVM (/dev/kvm)");
+        }
+        if self.tdx == Some(false) {
+            missing.push("TDX");
+        }
+        if self.qemu_version.is_none() && self.cpu_models.is_none() {
+            missing.push("a working QEMU");
+        }
+        missing
+    }
+
+    /// Host CPU flags known to be missing for the guest TSC settings.
+    pub fn missing_tsc_flags(&self, tsc: &TscConfig) -> Vec<&'static str> {
+        let Some(flags) = &self.cpu_flags else {
+            return vec![];
+        };
+        let mut required = vec![];
+        if tsc.invariant {
+            required.extend(["constant_tsc", "nonstop_tsc"]);
+        }
+        if tsc.frequency > 0 {
+            required.push("tsc_scaling");
+        }
+        required
+            .into_iter()
+            .filter(|flag| !flags.iter().any(|f| f == flag))
+            .collect()
+    }
+
+    pub fn to_pb(&self) -> pb::HostInfo {
+        pb::HostInfo {
+            qemu_version: self.qemu_version.clone(),
+            cpu_models: self.cpu_models.clone().unwrap_or_default(),
+            machines: self
+                .machines
+                .as_ref()
+                .map(|m| m.names.clone())
+                .unwrap_or_default(),
+            kvm: self.kvm,
+            tdx: self.tdx,
+            probed_at_ms: self
+                .probed_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+}
+
+/// [`HostCapabilities`] probed on first use and again once `refresh` has passed.
+pub struct CapabilityCache {
+    qemu: PathBuf,
+    /// `None` keeps the probes until [`Self::invalidate`]
+    refresh: Option<Duration>,
+    cached: Mutex<Option<(Instant, Arc<HostCapabilities>)>>,
+}
+
+impl CapabilityCache {
+    pub fn new(qemu: PathBuf, refresh: Option<Duration>) -> Self {
+        Self {
+            qemu,
+            refresh,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub async fn get(&self) -> Arc<HostCapabilities> {
+        if let Some((at, caps)) = &*self.cached.lock().unwrap() {
+            if self.refresh.is_none_or(|refresh| at.elapsed() < refresh) {
+                return caps.clone();
+            }
+        }
+        let qemu = self.qemu.clone();
+        let caps = match tokio::task::spawn_blocking(move || HostCapabilities::probe(&qemu)).await {
+            Ok(caps) => Arc::new(caps),
+            Err(err) => {
+                warn!("Host capability probe panicked: {err}");
+                Arc::new(HostCapabilities {
+                    qemu_version: None,
+                    cpu_models: None,
+                    machines: None,
+                    kvm: None,
+                    tdx: None,
+                    cpu_flags: None,
+                    probed_at: SystemTime::now(),
+                })
+            }
+        };
+        *self.cached.lock().unwrap() = Some((Instant::now(), caps.clone()));
+        caps
+    }
+
+    /// The probes if they ran already, without probing.
+    pub fn cached(&self) -> Option<Arc<HostCapabilities>> {
+        self.cached
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, caps)| caps.clone())
+    }
+
+    /// Drop the cached probes so the next [`Self::get`] runs them again.
+    pub fn invalidate(&self) {
+        info!("Host capabilities will be probed again");
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+impl App {
+    /// Refuse to launch VMs on a host known to lack what CVMs need, or without the free space
+    /// in the run dir to set them up.
+    pub(crate) async fn preflight(&self) -> Result<()> {
+        let missing = self.capabilities.get().await.missing();
+        if !missing.is_empty() {
+            bail!("Host is missing {}", missing.join(", "));
+        }
+        self.check_run_dir_space()
+    }
+}
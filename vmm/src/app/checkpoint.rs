@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Memory checkpoints of running VMs.
+//!
+//! A checkpoint is the migration stream of a VM saved to a file in its work dir, without the
+//! disks. Restoring it relaunches QEMU waiting for an incoming migration and feeds it the file,
+//! so the guest resumes from the checkpoint on its disks as they are at that point.
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use super::{App, QmpClient};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long QEMU has to exit after the VM is stopped for a restore.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
+
+/// Wait for the migration QEMU runs to end, cancelling it after `timeout`.
+async fn wait_migration(qmp: &mut QmpClient, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = qmp.execute("query-migrate", None).await?;
+        match status.get("status").and_then(Value::as_str) {
+            Some("completed") => return Ok(()),
+            Some("failed") | Some("cancelled") => bail!(
+                "Migration failed: {}",
+                status
+                    .get("error-desc")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error")
+            ),
+            _ => {}
+        }
+        if Instant::now() >= deadline {
+            qmp.execute("migrate_cancel", None).await.ok();
+            bail!("Migration did not complete within {timeout:?}");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+impl App {
+    fn checkpoint_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.cvm.migration.timeout)
+    }
+
+    /// Save the memory and device state of the running VM `id` to its checkpoint file,
+    /// replacing the previous checkpoint.
+    ///
+    /// The state is saved live unless `pause` is set, in which case the guest is paused for
+    /// the whole save. Either way the guest is paused for the final pass and resumed after.
+    pub async fn checkpoint_vm(&self, id: &str, pause: bool) -> Result<pb::CheckpointInfo> {
+        if !self.is_running(id).await? {
+            bail!("VM {id} is not running");
+        }
+        if self
+            .lock()
+            .get(id)
+            .is_some_and(|vm| vm.state.incoming_migration.is_some())
+        {
+            bail!("VM {id} is waiting for an incoming migration");
+        }
+        let path = self.work_dir(id).checkpoint_file();
+        let tmp_path = path.with_extension("mem.tmp");
+        let started = Instant::now();
+        let mut qmp = self.qmp(id).await?;
+        if pause {
+            qmp.execute("stop", None)
+                .await
+                .context("Failed to pause VM")?;
+        }
+        let uri = format!("exec:cat > {}", shell_quote(&tmp_path));
+        let result = async {
+            qmp.execute("migrate", Some(json!({ "uri": uri }))).await?;
+            wait_migration(&mut qmp, self.checkpoint_timeout()).await
+        }
+        .await;
+        // A completed migration leaves the guest paused as well
+        if let Err(err) = qmp.execute("cont", None).await {
+            warn!("Failed to resume VM {id} after checkpointing: {err:?}");
+        }
+        if let Err(err) = result {
+            fs_err::remove_file(&tmp_path).ok();
+            return Err(err.context("Failed to checkpoint VM"));
+        }
+        fs_err::rename(&tmp_path, &path)?;
+        let size = fs_err::metadata(&path)?.len();
+        let duration = started.elapsed();
+        info!("Checkpointed VM {id}: {size} bytes in {duration:?}");
+        self.sync_guest_time_after(id, "checkpoint");
+        self.emit_event(
+            "vm.checkpoint",
+            Some(id),
+            json!({ "size": size, "duration_ms": duration.as_millis() as u64, "paused": pause }),
+        );
+        Ok(pb::CheckpointInfo {
+            size,
+            duration_ms: duration.as_millis() as u64,
+        })
+    }
+
+    /// Relaunch VM `id` from its checkpoint file, stopping it first if it runs.
+    pub async fn restore_checkpoint(&self, id: &str) -> Result<pb::CheckpointInfo> {
+        if !self.config.cvm.qmp_socket {
+            bail!("Restoring a checkpoint requires cvm.qmp_socket");
+        }
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        let path = self.work_dir(id).checkpoint_file();
+        let size = fs_err::metadata(&path)
+            .with_context(|| format!("VM {id} has no checkpoint"))?
+            .len();
+        let started = Instant::now();
+        if self.is_running(id).await? {
+            info!("Stopping VM {id} to restore its checkpoint");
+            self.stop_vm(id).await.context("Failed to stop VM")?;
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while self.is_running(id).await? {
+                if Instant::now() > deadline {
+                    bail!("VM {id} did not stop within {STOP_TIMEOUT:?}");
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+        self.launch_vm(id, Some("defer")).await?;
+        let uri = format!("exec:cat {}", shell_quote(&path));
+        let result = async {
+            let mut qmp = self.qmp(id).await?;
+            qmp.execute("migrate-incoming", Some(json!({ "uri": uri })))
+                .await?;
+            wait_migration(&mut qmp, self.checkpoint_timeout()).await
+        }
+        .await;
+        if let Err(err) = result {
+            // QEMU waiting for a stream that never completes has no guest to run
+            if let Err(err) = self.stop_vm(id).await {
+                warn!("Failed to stop VM {id} after a failed restore: {err:?}");
+            }
+            return Err(err.context("Failed to restore checkpoint"));
+        }
+        let duration = started.elapsed();
+        info!("Restored checkpoint of VM {id} in {duration:?}");
+        self.sync_guest_time_after(id, "checkpoint restore");
+        self.emit_event(
+            "vm.checkpoint_restore",
+            Some(id),
+            json!({ "size": size, "duration_ms": duration.as_millis() as u64 }),
+        );
+        Ok(pb::CheckpointInfo {
+            size,
+            duration_ms: duration.as_millis() as u64,
+        })
+    }
+}
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Field-level comparison of the stored config of a VM against a candidate config.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use super::{App, Manifest};
+
+/// Manifest fields assigned by the VMM rather than declared by the config.
+const IGNORED_FIELDS: &[&str] = &[
+    "id",
+    "created_at_ms",
+    "signed_by",
+    "origin",
+    "previous_image",
+];
+
+/// Fields that take effect without restarting the VM. `disks.<id>.throttle` is applied over
+/// QMP by `SetVmIoThrottle`, the others are only read by the VMM.
+const HOT_FIELDS: &[&str] = &[
+    "name",
+    "restart_policy",
+    "exit_codes",
+    "watchdog",
+    "boot_timeout",
+    "max_lifetime",
+    "hooks",
+    "scheduling",
+];
+
+fn is_hot(field: &str) -> bool {
+    HOT_FIELDS
+        .iter()
+        .any(|hot| field == *hot || field.starts_with(&format!("{hot}.")))
+        || (field.starts_with("disks.") && field.ends_with(".throttle"))
+}
+
+fn fingerprint(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// Manifest as JSON with disks keyed by id, so a change to one disk is reported on its own.
+fn manifest_value(manifest: &Manifest) -> Result<Map<String, Value>> {
+    let Value::Object(mut fields) = serde_json::to_value(manifest)? else {
+        bail!("Manifest is not an object");
+    };
+    for field in IGNORED_FIELDS {
+        fields.remove(*field);
+    }
+    if let Some(Value::Array(disks)) = fields.remove("disks") {
+        let disks = disks
+            .into_iter()
+            .map(|mut disk| {
+                let id = disk
+                    .as_object_mut()
+                    .and_then(|d| d.remove("id"))
+                    .and_then(|id| id.as_str().map(String::from))
+                    .unwrap_or_default();
+                (id, disk)
+            })
+            .collect();
+        fields.insert("disks".into(), Value::Object(disks));
+    }
+    Ok(fields)
+}
+
+fn change(
+    field: String,
+    current: Option<String>,
+    desired: Option<String>,
+) -> pb::ConfigFieldChange {
+    let kind = match (&current, &desired) {
+        (None, _) => "added",
+        (_, None) => "removed",
+        _ => "changed",
+    };
+    pb::ConfigFieldChange {
+        requires_restart: !is_hot(&field),
+        field,
+        kind: kind.into(),
+        current,
+        desired,
+    }
+}
+
+/// Compare two JSON objects, recursing into nested objects.
+fn diff_objects(
+    prefix: &str,
+    current: &Map<String, Value>,
+    desired: &Map<String, Value>,
+    changes: &mut Vec<pb::ConfigFieldChange>,
+) {
+    let mut keys = current.keys().chain(desired.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let field = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        // An absent field and an explicit null mean the same in the manifest
+        let current = current.get(key).filter(|v| !v.is_null());
+        let desired = desired.get(key).filter(|v| !v.is_null());
+        match (current, desired) {
+            (Some(Value::Object(c)), Some(Value::Object(d))) => diff_objects(&field, c, d, changes),
+            (c, d) if c != d => changes.push(change(
+                field,
+                c.map(Value::to_string),
+                d.map(Value::to_string),
+            )),
+            _ => {}
+        }
+    }
+}
+
+/// Field changes from the `current` to the `desired` manifest.
+fn manifest_changes(current: &Manifest, desired: &Manifest) -> Result<Vec<pb::ConfigFieldChange>> {
+    let mut changes = vec![];
+    diff_objects(
+        "",
+        &manifest_value(current)?,
+        &manifest_value(desired)?,
+        &mut changes,
+    );
+    Ok(changes)
+}
+
+impl App {
+    /// Diff the stored config of VM `id` against `desired`, the manifest of `config`.
+    ///
+    /// The compose file, user config and encrypted env are compared by fingerprint.
+    pub fn diff_vm_config(
+        &self,
+        id: &str,
+        desired: &Manifest,
+        config: &pb::VmConfiguration,
+    ) -> Result<pb::VmConfigDiff> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        let work_dir = self.work_dir(id);
+        let current = work_dir.manifest().context("Failed to read manifest")?;
+        let mut changes = manifest_changes(&current, desired)?;
+        for (field, path, desired) in [
+            (
+                "compose_file",
+                work_dir.app_compose_path(),
+                config.compose_file.as_bytes(),
+            ),
+            (
+                "user_config",
+                work_dir.user_config_path(),
+                config.user_config.as_bytes(),
+            ),
+            (
+                "encrypted_env",
+                work_dir.encrypted_env_path(),
+                &config.encrypted_env[..],
+            ),
+        ] {
+            let current = fs_err::read(&path).ok().filter(|c| !c.is_empty());
+            let desired = (!desired.is_empty()).then_some(desired);
+            if current.as_deref() != desired {
+                changes.push(change(
+                    field.into(),
+                    current.as_deref().map(fingerprint),
+                    desired.map(fingerprint),
+                ));
+            }
+        }
+        Ok(pb::VmConfigDiff {
+            in_sync: changes.is_empty(),
+            requires_restart: changes.iter().any(|c| c.requires_restart),
+            changes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(current: &Manifest, desired: &Manifest) -> Vec<pb::ConfigFieldChange> {
+        manifest_changes(current, desired).unwrap()
+    }
+
+    #[test]
+    fn unchanged_manifest_has_no_changes() {
+        let current = Manifest::for_test("vm-1");
+        assert!(changes(&current, &current.clone()).is_empty());
+
+        // Fields assigned by the VMM are not compared
+        let mut desired = Manifest::for_test("vm-2");
+        desired.name = current.name.clone();
+        desired.created_at_ms = 42;
+        desired.signed_by = Some("ci".into());
+        assert!(changes(&current, &desired).is_empty());
+    }
+
+    #[test]
+    fn boot_disk_swap_only_changes_the_image() {
+        let before = Manifest::for_test("vm-1");
+        let mut swapped = before.clone();
+        swapped.image = "dstack-0.5.1".into();
+        swapped.previous_image = Some(before.image.clone());
+        let diff = changes(&before, &swapped);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].field, "image");
+        assert!(diff[0].requires_restart);
+
+        // The previous image the swap recorded is not drift
+        let mut desired = swapped.clone();
+        desired.previous_image = None;
+        assert!(changes(&swapped, &desired).is_empty());
+    }
+
+    #[test]
+    fn hot_fields_apply_without_restart() {
+        let current = Manifest::for_test("vm-1");
+        let mut desired = current.clone();
+        desired.name = "renamed".into();
+        desired.max_lifetime = Some(3600);
+        let changes = changes(&current, &desired);
+        assert_eq!(changes.len(), 2);
+        let lifetime = &changes[0];
+        assert_eq!(lifetime.field, "max_lifetime");
+        assert_eq!(lifetime.kind, "added");
+        assert_eq!(lifetime.current, None);
+        assert_eq!(lifetime.desired.as_deref(), Some("3600"));
+        let name = &changes[1];
+        assert_eq!(name.field, "name");
+        assert_eq!(name.kind, "changed");
+        assert!(changes.iter().all(|c| !c.requires_restart));
+    }
+
+    #[test]
+    fn other_fields_require_restart() {
+        let current = Manifest::for_test("vm-1");
+        let mut desired = current.clone();
+        desired.vcpu = 8;
+        desired.image = "dstack-0.5.1".into();
+        let changes = changes(&current, &desired);
+        let fields = changes.iter().map(|c| c.field.as_str()).collect::<Vec<_>>();
+        assert_eq!(fields, ["image", "vcpu"]);
+        assert!(changes
+            .iter()
+            .all(|c| c.requires_restart && c.kind == "changed"));
+
+        let mut removed = current.clone();
+        removed.boot_timeout = Some(60);
+        let changes = manifest_changes(&removed, &current).unwrap();
+        assert_eq!(changes[0].kind, "removed");
+        assert!(!changes[0].requires_restart);
+    }
+
+    #[test]
+    fn nested_fields_are_classified_by_path() {
+        assert!(is_hot("hooks.pre_start"));
+        assert!(is_hot("disks.data.throttle"));
+        assert!(!is_hot("disks.data.size"));
+        assert!(!is_hot("named"));
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Inspection of the seed files a VM is booted with.
+//!
+//! dstack has no cloud-init drive, the VMM writes the seed files into the shared directory
+//! the guest mounts over 9p. `GetVmConfigDrive` lists that directory as the guest sees it, with
+//! the contents of the files that hold no secrets.
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use dstack_types::shared_filenames::{
+    compat_v3, APP_KEYS, DECRYPTED_ENV, DECRYPTED_ENV_JSON, ENCRYPTED_ENV, SYS_CONFIG, USER_CONFIG,
+};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::{App, REDACTED};
+
+/// Files listed without their contents.
+const SECRET_FILES: &[&str] = &[
+    APP_KEYS,
+    DECRYPTED_ENV,
+    DECRYPTED_ENV_JSON,
+    ENCRYPTED_ENV,
+    USER_CONFIG,
+    compat_v3::ENCRYPTED_ENV,
+    "certs/tmp-ca.key",
+];
+
+/// Keys of the sys-config replaced with [`REDACTED`].
+const SECRET_SYS_CONFIG_KEYS: &[&str] = &["guest_api_token"];
+
+/// Files larger than this are listed without their contents.
+const MAX_CONTENT_SIZE: u64 = 256 * 1024;
+
+fn seed_file(root: &Path, path: &Path) -> Result<pb::ConfigDriveFile> {
+    let relative = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .display()
+        .to_string();
+    let data = fs::read(path)?;
+    let mut file = pb::ConfigDriveFile {
+        path: relative.clone(),
+        size: data.len() as u64,
+        sha256: hex::encode(Sha256::digest(&data)),
+        content: None,
+        redacted: false,
+    };
+    if SECRET_FILES.contains(&relative.as_str()) {
+        file.redacted = true;
+        return Ok(file);
+    }
+    if file.size > MAX_CONTENT_SIZE {
+        return Ok(file);
+    }
+    let Ok(content) = String::from_utf8(data) else {
+        return Ok(file);
+    };
+    if relative == SYS_CONFIG || relative == compat_v3::SYS_CONFIG {
+        let mut config: Value = serde_json::from_str(&content)?;
+        for key in SECRET_SYS_CONFIG_KEYS {
+            if let Some(value) = config.get_mut(*key).filter(|v| !v.is_null()) {
+                *value = REDACTED.into();
+                file.redacted = true;
+            }
+        }
+        file.content = Some(serde_json::to_string_pretty(&config)?);
+    } else {
+        file.content = Some(content);
+    }
+    Ok(file)
+}
+
+/// The regular files under `dir`, sorted by path. Symlinks are not followed, the guest may
+/// create them if the directory is writable.
+fn collect_seed_files(root: &Path, dir: &Path, files: &mut Vec<pb::ConfigDriveFile>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_seed_files(root, &entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(seed_file(root, &entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+impl App {
+    /// The seed files in the shared directory of VM `id`, with secrets withheld.
+    pub fn vm_config_drive(&self, id: &str) -> Result<pb::VmConfigDrive> {
+        let Some(shared_ro) = self.lock().get(id).map(|vm| vm.config.image.info.shared_ro) else {
+            bail!("VM not found: {id}");
+        };
+        let shared_dir = self.shared_dir(id);
+        let mut files = vec![];
+        if shared_dir.exists() {
+            collect_seed_files(&shared_dir, &shared_dir, &mut files)?;
+        }
+        Ok(pb::VmConfigDrive { shared_ro, files })
+    }
+}
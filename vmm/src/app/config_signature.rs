@@ -0,0 +1,140 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detached Ed25519 signatures over VM definitions.
+//!
+//! A signature covers the protobuf encoding of the request with its `signature` field cleared.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use prost::Message;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::config::AuthConfig;
+
+pub fn vm_config_signed_message(config: &pb::VmConfiguration) -> Vec<u8> {
+    pb::VmConfiguration {
+        signature: vec![],
+        ..config.clone()
+    }
+    .encode_to_vec()
+}
+
+pub fn upgrade_signed_message(request: &pb::UpgradeAppRequest) -> Vec<u8> {
+    pb::UpgradeAppRequest {
+        signature: vec![],
+        ..request.clone()
+    }
+    .encode_to_vec()
+}
+
+/// Name of the configured key that produced `signature`.
+///
+/// Returns `None` for an unsigned message unless `auth.require_signed_configs` is set. A
+/// signature that matches no key is always rejected.
+pub fn verify_config_signature(
+    auth: &AuthConfig,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<Option<String>> {
+    if signature.is_empty() {
+        if auth.require_signed_configs {
+            bail!("VM config is not signed");
+        }
+        return Ok(None);
+    }
+    for key in &auth.signing_keys {
+        let public_key = hex::decode(&key.public_key)
+            .with_context(|| format!("Invalid public key of signing key {}", key.name))?;
+        if UnparsedPublicKey::new(&ED25519, &public_key)
+            .verify(message, signature)
+            .is_ok()
+        {
+            return Ok(Some(key.name.clone()));
+        }
+    }
+    bail!("VM config signature does not match any signing key");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SigningKey;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn key_pair(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn auth(require_signed_configs: bool, trusted: &[(&str, &Ed25519KeyPair)]) -> AuthConfig {
+        AuthConfig {
+            require_signed_configs,
+            signing_keys: trusted
+                .iter()
+                .map(|(name, key)| SigningKey {
+                    name: name.to_string(),
+                    public_key: hex::encode(key.public_key()),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn signed_config(key: &Ed25519KeyPair) -> pb::VmConfiguration {
+        let mut config = pb::VmConfiguration {
+            name: "web".into(),
+            image: "dstack-0.5.0".into(),
+            vcpu: 2,
+            memory: 2048,
+            ..Default::default()
+        };
+        config.signature = key
+            .sign(&vm_config_signed_message(&config))
+            .as_ref()
+            .to_vec();
+        config
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let (ci, release) = (key_pair(1), key_pair(2));
+        let auth = auth(true, &[("ci", &ci), ("release", &release)]);
+        let config = signed_config(&release);
+        let signer =
+            verify_config_signature(&auth, &vm_config_signed_message(&config), &config.signature)
+                .unwrap();
+        assert_eq!(signer.as_deref(), Some("release"));
+    }
+
+    #[test]
+    fn rejects_a_tampered_config() {
+        let ci = key_pair(1);
+        let auth = auth(false, &[("ci", &ci)]);
+        let mut config = signed_config(&ci);
+        config.vcpu = 64;
+        let message = vm_config_signed_message(&config);
+        assert!(verify_config_signature(&auth, &message, &config.signature).is_err());
+    }
+
+    #[test]
+    fn rejects_an_untrusted_key() {
+        let (ci, other) = (key_pair(1), key_pair(3));
+        let auth = auth(false, &[("ci", &ci)]);
+        let config = signed_config(&other);
+        let message = vm_config_signed_message(&config);
+        assert!(verify_config_signature(&auth, &message, &config.signature).is_err());
+    }
+
+    #[test]
+    fn unsigned_configs_follow_require_signed_configs() {
+        let ci = key_pair(1);
+        let message = vm_config_signed_message(&pb::VmConfiguration::default());
+        let optional = auth(false, &[("ci", &ci)]);
+        assert_eq!(
+            verify_config_signature(&optional, &message, &[]).unwrap(),
+            None
+        );
+        let required = auth(true, &[("ci", &ci)]);
+        assert!(verify_config_signature(&required, &message, &[]).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host CPU affinity and CPU weight of a VM.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+/// Range of the cgroup v2 `cpu.weight`.
+const CPU_WEIGHT_RANGE: std::ops::RangeInclusive<u32> = 1..=10000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct CpuConfig {
+    /// Host cores the vCPU threads may run on, any core if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub affinity: Vec<u32>,
+    /// Relative CPU weight (cgroup `cpu.weight`, 1-10000, 100 by default), 0 leaves it unset
+    #[serde(default)]
+    pub shares: u32,
+}
+
+impl CpuConfig {
+    pub fn to_pb(&self) -> pb::CpuConfig {
+        pb::CpuConfig {
+            affinity: format_cpu_list(&self.affinity),
+            shares: self.shares,
+        }
+    }
+
+    /// Cores in the affinity set that are not online on this host.
+    pub fn offline_cores(&self) -> Result<Vec<u32>> {
+        let online = online_cpus()?;
+        Ok(self
+            .affinity
+            .iter()
+            .filter(|core| !online.contains(core))
+            .copied()
+            .collect())
+    }
+
+    /// Wrap the VM command so it runs in its own scope with the configured CPU weight.
+    pub fn wrap_command(&self, args: &mut Vec<String>, unit: &str) {
+        if self.shares == 0 {
+            return;
+        }
+        let weight = format!("CPUWeight={}", self.shares);
+        let wrapper = [
+            "systemd-run",
+            "--scope",
+            "--quiet",
+            "--collect",
+            "--unit",
+            unit,
+            "-p",
+            &weight,
+            "--",
+        ];
+        args.splice(0..0, wrapper.into_iter().map(String::from));
+    }
+}
+
+/// Parse a Linux cpulist such as `0-3,8,10-11`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>> {
+    let mut cpus = vec![];
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (part, part),
+        };
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u32>()
+                .with_context(|| format!("Invalid CPU list: {list}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            bail!("Invalid CPU range: {part}");
+        }
+        cpus.extend(start..=end);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Format cores as a cpulist, the inverse of [`parse_cpu_list`].
+pub fn format_cpu_list(cpus: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn online_cpus() -> Result<Vec<u32>> {
+    let online = fs::read_to_string("/sys/devices/system/cpu/online")
+        .context("Failed to read online CPUs")?;
+    parse_cpu_list(online.trim())
+}
+
+pub fn resolve_cpu(cpu: &pb::CpuConfig, pin_numa: bool) -> Result<Option<CpuConfig>> {
+    let config = CpuConfig {
+        affinity: parse_cpu_list(&cpu.affinity)?,
+        shares: cpu.shares,
+    };
+    if config == CpuConfig::default() {
+        return Ok(None);
+    }
+    if config.shares != 0 && !CPU_WEIGHT_RANGE.contains(&config.shares) {
+        bail!("CPU shares must be within 1-10000");
+    }
+    if !config.affinity.is_empty() {
+        if pin_numa {
+            bail!("CPU affinity can not be combined with NUMA pinning");
+        }
+        let offline = config.offline_cores()?;
+        if !offline.is_empty() {
+            bail!(
+                "CPU affinity references cores that are not online: {}",
+                format_cpu_list(&offline)
+            );
+        }
+    }
+    Ok(Some(config))
+}
@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of QEMU processes that died without the supervisor noticing.
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use supervisor_client::supervisor::{ProcessInfo, ProcessStatus};
+use tracing::warn;
+
+use super::App;
+
+/// Why a process tracked as running is not, `None` if it is alive.
+pub(super) fn defunct_reason(pid: u32) -> Option<String> {
+    let Ok(stat) = fs_err::read_to_string(format!("/proc/{pid}/stat")) else {
+        return Some(format!("QEMU process {pid} no longer exists"));
+    };
+    // The state follows the command name, which may contain spaces
+    let state = stat.rsplit_once(')')?.1.split_whitespace().next()?;
+    matches!(state, "Z" | "X").then(|| format!("QEMU process {pid} is defunct"))
+}
+
+impl App {
+    /// Report a process the supervisor tracks as running but whose QEMU is gone as exited.
+    ///
+    /// The `vm.exit` event is fired once so auto-restart can act, and the supervisor is asked
+    /// to stop the process so it reaps it and records the exit itself.
+    pub(crate) async fn reconcile_process(&self, info: &mut ProcessInfo) {
+        if !info.state.status.is_running() {
+            return;
+        }
+        let Some(reason) = info.state.pid.and_then(defunct_reason) else {
+            return;
+        };
+        let id = info.config.id.clone();
+        let newly_reported = self
+            .lock()
+            .get_mut(&id)
+            .is_some_and(|vm| vm.state.restart.mark_exit_reported());
+        if newly_reported {
+            warn!("VM {id} is tracked as running but {reason}");
+            self.emit_event(
+                "vm.exit",
+                Some(&id),
+                json!({ "error": reason, "defunct": true }),
+            );
+        }
+        if let Err(err) = self.supervisor.stop(&id).await {
+            warn!("Failed to stop defunct process of VM {id}: {err:?}");
+        }
+        info.state.status = ProcessStatus::Error(reason);
+        info.state.stopped_at.get_or_insert_with(SystemTime::now);
+    }
+
+    /// List the supervisor processes, reconciled with [`App::reconcile_process`].
+    pub(crate) async fn list_processes(&self) -> Result<Vec<ProcessInfo>> {
+        let mut processes = self.supervisor.list().await.context("Failed to list VMs")?;
+        for process in &mut processes {
+            self.reconcile_process(process).await;
+        }
+        Ok(processes)
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Diagnostics bundles of VMs that did not finish booting in time, or collected on request.
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use serde_json::{json, Value};
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+use super::usage::process_stats;
+use super::{redact_vm_configuration, App, REDACTED};
+
+/// Boot progress the guest reports once it is ready.
+const BOOT_DONE: &str = "done";
+const SERIAL_TAIL_LINES: usize = 500;
+const STDERR_TAIL_LINES: usize = 200;
+const EVENTS_LIMIT: usize = 200;
+const QMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn write_json(path: impl AsRef<Path>, value: &impl serde::Serialize) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+impl App {
+    /// Capture a diagnostics bundle for each running VM that has not reported ready within its
+    /// `boot_timeout`. A bundle is captured once per boot and the VM is left running.
+    pub(crate) async fn check_boot_timeouts(&self) -> Result<()> {
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .map(|p| p.config.id)
+            .collect::<BTreeSet<_>>();
+        let now = Instant::now();
+        let mut timed_out = vec![];
+        {
+            let mut state = self.lock();
+            for vm in state.vms.values_mut() {
+                let manifest = &vm.config.manifest;
+                let Some(timeout) = manifest.boot_timeout else {
+                    continue;
+                };
+                if timeout == 0 || !running.contains(&manifest.id) {
+                    continue;
+                }
+                let Some(started) = vm.state.boot_started else {
+                    continue;
+                };
+                if vm.state.boot_diagnosed || vm.state.boot_progress == BOOT_DONE {
+                    continue;
+                }
+                if now.duration_since(started) < Duration::from_secs(timeout) {
+                    continue;
+                }
+                vm.state.boot_diagnosed = true;
+                timed_out.push((
+                    manifest.id.clone(),
+                    json!({
+                        "boot_timeout_secs": timeout,
+                        "boot_progress": vm.state.boot_progress,
+                        "boot_error": vm.state.boot_error,
+                    }),
+                ));
+            }
+        }
+        for (id, mut details) in timed_out {
+            error!("VM {id} did not finish booting in time: {details}");
+            match self.capture_boot_diagnostics(&id, &details).await {
+                Ok(dir) => {
+                    warn!("Boot diagnostics of VM {id} saved to {}", dir.display());
+                    details["bundle"] = dir.display().to_string().into();
+                }
+                Err(err) => error!("Failed to capture boot diagnostics of VM {id}: {err:?}"),
+            }
+            self.emit_event("vm.boot_timeout", Some(&id), details);
+        }
+        Ok(())
+    }
+
+    async fn capture_boot_diagnostics(&self, id: &str, summary: &Value) -> Result<PathBuf> {
+        let dir = self
+            .work_dir(id)
+            .diagnostics_dir()
+            .join(format!("boot-timeout-{}", unix_timestamp()));
+        fs::create_dir_all(&dir)?;
+        write_json(dir.join("summary.json"), summary)?;
+        self.write_runtime_diagnostics(id, &dir, &["query-status"])
+            .await?;
+        Ok(dir)
+    }
+
+    /// Write the serial console and QEMU stderr tails and the output of the QMP `commands`
+    /// into `dir`. QMP failures are recorded in place of the output.
+    async fn write_runtime_diagnostics(
+        &self,
+        id: &str,
+        dir: &Path,
+        commands: &[&str],
+    ) -> Result<()> {
+        let work_dir = self.work_dir(id);
+        let serial = work_dir
+            .serial_tail(SERIAL_TAIL_LINES, &self.config.log_limits)
+            .context("Failed to read serial log")?;
+        fs::write(dir.join("serial.log"), serial.join("\n"))?;
+        let stderr = work_dir
+            .stderr_tail(STDERR_TAIL_LINES, &self.config.log_limits)
+            .context("Failed to read QEMU stderr")?;
+        fs::write(dir.join("stderr.log"), stderr.join("\n"))?;
+        let mut qmp = match timeout(QMP_TIMEOUT, self.qmp(id)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("QMP timed out")),
+        };
+        for command in commands {
+            let output = match &mut qmp {
+                Ok(qmp) => match timeout(QMP_TIMEOUT, qmp.execute(command, None)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow!("QMP timed out")),
+                },
+                Err(err) => Err(anyhow!("{err:?}")),
+            }
+            .unwrap_or_else(|err| json!({ "error": format!("{err:?}") }));
+            let name = command.strip_prefix("query-").unwrap_or(command);
+            write_json(dir.join(format!("qmp-{name}.json")), &output)?;
+        }
+        Ok(())
+    }
+
+    /// Gather the redacted config, launch command, recent events, logs, QMP state and process
+    /// stats of a VM into a `.tar.gz` in its diagnostics directory and return its path.
+    pub async fn collect_diagnostics(&self, id: &str) -> Result<PathBuf> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let diagnostics_dir = self.work_dir(id).diagnostics_dir();
+        let name = format!("collected-{}", unix_timestamp());
+        let dir = diagnostics_dir.join(&name);
+        fs::create_dir_all(&dir)?;
+
+        let mut info = self.vm_info(id).await?.context("VM not found")?;
+        if let Some(config) = &mut info.configuration {
+            redact_vm_configuration(config);
+        }
+        write_json(dir.join("info.json"), &info)?;
+
+        let process = self.supervisor.info(id).await?;
+        let mut stats = json!({ "process": null });
+        if let Some(process) = &process {
+            let mut launch = process.config.clone();
+            for value in launch.env.values_mut() {
+                *value = REDACTED.to_string();
+            }
+            write_json(dir.join("launch.json"), &launch)?;
+            stats["process"] = serde_json::to_value(&process.state)?;
+            if let Some((cpu_ticks, rss_kb)) = process.state.pid.and_then(process_stats) {
+                stats["cpu_ticks"] = cpu_ticks.into();
+                stats["rss_kb"] = rss_kb.into();
+            }
+        }
+        write_json(dir.join("stats.json"), &stats)?;
+        write_json(
+            dir.join("events.json"),
+            &self.events.query(Some(id), 0, EVENTS_LIMIT),
+        )?;
+        self.write_runtime_diagnostics(id, &dir, &["query-status", "query-block"])
+            .await?;
+
+        let bundle = diagnostics_dir.join(format!("{name}.tar.gz"));
+        let output = Command::new("tar")
+            .arg("-czf")
+            .arg(&bundle)
+            .arg("-C")
+            .arg(&diagnostics_dir)
+            .arg(&name)
+            .output()
+            .context("Failed to run tar")?;
+        fs::remove_dir_all(&dir).ok();
+        if !output.status.success() {
+            bail!(
+                "Failed to archive diagnostics: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        info!("Diagnostics of VM {id} collected to {}", bundle.display());
+        Ok(bundle)
+    }
+}
@@ -0,0 +1,685 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-disk configuration and runtime disk operations.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::disk_source::DiskSource;
+use super::{App, Manifest};
+
+/// Drive ids of the disks attached to a CVM: `hd0` is the rootfs, `hd1` the data disk.
+pub const DISK_IDS: &[&str] = &["hd0", "hd1"];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct IoThrottle {
+    #[serde(default)]
+    pub iops_rd: u64,
+    #[serde(default)]
+    pub iops_wr: u64,
+    #[serde(default)]
+    pub bps_rd: u64,
+    #[serde(default)]
+    pub bps_wr: u64,
+}
+
+impl IoThrottle {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Options appended to the `-drive` argument. Zero means unlimited.
+    pub fn drive_opts(&self) -> String {
+        let mut opts = String::new();
+        for (key, value) in [
+            ("iops-read", self.iops_rd),
+            ("iops-write", self.iops_wr),
+            ("bps-read", self.bps_rd),
+            ("bps-write", self.bps_wr),
+        ] {
+            if value > 0 {
+                opts.push_str(&format!(",throttling.{key}={value}"));
+            }
+        }
+        opts
+    }
+
+    /// The throttle of a block device in the `inserted` object of `query-block`.
+    pub fn from_qmp(inserted: &Value) -> Self {
+        Self {
+            iops_rd: json_u64(inserted, "iops_rd"),
+            iops_wr: json_u64(inserted, "iops_wr"),
+            bps_rd: json_u64(inserted, "bps_rd"),
+            bps_wr: json_u64(inserted, "bps_wr"),
+        }
+    }
+
+    pub fn to_qmp_args(self, device: &str) -> Value {
+        json!({
+            "device": device,
+            "bps": 0,
+            "bps_rd": self.bps_rd,
+            "bps_wr": self.bps_wr,
+            "iops": 0,
+            "iops_rd": self.iops_rd,
+            "iops_wr": self.iops_wr,
+        })
+    }
+}
+
+impl From<&pb::IoThrottle> for IoThrottle {
+    fn from(t: &pb::IoThrottle) -> Self {
+        Self {
+            iops_rd: t.iops_rd,
+            iops_wr: t.iops_wr,
+            bps_rd: t.bps_rd,
+            bps_wr: t.bps_wr,
+        }
+    }
+}
+
+impl From<&IoThrottle> for pb::IoThrottle {
+    fn from(t: &IoThrottle) -> Self {
+        Self {
+            iops_rd: t.iops_rd,
+            iops_wr: t.iops_wr,
+            bps_rd: t.bps_rd,
+            bps_wr: t.bps_wr,
+        }
+    }
+}
+
+/// Host page cache mode of a drive.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskCache {
+    None,
+    Writeback,
+    Writethrough,
+    Directsync,
+}
+
+impl DiskCache {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskCache::None => "none",
+            DiskCache::Writeback => "writeback",
+            DiskCache::Writethrough => "writethrough",
+            DiskCache::Directsync => "directsync",
+        }
+    }
+
+    /// Whether the host page cache is bypassed with `O_DIRECT`.
+    fn is_direct(&self) -> bool {
+        matches!(self, DiskCache::None | DiskCache::Directsync)
+    }
+
+    /// Whether the guest sees a write-through cache.
+    fn is_write_through(&self) -> bool {
+        matches!(self, DiskCache::Writethrough | DiskCache::Directsync)
+    }
+}
+
+impl FromStr for DiskCache {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => DiskCache::None,
+            "writeback" => DiskCache::Writeback,
+            "writethrough" => DiskCache::Writethrough,
+            "directsync" => DiskCache::Directsync,
+            _ => bail!("Invalid disk cache mode: {s}"),
+        })
+    }
+}
+
+/// Asynchronous I/O backend of a drive.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskAio {
+    Threads,
+    Native,
+    IoUring,
+}
+
+impl DiskAio {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskAio::Threads => "threads",
+            DiskAio::Native => "native",
+            DiskAio::IoUring => "io_uring",
+        }
+    }
+}
+
+impl FromStr for DiskAio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "threads" => DiskAio::Threads,
+            "native" => DiskAio::Native,
+            "io_uring" => DiskAio::IoUring,
+            _ => bail!("Invalid disk AIO mode: {s}"),
+        })
+    }
+}
+
+/// Handling of guest discard (TRIM/UNMAP) requests.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskDiscard {
+    /// Pass discards through to the image, punching holes in it
+    Unmap,
+    /// Drop discards
+    Ignore,
+}
+
+impl DiskDiscard {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskDiscard::Unmap => "unmap",
+            DiskDiscard::Ignore => "ignore",
+        }
+    }
+}
+
+impl FromStr for DiskDiscard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "unmap" | "on" => DiskDiscard::Unmap,
+            "ignore" | "off" => DiskDiscard::Ignore,
+            _ => bail!("Invalid disk discard mode: {s}"),
+        })
+    }
+}
+
+/// Detection of all-zero writes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectZeroes {
+    Off,
+    On,
+    /// Turn zero writes into discards. Requires discard `unmap`.
+    Unmap,
+}
+
+impl DetectZeroes {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectZeroes::Off => "off",
+            DetectZeroes::On => "on",
+            DetectZeroes::Unmap => "unmap",
+        }
+    }
+}
+
+impl FromStr for DetectZeroes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => DetectZeroes::Off,
+            "on" => DetectZeroes::On,
+            "unmap" => DetectZeroes::Unmap,
+            _ => bail!("Invalid detect-zeroes mode: {s}"),
+        })
+    }
+}
+
+/// Id of the writable qcow2 overlay, the only disk with discard enabled by default.
+const DATA_DISK_ID: &str = "hd1";
+
+/// QEMU I/O thread serving a virtio-blk disk in place of the main loop.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum IoThread {
+    /// A thread of the disk alone
+    Dedicated,
+    /// A named thread shared by the disks of the VM naming it
+    Shared(String),
+}
+
+impl IoThread {
+    /// Id of the `-object iothread` serving disk `disk`.
+    pub fn qemu_id(&self, disk: &str) -> String {
+        match self {
+            IoThread::Dedicated => format!("iothread-{disk}"),
+            IoThread::Shared(name) => format!("iothread-{name}"),
+        }
+    }
+}
+
+impl FromStr for IoThread {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "dedicated" {
+            return Ok(IoThread::Dedicated);
+        }
+        let valid = !s.is_empty()
+            && s.len() <= 32
+            && s.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid {
+            bail!("Invalid iothread name: {s}");
+        }
+        // Would share the id of the dedicated thread of that disk
+        if DISK_IDS.contains(&s) {
+            bail!("Invalid iothread name: {s}, disk ids are reserved");
+        }
+        Ok(IoThread::Shared(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for IoThread {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<IoThread> for String {
+    fn from(thread: IoThread) -> Self {
+        match thread {
+            IoThread::Dedicated => "dedicated".into(),
+            IoThread::Shared(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DiskConfig {
+    /// Drive id, one of [`DISK_IDS`]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<IoThrottle>,
+    /// QEMU default (`writeback`) if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<DiskCache>,
+    /// QEMU default (`threads`) if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aio: Option<DiskAio>,
+    /// `unmap` for the data disk and QEMU default (`ignore`) otherwise if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discard: Option<DiskDiscard>,
+    /// Same default as `discard`, `unmap` for the data disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detect_zeroes: Option<DetectZeroes>,
+    /// Network storage used in place of the local image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<DiskSource>,
+    /// Host file holding the RBD key or iSCSI password of `source`, read by QEMU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<PathBuf>,
+    /// Served by the QEMU main loop if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<IoThread>,
+}
+
+impl DiskConfig {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_pb(&self) -> pb::DiskConfig {
+        pb::DiskConfig {
+            id: self.id.clone(),
+            throttle: self.throttle.as_ref().map(Into::into),
+            cache: self.cache.map(|c| c.as_str().into()).unwrap_or_default(),
+            aio: self.aio.map(|a| a.as_str().into()).unwrap_or_default(),
+            discard: self.discard.map(|d| d.as_str().into()).unwrap_or_default(),
+            detect_zeroes: self
+                .detect_zeroes
+                .map(|d| d.as_str().into())
+                .unwrap_or_default(),
+            source: self
+                .source
+                .as_ref()
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+            secret_file: self
+                .secret_file
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            iothread: self.iothread.clone().map(String::from).unwrap_or_default(),
+        }
+    }
+
+    /// Options appended to the `-device virtio-blk-pci` argument.
+    pub fn device_opts(&self) -> String {
+        match &self.iothread {
+            Some(thread) => format!(",iothread={}", thread.qemu_id(&self.id)),
+            None => String::new(),
+        }
+    }
+
+    /// Discard mode in effect, including the data disk default.
+    pub fn effective_discard(&self) -> Option<DiskDiscard> {
+        self.discard
+            .or((self.id == DATA_DISK_ID).then_some(DiskDiscard::Unmap))
+    }
+
+    /// Detect-zeroes mode in effect, including the data disk default.
+    pub fn effective_detect_zeroes(&self) -> Option<DetectZeroes> {
+        self.detect_zeroes.or_else(|| {
+            (self.id == DATA_DISK_ID).then(|| match self.effective_discard() {
+                Some(DiskDiscard::Unmap) => DetectZeroes::Unmap,
+                _ => DetectZeroes::On,
+            })
+        })
+    }
+
+    /// Options appended to the `-drive` argument.
+    pub fn drive_opts(&self) -> String {
+        let mut opts = self
+            .throttle
+            .as_ref()
+            .map(|throttle| throttle.drive_opts())
+            .unwrap_or_default();
+        if let Some(cache) = self.cache {
+            opts.push_str(&format!(",cache={}", cache.as_str()));
+        }
+        if let Some(aio) = self.aio {
+            opts.push_str(&format!(",aio={}", aio.as_str()));
+        }
+        if let Some(discard) = self.effective_discard() {
+            opts.push_str(&format!(",discard={}", discard.as_str()));
+        }
+        if let Some(detect_zeroes) = self.effective_detect_zeroes() {
+            opts.push_str(&format!(",detect-zeroes={}", detect_zeroes.as_str()));
+        }
+        opts
+    }
+
+    /// QEMU arguments attaching a network disk as a virtio-blk device, `None` for local disks.
+    ///
+    /// The remote device is a `-blockdev` protocol node `<id>-storage` under a raw format node
+    /// named after the drive, so the drive id stays the name the guest and QMP see.
+    pub fn blockdev_args(&self, read_only: bool) -> Option<Vec<String>> {
+        let source = self.source.as_ref()?;
+        let mut args = vec![];
+        let secret_id = format!("{}-secret", self.id);
+        if let Some(secret_file) = &self.secret_file {
+            args.push("-object".into());
+            args.push(format!(
+                "secret,id={secret_id},format=raw,file={}",
+                secret_file.display().to_string().replace(',', ",,")
+            ));
+        }
+        let storage = format!("{}-storage", self.id);
+        let mut protocol = format!(
+            "{},node-name={storage}",
+            source.blockdev_opts(self.secret_file.is_some().then_some(secret_id.as_str()))
+        );
+        let mut format = format!("driver=raw,node-name={},file={storage}", self.id);
+        if read_only {
+            protocol.push_str(",read-only=on");
+            format.push_str(",read-only=on");
+        }
+        if self.cache.is_some_and(|c| c.is_direct()) {
+            protocol.push_str(",cache.direct=on");
+        }
+        if let Some(discard) = self.effective_discard() {
+            format.push_str(&format!(",discard={}", discard.as_str()));
+        }
+        if let Some(detect_zeroes) = self.effective_detect_zeroes() {
+            format.push_str(&format!(",detect-zeroes={}", detect_zeroes.as_str()));
+        }
+        let mut device = format!("virtio-blk-pci,drive={}{}", self.id, self.device_opts());
+        if self.cache.is_some_and(|c| c.is_write_through()) {
+            device.push_str(",write-cache=off");
+        }
+        args.extend(["-blockdev".into(), protocol]);
+        args.extend(["-blockdev".into(), format]);
+        args.extend(["-device".into(), device]);
+        Some(args)
+    }
+}
+
+fn parse_opt<T: FromStr<Err = anyhow::Error>>(s: &str) -> Result<Option<T>> {
+    match s {
+        "" => Ok(None),
+        s => s.parse().map(Some),
+    }
+}
+
+pub fn resolve_disks(disks: &[pb::DiskConfig]) -> Result<Vec<DiskConfig>> {
+    let mut resolved: Vec<DiskConfig> = Vec::new();
+    for disk in disks {
+        if !DISK_IDS.contains(&disk.id.as_str()) {
+            bail!("Unknown disk: {}", disk.id);
+        }
+        if resolved.iter().any(|d| d.id == disk.id) {
+            bail!("Duplicate disk: {}", disk.id);
+        }
+        let cache = parse_opt::<DiskCache>(&disk.cache)?;
+        let aio = parse_opt::<DiskAio>(&disk.aio)?;
+        if aio == Some(DiskAio::Native) && !cache.is_some_and(|c| c.is_direct()) {
+            bail!(
+                "Disk {}: aio=native requires cache=none or directsync",
+                disk.id
+            );
+        }
+        let config = DiskConfig {
+            id: disk.id.clone(),
+            throttle: disk
+                .throttle
+                .as_ref()
+                .map(IoThrottle::from)
+                .filter(|t| !t.is_empty()),
+            cache,
+            aio,
+            discard: parse_opt(&disk.discard)?,
+            detect_zeroes: parse_opt(&disk.detect_zeroes)?,
+            source: parse_opt(&disk.source)
+                .with_context(|| format!("Disk {}: invalid source", disk.id))?,
+            secret_file: (!disk.secret_file.is_empty()).then(|| disk.secret_file.clone().into()),
+            iothread: parse_opt(&disk.iothread)
+                .with_context(|| format!("Disk {}: invalid iothread", disk.id))?,
+        };
+        if config.detect_zeroes == Some(DetectZeroes::Unmap)
+            && config.effective_discard() != Some(DiskDiscard::Unmap)
+        {
+            bail!(
+                "Disk {}: detect_zeroes=unmap requires discard=unmap",
+                disk.id
+            );
+        }
+        match &config.source {
+            Some(source) => {
+                if config.throttle.is_some() || config.aio.is_some() {
+                    bail!(
+                        "Disk {}: throttle and aio are not supported with a network source",
+                        disk.id
+                    );
+                }
+                source
+                    .validate_secret(config.secret_file.as_deref())
+                    .with_context(|| format!("Disk {}", disk.id))?;
+            }
+            None if config.secret_file.is_some() => {
+                bail!("Disk {}: secret_file requires a source", disk.id);
+            }
+            None => {}
+        }
+        resolved.push(config);
+    }
+    Ok(resolved)
+}
+
+/// Check the host QEMU can open a drive in `dir` with the given AIO backend.
+///
+/// Starts QEMU without a machine on a scratch image and quits it from the monitor.
+pub fn probe_qemu_aio(qemu: &Path, dir: &Path, aio: DiskAio) -> Result<()> {
+    let image = dir.join(format!(".aio-probe-{}.img", aio.as_str()));
+    fs_err::File::create(&image)?.set_len(1024 * 1024)?;
+    let direct = if aio == DiskAio::Native { "on" } else { "off" };
+    let result = Command::new(qemu)
+        .args(["-nodefaults", "-machine", "none", "-display", "none"])
+        .args(["-monitor", "stdio", "-drive"])
+        .arg(format!(
+            "if=none,id=probe,format=raw,file={},aio={},cache.direct={direct}",
+            image.display(),
+            aio.as_str()
+        ))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                // QEMU may already have exited on an unsupported backend
+                stdin.write_all(b"quit\n").ok();
+            }
+            child.wait_with_output()
+        });
+    fs_err::remove_file(&image).ok();
+    let output = result.with_context(|| format!("Failed to run {}", qemu.display()))?;
+    if !output.status.success() {
+        bail!(
+            "QEMU does not support aio={}: {}",
+            aio.as_str(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+impl Manifest {
+    pub fn disk(&self, id: &str) -> Option<&DiskConfig> {
+        self.disks.iter().find(|d| d.id == id)
+    }
+
+    /// Ids of the I/O threads the disks of the VM are served by, each once.
+    pub fn iothread_ids(&self) -> Vec<String> {
+        let mut ids = vec![];
+        for disk in &self.disks {
+            if let Some(thread) = &disk.iothread {
+                let id = thread.qemu_id(&disk.id);
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+
+    pub fn disk_mut(&mut self, id: &str) -> &mut DiskConfig {
+        let index = match self.disks.iter().position(|d| d.id == id) {
+            Some(index) => index,
+            None => {
+                self.disks.push(DiskConfig::new(id));
+                self.disks.len() - 1
+            }
+        };
+        &mut self.disks[index]
+    }
+}
+
+fn json_u64(value: &Value, key: &str) -> u64 {
+    value.get(key).and_then(Value::as_u64).unwrap_or_default()
+}
+
+impl App {
+    /// Update the I/O throttle of a disk. Applied immediately via QMP if the VM is running.
+    pub async fn set_vm_io_throttle(
+        &self,
+        id: &str,
+        disk: &str,
+        throttle: IoThrottle,
+    ) -> Result<()> {
+        if !DISK_IDS.contains(&disk) {
+            bail!("Unknown disk: {disk}");
+        }
+        let work_dir = self.work_dir(id);
+        let mut manifest = work_dir.manifest().context("Failed to read manifest")?;
+        if manifest.disk(disk).is_some_and(|d| d.source.is_some()) {
+            bail!("Disk {disk} has a network source, which cannot be throttled");
+        }
+        if self.is_running(id).await? {
+            let mut qmp = self.qmp(id).await?;
+            qmp.execute("block_set_io_throttle", Some(throttle.to_qmp_args(disk)))
+                .await
+                .context("Failed to apply I/O throttle")?;
+        }
+        manifest.disk_mut(disk).throttle = (!throttle.is_empty()).then_some(throttle);
+        work_dir
+            .put_manifest(&manifest)
+            .context("Failed to write manifest")?;
+        self.load_vm(&work_dir, &Default::default(), false)
+            .await
+            .context("Failed to reload VM")
+    }
+
+    pub async fn vm_disk_stats(&self, id: &str) -> Result<Vec<pb::VmDiskStats>> {
+        let manifest = self
+            .work_dir(id)
+            .manifest()
+            .context("Failed to read manifest")?;
+        if !self.is_running(id).await? {
+            return Ok(manifest
+                .disks
+                .iter()
+                .map(|d| pb::VmDiskStats {
+                    disk: d.id.clone(),
+                    throttle: d.throttle.as_ref().map(Into::into),
+                    discard: d.effective_discard() == Some(DiskDiscard::Unmap),
+                    ..Default::default()
+                })
+                .collect());
+        }
+        let mut qmp = self.qmp(id).await?;
+        let blockstats = qmp.execute("query-blockstats", None).await?;
+        let blocks = qmp.execute("query-block", None).await?;
+        let mut disks = vec![];
+        for block in blocks.as_array().into_iter().flatten() {
+            let Some(device) = block.get("device").and_then(Value::as_str) else {
+                continue;
+            };
+            if device.is_empty() {
+                continue;
+            }
+            let stats = blockstats
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|s| s.get("device").and_then(Value::as_str) == Some(device))
+                .and_then(|s| s.get("stats"));
+            let stat = |key: &str| stats.map(|s| json_u64(s, key)).unwrap_or_default();
+            let discard = manifest
+                .disk(device)
+                .cloned()
+                .unwrap_or_else(|| DiskConfig::new(device))
+                .effective_discard();
+            disks.push(pb::VmDiskStats {
+                disk: device.to_string(),
+                rd_bytes: stat("rd_bytes"),
+                wr_bytes: stat("wr_bytes"),
+                rd_operations: stat("rd_operations"),
+                wr_operations: stat("wr_operations"),
+                throttle: block
+                    .get("inserted")
+                    .map(|inserted| (&IoThrottle::from_qmp(inserted)).into()),
+                discard: discard == Some(DiskDiscard::Unmap),
+            });
+        }
+        Ok(disks)
+    }
+}
@@ -0,0 +1,418 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rolling the data disk of a VM back to one of its internal qcow2 snapshots, and pruning
+//! old snapshots by a retention policy.
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use super::App;
+use crate::config::SnapshotRetentionConfig;
+
+/// How long QEMU has to exit and release the disk after the VM is stopped.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An internal snapshot of a qcow2 image as reported by `qemu-img info`.
+struct DiskSnapshot {
+    name: String,
+    date_sec: u64,
+    vm_state_size: u64,
+}
+
+/// `qemu-img info` of the image at `path`.
+async fn qemu_img_info(path: &Path) -> Result<Value> {
+    // The image may be open by a running QEMU, only its metadata is read
+    let output = Command::new("qemu-img")
+        .args(["info", "--force-share", "--output=json"])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img info failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("Invalid qemu-img info")
+}
+
+/// Bytes the image occupies on the host.
+fn actual_size(info: &Value) -> u64 {
+    info.get("actual-size")
+        .and_then(Value::as_u64)
+        .unwrap_or_default()
+}
+
+/// The internal snapshots in `qemu-img info`, oldest first.
+fn parse_disk_snapshots(info: &Value) -> Vec<DiskSnapshot> {
+    let field = |s: &Value, key: &str| s.get(key).and_then(Value::as_u64).unwrap_or_default();
+    let mut snapshots = info
+        .get("snapshots")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|s| DiskSnapshot {
+            name: s
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            date_sec: field(s, "date-sec"),
+            vm_state_size: field(s, "vm-state-size"),
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort_by_key(|s| s.date_sec);
+    snapshots
+}
+
+/// The internal snapshots of the image at `path`, oldest first.
+async fn list_disk_snapshots(path: &Path) -> Result<Vec<DiskSnapshot>> {
+    Ok(parse_disk_snapshots(&qemu_img_info(path).await?))
+}
+
+/// Delete the snapshot `name` of the image at `path`. The image must not be in use.
+async fn delete_disk_snapshot(path: &Path, name: &str) -> Result<()> {
+    let output = Command::new("qemu-img")
+        .args(["snapshot", "-d", name])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img snapshot failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The snapshots a retention policy removes, oldest first.
+///
+/// A snapshot is removed only if every rule that is set selects it: it is not one of the
+/// newest `keep_last` and it is older than `max_age` seconds.
+fn select_expired(
+    snapshots: &[DiskSnapshot],
+    keep_last: u32,
+    max_age: u64,
+    now: u64,
+) -> Vec<&DiskSnapshot> {
+    let keep_from = match keep_last {
+        0 => snapshots.len(),
+        n => snapshots.len().saturating_sub(n as usize),
+    };
+    snapshots
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i < keep_from)
+        .filter(|(_, s)| max_age == 0 || now.saturating_sub(s.date_sec) > max_age)
+        .map(|(_, s)| s)
+        .collect()
+}
+
+/// Revert the image at `path` to its snapshot `name`. The image must not be in use.
+async fn apply_disk_snapshot(path: &Path, name: &str) -> Result<()> {
+    let output = Command::new("qemu-img")
+        .args(["snapshot", "-a", name])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "qemu-img snapshot failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+impl App {
+    /// Roll the data disk of VM `id` back to its snapshot `name`, stopping the VM for it and
+    /// relaunching it afterwards if it was running.
+    ///
+    /// Restoring a snapshot older than another one of the disk is refused unless `force` is
+    /// set, since the state the newer snapshot was taken from is lost.
+    pub async fn restore_snapshot(
+        &self,
+        id: &str,
+        name: &str,
+        force: bool,
+    ) -> Result<pb::RestoreSnapshotResponse> {
+        let info = self.vm_info(id).await?.context("VM not found")?;
+        let work_dir = self.work_dir(id);
+        let manifest = work_dir.manifest().context("Failed to read manifest")?;
+        if manifest.disk("hd1").is_some_and(|d| d.source.is_some()) {
+            bail!("Cannot restore a snapshot of a data disk on network storage");
+        }
+        let hda_path = work_dir.hda_path();
+        let snapshots = list_disk_snapshots(&hda_path).await?;
+        let Some(target) = snapshots.iter().find(|s| s.name == name) else {
+            bail!("Snapshot {name} not found");
+        };
+        let newer_snapshots = snapshots
+            .iter()
+            .filter(|s| s.date_sec > target.date_sec)
+            .map(|s| s.name.clone())
+            .collect::<Vec<_>>();
+        if !newer_snapshots.is_empty() && !force {
+            bail!(
+                "Snapshot {name} is older than {}, set force to restore it anyway",
+                newer_snapshots.join(", ")
+            );
+        }
+
+        let was_running = self.is_running(id).await?;
+        if was_running {
+            info!("Stopping VM {id} to restore snapshot {name}");
+            self.stop_vm(id).await.context("Failed to stop VM")?;
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while self.is_running(id).await? {
+                if Instant::now() > deadline {
+                    bail!("VM {id} did not stop within {STOP_TIMEOUT:?}");
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+        apply_disk_snapshot(&hda_path, name)
+            .await
+            .with_context(|| format!("Failed to restore snapshot {name}"))?;
+        // The running VM is based on it now, pruning must leave it alone
+        fs::write(work_dir.restored_snapshot_file(), name)?;
+        info!("Restored snapshot {name} of VM {id}");
+        if was_running {
+            self.start_vm(id).await.context("Failed to relaunch VM")?;
+        }
+        self.emit_event(
+            "vm.snapshot_restore",
+            Some(id),
+            json!({
+                "snapshot": name,
+                "previous_status": info.status,
+                "relaunched": was_running,
+                "newer_snapshots": newer_snapshots,
+            }),
+        );
+        Ok(pb::RestoreSnapshotResponse {
+            previous_status: info.status,
+            was_running,
+            snapshot_date: target.date_sec,
+            newer_snapshots,
+        })
+    }
+
+    /// Delete the data disk snapshots of the VMs `ids`, or of all VMs if empty, that the
+    /// retention policy selects. The snapshot a running VM was last restored from is kept.
+    pub async fn prune_snapshots(
+        &self,
+        ids: &[String],
+        keep_last: u32,
+        max_age: u64,
+        dry_run: bool,
+    ) -> Result<pb::PruneSnapshotsResponse> {
+        if keep_last == 0 && max_age == 0 {
+            bail!("Set keep_last or max_age_secs");
+        }
+        let ids = match ids {
+            [] => self
+                .lock()
+                .iter_vms()
+                .map(|vm| vm.config.manifest.id.clone())
+                .collect(),
+            ids => ids.to_vec(),
+        };
+        let mut response = pb::PruneSnapshotsResponse {
+            dry_run,
+            ..Default::default()
+        };
+        for id in ids {
+            let result = self
+                .prune_vm_snapshots(&id, keep_last, max_age, dry_run, &mut response)
+                .await;
+            if let Err(err) = result {
+                warn!("Failed to prune snapshots of VM {id}: {err:?}");
+                response.errors.push(format!("{id}: {err:#}"));
+            }
+        }
+        Ok(response)
+    }
+
+    async fn prune_vm_snapshots(
+        &self,
+        id: &str,
+        keep_last: u32,
+        max_age: u64,
+        dry_run: bool,
+        response: &mut pb::PruneSnapshotsResponse,
+    ) -> Result<()> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        let work_dir = self.work_dir(id);
+        let manifest = work_dir.manifest()?;
+        let hda_path = work_dir.hda_path();
+        if manifest.disk("hd1").is_some_and(|d| d.source.is_some()) || !hda_path.exists() {
+            return Ok(());
+        }
+        let info = qemu_img_info(&hda_path).await?;
+        let snapshots = parse_disk_snapshots(&info);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let running = self.is_running(id).await?;
+        let booted_from = match running {
+            true => fs::read_to_string(work_dir.restored_snapshot_file()).ok(),
+            false => None,
+        };
+        let expired = select_expired(&snapshots, keep_last, max_age, now)
+            .into_iter()
+            .filter(|s| booted_from.as_deref() != Some(s.name.as_str()))
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        let mut pruned = vec![];
+        for snapshot in expired {
+            if !dry_run {
+                let result = match running {
+                    // QEMU holds the image, it deletes the snapshot itself
+                    true => self.delete_snapshot_online(id, &snapshot.name).await,
+                    false => delete_disk_snapshot(&hda_path, &snapshot.name).await,
+                };
+                result.with_context(|| format!("Failed to delete snapshot {}", snapshot.name))?;
+            }
+            pruned.push(snapshot.name.clone());
+            response.pruned.push(pb::PrunedSnapshot {
+                id: id.to_string(),
+                name: snapshot.name.clone(),
+                date: snapshot.date_sec,
+                vm_state_size: snapshot.vm_state_size,
+            });
+        }
+        if dry_run {
+            return Ok(());
+        }
+        let reclaimed = match qemu_img_info(&hda_path).await {
+            Ok(after) => actual_size(&info).saturating_sub(actual_size(&after)),
+            Err(err) => {
+                warn!("Failed to measure the data disk of VM {id}: {err:?}");
+                0
+            }
+        };
+        response.reclaimed_bytes += reclaimed;
+        info!(
+            "Pruned snapshots {} of VM {id}, reclaimed {reclaimed} bytes",
+            pruned.join(", ")
+        );
+        self.emit_event(
+            "vm.snapshot_prune",
+            Some(id),
+            json!({ "snapshots": pruned, "reclaimed_bytes": reclaimed }),
+        );
+        Ok(())
+    }
+
+    async fn delete_snapshot_online(&self, id: &str, name: &str) -> Result<()> {
+        let mut qmp = self.qmp(id).await?;
+        qmp.execute(
+            "blockdev-snapshot-delete-internal-sync",
+            Some(json!({ "device": "hd1", "name": name })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Prune the snapshots of all VMs by the configured retention policy.
+    pub(crate) async fn apply_snapshot_retention(&self, policy: &SnapshotRetentionConfig) {
+        match self
+            .prune_snapshots(&[], policy.keep_last, policy.max_age, false)
+            .await
+        {
+            Ok(response) if !response.pruned.is_empty() => info!(
+                "Snapshot retention pruned {} snapshots, reclaimed {} bytes",
+                response.pruned.len(),
+                response.reclaimed_bytes
+            ),
+            Ok(_) => {}
+            Err(err) => warn!("Failed to apply the snapshot retention: {err:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    /// Snapshots taken `ages` seconds before `NOW`, oldest first.
+    fn snapshots(ages: &[u64]) -> Vec<DiskSnapshot> {
+        let info = json!({
+            "snapshots": ages
+                .iter()
+                .map(|age| json!({ "name": format!("age-{age}"), "date-sec": NOW - age }))
+                .collect::<Vec<_>>(),
+        });
+        parse_disk_snapshots(&info)
+    }
+
+    fn names(selected: Vec<&DiskSnapshot>) -> Vec<&str> {
+        selected.into_iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_snapshots_oldest_first() {
+        let snapshots = snapshots(&[10, 300, 20]);
+        let names = snapshots
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["age-300", "age-20", "age-10"]);
+        assert!(parse_disk_snapshots(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn max_age_boundary() {
+        let snapshots = snapshots(&[3601, 3600, 3599]);
+        // A snapshot exactly `max_age` old is kept
+        assert_eq!(
+            names(select_expired(&snapshots, 0, 3600, NOW)),
+            ["age-3601"]
+        );
+        // Snapshots dated in the future are not expired
+        assert!(select_expired(&snapshots, 0, 3600, NOW - 7200).is_empty());
+    }
+
+    #[test]
+    fn without_max_age_only_keep_last_applies() {
+        let snapshots = snapshots(&[86400 * 365, 86400 * 30, 60, 1]);
+        assert_eq!(
+            names(select_expired(&snapshots, 2, 0, NOW)),
+            ["age-31536000", "age-2592000"]
+        );
+        assert!(select_expired(&snapshots, 4, 0, NOW).is_empty());
+        assert!(select_expired(&snapshots, 10, 0, NOW).is_empty());
+    }
+
+    #[test]
+    fn both_rules_must_select() {
+        let snapshots = snapshots(&[7200, 5000, 4000, 100]);
+        // The oldest three are beyond keep_last, the newest of them is not old enough
+        assert_eq!(
+            names(select_expired(&snapshots, 1, 4500, NOW)),
+            ["age-7200", "age-5000"]
+        );
+        assert!(select_expired(&[], 1, 4500, NOW).is_empty());
+    }
+}
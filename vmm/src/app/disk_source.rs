@@ -0,0 +1,449 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network storage backing a disk in place of a local image, attached with `-blockdev`.
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const NBD_DEFAULT_PORT: u16 = 10809;
+const ISCSI_DEFAULT_PORT: u16 = 3260;
+
+/// Remote block device, written as the URI QEMU accepts for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiskSource {
+    /// `nbd://host[:port][/export]`
+    Nbd {
+        host: String,
+        port: u16,
+        export: Option<String>,
+    },
+    /// `rbd:pool/image[@snapshot][:id=user][:conf=/path/to/ceph.conf]`
+    Rbd {
+        pool: String,
+        image: String,
+        snapshot: Option<String>,
+        user: Option<String>,
+        conf: Option<String>,
+    },
+    /// `iscsi://[user@]host[:port]/target-iqn/lun`
+    Iscsi {
+        user: Option<String>,
+        host: String,
+        port: u16,
+        target: String,
+        lun: u32,
+    },
+}
+
+/// Split `host[:port]`, accepting bracketed IPv6 addresses.
+fn parse_host_port(s: &str, default_port: u16) -> Result<(String, u16)> {
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').context("Unterminated IPv6 address")?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match s.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("Missing host");
+    }
+    let port = match port {
+        Some(port) => port.parse().context("Invalid port")?,
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+fn fmt_host(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    }
+}
+
+/// Escape a value for a QEMU option list, where `,` separates options.
+fn qemu_opt(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+impl FromStr for DiskSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(rest) = s.strip_prefix("nbd://") {
+            let (server, export) = match rest.split_once('/') {
+                Some((server, export)) => (server, Some(export).filter(|e| !e.is_empty())),
+                None => (rest, None),
+            };
+            let (host, port) = parse_host_port(server, NBD_DEFAULT_PORT)
+                .with_context(|| format!("Invalid NBD source: {s}"))?;
+            return Ok(DiskSource::Nbd {
+                host,
+                port,
+                export: export.map(Into::into),
+            });
+        }
+        if let Some(rest) = s.strip_prefix("rbd:") {
+            let mut parts = rest.split(':');
+            let path = parts.next().unwrap_or_default();
+            let (path, snapshot) = match path.split_once('@') {
+                Some((path, snapshot)) => (path, Some(snapshot.to_string())),
+                None => (path, None),
+            };
+            let Some((pool, image)) = path.split_once('/') else {
+                bail!("Invalid RBD source, expected rbd:pool/image: {s}");
+            };
+            if pool.is_empty() || image.is_empty() {
+                bail!("Invalid RBD source, expected rbd:pool/image: {s}");
+            }
+            let (mut user, mut conf) = (None, None);
+            for opt in parts {
+                match opt.split_once('=') {
+                    Some(("id", value)) => user = Some(value.to_string()),
+                    Some(("conf", value)) => conf = Some(value.to_string()),
+                    Some(("key", _)) => {
+                        bail!("RBD keys must be given in a secret file, not in the source")
+                    }
+                    _ => bail!("Unsupported RBD option: {opt}"),
+                }
+            }
+            return Ok(DiskSource::Rbd {
+                pool: pool.into(),
+                image: image.into(),
+                snapshot,
+                user,
+                conf,
+            });
+        }
+        if let Some(rest) = s.strip_prefix("iscsi://") {
+            let (user, rest) = match rest.split_once('@') {
+                Some((user, rest)) => {
+                    if user.contains('%') {
+                        bail!("iSCSI passwords must be given in a secret file, not in the source");
+                    }
+                    (Some(user.to_string()), rest)
+                }
+                None => (None, rest),
+            };
+            let mut parts = rest.splitn(3, '/');
+            let portal = parts.next().unwrap_or_default();
+            let (Some(target), Some(lun)) = (parts.next(), parts.next()) else {
+                bail!("Invalid iSCSI source, expected iscsi://host[:port]/target/lun: {s}");
+            };
+            let (host, port) = parse_host_port(portal, ISCSI_DEFAULT_PORT)
+                .with_context(|| format!("Invalid iSCSI source: {s}"))?;
+            return Ok(DiskSource::Iscsi {
+                user,
+                host,
+                port,
+                target: target.into(),
+                lun: lun
+                    .parse()
+                    .with_context(|| format!("Invalid iSCSI LUN: {lun}"))?,
+            });
+        }
+        bail!("Unsupported disk source, expected nbd://, rbd: or iscsi://: {s}")
+    }
+}
+
+impl fmt::Display for DiskSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskSource::Nbd { host, port, export } => {
+                write!(f, "nbd://{}:{port}", fmt_host(host))?;
+                if let Some(export) = export {
+                    write!(f, "/{export}")?;
+                }
+                Ok(())
+            }
+            DiskSource::Rbd {
+                pool,
+                image,
+                snapshot,
+                user,
+                conf,
+            } => {
+                write!(f, "rbd:{pool}/{image}")?;
+                if let Some(snapshot) = snapshot {
+                    write!(f, "@{snapshot}")?;
+                }
+                if let Some(user) = user {
+                    write!(f, ":id={user}")?;
+                }
+                if let Some(conf) = conf {
+                    write!(f, ":conf={conf}")?;
+                }
+                Ok(())
+            }
+            DiskSource::Iscsi {
+                user,
+                host,
+                port,
+                target,
+                lun,
+            } => {
+                write!(f, "iscsi://")?;
+                if let Some(user) = user {
+                    write!(f, "{user}@")?;
+                }
+                write!(f, "{}:{port}/{target}/{lun}", fmt_host(host))
+            }
+        }
+    }
+}
+
+impl Serialize for DiskSource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DiskSource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl DiskSource {
+    /// Check the credentials the source is used with.
+    pub fn validate_secret(&self, secret_file: Option<&Path>) -> Result<()> {
+        let Some(secret_file) = secret_file else {
+            return Ok(());
+        };
+        if !secret_file.is_absolute() {
+            bail!(
+                "Secret file must be an absolute path: {}",
+                secret_file.display()
+            );
+        }
+        match self {
+            DiskSource::Nbd { .. } => bail!("NBD sources do not take a secret"),
+            DiskSource::Iscsi { user: None, .. } => {
+                bail!("iSCSI secret requires a user in the source")
+            }
+            DiskSource::Rbd { .. } | DiskSource::Iscsi { .. } => Ok(()),
+        }
+    }
+
+    /// Options of the `-blockdev` protocol node, authenticating with the QEMU secret object
+    /// `secret_id` if given.
+    pub fn blockdev_opts(&self, secret_id: Option<&str>) -> String {
+        let mut opts = match self {
+            DiskSource::Nbd { host, port, export } => {
+                let mut opts = format!(
+                    "driver=nbd,server.type=inet,server.host={},server.port={port}",
+                    qemu_opt(host)
+                );
+                if let Some(export) = export {
+                    opts.push_str(&format!(",export={}", qemu_opt(export)));
+                }
+                opts
+            }
+            DiskSource::Rbd {
+                pool,
+                image,
+                snapshot,
+                user,
+                conf,
+            } => {
+                let mut opts = format!(
+                    "driver=rbd,pool={},image={}",
+                    qemu_opt(pool),
+                    qemu_opt(image)
+                );
+                for (key, value) in [("snapshot", snapshot), ("user", user), ("conf", conf)] {
+                    if let Some(value) = value {
+                        opts.push_str(&format!(",{key}={}", qemu_opt(value)));
+                    }
+                }
+                opts
+            }
+            DiskSource::Iscsi {
+                user,
+                host,
+                port,
+                target,
+                lun,
+            } => {
+                let mut opts = format!(
+                    "driver=iscsi,transport=tcp,portal={}:{port},target={},lun={lun}",
+                    qemu_opt(&fmt_host(host)),
+                    qemu_opt(target)
+                );
+                if let Some(user) = user {
+                    opts.push_str(&format!(",user={}", qemu_opt(user)));
+                }
+                opts
+            }
+        };
+        if let Some(secret_id) = secret_id {
+            let key = match self {
+                DiskSource::Rbd { .. } => "key-secret",
+                _ => "password-secret",
+            };
+            opts.push_str(&format!(",{key}={secret_id}"));
+        }
+        opts
+    }
+
+    /// Check the source is reachable and readable with its credentials using `qemu-img info`.
+    pub fn probe(&self, secret_file: Option<&Path>) -> Result<()> {
+        let mut command = Command::new("qemu-img");
+        command.arg("info");
+        if let Some(secret_file) = secret_file {
+            command.arg("--object").arg(format!(
+                "secret,id=probe-secret,format=raw,file={}",
+                qemu_opt(&secret_file.to_string_lossy())
+            ));
+        }
+        let output = command
+            .arg("--image-opts")
+            .arg(self.blockdev_opts(secret_file.map(|_| "probe-secret")))
+            .output()
+            .context("Failed to run qemu-img")?;
+        if !output.status.success() {
+            bail!(
+                "Disk source {self} is not reachable: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> DiskSource {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            parse("nbd://storage"),
+            DiskSource::Nbd {
+                host: "storage".into(),
+                port: NBD_DEFAULT_PORT,
+                export: None,
+            }
+        );
+        assert_eq!(
+            parse("nbd://[fd00::1]:10900/data"),
+            DiskSource::Nbd {
+                host: "fd00::1".into(),
+                port: 10900,
+                export: Some("data".into()),
+            }
+        );
+        assert_eq!(
+            parse("rbd:vms/app-1@base:id=vmm:conf=/etc/ceph/ceph.conf"),
+            DiskSource::Rbd {
+                pool: "vms".into(),
+                image: "app-1".into(),
+                snapshot: Some("base".into()),
+                user: Some("vmm".into()),
+                conf: Some("/etc/ceph/ceph.conf".into()),
+            }
+        );
+        assert_eq!(
+            parse("iscsi://vmm@10.0.0.5/iqn.2025-01.net.example:disks/3"),
+            DiskSource::Iscsi {
+                user: Some("vmm".into()),
+                host: "10.0.0.5".into(),
+                port: ISCSI_DEFAULT_PORT,
+                target: "iqn.2025-01.net.example:disks".into(),
+                lun: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        for s in [
+            "",
+            "file:///disk.img",
+            "nbd://",
+            "nbd://host:port",
+            "nbd://[fd00::1",
+            "rbd:vms",
+            "rbd:/image",
+            "rbd:vms/app:key=AQBsecret",
+            "rbd:vms/app:mon_host=ceph",
+            "iscsi://host/target",
+            "iscsi://host/target/lun",
+            "iscsi://vmm%password@host/target/0",
+        ] {
+            assert!(s.parse::<DiskSource>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "nbd://storage:10809",
+            "nbd://[fd00::1]:10900/data",
+            "rbd:vms/app-1",
+            "rbd:vms/app-1@base:id=vmm:conf=/etc/ceph/ceph.conf",
+            "iscsi://10.0.0.5:3260/iqn.2025-01.net.example:disks/3",
+            "iscsi://vmm@[fd00::5]:3261/iqn.2025-01.net.example:disks/0",
+        ] {
+            let source = parse(s);
+            assert_eq!(source.to_string(), s);
+            assert_eq!(parse(&source.to_string()), source);
+            let json = serde_json::to_string(&source).unwrap();
+            assert_eq!(serde_json::from_str::<DiskSource>(&json).unwrap(), source);
+        }
+        // Default ports are written out
+        assert_eq!(parse("nbd://storage").to_string(), "nbd://storage:10809");
+    }
+
+    #[test]
+    fn escapes_blockdev_opts() {
+        assert_eq!(
+            parse("nbd://[fd00::1]/data,file=/etc/shadow").blockdev_opts(None),
+            "driver=nbd,server.type=inet,server.host=fd00::1,server.port=10809,\
+             export=data,,file=/etc/shadow"
+        );
+        assert_eq!(
+            parse("rbd:vms/app,1:id=vmm:conf=/etc/ceph,x.conf").blockdev_opts(Some("sec0")),
+            "driver=rbd,pool=vms,image=app,,1,user=vmm,conf=/etc/ceph,,x.conf,key-secret=sec0"
+        );
+        assert_eq!(
+            parse("iscsi://vmm@[fd00::5]/iqn.2025-01.net.example:disks/0")
+                .blockdev_opts(Some("sec0")),
+            "driver=iscsi,transport=tcp,portal=[fd00::5]:3260,\
+             target=iqn.2025-01.net.example:disks,lun=0,user=vmm,password-secret=sec0"
+        );
+    }
+
+    #[test]
+    fn validates_secrets() {
+        let secret = Path::new("/etc/dstack/secret");
+        assert!(parse("rbd:vms/app").validate_secret(Some(secret)).is_ok());
+        assert!(parse("iscsi://vmm@host/t/0")
+            .validate_secret(Some(secret))
+            .is_ok());
+        assert!(parse("iscsi://host/t/0")
+            .validate_secret(Some(secret))
+            .is_err());
+        assert!(parse("nbd://host").validate_secret(Some(secret)).is_err());
+        assert!(parse("rbd:vms/app")
+            .validate_secret(Some(Path::new("secret")))
+            .is_err());
+        assert!(parse("nbd://host").validate_secret(None).is_ok());
+    }
+}
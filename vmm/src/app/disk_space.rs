@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Free space checks of the dirs VMs are launched in.
+//!
+//! A launch creates sockets, logs and overlay disks in the VM work dir. On a full disk those
+//! fail one by one with errors that do not tell why, leaving a half set up VM behind. Checking
+//! the free space before anything is created fails the launch early with the numbers instead.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use nix::sys::statvfs::statvfs;
+
+use super::{App, VmmError};
+
+/// Bytes available to unprivileged users in the filesystem of `path`.
+pub fn available_bytes(path: &Path) -> Result<u64> {
+    let stat = statvfs(path)
+        .with_context(|| format!("Failed to get the free space of {}", path.display()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Fail with [`VmmError::InsufficientDiskSpace`] if less than `required` bytes are available
+/// at `path`. 0 skips the check.
+pub fn check_free_space(path: &Path, required: u64) -> Result<()> {
+    if required == 0 {
+        return Ok(());
+    }
+    let available = available_bytes(path)?;
+    if available < required {
+        return Err(VmmError::InsufficientDiskSpace {
+            path: path.display().to_string(),
+            required,
+            available,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+impl App {
+    /// Fail unless the run dir has `disk_space.min_free_bytes` available.
+    pub(crate) fn check_run_dir_space(&self) -> Result<()> {
+        check_free_space(&self.config.run_path, self.config.disk_space.min_free_bytes)
+    }
+}
@@ -0,0 +1,240 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! VNC/SPICE display output for debugging guests.
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::{App, AppState, Manifest};
+use crate::config::{DisplayPortsConfig, Protocol};
+
+/// VNC ports are expressed as display numbers relative to this port.
+const VNC_BASE_PORT: u16 = 5900;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayProtocol {
+    #[default]
+    None,
+    Vnc,
+    Spice,
+}
+
+impl DisplayProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisplayProtocol::None => "none",
+            DisplayProtocol::Vnc => "vnc",
+            DisplayProtocol::Spice => "spice",
+        }
+    }
+}
+
+impl std::str::FromStr for DisplayProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "" | "none" => DisplayProtocol::None,
+            "vnc" => DisplayProtocol::Vnc,
+            "spice" => DisplayProtocol::Spice,
+            _ => bail!("Invalid display: {s}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct DisplayConfig {
+    #[serde(default)]
+    pub protocol: DisplayProtocol,
+    /// Host address to listen on, loopback if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// Host port, 0 to allocate one from `cvm.display`
+    #[serde(default)]
+    pub port: u16,
+}
+
+impl DisplayConfig {
+    fn address(&self) -> IpAddr {
+        self.address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    pub fn to_pb(&self) -> pb::DisplayConfig {
+        pb::DisplayConfig {
+            protocol: self.protocol.as_str().into(),
+            address: self.address.map(|a| a.to_string()).unwrap_or_default(),
+            port: self.port as u32,
+        }
+    }
+}
+
+pub fn resolve_display(display: &pb::DisplayConfig) -> Result<Option<DisplayConfig>> {
+    let protocol: DisplayProtocol = display.protocol.parse()?;
+    if protocol == DisplayProtocol::None {
+        return Ok(None);
+    }
+    let address = match display.address.as_str() {
+        "" => None,
+        addr => Some(
+            addr.parse::<IpAddr>()
+                .with_context(|| format!("Invalid display address: {addr}"))?,
+        ),
+    };
+    let port = u16::try_from(display.port).context("Invalid display port")?;
+    if protocol == DisplayProtocol::Vnc && port != 0 && port < VNC_BASE_PORT {
+        bail!("VNC port must be at least {VNC_BASE_PORT}");
+    }
+    Ok(Some(DisplayConfig {
+        protocol,
+        address,
+        port,
+    }))
+}
+
+/// The endpoint a VM display is served on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayEndpoint {
+    pub protocol: DisplayProtocol,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl DisplayEndpoint {
+    /// QEMU arguments serving the display.
+    pub fn qemu_args(&self) -> Vec<String> {
+        let server = match self.protocol {
+            DisplayProtocol::None => return vec![],
+            DisplayProtocol::Vnc => {
+                let host = match self.address {
+                    IpAddr::V4(addr) => addr.to_string(),
+                    IpAddr::V6(addr) => format!("[{addr}]"),
+                };
+                let display = self.port - VNC_BASE_PORT;
+                ["-vnc".into(), format!("{host}:{display}")]
+            }
+            DisplayProtocol::Spice => [
+                "-spice".into(),
+                format!(
+                    "port={},addr={},disable-ticketing=on",
+                    self.port, self.address
+                ),
+            ],
+        };
+        let mut args = vec![
+            "-display".into(),
+            "none".into(),
+            "-device".into(),
+            "virtio-vga".into(),
+        ];
+        args.extend(server);
+        args
+    }
+
+    pub fn to_pb(&self) -> pb::DisplayEndpoint {
+        pb::DisplayEndpoint {
+            protocol: self.protocol.as_str().into(),
+            address: self.address.to_string(),
+            port: self.port as u32,
+        }
+    }
+}
+
+pub(crate) fn port_available(address: IpAddr, port: u16) -> bool {
+    TcpListener::bind((address, port)).is_ok()
+}
+
+/// Pick the display endpoint for a VM. Ports in `taken` are used by other VMs. A previously
+/// allocated port is reused if it is still free.
+pub fn allocate_display(
+    display: &DisplayConfig,
+    cfg: &DisplayPortsConfig,
+    taken: &[u16],
+    previous: Option<u16>,
+) -> Result<Option<DisplayEndpoint>> {
+    if display.protocol == DisplayProtocol::None {
+        return Ok(None);
+    }
+    let address = display.address();
+    let port = if display.port != 0 {
+        if display.protocol == DisplayProtocol::Vnc && display.port < VNC_BASE_PORT {
+            bail!("VNC port must be at least {VNC_BASE_PORT}");
+        }
+        if taken.contains(&display.port) {
+            bail!("Display port {} is used by another VM", display.port);
+        }
+        if !port_available(address, display.port) {
+            bail!(
+                "Display port {} is already in use on the host",
+                display.port
+            );
+        }
+        display.port
+    } else {
+        let mut from = cfg.port_start;
+        if display.protocol == DisplayProtocol::Vnc {
+            from = from.max(VNC_BASE_PORT);
+        }
+        previous
+            .into_iter()
+            .chain(from..=cfg.port_end)
+            .filter(|p| (from..=cfg.port_end).contains(p))
+            .find(|p| !taken.contains(p) && port_available(address, *p))
+            .context("No free display port")?
+    };
+    Ok(Some(DisplayEndpoint {
+        protocol: display.protocol,
+        address,
+        port,
+    }))
+}
+
+impl AppState {
+    /// The VM other than `except` that has the explicit display port configured.
+    pub(crate) fn display_port_owner(&self, port: u16, except: &str) -> Option<&str> {
+        self.iter_vms()
+            .map(|vm| &vm.config.manifest)
+            .find(|m| m.id != except && m.display.is_some_and(|d| d.port == port))
+            .map(|m| m.id.as_str())
+    }
+
+    /// Display ports configured or allocated for VMs other than `except`.
+    fn display_ports_taken(&self, except: &str) -> Vec<u16> {
+        self.iter_vms()
+            .filter(|vm| vm.config.manifest.id != except)
+            .flat_map(|vm| {
+                let configured = vm.config.manifest.display.map(|d| d.port);
+                let allocated = vm.state.display.map(|d| d.port);
+                configured.into_iter().chain(allocated)
+            })
+            .filter(|port| *port != 0)
+            .collect()
+    }
+}
+
+impl App {
+    pub(crate) fn try_allocate_display(
+        &self,
+        manifest: &Manifest,
+    ) -> Result<Option<DisplayEndpoint>> {
+        let Some(display) = &manifest.display else {
+            return Ok(None);
+        };
+        let state = self.lock();
+        let mut taken = state.display_ports_taken(&manifest.id);
+        taken.extend(
+            state
+                .ports
+                .taken(Protocol::Tcp, display.address(), &manifest.id),
+        );
+        let previous = state
+            .get(&manifest.id)
+            .and_then(|vm| vm.state.display)
+            .map(|d| d.port);
+        allocate_display(display, &self.config.cvm.display, &taken, previous)
+    }
+}
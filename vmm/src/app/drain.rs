@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Draining the host for maintenance.
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use super::{App, VmmError};
+
+const DEFAULT_CONCURRENCY: u32 = 4;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DrainState {
+    draining: bool,
+    /// Bumped on every drain and undrain so stale shutdown tasks stop early
+    generation: u64,
+    in_progress: bool,
+    total: u32,
+    shut_down: u32,
+    forced: Vec<String>,
+}
+
+impl DrainState {
+    fn to_pb(&self) -> pb::DrainStatus {
+        pb::DrainStatus {
+            draining: self.draining,
+            total: self.total,
+            shut_down: self.shut_down,
+            forced: self.forced.clone(),
+            in_progress: self.in_progress,
+        }
+    }
+}
+
+impl App {
+    pub fn is_draining(&self) -> bool {
+        self.lock().drain.draining
+    }
+
+    /// Fail with [`VmmError::Draining`] if the host does not accept new VMs.
+    pub fn ensure_not_draining(&self) -> Result<()> {
+        if self.is_draining() {
+            return Err(VmmError::Draining.into());
+        }
+        Ok(())
+    }
+
+    pub fn drain_status(&self) -> pb::DrainStatus {
+        self.lock().drain.to_pb()
+    }
+
+    pub async fn drain_host(&self, request: pb::DrainHostRequest) -> Result<pb::DrainStatus> {
+        let running = if request.shutdown_vms {
+            self.running_vm_ids().await?
+        } else {
+            vec![]
+        };
+        let generation = {
+            let mut state = self.lock();
+            let drain = &mut state.drain;
+            if drain.in_progress {
+                drain.draining = true;
+                return Ok(drain.to_pb());
+            }
+            drain.draining = true;
+            drain.generation += 1;
+            drain.in_progress = !running.is_empty();
+            drain.total = running.len() as u32;
+            drain.shut_down = 0;
+            drain.forced.clear();
+            drain.generation
+        };
+        info!("Host is draining, {} VMs to shut down", running.len());
+        self.emit_event(
+            "host.drain",
+            None,
+            json!({ "vms_to_shut_down": running.len() }),
+        );
+        if !running.is_empty() {
+            let concurrency = match request.concurrency {
+                0 => DEFAULT_CONCURRENCY,
+                n => n,
+            };
+            let timeout = match request.shutdown_timeout_secs {
+                0 => DEFAULT_SHUTDOWN_TIMEOUT,
+                n => Duration::from_secs(n as u64),
+            };
+            tokio::spawn(
+                self.clone()
+                    .shutdown_all(running, generation, concurrency, timeout),
+            );
+        }
+        Ok(self.drain_status())
+    }
+
+    pub fn undrain_host(&self) -> pb::DrainStatus {
+        let status = {
+            let mut state = self.lock();
+            let drain = &mut state.drain;
+            drain.draining = false;
+            drain.generation += 1;
+            drain.to_pb()
+        };
+        info!("Host is no longer draining");
+        self.emit_event("host.undrain", None, json!({}));
+        status
+    }
+
+    async fn running_vm_ids(&self) -> Result<Vec<String>> {
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .map(|p| p.config.id)
+            .collect::<Vec<_>>();
+        let state = self.lock();
+        Ok(running
+            .into_iter()
+            .filter(|id| state.get(id).is_some())
+            .collect())
+    }
+
+    async fn shutdown_all(
+        self,
+        ids: Vec<String>,
+        generation: u64,
+        concurrency: u32,
+        timeout: Duration,
+    ) {
+        let semaphore = Arc::new(Semaphore::new(concurrency as usize));
+        let mut tasks = tokio::task::JoinSet::new();
+        for id in ids {
+            let app = self.clone();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                let Ok(_permit) = semaphore.acquire().await else {
+                    return;
+                };
+                if app.lock().drain.generation != generation {
+                    return;
+                }
+                let forced = app.shutdown_gracefully(&id, timeout).await;
+                let mut state = app.lock();
+                let drain = &mut state.drain;
+                if drain.generation == generation {
+                    drain.shut_down += 1;
+                    if forced {
+                        drain.forced.push(id);
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+        let mut state = self.lock();
+        if state.drain.generation == generation {
+            state.drain.in_progress = false;
+            info!("Host drained, {} VMs shut down", state.drain.shut_down);
+        }
+    }
+
+    /// Ask the guest to power off, stopping the VM forcibly after `timeout`.
+    ///
+    /// The VM keeps its started flag, so it is booted again once the host leaves maintenance.
+    /// Returns whether the VM had to be stopped forcibly.
+    async fn shutdown_gracefully(&self, id: &str, timeout: Duration) -> bool {
+        let Err(err) = self.power_off_guest(id, timeout).await else {
+            info!("VM {id} shut down");
+            return false;
+        };
+        warn!("Graceful shutdown of VM {id} failed ({err:?}), stopping it");
+        if let Err(err) = self.supervisor.stop(id).await {
+            warn!("Failed to stop VM {id}: {err:?}");
+        }
+        true
+    }
+}
@@ -0,0 +1,35 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Errors callers may want to tell apart, carried inside `anyhow::Error`.
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum VmmError {
+    /// A configured host limit would be exceeded
+    #[error("Resource exhausted: {0}")]
+    ResourceExhausted(String),
+    /// The host is draining for maintenance
+    #[error("Host is draining, not accepting new VMs")]
+    Draining,
+    /// The QMP socket of a VM was still unavailable after the startup window
+    #[error("QMP socket of VM {0} did not come up")]
+    QmpUnavailable(String),
+    /// A host port is already claimed by another VM or the VMM
+    #[error("{port} requested by {claimant} is already claimed by {owner}")]
+    PortConflict {
+        port: String,
+        claimant: String,
+        owner: String,
+    },
+    /// A path VMs are launched in has less free space than configured
+    #[error(
+        "Insufficient disk space at {path}: {required} bytes required, {available} bytes available"
+    )]
+    InsufficientDiskSpace {
+        path: String,
+        required: u64,
+        available: u64,
+    },
+}
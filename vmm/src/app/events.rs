@@ -0,0 +1,323 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recent lifecycle events kept in memory, globally and per VM.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+
+use super::App;
+use crate::config::EventsConfig;
+
+#[derive(Debug, Clone)]
+struct Event {
+    seq: u64,
+    timestamp_ms: u64,
+    event: String,
+    vm_id: Option<String>,
+    details: Value,
+}
+
+impl Event {
+    /// Approximate memory held by the event.
+    fn size(&self) -> usize {
+        self.event.len()
+            + self.vm_id.as_ref().map_or(0, String::len)
+            + self.details.to_string().len()
+    }
+
+    fn to_pb(&self) -> pb::VmEvent {
+        pb::VmEvent {
+            seq: self.seq,
+            timestamp_ms: self.timestamp_ms,
+            event: self.event.clone(),
+            vm_id: self.vm_id.clone(),
+            details: self.details.to_string(),
+        }
+    }
+}
+
+/// Events bounded in count and bytes, dropping the oldest first.
+#[derive(Default)]
+struct Ring {
+    events: VecDeque<(Event, usize)>,
+    bytes: usize,
+    /// Seq of the newest event dropped to make room
+    evicted_through: u64,
+    /// Timestamp of the newest event dropped to make room
+    evicted_through_ms: u64,
+}
+
+impl Ring {
+    fn push(&mut self, event: Event, capacity: usize, max_bytes: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let size = event.size();
+        while !self.events.is_empty()
+            && (self.events.len() >= capacity || (max_bytes != 0 && self.bytes + size > max_bytes))
+        {
+            self.evict();
+        }
+        self.bytes += size;
+        self.events.push_back((event, size));
+    }
+
+    fn evict(&mut self) {
+        if let Some((event, size)) = self.events.pop_front() {
+            self.bytes -= size;
+            self.evicted_through = event.seq;
+            self.evicted_through_ms = event.timestamp_ms;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    next_seq: u64,
+    global: Ring,
+    vms: HashMap<String, Ring>,
+}
+
+/// Memory held by the event buffers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EventUsage {
+    pub events: usize,
+    pub bytes: usize,
+}
+
+/// Ring buffers of the last events. Has a lock of its own, so events can be recorded while
+/// the app state is locked.
+pub struct EventBuffer {
+    config: EventsConfig,
+    buffers: Mutex<Buffers>,
+}
+
+impl EventBuffer {
+    pub fn new(config: EventsConfig) -> Self {
+        Self {
+            config,
+            buffers: Mutex::new(Buffers::default()),
+        }
+    }
+
+    fn record(&self, event: &str, vm_id: Option<&str>, details: &Value) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.next_seq += 1;
+        let mut event = Event {
+            seq: buffers.next_seq,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event: event.to_string(),
+            vm_id: vm_id.map(String::from),
+            details: details.clone(),
+        };
+        // An event alone over a budget would flush the whole buffer, keep only its size.
+        let max_bytes = match vm_id {
+            Some(_) => min_nonzero(self.config.max_bytes, self.config.per_vm_max_bytes),
+            None => self.config.max_bytes,
+        };
+        let size = event.size();
+        if max_bytes != 0 && size > max_bytes {
+            event.details = json!({ "truncated": true, "bytes": size });
+        }
+        if let Some(id) = vm_id {
+            let buffer = buffers.vms.entry(id.to_string()).or_default();
+            buffer.push(
+                event.clone(),
+                self.config.per_vm_capacity,
+                self.config.per_vm_max_bytes,
+            );
+        }
+        buffers
+            .global
+            .push(event, self.config.capacity, self.config.max_bytes);
+    }
+
+    /// Events after `since_seq`, oldest first. Those of VM `id` if given, all otherwise.
+    /// At most `limit` of the newest are returned unless `limit` is 0.
+    ///
+    /// If events after `since_seq` were dropped from the buffer, an unlimited query starts
+    /// with an `events.truncated` marker carrying the seq of the last dropped event.
+    pub fn query(&self, id: Option<&str>, since_seq: u64, limit: usize) -> Vec<pb::VmEvent> {
+        let buffers = self.buffers.lock().unwrap();
+        let buffer = match id {
+            Some(id) => match buffers.vms.get(id) {
+                Some(buffer) => buffer,
+                None => return vec![],
+            },
+            None => &buffers.global,
+        };
+        let events = buffer
+            .events
+            .iter()
+            .map(|(e, _)| e)
+            .filter(|e| e.seq > since_seq)
+            .collect::<Vec<_>>();
+        let skip = match limit {
+            0 => 0,
+            limit => events.len().saturating_sub(limit),
+        };
+        let mut result = Vec::with_capacity(events.len() - skip + 1);
+        if limit == 0 && buffer.evicted_through > since_seq {
+            let through = buffer.evicted_through;
+            result.push(pb::VmEvent {
+                seq: through,
+                timestamp_ms: 0,
+                event: "events.truncated".into(),
+                vm_id: id.map(String::from),
+                details: json!({ "through_seq": through }).to_string(),
+            });
+        }
+        result.extend(events[skip..].iter().map(|e| e.to_pb()));
+        result
+    }
+
+    /// Names and timestamps of the events of VM `id` at or after `since_ms`, oldest first, and
+    /// whether some of the events since then were dropped.
+    pub fn vm_events_since(&self, id: &str, since_ms: u64) -> (Vec<(u64, String)>, bool) {
+        let buffers = self.buffers.lock().unwrap();
+        let Some(buffer) = buffers.vms.get(id) else {
+            return (vec![], false);
+        };
+        let events = buffer
+            .events
+            .iter()
+            .map(|(e, _)| e)
+            .filter(|e| e.timestamp_ms >= since_ms)
+            .map(|e| (e.timestamp_ms, e.event.clone()))
+            .collect();
+        (events, buffer.evicted_through_ms >= since_ms)
+    }
+
+    /// Events held and their bytes, over the global and the per VM buffers.
+    pub fn usage(&self) -> EventUsage {
+        let buffers = self.buffers.lock().unwrap();
+        std::iter::once(&buffers.global)
+            .chain(buffers.vms.values())
+            .fold(EventUsage::default(), |usage, ring| EventUsage {
+                events: usage.events + ring.events.len(),
+                bytes: usage.bytes + ring.bytes,
+            })
+    }
+
+    /// Drop the events of a VM that no longer exists. Its events stay in the global buffer.
+    pub fn forget_vm(&self, id: &str) {
+        self.buffers.lock().unwrap().vms.remove(id);
+    }
+}
+
+fn min_nonzero(a: usize, b: usize) -> usize {
+    match (a, b) {
+        (0, b) => b,
+        (a, 0) => a,
+        (a, b) => a.min(b),
+    }
+}
+
+impl App {
+    /// Record a lifecycle event and post it to the subscribed webhooks.
+    pub(crate) fn emit_event(&self, event: &str, vm_id: Option<&str>, details: Value) {
+        self.events.record(event, vm_id, &details);
+        self.webhooks.emit(event, vm_id, details);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An event of 15 bytes: "vm.start" and `{"n":N}` for a single digit N.
+    fn event(seq: u64) -> Event {
+        Event {
+            seq,
+            timestamp_ms: seq * 1000,
+            event: "vm.start".into(),
+            vm_id: None,
+            details: json!({ "n": seq % 10 }),
+        }
+    }
+
+    fn seqs(ring: &Ring) -> Vec<u64> {
+        ring.events.iter().map(|(e, _)| e.seq).collect()
+    }
+
+    #[test]
+    fn ring_evicts_oldest_by_count() {
+        let mut ring = Ring::default();
+        for seq in 1..=5 {
+            ring.push(event(seq), 3, 0);
+        }
+        assert_eq!(seqs(&ring), [3, 4, 5]);
+        assert_eq!(ring.bytes, 3 * 15);
+        assert_eq!(ring.evicted_through, 2);
+        assert_eq!(ring.evicted_through_ms, 2000);
+    }
+
+    #[test]
+    fn ring_evicts_oldest_by_bytes() {
+        let mut ring = Ring::default();
+        for seq in 1..=4 {
+            ring.push(event(seq), 100, 40);
+            assert!(ring.bytes <= 40);
+        }
+        assert_eq!(seqs(&ring), [3, 4]);
+        assert_eq!(ring.bytes, 30);
+        assert_eq!(ring.evicted_through, 2);
+
+        // A larger event makes room by evicting as many as needed
+        let mut large = event(5);
+        large.details = json!({ "message": "x".repeat(10) });
+        let size = large.size();
+        ring.push(large, 100, 40);
+        assert_eq!(seqs(&ring), [5]);
+        assert_eq!(ring.bytes, size);
+        assert_eq!(ring.evicted_through, 4);
+    }
+
+    #[test]
+    fn ring_without_capacity_keeps_nothing() {
+        let mut ring = Ring::default();
+        ring.push(event(1), 0, 0);
+        assert!(ring.events.is_empty());
+        assert_eq!(ring.bytes, 0);
+    }
+
+    #[test]
+    fn overfilled_buffer_reports_truncation() {
+        let buffer = EventBuffer::new(EventsConfig {
+            capacity: 3,
+            per_vm_capacity: 2,
+            max_bytes: 0,
+            per_vm_max_bytes: 0,
+        });
+        for _ in 0..5 {
+            buffer.record("vm.start", Some("vm-1"), &json!({}));
+        }
+        let events = buffer.query(None, 0, 0);
+        assert_eq!(events[0].event, "events.truncated");
+        assert_eq!(events[0].seq, 2);
+        assert_eq!(
+            events[1..].iter().map(|e| e.seq).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        let events = buffer.query(Some("vm-1"), 0, 0);
+        assert_eq!(events[0].event, "events.truncated");
+        assert_eq!(events[0].seq, 3);
+        assert_eq!(events.len(), 3);
+        // Nothing was dropped after seq 3 and a limited query has no marker
+        assert_eq!(buffer.query(None, 3, 0).len(), 2);
+        assert_eq!(buffer.query(None, 0, 1).len(), 1);
+
+        let usage = buffer.usage();
+        assert_eq!(usage.events, 5);
+        let size = "vm.start".len() + "vm-1".len() + "{}".len();
+        assert_eq!(usage.bytes, 5 * size);
+    }
+}
@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Access to the QEMU guest agent of a VM, over the virtio-serial channel attached when both
+//! `cvm.guest_agent` and the `guest_agent` flag of the VM are set.
+//!
+//! The agent runs inside the guest, so what it reports is only as trustworthy as the guest.
+//! `guest-exec` runs commands as the agent user, usually root, bypassing whatever the app
+//! exposes. See docs/guest-agent.md.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+use tracing::warn;
+
+use super::{App, QmpClient};
+
+/// Timeout of `GuestAgentExec` if the request sets none.
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn decode_output(status: &Value, field: &str) -> Result<Vec<u8>> {
+    match status.get(field).and_then(Value::as_str) {
+        Some(data) => BASE64_STANDARD
+            .decode(data)
+            .with_context(|| format!("Invalid {field} from the guest agent")),
+        None => Ok(vec![]),
+    }
+}
+
+impl App {
+    pub(super) async fn guest_agent(&self, id: &str) -> Result<QmpClient> {
+        if !self.config.cvm.guest_agent {
+            bail!("Guest agent is disabled");
+        }
+        let enabled = self
+            .lock()
+            .get(id)
+            .context("VM not found")?
+            .config
+            .manifest
+            .guest_agent;
+        if !enabled {
+            bail!("VM has no guest agent channel");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        QmpClient::connect_guest_agent(self.work_dir(id).qga_socket()).await
+    }
+
+    /// Check that the guest agent of a VM answers and report its version.
+    pub async fn guest_agent_ping(&self, id: &str) -> Result<pb::GuestAgentPingResponse> {
+        let result = async {
+            let mut agent = self.guest_agent(id).await?;
+            agent.execute("guest-ping", None).await?;
+            let info = agent.execute("guest-info", None).await?;
+            anyhow::Ok(
+                info.get("version")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        }
+        .await;
+        Ok(match result {
+            Ok(version) => pb::GuestAgentPingResponse {
+                responsive: true,
+                version,
+                error: String::new(),
+            },
+            Err(err) => pb::GuestAgentPingResponse {
+                responsive: false,
+                version: String::new(),
+                error: format!("{err:#}"),
+            },
+        })
+    }
+
+    /// Run a command in the guest through the agent and wait for it to exit.
+    pub async fn guest_agent_exec(
+        &self,
+        request: &pb::GuestAgentExecRequest,
+    ) -> Result<pb::GuestAgentExecResponse> {
+        if request.path.is_empty() {
+            bail!("Command path is required");
+        }
+        let mut agent = self.guest_agent(&request.id).await?;
+        warn!(
+            "Running {} {:?} in VM {} through the guest agent",
+            request.path, request.args, request.id
+        );
+        let mut arguments = json!({
+            "path": request.path,
+            "arg": request.args,
+            "env": request.env,
+            "capture-output": true,
+        });
+        if !request.input.is_empty() {
+            arguments["input-data"] = BASE64_STANDARD.encode(&request.input).into();
+        }
+        let pid = agent
+            .execute("guest-exec", Some(arguments))
+            .await?
+            .get("pid")
+            .and_then(Value::as_i64)
+            .context("Invalid guest-exec response")?;
+        let timeout = match request.timeout_secs {
+            0 => DEFAULT_EXEC_TIMEOUT,
+            secs => Duration::from_secs(secs as u64),
+        };
+        let started = Instant::now();
+        loop {
+            let status = agent
+                .execute("guest-exec-status", Some(json!({ "pid": pid })))
+                .await?;
+            if status.get("exited").and_then(Value::as_bool) == Some(true) {
+                return Ok(pb::GuestAgentExecResponse {
+                    exit_code: status
+                        .get("exitcode")
+                        .and_then(Value::as_i64)
+                        .map(|c| c as i32),
+                    signal: status
+                        .get("signal")
+                        .and_then(Value::as_i64)
+                        .map(|s| s as i32),
+                    stdout: decode_output(&status, "out-data")?,
+                    stderr: decode_output(&status, "err-data")?,
+                    truncated: status.get("out-truncated").and_then(Value::as_bool) == Some(true)
+                        || status.get("err-truncated").and_then(Value::as_bool) == Some(true),
+                });
+            }
+            if started.elapsed() >= timeout {
+                bail!(
+                    "Command did not exit within {timeout:?}, it keeps running as guest pid {pid}"
+                );
+            }
+            sleep(EXEC_POLL_INTERVAL).await;
+        }
+    }
+}
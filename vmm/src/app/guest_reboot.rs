@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reboot of the guest OS in place, keeping the QEMU process, its devices and disks.
+//!
+//! The guest agent has the guest reboot cleanly when the VM has one. Otherwise, or when a hard
+//! reboot is asked for, QMP `system_reset` resets the machine like its reset button. The guest
+//! is back once it reports ready again, or for images without progress reporting once it sends
+//! a heartbeat. Its return is reported with a `vm.guest_rebooted` event.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::attestation::READY_PROGRESS;
+use super::App;
+
+/// How long the return of a rebooted guest is watched for.
+const REBOOT_WATCH: Duration = Duration::from_secs(600);
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+impl App {
+    /// Reboot the guest of the running VM `id` and wait up to `wait` for it to be back.
+    pub async fn reboot_guest(
+        &self,
+        id: &str,
+        hard: bool,
+        wait: Duration,
+    ) -> Result<pb::RebootGuestResponse> {
+        let has_agent = self
+            .lock()
+            .get(id)
+            .context("VM not found")?
+            .config
+            .manifest
+            .guest_agent
+            && self.config.cvm.guest_agent;
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        let method = if has_agent && !hard {
+            let mut agent = self.guest_agent(id).await?;
+            agent
+                .execute_no_reply("guest-shutdown", Some(json!({ "mode": "reboot" })))
+                .await
+                .context("Failed to reboot the guest")?;
+            "agent"
+        } else {
+            let mut qmp = self.qmp(id).await?;
+            qmp.execute("system_reset", None)
+                .await
+                .context("Failed to reset the VM")?;
+            "reset"
+        };
+        let started = Instant::now();
+        {
+            let mut state = self.lock();
+            let vm = state.get_mut(id).context("VM not found")?;
+            vm.state.attestation = None;
+            if vm.config.image.info.shared_ro {
+                vm.state.start(false);
+            } else {
+                vm.state.reset_na();
+            }
+        }
+        info!("Rebooting the guest of VM {id} with {method}");
+        self.emit_event("vm.guest_reboot", Some(id), json!({ "method": method }));
+
+        let app = self.clone();
+        let watched_id = id.to_string();
+        tokio::spawn(async move {
+            let id = watched_id;
+            if app.wait_guest_back(&id, REBOOT_WATCH).await {
+                let back_after_ms = started.elapsed().as_millis() as u64;
+                info!("The guest of VM {id} is back after {back_after_ms} ms");
+                app.emit_event(
+                    "vm.guest_rebooted",
+                    Some(&id),
+                    json!({ "method": method, "back_after_ms": back_after_ms }),
+                );
+            } else {
+                warn!("The guest of VM {id} is not back {REBOOT_WATCH:?} after its reboot");
+            }
+        });
+        let back = !wait.is_zero() && self.wait_guest_back(id, wait).await;
+        Ok(pb::RebootGuestResponse {
+            method: method.into(),
+            back,
+            back_after_ms: back.then(|| started.elapsed().as_millis() as u64),
+        })
+    }
+
+    /// Wait up to `timeout` for the guest of VM `id` to be back from a reboot.
+    async fn wait_guest_back(&self, id: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let back = match self.lock().get(id) {
+                Some(vm) if vm.config.image.info.shared_ro => {
+                    READY_PROGRESS.contains(&vm.state.boot_progress.as_str())
+                }
+                Some(vm) => vm.state.last_heartbeat.is_some(),
+                None => return false,
+            };
+            if back {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+        }
+    }
+}
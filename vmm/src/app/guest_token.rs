@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rotation of the per-VM guest API tokens.
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::App;
+
+/// A non-secret digest to compare a token against without revealing it.
+pub fn token_fingerprint(token: &str) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(token.as_bytes())))
+}
+
+impl App {
+    /// Replace the guest token of a VM and return the new one.
+    ///
+    /// The guest receives the new token with its sys-config on the next boot. Until then the
+    /// token it booted with stays valid, unless `revoke_previous` is set.
+    pub fn rotate_vm_token(&self, id: &str, revoke_previous: bool) -> Result<String> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        let token = self
+            .work_dir(id)
+            .rotate_guest_api_token()
+            .context("Failed to rotate guest token")?;
+        {
+            let mut state = self.lock();
+            let vm = state.get_mut(id).context("VM not found")?;
+            let previous = std::mem::replace(&mut vm.guest_token, token.clone());
+            if revoke_previous {
+                vm.boot_guest_token = None;
+            } else if vm.boot_guest_token.is_none() {
+                vm.boot_guest_token = Some(previous);
+            }
+        }
+        self.sync_dynamic_config(id)
+            .context("Failed to update sys-config")?;
+        info!(
+            "Rotated guest token of VM {id}, previous token {}",
+            if revoke_previous {
+                "revoked"
+            } else {
+                "valid until restart"
+            }
+        );
+        Ok(token)
+    }
+
+    /// Id of the VM at `cid` if `token` is its guest token.
+    pub fn authenticate_guest(&self, cid: u32, token: &str) -> Result<String> {
+        self.lock()
+            .find_by_guest_token(token)
+            .filter(|vm| vm.config.cid == cid)
+            .map(|vm| vm.config.manifest.id.clone())
+            .context("Invalid guest token")
+    }
+
+    pub fn vm_token_fingerprint(&self, id: &str) -> Result<String> {
+        let state = self.lock();
+        let vm = state.get(id).context("VM not found")?;
+        Ok(token_fingerprint(&vm.guest_token))
+    }
+}
@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Access to the QEMU human monitor (HMP) of a VM.
+//!
+//! HMP is unstructured and unrestricted: a command can stop, reconfigure or tear down the VM
+//! just like the QMP socket. Prefer the dedicated RPCs and use this for debugging only.
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+use tracing::warn;
+
+use super::App;
+
+const HMP_TIMEOUT: Duration = Duration::from_secs(10);
+const PROMPT: &str = "(qemu) ";
+
+/// Read until the monitor prints its prompt, returning what came before it.
+async fn read_until_prompt(stream: &mut UnixStream) -> Result<String> {
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream
+            .read(&mut buf)
+            .await
+            .context("Failed to read from HMP socket")?;
+        if n == 0 {
+            bail!("HMP connection closed");
+        }
+        output.extend_from_slice(&buf[..n]);
+        if output.ends_with(PROMPT.as_bytes()) {
+            output.truncate(output.len() - PROMPT.len());
+            return Ok(strip_ansi_escapes::strip_str(String::from_utf8_lossy(
+                &output,
+            )));
+        }
+    }
+}
+
+/// Run one command on the HMP socket and return its text output.
+async fn hmp_command(socket: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to HMP socket {}", socket.display()))?;
+    // Banner
+    read_until_prompt(&mut stream).await?;
+    stream
+        .write_all(format!("{command}\n").as_bytes())
+        .await
+        .context("Failed to write to HMP socket")?;
+    let output = read_until_prompt(&mut stream).await?;
+    // The monitor echoes the command line before its output
+    let output = output.split_once('\n').map_or("", |(_, rest)| rest);
+    Ok(output.replace('\r', ""))
+}
+
+impl App {
+    pub async fn hmp_command(&self, id: &str, command: &str) -> Result<String> {
+        if !self.config.cvm.hmp_socket {
+            bail!("HMP socket is disabled");
+        }
+        if command.trim().is_empty() || command.contains('\n') {
+            bail!("HMP command must be a single non-empty line");
+        }
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        warn!("Running HMP command on VM {id}: {command}");
+        let socket = self.work_dir(id).hmp_socket();
+        // The monitor serves one client at a time, an attached operator blocks the command
+        timeout(HMP_TIMEOUT, hmp_command(&socket, command))
+            .await
+            .context("Timed out waiting for the monitor, is another client attached?")?
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host commands run around the lifecycle of a VM.
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+use supervisor_client::supervisor::ProcessStatus;
+use tracing::{error, info};
+
+use super::{App, VmConfig};
+use crate::config::CvmConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// Before QEMU is launched, a failure aborts the launch
+    PreStart,
+    /// After QEMU was launched
+    PostStart,
+    /// After QEMU exited, for any reason
+    PostStop,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::PreStart => "pre_start",
+            HookEvent::PostStart => "post_start",
+            HookEvent::PostStop => "post_stop",
+        }
+    }
+}
+
+/// Commands run with `sh -c` in the VM work directory.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct LifecycleHooks {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pre_start: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub post_start: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub post_stop: String,
+    /// Seconds each hook may run, `cvm.lifecycle_hooks.timeout` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl LifecycleHooks {
+    fn command(&self, event: HookEvent) -> &str {
+        match event {
+            HookEvent::PreStart => &self.pre_start,
+            HookEvent::PostStart => &self.post_start,
+            HookEvent::PostStop => &self.post_stop,
+        }
+    }
+
+    pub fn to_pb(&self) -> pb::LifecycleHooks {
+        pb::LifecycleHooks {
+            pre_start: self.pre_start.clone(),
+            post_start: self.post_start.clone(),
+            post_stop: self.post_stop.clone(),
+            timeout_secs: self.timeout.unwrap_or_default(),
+        }
+    }
+}
+
+pub fn resolve_hooks(
+    hooks: &pb::LifecycleHooks,
+    cvm_config: &CvmConfig,
+) -> Result<Option<LifecycleHooks>> {
+    let hooks = LifecycleHooks {
+        pre_start: hooks.pre_start.clone(),
+        post_start: hooks.post_start.clone(),
+        post_stop: hooks.post_stop.clone(),
+        timeout: (hooks.timeout_secs > 0).then_some(hooks.timeout_secs),
+    };
+    if hooks.pre_start.is_empty() && hooks.post_start.is_empty() && hooks.post_stop.is_empty() {
+        return Ok(None);
+    }
+    let cfg = &cvm_config.lifecycle_hooks;
+    if !cfg.enabled {
+        bail!("Lifecycle hooks are disabled on this host");
+    }
+    if let Some(timeout) = hooks.timeout {
+        if timeout > cfg.max_timeout {
+            bail!(
+                "Hook timeout {timeout}s exceeds the maximum of {}s",
+                cfg.max_timeout
+            );
+        }
+    }
+    Ok(Some(hooks))
+}
+
+fn exit_status_env(status: &ProcessStatus) -> String {
+    match status {
+        ProcessStatus::Exited(code) => code.to_string(),
+        ProcessStatus::Error(err) => err.clone(),
+        ProcessStatus::Stopped => "stopped".into(),
+        ProcessStatus::Running => String::new(),
+    }
+}
+
+impl App {
+    async fn run_vm_hook(
+        &self,
+        vm: &VmConfig,
+        event: HookEvent,
+        extra_env: &[(&str, String)],
+    ) -> Result<()> {
+        let Some(hooks) = &vm.manifest.hooks else {
+            return Ok(());
+        };
+        let command = hooks.command(event);
+        if command.is_empty() {
+            return Ok(());
+        }
+        let id = &vm.manifest.id;
+        let timeout = hooks
+            .timeout
+            .unwrap_or(self.config.cvm.lifecycle_hooks.timeout);
+        info!("Running {} hook of VM {id}", event.as_str());
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&vm.workdir)
+            .env("DSTACK_HOOK", event.as_str())
+            .env("DSTACK_VM_ID", id)
+            .env("DSTACK_VM_NAME", &vm.manifest.name)
+            .env("DSTACK_APP_ID", &vm.manifest.app_id)
+            .env("DSTACK_VM_IMAGE", &vm.manifest.image)
+            .env("DSTACK_VM_CID", vm.cid.to_string())
+            .envs(extra_env.iter().cloned())
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(Duration::from_secs(timeout), output)
+            .await
+            .map_err(|_| anyhow::anyhow!("{} hook timed out after {timeout}s", event.as_str()))?
+            .with_context(|| format!("Failed to run {} hook", event.as_str()))?;
+        if !output.status.success() {
+            bail!(
+                "{} hook failed with {}: {}",
+                event.as_str(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    fn spawn_vm_hook(
+        &self,
+        vm: VmConfig,
+        event: HookEvent,
+        extra_env: Vec<(&'static str, String)>,
+    ) {
+        if vm
+            .manifest
+            .hooks
+            .as_ref()
+            .is_none_or(|h| h.command(event).is_empty())
+        {
+            return;
+        }
+        let app = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = app.run_vm_hook(&vm, event, &extra_env).await {
+                error!("VM {}: {err:?}", vm.manifest.id);
+            }
+        });
+    }
+
+    /// Run the `pre_start` hook of a VM about to be launched.
+    pub(crate) async fn run_pre_start_hook(&self, id: &str) -> Result<()> {
+        let vm = {
+            let state = self.lock();
+            state.get(id).context("VM not found")?.config.clone()
+        };
+        self.run_vm_hook(&vm, HookEvent::PreStart, &[]).await
+    }
+
+    pub(crate) fn spawn_post_start_hook(&self, vm: &VmConfig) {
+        self.spawn_vm_hook(vm.clone(), HookEvent::PostStart, vec![]);
+    }
+
+    /// Run the `post_stop` hook of VMs whose QEMU exited since they were launched, and release
+    /// their host ports.
+    pub(crate) async fn check_stopped_vms(&self) -> Result<()> {
+        let processes = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .map(|p| (p.config.id, p.state.status))
+            .collect::<HashMap<_, _>>();
+        let mut stopped = vec![];
+        {
+            let mut state = self.lock();
+            for vm in state.vms.values_mut() {
+                if !vm.state.post_stop_pending {
+                    continue;
+                }
+                let status = processes.get(&vm.config.manifest.id);
+                if status.is_some_and(|s| s.is_running()) {
+                    continue;
+                }
+                vm.state.post_stop_pending = false;
+                let exit_status = status.map(exit_status_env).unwrap_or_default();
+                stopped.push((vm.config.clone(), exit_status));
+            }
+            for (vm, _) in &stopped {
+                state.ports.release(&vm.manifest.id, None);
+            }
+        }
+        for (vm, exit_status) in stopped {
+            self.spawn_vm_hook(
+                vm,
+                HookEvent::PostStop,
+                vec![("DSTACK_VM_EXIT_STATUS", exit_status)],
+            );
+        }
+        Ok(())
+    }
+}
@@ -129,3 +129,16 @@ fn guess_version(base_path: &Path) -> Option<String> {
     };
     Some(version.to_string())
 }
+
+/// Reject image names that are not a plain directory name under the image path.
+pub fn check_image_name(name: &str) -> Result<()> {
+    if name.len() > 64
+        || name.contains("..")
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        bail!("Invalid image name");
+    }
+    Ok(())
+}
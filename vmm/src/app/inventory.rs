@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! VM definitions pulled from an external inventory service, reconciled onto this host.
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc::VmConfiguration;
+use reqwest::header::{AUTHORIZATION, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::{verify_config_signature, vm_config_signed_message, App, VmOrigin};
+use crate::config::InventoryConfig;
+use crate::main_service::create_manifest_from_vm_config;
+
+/// A VM the inventory declares for this host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryVm {
+    /// UUID of the VM, kept across reconciliations
+    pub id: String,
+    pub configuration: VmConfiguration,
+}
+
+/// Source of the VMs this host should run.
+#[rocket::async_trait]
+pub trait InventoryProvider: Send + Sync {
+    async fn fetch(&self) -> Result<Vec<InventoryVm>>;
+}
+
+struct CachedList {
+    fetched_at: Instant,
+    etag: Option<String>,
+    vms: Vec<InventoryVm>,
+}
+
+/// Inventory served as a JSON list of [`InventoryVm`] at a URL.
+///
+/// The list is reused for `cache_ttl` after a fetch, and revalidated with its ETag after that.
+pub struct HttpInventory {
+    url: String,
+    token: String,
+    cache_ttl: Duration,
+    client: reqwest::Client,
+    cache: tokio::sync::Mutex<Option<CachedList>>,
+}
+
+impl HttpInventory {
+    pub fn new(config: &InventoryConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()
+            .unwrap_or_default();
+        Self {
+            url: config.url.clone(),
+            token: config.token.clone(),
+            cache_ttl: Duration::from_secs(config.cache_ttl),
+            client,
+            cache: Default::default(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl InventoryProvider for HttpInventory {
+    async fn fetch(&self) -> Result<Vec<InventoryVm>> {
+        let mut cache = self.cache.lock().await;
+        if let Some(cached) = &*cache {
+            if cached.fetched_at.elapsed() < self.cache_ttl {
+                return Ok(cached.vms.clone());
+            }
+        }
+        let mut request = self.client.get(&self.url);
+        if !self.token.is_empty() {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", self.token));
+        }
+        if let Some(etag) = cache.as_ref().and_then(|c| c.etag.as_ref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch inventory from {}", self.url))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cache.as_mut() {
+                cached.fetched_at = Instant::now();
+                return Ok(cached.vms.clone());
+            }
+        }
+        if !response.status().is_success() {
+            bail!("Inventory {} returned {}", self.url, response.status());
+        }
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let vms: Vec<InventoryVm> = response.json().await.context("Invalid inventory")?;
+        *cache = Some(CachedList {
+            fetched_at: Instant::now(),
+            etag,
+            vms: vms.clone(),
+        });
+        Ok(vms)
+    }
+}
+
+/// An inventory provider and the last list it returned successfully.
+pub struct Inventory {
+    provider: Box<dyn InventoryProvider>,
+    prune: bool,
+    last_good: Mutex<Option<Vec<InventoryVm>>>,
+}
+
+impl Inventory {
+    pub fn new(provider: Box<dyn InventoryProvider>, prune: bool) -> Self {
+        Self {
+            provider,
+            prune,
+            last_good: Mutex::new(None),
+        }
+    }
+
+    /// The configured inventory, if any.
+    pub fn from_config(config: &InventoryConfig) -> Option<Self> {
+        if config.url.is_empty() {
+            return None;
+        }
+        Some(Self::new(
+            Box::new(HttpInventory::new(config)),
+            config.prune,
+        ))
+    }
+
+    /// The current list, or the last valid one if the provider fails.
+    async fn desired(&self) -> Result<Vec<InventoryVm>> {
+        let fetched = self.provider.fetch().await.and_then(|vms| {
+            check_inventory(&vms)?;
+            Ok(vms)
+        });
+        match fetched {
+            Ok(vms) => {
+                *self.last_good.lock().unwrap() = Some(vms.clone());
+                Ok(vms)
+            }
+            Err(err) => {
+                let last_good = self.last_good.lock().unwrap().clone();
+                match last_good {
+                    Some(vms) => {
+                        warn!("Failed to fetch inventory, keeping the last known VMs: {err:?}");
+                        Ok(vms)
+                    }
+                    None => Err(err),
+                }
+            }
+        }
+    }
+}
+
+/// VM ids name work dirs, they must be unique UUIDs.
+fn check_inventory(vms: &[InventoryVm]) -> Result<()> {
+    let mut ids = BTreeSet::new();
+    for vm in vms {
+        uuid::Uuid::parse_str(&vm.id)
+            .with_context(|| format!("Invalid VM id in inventory: {}", vm.id))?;
+        if !ids.insert(&vm.id) {
+            bail!("Duplicate VM id in inventory: {}", vm.id);
+        }
+    }
+    Ok(())
+}
+
+impl App {
+    /// Create, replace and optionally remove VMs to match the inventory. Does nothing if no
+    /// inventory is configured.
+    pub async fn reconcile_inventory(&self) -> Result<()> {
+        let Some(inventory) = &self.inventory else {
+            return Ok(());
+        };
+        let desired = inventory.desired().await?;
+        for vm in &desired {
+            if let Err(err) = self.apply_inventory_vm(vm).await {
+                error!(
+                    "Failed to reconcile VM {} with the inventory: {err:?}",
+                    vm.id
+                );
+            }
+        }
+        let desired_ids = desired.iter().map(|vm| &vm.id).collect::<BTreeSet<_>>();
+        let extra = self
+            .lock()
+            .iter_vms()
+            .map(|vm| vm.config.manifest.id.clone())
+            .filter(|id| !desired_ids.contains(id))
+            .collect::<Vec<_>>();
+        for id in extra {
+            if !inventory.prune {
+                warn!("VM {id} is not in the inventory");
+                continue;
+            }
+            info!("Removing VM {id}, it is not in the inventory");
+            if let Err(err) = self.prune_vm(&id).await {
+                error!("Failed to remove VM {id}: {err:?}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply_inventory_vm(&self, vm: &InventoryVm) -> Result<()> {
+        let config = &vm.configuration;
+        let signed_by = verify_config_signature(
+            &self.config.auth,
+            &vm_config_signed_message(config),
+            &config.signature,
+        )?;
+        let mut manifest = create_manifest_from_vm_config(config.clone(), &self.config.cvm)?;
+        manifest.id = vm.id.clone();
+        manifest.signed_by = signed_by;
+        manifest.origin = Some(VmOrigin::Inventory {
+            url: self.config.inventory.url.clone(),
+        });
+        let exists = self.lock().get(&vm.id).is_some();
+        if !exists {
+            info!("Creating VM {} from the inventory", vm.id);
+            self.ensure_not_draining()?;
+            return self.create_vm(manifest, config, !config.stopped).await;
+        }
+        if self.diff_vm_config(&vm.id, &manifest, config)?.in_sync {
+            return Ok(());
+        }
+        info!("Replacing the config of VM {} from the inventory", vm.id);
+        self.replace_vm(&vm.id, manifest, config).await
+    }
+
+    async fn prune_vm(&self, id: &str) -> Result<()> {
+        if self.is_running(id).await? {
+            self.stop_vm(id).await.context("Failed to stop VM")?;
+        }
+        self.remove_vm(id).await
+    }
+}
@@ -0,0 +1,411 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A digest over the launch parameters of a VM, reproducible from the VM config alone.
+//!
+//! The QEMU arguments are rendered as for a launch and normalized: wrappers such as `taskset`
+//! are dropped, paths in the VM work dir and the image dir become placeholders, the boot files
+//! are replaced by the hashes of their contents and per-instance values such as the vsock CID
+//! and the MACs are masked. Display endpoints are allocated per host and left out. The same
+//! config and boot files give the same digest on any host with the same settings.
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use dstack_types::AppCompose;
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde::Serialize;
+use sha2::{Digest, Sha384};
+
+use super::image::Image;
+use super::qemu::BootSpec;
+use super::{App, MrConfigInputs, VmConfig};
+use crate::main_service::create_manifest_from_vm_config;
+
+/// Bumped whenever the normalization changes, as it changes the digests.
+const DIGEST_VERSION: &str = "dstack-launch-digest-v1";
+
+/// Options of QEMU arguments that differ between instances of the same config.
+const VOLATILE_OPTS: &[&str] = &["guest-cid", "mac"];
+
+/// Arguments whose value is a boot file, hashed on its own.
+const BOOT_FILE_ARGS: &[(&str, &str)] = &[
+    ("-bios", "${BIOS}"),
+    ("-kernel", "${KERNEL}"),
+    ("-initrd", "${INITRD}"),
+];
+
+#[derive(Serialize)]
+struct LaunchInputs<'a> {
+    version: &'a str,
+    argv: &'a [String],
+    firmware_sha384: &'a str,
+    kernel_sha384: &'a str,
+    initrd_sha384: &'a str,
+    cmdline: &'a str,
+    machine: &'a str,
+    cpu: &'a str,
+    vcpu: u32,
+    memory: u32,
+}
+
+impl LaunchInputs<'_> {
+    fn digest(&self) -> Result<String> {
+        Ok(hex::encode(Sha384::digest(serde_json::to_vec(self)?)))
+    }
+}
+
+fn file_sha384(path: &Path) -> Result<String> {
+    let data = fs::read(path)?;
+    Ok(hex::encode(Sha384::digest(&data)))
+}
+
+fn arg_value<'a>(argv: &'a [String], flag: &str) -> &'a str {
+    argv.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| argv.get(i + 1))
+        .map_or("", String::as_str)
+}
+
+fn mask_volatile_opts(arg: &str) -> String {
+    arg.split(',')
+        .map(|opt| match opt.split_once('=') {
+            Some((key, _)) if VOLATILE_OPTS.contains(&key) => format!("{key}=*"),
+            _ => opt.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The QEMU arguments of `argv` without the binary and its wrappers, normalized.
+fn normalize_argv(argv: &[String], qemu: &str, workdir: &Path, image_dir: &Path) -> Vec<String> {
+    let start = argv.iter().position(|arg| arg == qemu).map_or(0, |i| i + 1);
+    let workdir = workdir.to_string_lossy();
+    let image_dir = image_dir.to_string_lossy();
+    let mut normalized: Vec<String> = Vec::with_capacity(argv.len() - start);
+    for arg in &argv[start..] {
+        let flag = normalized.last().map_or("", String::as_str);
+        let arg = if let Some((_, placeholder)) = BOOT_FILE_ARGS.iter().find(|(f, _)| *f == flag) {
+            placeholder.to_string()
+        } else if flag == "-append" {
+            arg.clone()
+        } else {
+            mask_volatile_opts(
+                &arg.replace(&*workdir, "${WORKDIR}")
+                    .replace(&*image_dir, "${IMAGE}"),
+            )
+        };
+        normalized.push(arg);
+    }
+    normalized
+}
+
+/// The launch digest of booting `boot` with the normalized QEMU arguments `argv`. The boot files
+/// are hashed as resolved, so only their contents count, not where they were found.
+fn digest_launch(
+    argv: Vec<String>,
+    boot: BootSpec,
+    vm_config: &VmConfig,
+) -> Result<pb::LaunchDigestResponse> {
+    let firmware_sha384 = match &vm_config.image.bios {
+        Some(bios) => file_sha384(bios).context("Failed to hash the firmware")?,
+        None => String::new(),
+    };
+    let kernel_sha384 = file_sha384(&boot.kernel).context("Failed to hash the kernel")?;
+    let initrd_sha384 = match &boot.initrd {
+        Some(initrd) => file_sha384(initrd).context("Failed to hash the initrd")?,
+        None => String::new(),
+    };
+    let cmdline = boot.cmdline.unwrap_or_default();
+    let inputs = LaunchInputs {
+        version: DIGEST_VERSION,
+        argv: &argv,
+        firmware_sha384: &firmware_sha384,
+        kernel_sha384: &kernel_sha384,
+        initrd_sha384: &initrd_sha384,
+        cmdline: &cmdline,
+        machine: arg_value(&argv, "-machine"),
+        cpu: arg_value(&argv, "-cpu"),
+        vcpu: vm_config.manifest.vcpu,
+        memory: vm_config.manifest.memory,
+    };
+    Ok(pb::LaunchDigestResponse {
+        digest: inputs.digest()?,
+        version: DIGEST_VERSION.into(),
+        machine: inputs.machine.into(),
+        cpu: inputs.cpu.into(),
+        vcpu: inputs.vcpu,
+        memory: inputs.memory,
+        argv,
+        firmware_sha384,
+        kernel_sha384,
+        initrd_sha384,
+        cmdline,
+    })
+}
+
+impl App {
+    /// The launch digest of VM `id`, or of `spec` if given.
+    pub fn launch_digest(
+        &self,
+        id: &str,
+        spec: Option<pb::VmConfiguration>,
+    ) -> Result<pb::LaunchDigestResponse> {
+        let (vm_config, mr_config) = match spec {
+            Some(_) if !id.is_empty() => bail!("Either a VM id or a spec can be given, not both"),
+            Some(spec) => self.spec_launch_config(spec)?,
+            None => {
+                let vm_config = self.lock().get(id).context("VM not found")?.config.clone();
+                let mr_config = if vm_config.uses_mr_config_id(&self.config.cvm) {
+                    Some(MrConfigInputs::read(&self.work_dir(id))?)
+                } else {
+                    None
+                };
+                ((*vm_config).clone(), mr_config)
+            }
+        };
+        let cfg = &self.config.cvm;
+        let gpus = self.try_allocate_gpus(&vm_config.manifest)?;
+        let boot = vm_config.validate_boot(cfg)?;
+        let process = vm_config
+            .render_qemu(
+                &vm_config.workdir,
+                cfg,
+                &boot,
+                &gpus,
+                None,
+                mr_config.as_ref(),
+            )
+            .context("Failed to build QEMU configuration")?
+            .pop()
+            .context("No VM process rendered")?;
+        let argv = std::iter::once(process.command)
+            .chain(process.args)
+            .collect::<Vec<_>>();
+        let image_dir = self.config.image_path.join(&vm_config.manifest.image);
+        let argv = normalize_argv(
+            &argv,
+            &cfg.qemu_path.to_string_lossy(),
+            &vm_config.workdir,
+            &image_dir,
+        );
+        digest_launch(argv, boot, &vm_config)
+    }
+
+    /// The config of a VM launched from `spec`, as far as it affects the launch.
+    fn spec_launch_config(
+        &self,
+        spec: pb::VmConfiguration,
+    ) -> Result<(VmConfig, Option<MrConfigInputs>)> {
+        if spec.compose_file.is_empty() {
+            bail!("The spec has no compose file");
+        }
+        let app_compose: AppCompose =
+            serde_json::from_str(&spec.compose_file).context("Failed to parse compose file")?;
+        let compose = spec.compose_file.clone().into_bytes();
+        let manifest = create_manifest_from_vm_config(spec, &self.config.cvm)?;
+        let image_path = self.config.image_path.join(&manifest.image);
+        let image = Image::load(&image_path)
+            .with_context(|| format!("Failed to load image: {}", image_path.display()))?;
+        let vm_config = VmConfig {
+            workdir: self.work_dir(&manifest.id).path().to_path_buf(),
+            cid: self.config.cvm.cid_start,
+            gateway_enabled: app_compose.gateway_enabled(),
+            manifest,
+            image,
+        };
+        let mr_config = vm_config
+            .uses_mr_config_id(&self.config.cvm)
+            .then(|| MrConfigInputs {
+                compose,
+                app_id: hex::decode(&vm_config.manifest.app_id).unwrap_or_default(),
+            });
+        Ok((vm_config, mr_config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QEMU: &str = "/usr/bin/qemu-system-x86_64";
+    const WORKDIR: &str = "/var/lib/dstack/vm/vm-1";
+    const IMAGE_DIR: &str = "/var/lib/dstack/images/dstack-0.5.0";
+
+    fn argv() -> Vec<String> {
+        [
+            "/usr/bin/taskset",
+            "-c",
+            "0-1",
+            QEMU,
+            "-accel",
+            "kvm",
+            "-machine",
+            "q35,kernel-irqchip=split,confidential-guest-support=tdx",
+            "-cpu",
+            "host",
+            "-smp",
+            "2",
+            "-m",
+            "2048M",
+            "-nographic",
+            "-bios",
+            "/var/lib/dstack/images/dstack-0.5.0/ovmf.fd",
+            "-kernel",
+            "/var/lib/dstack/images/dstack-0.5.0/bzImage",
+            "-initrd",
+            "/var/lib/dstack/images/dstack-0.5.0/initramfs.cpio.gz",
+            "-append",
+            "console=ttyS0 dstack.fde=1",
+            "-drive",
+            "file=/var/lib/dstack/vm/vm-1/hda.img,if=none,id=hd1,format=qcow2",
+            "-device",
+            "virtio-blk-pci,drive=hd1",
+            "-device",
+            "vhost-vsock-pci,guest-cid=1000",
+            "-netdev",
+            "user,id=net0",
+            "-device",
+            "virtio-net-pci,netdev=net0,mac=02:2d:62:b0:03:5f",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    #[test]
+    fn normalizes_argv() {
+        let argv = normalize_argv(&argv(), QEMU, Path::new(WORKDIR), Path::new(IMAGE_DIR));
+        assert_eq!(argv[0], "-accel");
+        assert_eq!(arg_value(&argv, "-bios"), "${BIOS}");
+        assert_eq!(arg_value(&argv, "-kernel"), "${KERNEL}");
+        assert_eq!(arg_value(&argv, "-initrd"), "${INITRD}");
+        assert_eq!(arg_value(&argv, "-append"), "console=ttyS0 dstack.fde=1");
+        assert_eq!(
+            arg_value(&argv, "-drive"),
+            "file=${WORKDIR}/hda.img,if=none,id=hd1,format=qcow2"
+        );
+        assert!(argv.contains(&"vhost-vsock-pci,guest-cid=*".to_string()));
+        assert!(argv.contains(&"virtio-net-pci,netdev=net0,mac=*".to_string()));
+    }
+
+    #[test]
+    fn argv_is_the_same_across_instances() {
+        let other = argv()
+            .into_iter()
+            .map(|arg| {
+                arg.replace("vm-1", "vm-2")
+                    .replace("1000", "1001")
+                    .replace("02:2d:62:b0:03:5f", "02:00:00:00:00:01")
+            })
+            .collect::<Vec<_>>();
+        let argv = normalize_argv(&argv(), QEMU, Path::new(WORKDIR), Path::new(IMAGE_DIR));
+        let other = normalize_argv(
+            &other,
+            QEMU,
+            Path::new("/var/lib/dstack/vm/vm-2"),
+            Path::new(IMAGE_DIR),
+        );
+        assert_eq!(argv, other);
+    }
+
+    /// Known answer, changes only when the normalization or the inputs change, which must
+    /// come with a bump of `DIGEST_VERSION`.
+    #[test]
+    fn known_digest() {
+        let argv = normalize_argv(&argv(), QEMU, Path::new(WORKDIR), Path::new(IMAGE_DIR));
+        let sha384 = |data: &[u8]| hex::encode(Sha384::digest(data));
+        let (firmware, kernel, initrd) = (sha384(b"ovmf"), sha384(b"kernel"), sha384(b"initrd"));
+        let inputs = LaunchInputs {
+            version: DIGEST_VERSION,
+            argv: &argv,
+            firmware_sha384: &firmware,
+            kernel_sha384: &kernel,
+            initrd_sha384: &initrd,
+            cmdline: arg_value(&argv, "-append"),
+            machine: arg_value(&argv, "-machine"),
+            cpu: arg_value(&argv, "-cpu"),
+            vcpu: 2,
+            memory: 2048,
+        };
+        assert_eq!(
+            inputs.digest().unwrap(),
+            "5f3c94508a7dde50a6e54b7b248226b2cedb88885e71b8dbd937b00634f8e00a8b4c9c06ccde4c84ae215651b9fcd7a2"
+        );
+    }
+
+    /// A VM booting a kernel found through an allowlisted environment variable.
+    fn env_vm(dir: &Path) -> VmConfig {
+        let mut manifest = crate::app::Manifest::for_test("vm-1");
+        manifest.kernel = Some("${DSTACK_VMM_TEST_DIGEST_DIR}/bzImage".into());
+        manifest.initrd = Some("${DSTACK_VMM_TEST_DIGEST_DIR}/initrd".into());
+        manifest.cmdline = Some("console=ttyS0 root=${DSTACK_VMM_TEST_DIGEST_ROOT}".into());
+        let info = serde_json::from_value(serde_json::json!({
+            "kernel": "bzImage",
+            "initrd": "initrd",
+        }))
+        .unwrap();
+        VmConfig {
+            manifest,
+            image: Image {
+                info,
+                initrd: dir.join("image-initrd"),
+                kernel: dir.join("image-bzImage"),
+                hda: None,
+                rootfs: None,
+                bios: None,
+                digest: None,
+            },
+            cid: 1000,
+            workdir: WORKDIR.into(),
+            gateway_enabled: false,
+        }
+    }
+
+    fn env_digest(dir: &Path, root: &str) -> pb::LaunchDigestResponse {
+        std::env::set_var("DSTACK_VMM_TEST_DIGEST_DIR", dir);
+        std::env::set_var("DSTACK_VMM_TEST_DIGEST_ROOT", root);
+        let mut cfg = crate::config::Config::for_test().cvm;
+        cfg.boot_env_allowlist = vec!["DSTACK_VMM_TEST_DIGEST_*".into()];
+        let vm = env_vm(dir);
+        let boot = vm.validate_boot(&cfg).unwrap();
+        let argv = [
+            QEMU.to_string(),
+            "-kernel".into(),
+            boot.kernel.display().to_string(),
+            "-initrd".into(),
+            boot.initrd.as_ref().unwrap().display().to_string(),
+            "-append".into(),
+            boot.cmdline.clone().unwrap(),
+        ];
+        let argv = normalize_argv(&argv, QEMU, Path::new(WORKDIR), Path::new(IMAGE_DIR));
+        digest_launch(argv, boot, &vm).unwrap()
+    }
+
+    /// The digest covers the resolved boot inputs: the contents of the boot files wherever an
+    /// environment variable points to, and the expanded kernel cmdline.
+    #[test]
+    fn digest_follows_resolved_env() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        for dir in &dirs {
+            fs::write(dir.path().join("bzImage"), b"kernel").unwrap();
+            fs::write(dir.path().join("initrd"), b"initrd").unwrap();
+        }
+        let first = env_digest(dirs[0].path(), "/dev/vda1");
+        let moved = env_digest(dirs[1].path(), "/dev/vda1");
+        assert_eq!(first.digest, moved.digest);
+        assert_eq!(first.kernel_sha384, sha384_hex(b"kernel"));
+        assert_eq!(first.cmdline, "console=ttyS0 root=/dev/vda1");
+
+        let other_root = env_digest(dirs[1].path(), "/dev/vda2");
+        assert_ne!(first.digest, other_root.digest);
+
+        fs::write(dirs[1].path().join("bzImage"), b"other kernel").unwrap();
+        let other_kernel = env_digest(dirs[1].path(), "/dev/vda1");
+        assert_ne!(first.digest, other_kernel.digest);
+    }
+
+    fn sha384_hex(data: &[u8]) -> String {
+        hex::encode(Sha384::digest(data))
+    }
+}
@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Maximum lifetime of VMs.
+//!
+//! A VM with `max_lifetime` set is stopped gracefully once that long has passed since its QEMU
+//! was launched, plus the extensions granted with `ExtendVmLifetime`, and reported with a
+//! `vm.lifetime_expired` event. The stop clears its started flag, so neither auto-restart nor a
+//! restart of the VMM brings it back, until it is started again with a new lifetime. A VM that
+//! exited on its own past its lifetime is left stopped the same way. Extensions last until QEMU
+//! is launched again and are not kept across restarts of the VMM.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use super::{App, VmState};
+
+/// How long the guest of an expired VM is given to power off before it is stopped forcibly.
+const EXPIRED_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl VmState {
+    /// When the VM, whose QEMU was launched at `launched_at`, runs out of its lifetime.
+    /// `None` if it has none.
+    pub(crate) fn lifetime_deadline(&self, launched_at: Option<SystemTime>) -> Option<SystemTime> {
+        let lifetime = self.config.manifest.max_lifetime.filter(|l| *l > 0)?;
+        Some(launched_at? + Duration::from_secs(lifetime) + self.state.lifetime_extension)
+    }
+
+    pub(crate) fn lifetime_details(&self, deadline: SystemTime) -> Value {
+        json!({
+            "max_lifetime_secs": self.config.manifest.max_lifetime.unwrap_or_default(),
+            "extension_secs": self.state.lifetime_extension.as_secs(),
+            "expired_at_ms": unix_ms(deadline),
+        })
+    }
+}
+
+impl App {
+    /// Stop the running VMs that exceeded their max lifetime.
+    pub(crate) async fn check_vm_lifetimes(&self) -> Result<()> {
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .map(|p| (p.config.id, p.state.started_at))
+            .collect::<Vec<_>>();
+        let now = SystemTime::now();
+        let mut expired = vec![];
+        {
+            let mut state = self.lock();
+            for (id, launched_at) in running {
+                let Some(vm) = state.get_mut(&id) else {
+                    continue;
+                };
+                if vm.state.lifetime_expired {
+                    continue;
+                }
+                let Some(deadline) = vm.lifetime_deadline(launched_at) else {
+                    continue;
+                };
+                if deadline > now {
+                    continue;
+                }
+                vm.state.lifetime_expired = true;
+                expired.push((id, vm.lifetime_details(deadline)));
+            }
+        }
+        for (id, details) in expired {
+            // A guest taking long to power off must not hold up the other checks
+            let app = self.clone();
+            tokio::spawn(async move { app.stop_expired_vm(&id, details).await });
+        }
+        Ok(())
+    }
+
+    async fn stop_expired_vm(&self, id: &str, mut details: Value) {
+        warn!("VM {id} exceeded its max lifetime, stopping it: {details}");
+        match self.stop_vm_with(id, true, EXPIRED_STOP_TIMEOUT).await {
+            Ok(response) => {
+                info!("Stopped VM {id} at the end of its lifetime");
+                details["path"] = response.path.into();
+            }
+            Err(err) => {
+                error!("Failed to stop VM {id} at the end of its lifetime: {err:?}");
+                details["error"] = format!("{err:#}").into();
+            }
+        }
+        self.emit_event("vm.lifetime_expired", Some(id), details);
+    }
+
+    /// Leave VM `id` stopped after it exited past its lifetime, instead of restarting it.
+    pub(crate) fn keep_expired_vm_stopped(&self, id: &str, mut details: Value) {
+        info!("VM {id} exited past its max lifetime, not restarting it");
+        if let Err(err) = self.set_started(id, false) {
+            warn!("Failed to mark VM {id} stopped: {err:?}");
+        }
+        details["exited"] = true.into();
+        self.emit_event("vm.lifetime_expired", Some(id), details);
+    }
+
+    /// Give the running VM `id` `extra` more time before it is stopped.
+    pub async fn extend_vm_lifetime(
+        &self,
+        id: &str,
+        extra: Duration,
+    ) -> Result<pb::ExtendVmLifetimeResponse> {
+        if extra.is_zero() {
+            bail!("The extension must not be empty");
+        }
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let launched_at = self
+            .supervisor
+            .info(id)
+            .await?
+            .filter(|p| p.state.status.is_running())
+            .context("VM is not running")?
+            .state
+            .started_at
+            .context("Launch time of the VM is unknown")?;
+        let (deadline, extension) = {
+            let mut state = self.lock();
+            let vm = state.get_mut(id).context("VM not found")?;
+            let deadline = vm
+                .lifetime_deadline(Some(launched_at))
+                .context("VM has no max lifetime")?;
+            if vm.state.lifetime_expired {
+                bail!("VM already exceeded its lifetime and is being stopped");
+            }
+            vm.state.lifetime_extension += extra;
+            (deadline + extra, vm.state.lifetime_extension)
+        };
+        info!(
+            "Extended the lifetime of VM {id} by {extra:?}, to {} ms",
+            unix_ms(deadline)
+        );
+        self.emit_event(
+            "vm.lifetime_extended",
+            Some(id),
+            json!({
+                "extra_secs": extra.as_secs(),
+                "extension_secs": extension.as_secs(),
+                "expires_at_ms": unix_ms(deadline),
+            }),
+        );
+        Ok(pb::ExtendVmLifetimeResponse {
+            expires_at_ms: unix_ms(deadline),
+            extension_secs: extension.as_secs(),
+        })
+    }
+}
@@ -0,0 +1,287 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sorting, filtering and token pagination of the VM list of `Status`.
+//!
+//! A paged listing is computed once: its first call stores the ordered VM ids under a page
+//! token, and later pages are read from them. VMs created after the first page are not listed
+//! and VMs are not repeated or skipped while their state changes, only removed VMs drop out.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use super::qemu::VmInfo;
+
+/// How long the ids of a paged listing are kept after its last page was read.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(300);
+/// Paged listings kept at once, the least recently read is dropped first.
+const MAX_SNAPSHOTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Created,
+    Name,
+    Status,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "" | "created" => Self::Created,
+            "name" => Self::Name,
+            "status" => Self::Status,
+            _ => bail!("Invalid sort key: {s}, expected created, name or status"),
+        })
+    }
+}
+
+/// Sort VMs by `key`, ties broken by creation time and id.
+pub fn sort_vms(infos: &mut [VmInfo], key: SortKey, descending: bool) {
+    infos.sort_by(|a, b| {
+        let (a, b) = if descending { (b, a) } else { (a, b) };
+        let primary = match key {
+            SortKey::Created => std::cmp::Ordering::Equal,
+            SortKey::Name => a.manifest.name.cmp(&b.manifest.name),
+            SortKey::Status => a.status.cmp(b.status),
+        };
+        primary
+            .then(a.manifest.created_at_ms.cmp(&b.manifest.created_at_ms))
+            .then(a.manifest.id.cmp(&b.manifest.id))
+    });
+}
+
+/// Comma separated `key=value` and `key!=value` requirements, all of which a VM must meet.
+///
+/// VMs have no free-form labels, the keys are attributes of the VM: `name`, `app_id`,
+/// `image`, `status`, `network_group`, `signed_by`, `anti_affinity` (one of its scheduling
+/// labels) and `attestation` (its attestation status).
+#[derive(Debug, Default)]
+pub struct Selector {
+    requirements: Vec<(String, bool, String)>,
+}
+
+const SELECTOR_KEYS: &[&str] = &[
+    "name",
+    "app_id",
+    "image",
+    "status",
+    "network_group",
+    "signed_by",
+    "anti_affinity",
+    "attestation",
+];
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut requirements = vec![];
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, equal, value) = match term.split_once("!=") {
+                Some((key, value)) => (key, false, value),
+                None => {
+                    let (key, value) = term
+                        .split_once('=')
+                        .with_context(|| format!("Invalid selector term: {term}"))?;
+                    (key, true, value)
+                }
+            };
+            let key = key.trim();
+            if !SELECTOR_KEYS.contains(&key) {
+                bail!("Unknown selector key: {key}");
+            }
+            requirements.push((key.to_string(), equal, value.trim().to_string()));
+        }
+        Ok(Self { requirements })
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, info: &VmInfo) -> bool {
+        let manifest = &info.manifest;
+        self.requirements.iter().all(|(key, equal, value)| {
+            let found = match key.as_str() {
+                "name" => manifest.name == *value,
+                "app_id" => manifest.app_id == *value,
+                "image" => manifest.image == *value,
+                "status" => info.status == value,
+                "network_group" => manifest.network_group.as_deref().unwrap_or("") == value,
+                "signed_by" => manifest.signed_by.as_deref().unwrap_or("") == value,
+                "anti_affinity" => manifest
+                    .scheduling
+                    .as_ref()
+                    .is_some_and(|s| s.anti_affinity.contains(value)),
+                "attestation" => info.attestation_status.as_str() == value,
+                _ => false,
+            };
+            found == *equal
+        })
+    }
+}
+
+struct Snapshot {
+    ids: Vec<String>,
+    last_read: Instant,
+}
+
+/// Ordered VM ids of the paged listings in progress, keyed by page token.
+#[derive(Default)]
+pub(crate) struct ListSnapshots {
+    snapshots: HashMap<String, Snapshot>,
+}
+
+impl ListSnapshots {
+    fn expire(&mut self) {
+        self.snapshots
+            .retain(|_, s| s.last_read.elapsed() < SNAPSHOT_TTL);
+        while self.snapshots.len() >= MAX_SNAPSHOTS {
+            let Some(oldest) = self
+                .snapshots
+                .iter()
+                .min_by_key(|(_, s)| s.last_read)
+                .map(|(token, _)| token.clone())
+            else {
+                break;
+            };
+            self.snapshots.remove(&oldest);
+        }
+    }
+
+    /// Keep the ids of a listing and return the token of the page starting at `offset`.
+    pub fn insert(&mut self, ids: Vec<String>, offset: usize) -> String {
+        self.expire();
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        self.snapshots.insert(
+            token.clone(),
+            Snapshot {
+                ids,
+                last_read: Instant::now(),
+            },
+        );
+        format!("{token}:{offset}")
+    }
+
+    /// Ids of the page of `page_token` and the token of the page after it, if any.
+    pub fn page(&mut self, page_token: &str, limit: usize) -> Result<(Vec<String>, u32, String)> {
+        self.expire();
+        let (token, offset) = page_token
+            .split_once(':')
+            .and_then(|(token, offset)| Some((token, offset.parse::<usize>().ok()?)))
+            .context("Invalid page token")?;
+        let snapshot = self
+            .snapshots
+            .get_mut(token)
+            .context("Page token expired, list again from the first page")?;
+        snapshot.last_read = Instant::now();
+        let total = snapshot.ids.len();
+        let end = if limit == 0 {
+            total
+        } else {
+            offset.saturating_add(limit).min(total)
+        };
+        let ids = snapshot.ids.get(offset..end).unwrap_or_default().to_vec();
+        let next = if end < total {
+            format!("{token}:{end}")
+        } else {
+            self.snapshots.remove(token);
+            String::new()
+        };
+        Ok((ids, total as u32, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("vm-{i}")).collect()
+    }
+
+    #[test]
+    fn parses_selectors() {
+        let selector: Selector = " name=web , status!=running,,image = dstack-0.5.0"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            selector.requirements,
+            [
+                ("name".to_string(), true, "web".to_string()),
+                ("status".to_string(), false, "running".to_string()),
+                ("image".to_string(), true, "dstack-0.5.0".to_string()),
+            ]
+        );
+        assert!("".parse::<Selector>().unwrap().requirements.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for s in ["name", "name=web,status", "label=web", "=web", "Name=web"] {
+            assert!(s.parse::<Selector>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn pages_through_a_snapshot() {
+        let mut snapshots = ListSnapshots::default();
+        let token = snapshots.insert(ids(5), 2);
+        let (page, total, next) = snapshots.page(&token, 2).unwrap();
+        assert_eq!(page, ["vm-2", "vm-3"]);
+        assert_eq!(total, 5);
+        let (page, total, next) = snapshots.page(&next, 2).unwrap();
+        assert_eq!(page, ["vm-4"]);
+        assert_eq!(total, 5);
+        assert_eq!(next, "");
+        // The last page drops the snapshot
+        assert!(snapshots.page(&token, 2).is_err());
+    }
+
+    #[test]
+    fn last_page_ending_at_the_limit_has_no_next_token() {
+        let mut snapshots = ListSnapshots::default();
+        let token = snapshots.insert(ids(4), 2);
+        let (page, _, next) = snapshots.page(&token, 2).unwrap();
+        assert_eq!(page, ["vm-2", "vm-3"]);
+        assert_eq!(next, "");
+
+        let token = snapshots.insert(ids(4), 2);
+        let (page, _, next) = snapshots.page(&token, 0).unwrap();
+        assert_eq!(page, ["vm-2", "vm-3"]);
+        assert_eq!(next, "");
+    }
+
+    #[test]
+    fn rejects_stale_and_forged_page_tokens() {
+        let mut snapshots = ListSnapshots::default();
+        let token = snapshots.insert(ids(4), 2);
+        let (key, _) = token.split_once(':').unwrap();
+        for forged in [
+            "",
+            "garbage",
+            format!("{key}:x").as_str(),
+            format!("{key}:-1").as_str(),
+        ] {
+            assert!(snapshots.page(forged, 2).is_err(), "{forged}");
+        }
+        let unknown = format!("{}:2", "0".repeat(32));
+        assert!(snapshots.page(&unknown, 2).is_err());
+
+        // An offset past the end reads an empty last page
+        let (page, total, next) = snapshots.page(&format!("{key}:10"), 2).unwrap();
+        assert!(page.is_empty());
+        assert_eq!(total, 4);
+        assert_eq!(next, "");
+
+        let token = snapshots.insert(ids(4), 2);
+        snapshots
+            .snapshots
+            .values_mut()
+            .for_each(|s| s.last_read -= SNAPSHOT_TTL);
+        assert!(snapshots.page(&token, 2).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! MAC addresses of VM NICs, configured or derived from the VM id so they survive restarts.
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use super::{App, Manifest};
+
+/// Normalize a MAC address to lowercase `xx:xx:xx:xx:xx:xx`, rejecting multicast addresses.
+pub fn parse_mac(mac: &str) -> Result<String> {
+    let octets = mac
+        .split([':', '-'])
+        .map(|octet| match octet.len() {
+            2 => u8::from_str_radix(octet, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>();
+    let Some(octets) = octets.filter(|o| o.len() == 6) else {
+        bail!("Invalid MAC address: {mac}");
+    };
+    if octets[0] & 1 != 0 {
+        bail!("MAC address {mac} is multicast");
+    }
+    Ok(format_mac(&octets))
+}
+
+fn format_mac(octets: &[u8]) -> String {
+    octets
+        .iter()
+        .map(|o| format!("{o:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A locally administered unicast address derived from the VM id.
+pub fn derived_mac(id: &str) -> String {
+    let hash = Sha256::digest(id.as_bytes());
+    let mut octets = [0x02, 0, 0, 0, 0, 0];
+    octets[1..].copy_from_slice(&hash[..5]);
+    format_mac(&octets)
+}
+
+impl Manifest {
+    /// MAC address of the NIC of the VM.
+    pub fn mac(&self) -> String {
+        self.network
+            .as_ref()
+            .and_then(|n| n.mac.clone())
+            .unwrap_or_else(|| derived_mac(&self.id))
+    }
+}
+
+/// Check the MAC addresses of `manifests` are valid and distinct.
+pub fn check_unique_macs<'a>(manifests: impl IntoIterator<Item = &'a Manifest>) -> Result<()> {
+    let mut owners = HashMap::new();
+    for manifest in manifests {
+        for mac in manifest.macs() {
+            let mac = parse_mac(&mac)?;
+            if let Some(other) = owners.insert(mac.clone(), &manifest.id) {
+                if *other == manifest.id {
+                    bail!("NICs of VM {other} share the MAC address {mac}");
+                }
+                bail!(
+                    "VMs {other} and {} share the MAC address {mac}",
+                    manifest.id
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl App {
+    /// Fail if the NICs of `manifest` share a MAC address, with each other or another VM.
+    pub(crate) fn ensure_mac_unused(&self, manifest: &Manifest) -> Result<()> {
+        let macs = manifest.macs();
+        if let Some((i, mac)) = macs
+            .iter()
+            .enumerate()
+            .find(|(i, mac)| macs[..*i].contains(mac))
+        {
+            bail!("MAC address {mac} of NIC {i} is already used by another NIC of the VM");
+        }
+        let state = self.lock();
+        for vm in state.vms.values() {
+            if vm.config.manifest.id == manifest.id {
+                continue;
+            }
+            let used = vm.config.manifest.macs();
+            if let Some(mac) = macs.iter().find(|mac| used.contains(mac)) {
+                bail!(
+                    "MAC address {mac} is already used by VM {}",
+                    vm.config.manifest.id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::nic::{NicBackend, NicConfig, NicModel};
+    use crate::app::VmNetworkConfig;
+
+    fn with_mac(id: &str, mac: &str) -> Manifest {
+        let mut manifest = Manifest::for_test(id);
+        manifest.network = Some(VmNetworkConfig {
+            mac: Some(mac.to_string()),
+            ..Default::default()
+        });
+        manifest
+    }
+
+    fn nic(mac: Option<&str>) -> NicConfig {
+        NicConfig {
+            backend: NicBackend::User,
+            ifname: None,
+            mac: mac.map(String::from),
+            model: NicModel::default(),
+            network_group: None,
+        }
+    }
+
+    #[test]
+    fn derived_mac_is_stable() {
+        assert_eq!(derived_mac("vm-1"), "02:2d:62:b0:03:5f");
+        assert_eq!(derived_mac("vm-1"), derived_mac("vm-1"));
+        assert_ne!(derived_mac("vm-1"), derived_mac("vm-2"));
+    }
+
+    #[test]
+    fn derived_mac_is_locally_administered_unicast() {
+        for id in ["vm-1", "vm-2", "", "a-much-longer-vm-id"] {
+            let mac = derived_mac(id);
+            let first = u8::from_str_radix(&mac[..2], 16).unwrap();
+            assert_eq!(first & 0b11, 0b10, "{mac}");
+            assert_eq!(parse_mac(&mac).unwrap(), mac);
+        }
+    }
+
+    #[test]
+    fn parse_mac_normalizes_and_rejects() {
+        assert_eq!(parse_mac("AA-BB-CC-DD-EE-FF").unwrap(), "aa:bb:cc:dd:ee:ff");
+        assert!(parse_mac("01:00:5e:00:00:01").is_err());
+        assert!(parse_mac("aa:bb:cc:dd:ee").is_err());
+        assert!(parse_mac("aa:bb:cc:dd:ee:fg").is_err());
+        assert!(parse_mac("aaa:bb:cc:dd:ee:ff").is_err());
+    }
+
+    #[test]
+    fn configured_mac_overrides_derived() {
+        assert_eq!(Manifest::for_test("vm-1").mac(), derived_mac("vm-1"));
+        assert_eq!(
+            with_mac("vm-1", "52:54:00:12:34:56").mac(),
+            "52:54:00:12:34:56"
+        );
+    }
+
+    #[test]
+    fn nics_without_mac_get_distinct_derived_ones() {
+        let mut manifest = Manifest::for_test("vm-1");
+        manifest.nics = vec![nic(None), nic(None)];
+        let macs = manifest.macs();
+        assert_eq!(macs.len(), 3);
+        assert_eq!(macs[1], derived_mac("vm-1/net1"));
+        assert!(check_unique_macs([&manifest]).is_ok());
+    }
+
+    #[test]
+    fn shared_macs_are_rejected() {
+        let a = with_mac("vm-1", "52:54:00:12:34:56");
+        let b = with_mac("vm-2", "52:54:00:12:34:56");
+        let err = check_unique_macs([&a, &b]).unwrap_err().to_string();
+        assert!(err.contains("vm-1") && err.contains("vm-2"), "{err}");
+
+        // Equal once normalized
+        let c = with_mac("vm-3", "52-54-00-12-34-56");
+        assert!(check_unique_macs([&a, &c]).is_err());
+
+        let mut d = Manifest::for_test("vm-4");
+        d.nics = vec![nic(Some(&d.mac()))];
+        let err = check_unique_macs([&d]).unwrap_err().to_string();
+        assert!(err.contains("NICs of VM vm-4"), "{err}");
+
+        let e = Manifest::for_test("vm-5");
+        assert!(check_unique_macs([&a, &e]).is_ok());
+    }
+}
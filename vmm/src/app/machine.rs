@@ -0,0 +1,228 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pinning the QEMU machine type of a VM, so its ABI survives QEMU upgrades.
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use super::App;
+
+/// Machine type of VMs that do not pin one.
+pub const DEFAULT_MACHINE_TYPE: &str = "q35";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MachineConfig {
+    /// QEMU machine type, e.g. `q35`
+    #[serde(rename = "type")]
+    pub machine_type: String,
+    /// Version of the machine type, e.g. `8.2`, the newest the host QEMU has if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl MachineConfig {
+    pub fn to_pb(&self) -> pb::MachineConfig {
+        pb::MachineConfig {
+            r#type: self.machine_type.clone(),
+            version: self.version.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Name of the machine for `-machine`, like `pc-q35-8.2` for a pinned `q35`.
+    pub fn qemu_name(&self) -> String {
+        let Some(version) = &self.version else {
+            return self.machine_type.clone();
+        };
+        match self.machine_type.as_str() {
+            "q35" => format!("pc-q35-{version}"),
+            "pc" => format!("pc-i440fx-{version}"),
+            other => format!("{other}-{version}"),
+        }
+    }
+
+    /// The versioned machine the host QEMU resolves the unpinned type to, if it is newer than
+    /// the pinned version.
+    pub fn outdated<'a>(&self, machines: &'a QemuMachines) -> Option<&'a str> {
+        let pinned = parse_version(self.version.as_deref()?)?;
+        let default = machines.aliases.get(&self.machine_type)?;
+        let current = parse_version(default.rsplit('-').next()?)?;
+        (current > pinned).then_some(default.as_str())
+    }
+
+    /// Fail if the host QEMU lacks the machine, and describe how it is outdated if it is.
+    pub fn check(&self, machines: &QemuMachines) -> Result<Option<String>> {
+        let name = self.qemu_name();
+        if !machines.contains(&name) {
+            bail!("Machine {name} is not supported by the host QEMU");
+        }
+        Ok(self
+            .outdated(machines)
+            .map(|current| format!("Machine {name} is older than the host default {current}")))
+    }
+}
+
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+pub fn resolve_machine(machine: &pb::MachineConfig) -> Result<Option<MachineConfig>> {
+    if machine.r#type.is_empty() && machine.version.is_empty() {
+        return Ok(None);
+    }
+    let machine_type = match machine.r#type.as_str() {
+        "" => DEFAULT_MACHINE_TYPE,
+        t => t,
+    };
+    if !machine_type
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("Invalid machine type: {machine_type}");
+    }
+    let version = match machine.version.as_str() {
+        "" => None,
+        v if parse_version(v).is_some() => Some(v.to_string()),
+        v => bail!("Invalid machine version: {v}, expected e.g. 8.2"),
+    };
+    Ok(Some(MachineConfig {
+        machine_type: machine_type.to_string(),
+        version,
+    }))
+}
+
+/// Machines listed by `qemu -machine help`.
+#[derive(Debug, Clone, Default)]
+pub struct QemuMachines {
+    pub names: Vec<String>,
+    /// Unversioned names and the versioned machine each is an alias of
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl QemuMachines {
+    /// Parse entries like `q35   Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)`.
+    pub fn parse(output: &str) -> Self {
+        let mut machines = Self::default();
+        let entries = output
+            .lines()
+            .skip_while(|line| !line.starts_with("Supported machines"))
+            .skip(1);
+        for line in entries {
+            let Some(name) = line.split_whitespace().next() else {
+                continue;
+            };
+            if let Some((_, target)) = line.split_once("(alias of ") {
+                let target = target.trim_end().trim_end_matches(')');
+                machines
+                    .aliases
+                    .insert(name.to_string(), target.to_string());
+            }
+            machines.names.push(name.to_string());
+        }
+        machines
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+}
+
+impl App {
+    /// Refuse to launch VM `id` with a machine the host QEMU lacks, and warn if its pinned
+    /// machine is older than the one the host would pick.
+    pub(crate) async fn check_machine(&self, id: &str) -> Result<()> {
+        let Some(machine) = self
+            .lock()
+            .get(id)
+            .and_then(|vm| vm.config.manifest.machine.clone())
+        else {
+            return Ok(());
+        };
+        let capabilities = self.capabilities.get().await;
+        let Some(machines) = &capabilities.machines else {
+            return Ok(());
+        };
+        if let Some(outdated) = machine.check(machines)? {
+            warn!("VM {id}: {outdated}, QEMU fixes to the machine since then do not apply");
+            self.emit_event(
+                "vm.machine_outdated",
+                Some(id),
+                json!({
+                    "machine": machine.qemu_name(),
+                    "host_default": machine.outdated(machines),
+                }),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machines() -> QemuMachines {
+        QemuMachines::parse(include_str!("../../tests/fixtures/qemu-machine-help.txt"))
+    }
+
+    fn machine(machine_type: &str, version: Option<&str>) -> MachineConfig {
+        MachineConfig {
+            machine_type: machine_type.into(),
+            version: version.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn parses_machine_help() {
+        let machines = machines();
+        assert_eq!(machines.names.len(), 13);
+        assert_eq!(machines.names[0], "microvm");
+        assert!(machines.contains("q35"));
+        assert!(machines.contains("pc-q35-6.2"));
+        assert!(!machines.contains("Supported"));
+        assert_eq!(
+            machines.aliases,
+            BTreeMap::from([
+                ("pc".to_string(), "pc-i440fx-8.2".to_string()),
+                ("q35".to_string(), "pc-q35-8.2".to_string()),
+            ])
+        );
+        assert!(QemuMachines::parse("").names.is_empty());
+    }
+
+    #[test]
+    fn flags_outdated_machines() {
+        let machines = machines();
+        let old = machine("q35", Some("7.2"));
+        assert_eq!(old.qemu_name(), "pc-q35-7.2");
+        assert_eq!(old.outdated(&machines), Some("pc-q35-8.2"));
+        let warning = old.check(&machines).unwrap().unwrap();
+        assert!(warning.contains("pc-q35-7.2"), "{warning}");
+        assert!(warning.contains("pc-q35-8.2"), "{warning}");
+
+        let old = machine("pc", Some("8.1"));
+        assert!(old.check(&machines).unwrap().is_some());
+    }
+
+    #[test]
+    fn current_and_unpinned_machines_are_not_outdated() {
+        let machines = machines();
+        for machine in [machine("q35", Some("8.2")), machine("q35", None)] {
+            assert_eq!(machine.check(&machines).unwrap(), None);
+        }
+        // No alias to compare with
+        assert_eq!(machine("microvm", None).check(&machines).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_machines_the_host_lacks() {
+        let machines = machines();
+        assert!(machine("q35", Some("9.0")).check(&machines).is_err());
+        assert!(machine("virt", None).check(&machines).is_err());
+    }
+}
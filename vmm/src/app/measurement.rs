@@ -0,0 +1,64 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Boot inputs that end up in the measurements of a CVM.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use sha2::{Digest, Sha384};
+
+use super::{App, VmConfig};
+use crate::config::CvmConfig;
+
+/// Fail with the differing words if the effective cmdline is not exactly `expected`.
+pub fn check_cmdline(expected: &str, effective: &str) -> Result<()> {
+    if expected == effective {
+        return Ok(());
+    }
+    let expected_words = expected.split_whitespace().collect::<Vec<_>>();
+    let effective_words = effective.split_whitespace().collect::<Vec<_>>();
+    let missing = expected_words
+        .iter()
+        .filter(|w| !effective_words.contains(w))
+        .copied()
+        .collect::<Vec<_>>();
+    let unexpected = effective_words
+        .iter()
+        .filter(|w| !expected_words.contains(w))
+        .copied()
+        .collect::<Vec<_>>();
+    let diff = if missing.is_empty() && unexpected.is_empty() {
+        "same words in a different order or spacing".to_string()
+    } else {
+        format!(
+            "missing [{}], unexpected [{}]",
+            missing.join(" "),
+            unexpected.join(" ")
+        )
+    };
+    bail!("Kernel cmdline {effective:?} does not match the expected {expected:?}: {diff}");
+}
+
+impl VmConfig {
+    /// The measured view of the VM boot inputs.
+    pub fn measurements(&self, cfg: &CvmConfig) -> Result<pb::VmMeasurements> {
+        let cmdline = self.boot_spec(cfg)?.cmdline.unwrap_or_default();
+        Ok(pb::VmMeasurements {
+            cmdline_sha384: hex::encode(Sha384::digest(cmdline.as_bytes())),
+            matches_expected: self
+                .manifest
+                .expected_cmdline
+                .as_ref()
+                .map(|expected| *expected == cmdline),
+            expected_cmdline: self.manifest.expected_cmdline.clone(),
+            cmdline,
+        })
+    }
+}
+
+impl App {
+    pub fn vm_measurements(&self, id: &str) -> Result<pb::VmMeasurements> {
+        let config = self.lock().get(id).context("VM not found")?.config.clone();
+        config.measurements(&self.config.cvm)
+    }
+}
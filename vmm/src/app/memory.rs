@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Preallocation and locking of guest memory.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+/// Memory QEMU locks on top of guest RAM (firmware, device buffers, its own heap).
+const LOCK_OVERHEAD_MB: u64 = 256;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct MemoryOptions {
+    /// Allocate all guest memory at launch instead of on first touch
+    #[serde(default)]
+    pub prealloc: bool,
+    /// Lock QEMU memory in RAM (`mlock`), so it is never swapped out
+    #[serde(default)]
+    pub lock: bool,
+}
+
+impl MemoryOptions {
+    pub fn to_pb(&self) -> pb::MemoryOptions {
+        pb::MemoryOptions {
+            prealloc: self.prealloc,
+            lock: self.lock,
+        }
+    }
+
+    pub fn from_pb(options: &pb::MemoryOptions) -> Option<Self> {
+        let options = Self {
+            prealloc: options.prealloc,
+            lock: options.lock,
+        };
+        (options != Self::default()).then_some(options)
+    }
+
+    /// QEMU arguments of the options. Hugepages are preallocated by their backend already.
+    pub fn qemu_args(&self, hugepages: bool) -> Vec<&'static str> {
+        let mut args = vec![];
+        if self.prealloc && !hugepages {
+            args.push("-mem-prealloc");
+        }
+        if self.lock {
+            args.extend(["-overcommit", "mem-lock=on"]);
+        }
+        args
+    }
+}
+
+/// Soft `RLIMIT_MEMLOCK` of the VMM in bytes, inherited by the QEMU it launches. `None` if
+/// unlimited.
+fn memlock_limit() -> Result<Option<u64>> {
+    let limits = fs::read_to_string("/proc/self/limits")?;
+    let soft = limits
+        .lines()
+        .find_map(|line| line.strip_prefix("Max locked memory"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .context("No locked memory limit in /proc/self/limits")?;
+    if soft == "unlimited" {
+        return Ok(None);
+    }
+    Ok(Some(soft.parse().context("Invalid locked memory limit")?))
+}
+
+/// Whether QEMU may lock `memory_mb` of guest RAM. Processes with `CAP_IPC_LOCK`, such as
+/// root, are not bound by the limit.
+pub fn check_memlock(memory_mb: u32) -> Result<()> {
+    let Some(limit) = memlock_limit()? else {
+        return Ok(());
+    };
+    if is_root() {
+        return Ok(());
+    }
+    let needed = (memory_mb as u64 + LOCK_OVERHEAD_MB) * 1024 * 1024;
+    if limit < needed {
+        bail!(
+            "RLIMIT_MEMLOCK is {} MB, locking the memory of the VM needs about {} MB",
+            limit / 1024 / 1024,
+            needed / 1024 / 1024
+        );
+    }
+    Ok(())
+}
+
+fn is_root() -> bool {
+    fs::read_to_string("/proc/self/status").is_ok_and(|status| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|uids| uids.split_whitespace().nth(1))
+            == Some("0")
+    })
+}
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receiving side of live migration.
+//!
+//! The target VM is launched with `-incoming defer` and listens for the migration stream once
+//! accepted. A VM whose migration fails or does not complete in time is removed.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use super::ports::PortClaimant;
+use super::App;
+use crate::config::Protocol;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+impl App {
+    fn migration_uri(&self, port: u16) -> String {
+        format!("tcp:{}:{port}", self.config.cvm.migration.listen_address)
+    }
+
+    fn allocate_migration_port(&self, id: &str) -> Result<u16> {
+        let cfg = &self.config.cvm.migration;
+        let mut state = self.lock();
+        state.get(id).context("VM not found")?;
+        let port = state.ports.allocate(
+            Protocol::Tcp,
+            cfg.listen_address,
+            cfg.port_start..=cfg.port_end,
+            None,
+            PortClaimant::vm(id, "migration"),
+        )?;
+        state
+            .get_mut(id)
+            .context("VM not found")?
+            .state
+            .incoming_migration = Some(port);
+        Ok(port)
+    }
+
+    fn incoming_migration_port(&self, id: &str) -> Option<u16> {
+        self.lock()
+            .get(id)
+            .and_then(|vm| vm.state.incoming_migration)
+    }
+
+    fn clear_incoming_migration(&self, id: &str) {
+        let mut state = self.lock();
+        state.ports.release(id, Some("migration"));
+        if let Some(vm) = state.get_mut(id) {
+            vm.state.incoming_migration = None;
+        }
+    }
+
+    /// Launch a created VM paused in the incoming state. Returns the URI the migration
+    /// stream is accepted on after [`Self::accept_migration`].
+    pub async fn prepare_incoming_migration(&self, id: &str) -> Result<String> {
+        if !self.config.cvm.qmp_socket {
+            bail!("Incoming migration requires cvm.qmp_socket");
+        }
+        let port = self.allocate_migration_port(id)?;
+        if let Err(err) = self.launch_vm(id, Some("defer")).await {
+            self.clear_incoming_migration(id);
+            return Err(err);
+        }
+        let uri = self.migration_uri(port);
+        info!("VM {id} is waiting for an incoming migration on {uri}");
+        Ok(uri)
+    }
+
+    /// Start listening for the migration stream of a VM prepared for an incoming migration.
+    pub async fn accept_migration(&self, id: &str) -> Result<()> {
+        let port = self
+            .incoming_migration_port(id)
+            .context("VM is not waiting for an incoming migration")?;
+        let uri = self.migration_uri(port);
+        let result = async {
+            let mut qmp = self.qmp(id).await?;
+            qmp.execute("migrate-incoming", Some(json!({ "uri": uri })))
+                .await
+        }
+        .await;
+        if let Err(err) = result {
+            self.abort_incoming_migration(id).await;
+            return Err(err.context("Failed to accept migration"));
+        }
+        info!("VM {id} accepts the incoming migration on {uri}");
+        tokio::spawn(self.clone().watch_incoming_migration(id.to_string()));
+        Ok(())
+    }
+
+    async fn migration_status(&self, id: &str) -> Result<String> {
+        let mut qmp = self.qmp(id).await?;
+        let status = qmp.execute("query-migrate", None).await?;
+        Ok(status
+            .get("status")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    async fn watch_incoming_migration(self, id: String) {
+        let deadline = Instant::now() + Duration::from_secs(self.config.cvm.migration.timeout);
+        let reason = loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if self.incoming_migration_port(&id).is_none() {
+                // Removed by the operator meanwhile
+                return;
+            }
+            match self.migration_status(&id).await {
+                Ok(status) if status == "completed" => {
+                    self.clear_incoming_migration(&id);
+                    info!("Incoming migration of VM {id} completed");
+                    self.sync_guest_time_after(&id, "migration");
+                    return;
+                }
+                Ok(status) if status == "failed" || status == "cancelled" => {
+                    break format!("migration {status}");
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    if !self.is_running(&id).await.unwrap_or(false) {
+                        break format!("QEMU exited: {err:#}");
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                break "timed out".to_string();
+            }
+        };
+        error!("Incoming migration of VM {id} failed: {reason}");
+        self.abort_incoming_migration(&id).await;
+    }
+
+    /// Stop and remove a VM whose incoming migration failed.
+    pub(crate) async fn abort_incoming_migration(&self, id: &str) {
+        self.clear_incoming_migration(id);
+        let result = async {
+            self.set_started(id, false)?;
+            if self.is_running(id).await? {
+                self.supervisor.stop(id).await?;
+            }
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while self.is_running(id).await? {
+                if Instant::now() >= deadline {
+                    bail!("QEMU did not stop");
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            self.remove_vm(id).await
+        }
+        .await;
+        if let Err(err) = result {
+            warn!("Failed to remove VM {id} after a failed migration: {err:?}");
+        }
+    }
+}
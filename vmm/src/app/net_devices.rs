@@ -0,0 +1,197 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host bridges and taps set up for VMs, and removing the ones no running VM uses.
+//!
+//! The bridges of network groups are created by the VMM and carry a `dstack:<group>` alias,
+//! the taps on them and on the default bridge are created by `qemu-bridge-helper` for the QEMU
+//! of a VM. A bridge stays when the last VM of its group is removed, and a persistent tap stays
+//! when its QEMU is gone. Taps of `tap` NICs are set up by the operator and never listed.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use tracing::{info, warn};
+
+use super::net_stats::tap_interfaces;
+use super::network_group::{group_bridge, run_ip, MANAGED_ALIAS_PREFIX};
+use super::App;
+use crate::config::Networking;
+
+fn sys_net(name: &str) -> PathBuf {
+    PathBuf::from("/sys/class/net").join(name)
+}
+
+fn host_interfaces() -> Vec<String> {
+    let Ok(entries) = fs::read_dir("/sys/class/net") else {
+        return vec![];
+    };
+    let mut names = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn is_bridge(name: &str) -> bool {
+    sys_net(name).join("bridge").exists()
+}
+
+fn is_tap(name: &str) -> bool {
+    sys_net(name).join("tun_flags").exists()
+}
+
+/// Interfaces attached to `bridge`.
+fn bridge_ports(bridge: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(sys_net(bridge).join("brif")) else {
+        return vec![];
+    };
+    let mut ports = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    ports.sort();
+    ports
+}
+
+/// The network group of a bridge created by the VMM, `Some("")` for a generated name of a
+/// group created before bridges got an alias.
+fn managed_bridge_group(name: &str) -> Option<String> {
+    let alias = fs::read_to_string(sys_net(name).join("ifalias")).unwrap_or_default();
+    if let Some(group) = alias.trim().strip_prefix(MANAGED_ALIAS_PREFIX) {
+        return Some(group.to_string());
+    }
+    let generated = name
+        .strip_prefix("dstack-")
+        .is_some_and(|hash| hash.len() == 8 && hash.chars().all(|c| c.is_ascii_hexdigit()));
+    generated.then(String::new)
+}
+
+impl App {
+    /// The bridges and taps set up for VMs and the VMs using them.
+    pub async fn managed_network_devices(&self) -> Result<Vec<pb::ManagedNetworkDevice>> {
+        let Networking::Bridge(cfg) = &self.config.cvm.networking else {
+            return Ok(vec![]);
+        };
+        let running = self
+            .list_processes()
+            .await?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .filter_map(|p| Some((p.config.id, p.state.pid?)))
+            .collect::<Vec<_>>();
+        let tap_holders = running
+            .iter()
+            .flat_map(|(id, pid)| {
+                tap_interfaces(*pid)
+                    .into_iter()
+                    .map(|tap| (tap, id.clone()))
+            })
+            .collect::<HashMap<_, _>>();
+        let running = running
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<BTreeSet<_>>();
+
+        // Loaded VMs by the bridges of their network groups, and the taps of their tap NICs
+        let mut group_users = BTreeMap::<String, (String, Vec<String>)>::new();
+        let mut operator_taps = BTreeSet::new();
+        for vm in self.lock().iter_vms() {
+            let manifest = &vm.config.manifest;
+            for group in manifest.network_groups() {
+                let (_, users) = group_users
+                    .entry(group_bridge(cfg, Some(&group)))
+                    .or_insert_with(|| (group.clone(), vec![]));
+                users.push(manifest.id.clone());
+            }
+            operator_taps.extend(manifest.nics.iter().filter_map(|nic| nic.ifname.clone()));
+        }
+
+        let mut devices = vec![];
+        let add_taps = |bridge: &str, devices: &mut Vec<pb::ManagedNetworkDevice>| {
+            for port in bridge_ports(bridge) {
+                if !is_tap(&port) || operator_taps.contains(&port) {
+                    continue;
+                }
+                let holder = tap_holders.get(&port).cloned();
+                devices.push(pb::ManagedNetworkDevice {
+                    kind: "tap".into(),
+                    bridge: bridge.to_string(),
+                    network_group: String::new(),
+                    orphaned: holder.is_none(),
+                    vm_ids: holder.clone().into_iter().collect(),
+                    running_vm_ids: holder.into_iter().collect(),
+                    name: port,
+                });
+            }
+        };
+        add_taps(&cfg.bridge, &mut devices);
+        for name in host_interfaces() {
+            if name == cfg.bridge || !is_bridge(&name) {
+                continue;
+            }
+            let Some(alias_group) = managed_bridge_group(&name) else {
+                continue;
+            };
+            let (group, vm_ids) = group_users.remove(&name).unwrap_or_default();
+            let running_vm_ids = vm_ids
+                .iter()
+                .filter(|id| running.contains(*id))
+                .cloned()
+                .collect::<Vec<_>>();
+            let ports_held = bridge_ports(&name)
+                .iter()
+                .any(|port| tap_holders.contains_key(port));
+            devices.push(pb::ManagedNetworkDevice {
+                kind: "bridge".into(),
+                bridge: String::new(),
+                network_group: if alias_group.is_empty() {
+                    group
+                } else {
+                    alias_group
+                },
+                orphaned: running_vm_ids.is_empty() && !ports_held,
+                vm_ids,
+                running_vm_ids,
+                name: name.clone(),
+            });
+            add_taps(&name, &mut devices);
+        }
+        Ok(devices)
+    }
+
+    /// Remove the bridges and taps set up for VMs that no running VM uses, the taps first.
+    pub async fn cleanup_orphaned_network_devices(
+        &self,
+        dry_run: bool,
+    ) -> Result<pb::CleanupNetworkDevicesResponse> {
+        let mut orphans = self
+            .managed_network_devices()
+            .await
+            .context("Failed to list the network devices")?
+            .into_iter()
+            .filter(|device| device.orphaned)
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|device| device.kind == "bridge");
+        let mut response = pb::CleanupNetworkDevicesResponse {
+            dry_run,
+            ..Default::default()
+        };
+        for device in orphans {
+            if !dry_run {
+                if let Err(err) = run_ip(&["link", "del", "dev", &device.name]) {
+                    warn!("Failed to remove {} {}: {err:?}", device.kind, device.name);
+                    response.errors.push(format!("{}: {err:#}", device.name));
+                    continue;
+                }
+                info!("Removed orphaned {} {}", device.kind, device.name);
+            }
+            response.removed.push(device);
+        }
+        Ok(response)
+    }
+}
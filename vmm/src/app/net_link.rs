@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cutting the network of a running VM without stopping it.
+//!
+//! The link of the VM NIC is set down over QMP, the guest sees its cable unplugged and port
+//! forwards to it stop answering. A relaunched QEMU always starts with the link up.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+
+use super::App;
+
+/// Id of the netdev of the VM NIC on the QEMU command line, custom netdevs must use it too.
+pub(super) const NETDEV_ID: &str = "net0";
+
+impl App {
+    /// Bring the NIC link of a running VM up or down and return the new state.
+    pub async fn set_vm_network_enabled(
+        &self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<pb::VmNetworkState> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+        let mut qmp = self.qmp(id).await?;
+        qmp.execute(
+            "set_link",
+            Some(json!({ "name": NETDEV_ID, "up": enabled })),
+        )
+        .await
+        .context("Failed to set the link of the VM NIC")?;
+        if let Some(vm) = self.lock().get_mut(id) {
+            vm.state.network_disabled = !enabled;
+        }
+        self.emit_event("vm.network", Some(id), json!({ "enabled": enabled }));
+        Ok(pb::VmNetworkState {
+            id: id.to_string(),
+            enabled,
+        })
+    }
+}
@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network counters of VMs, read from the host tap devices their QEMU holds open.
+//!
+//! User-mode and passt networking do not go through a tap device, VMs using them have no
+//! interfaces here.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+
+use super::App;
+
+/// Names of the tap devices `pid` has open, from the `iff:` line of each `/dev/net/tun` fd.
+pub(super) fn tap_interfaces(pid: u32) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+        return vec![];
+    };
+    let mut interfaces = vec![];
+    for entry in entries.flatten() {
+        let is_tun =
+            fs::read_link(entry.path()).is_ok_and(|target| target.as_os_str() == "/dev/net/tun");
+        if !is_tun {
+            continue;
+        }
+        let fdinfo = format!("/proc/{pid}/fdinfo/{}", entry.file_name().to_string_lossy());
+        let Ok(fdinfo) = fs::read_to_string(fdinfo) else {
+            continue;
+        };
+        let ifname = fdinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("iff:"))
+            .map(|name| name.trim().to_string());
+        // Multiqueue taps hold one fd per queue
+        if let Some(ifname) = ifname.filter(|name| !interfaces.contains(name)) {
+            interfaces.push(ifname);
+        }
+    }
+    interfaces.sort();
+    interfaces
+}
+
+/// Counters of a tap device, turned around to the point of view of the guest: what the host
+/// transmits on the tap, the guest receives.
+fn nic_stats(ifname: &str) -> Option<pb::VmNicStats> {
+    let stat = |name: &str| -> Option<u64> {
+        fs::read_to_string(format!("/sys/class/net/{ifname}/statistics/{name}"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some(pb::VmNicStats {
+        ifname: ifname.to_string(),
+        rx_bytes: stat("tx_bytes")?,
+        tx_bytes: stat("rx_bytes")?,
+        rx_packets: stat("tx_packets")?,
+        tx_packets: stat("rx_packets")?,
+        rx_dropped: stat("tx_dropped")?,
+        tx_dropped: stat("rx_dropped")?,
+    })
+}
+
+fn pid_net_stats(pid: u32) -> Vec<pb::VmNicStats> {
+    tap_interfaces(pid)
+        .iter()
+        .filter_map(|ifname| nic_stats(ifname))
+        .collect()
+}
+
+impl App {
+    /// Per-interface network counters of a running VM.
+    pub async fn vm_net_stats(&self, id: &str) -> Result<Vec<pb::VmNicStats>> {
+        let info = self.supervisor.info(id).await?.context("VM not found")?;
+        if !info.state.status.is_running() {
+            bail!("VM {id} is not running");
+        }
+        let pid = info.state.pid.context("VM has no pid")?;
+        Ok(pid_net_stats(pid))
+    }
+
+    /// Network counters of all running VMs, by VM id.
+    pub async fn all_vm_net_stats(&self) -> Result<Vec<(String, Vec<pb::VmNicStats>)>> {
+        let stats = self
+            .list_processes()
+            .await?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .filter(|p| self.lock().get(&p.config.id).is_some())
+            .filter_map(|p| Some((p.config.id, pid_net_stats(p.state.pid?))))
+            .collect();
+        Ok(stats)
+    }
+}
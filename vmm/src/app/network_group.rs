@@ -0,0 +1,149 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network isolation groups: with bridge networking each group gets a bridge of its own, so
+//! VMs of different groups never share an L2 domain.
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::{App, Manifest};
+use crate::config::{BridgeNetworking, Networking};
+
+/// Longest interface name Linux accepts.
+const MAX_IFNAME_LEN: usize = 15;
+
+pub fn validate_network_group(group: &str) -> Result<()> {
+    let valid = !group.is_empty()
+        && group.len() <= 32
+        && group
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        bail!("Invalid network group: {group}");
+    }
+    Ok(())
+}
+
+/// The bridge VMs of `group` attach to, the default bridge for VMs without a group.
+pub fn group_bridge(cfg: &BridgeNetworking, group: Option<&str>) -> String {
+    let Some(group) = group else {
+        return cfg.bridge.clone();
+    };
+    match cfg.group_bridges.get(group) {
+        Some(bridge) => bridge.clone(),
+        None => {
+            let hash = hex::encode(Sha256::digest(group.as_bytes()));
+            format!("dstack-{}", &hash[..8])
+        }
+    }
+}
+
+fn bridge_exists(bridge: &str) -> bool {
+    Path::new("/sys/class/net").join(bridge).exists()
+}
+
+/// Check the host can keep `groups` apart with the configured networking.
+pub fn check_network_isolation(networking: &Networking, groups: &BTreeSet<String>) -> Result<()> {
+    let cfg = match networking {
+        // Every VM has a user-mode network stack of its own
+        Networking::User(_) | Networking::Passt(_) => return Ok(()),
+        Networking::Custom(_) => {
+            if let Some(group) = groups.first() {
+                bail!(
+                    "Network group {group} requested, but custom networking can not isolate groups"
+                );
+            }
+            return Ok(());
+        }
+        Networking::Bridge(cfg) => cfg,
+    };
+    if !Path::new(&cfg.bridge_helper).exists() {
+        bail!("Bridge helper not found: {}", cfg.bridge_helper);
+    }
+    let mut owners = HashMap::new();
+    owners.insert(cfg.bridge.clone(), "the default network".to_string());
+    let mut missing = false;
+    for group in groups {
+        let bridge = group_bridge(cfg, Some(group));
+        if bridge.is_empty() || bridge.len() > MAX_IFNAME_LEN {
+            bail!("Invalid bridge name {bridge:?} for network group {group}");
+        }
+        if let Some(other) = owners.insert(bridge.clone(), format!("network group {group}")) {
+            bail!(
+                "Refusing to place {other} and network group {group} on the same bridge {bridge}"
+            );
+        }
+        missing |= !bridge_exists(&bridge);
+    }
+    if missing && which::which("ip").is_err() {
+        bail!("`ip` is required to create the bridges of network groups");
+    }
+    Ok(())
+}
+
+impl App {
+    /// Validate the network groups of a VM about to start against all loaded VMs and create
+    /// the bridges of the groups if needed.
+    pub(crate) fn prepare_network_group(&self, manifest: &Manifest) -> Result<()> {
+        let own_groups = manifest.network_groups();
+        if own_groups.is_empty() {
+            return Ok(());
+        }
+        let groups = self
+            .lock()
+            .iter_vms()
+            .flat_map(|vm| vm.config.manifest.network_groups())
+            .chain(own_groups.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        check_network_isolation(&self.config.cvm.networking, &groups)?;
+        let Networking::Bridge(cfg) = &self.config.cvm.networking else {
+            return Ok(());
+        };
+        for group in &own_groups {
+            create_group_bridge(cfg, group)?;
+        }
+        Ok(())
+    }
+}
+
+/// Alias of the bridges created for network groups, followed by the group.
+pub(super) const MANAGED_ALIAS_PREFIX: &str = "dstack:";
+
+/// Run `ip` with `args`.
+pub(super) fn run_ip(args: &[&str]) -> Result<()> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .context("Failed to run ip")?;
+    if !output.status.success() {
+        bail!(
+            "`ip {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn create_group_bridge(cfg: &BridgeNetworking, group: &str) -> Result<()> {
+    let bridge = group_bridge(cfg, Some(group));
+    if bridge_exists(&bridge) {
+        return Ok(());
+    }
+    info!("Creating bridge {bridge} for network group {group}");
+    let alias = format!("{MANAGED_ALIAS_PREFIX}{group}");
+    for args in [
+        &["link", "add", "name", &bridge, "type", "bridge"][..],
+        &["link", "set", "dev", &bridge, "alias", &alias][..],
+        &["link", "set", &bridge, "up"][..],
+    ] {
+        run_ip(args).with_context(|| format!("Failed to create bridge {bridge}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,277 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Additional NICs of a VM, e.g. a data plane next to the management network.
+//!
+//! The first NIC (`net0`) follows `cvm.networking`. The ones a VM lists are attached after it
+//! as `net1`, `net2`, ... in order, each with its own backend, MAC, model and network group.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::mac::derived_mac;
+use super::network_group::group_bridge;
+use super::{parse_mac, validate_network_group, AppState, Manifest};
+use crate::config::{CvmConfig, Networking};
+
+/// Additional NICs a VM may have.
+const MAX_NICS: usize = 7;
+
+/// Longest interface name Linux accepts.
+const MAX_IFNAME_LEN: usize = 15;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NicBackend {
+    /// A user-mode network stack of its own, no host setup needed
+    User,
+    /// A host tap interface, set up by the operator
+    Tap,
+    /// The bridge of the network group, needs bridge networking
+    Bridge,
+}
+
+impl NicBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NicBackend::User => "user",
+            NicBackend::Tap => "tap",
+            NicBackend::Bridge => "bridge",
+        }
+    }
+}
+
+impl FromStr for NicBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "user" => NicBackend::User,
+            "tap" => NicBackend::Tap,
+            "bridge" => NicBackend::Bridge,
+            _ => bail!("Invalid NIC backend: {s}, expected user, tap or bridge"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NicModel {
+    #[default]
+    VirtioNet,
+    E1000,
+}
+
+impl NicModel {
+    fn qemu_device(&self) -> &'static str {
+        match self {
+            NicModel::VirtioNet => "virtio-net-pci",
+            NicModel::E1000 => "e1000",
+        }
+    }
+}
+
+impl fmt::Display for NicModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NicModel::VirtioNet => "virtio-net",
+            NicModel::E1000 => "e1000",
+        })
+    }
+}
+
+impl FromStr for NicModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "virtio-net" => NicModel::VirtioNet,
+            "e1000" => NicModel::E1000,
+            _ => bail!("Invalid NIC model: {s}, expected virtio-net or e1000"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NicConfig {
+    pub backend: NicBackend,
+    /// Host interface of the `tap` backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ifname: Option<String>,
+    /// Derived from the VM id and the NIC index if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub model: NicModel,
+    /// Network group of the `bridge` backend, the one of the VM if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_group: Option<String>,
+}
+
+impl NicConfig {
+    pub fn to_pb(&self) -> pb::NicConfig {
+        pb::NicConfig {
+            backend: self.backend.as_str().into(),
+            ifname: self.ifname.clone().unwrap_or_default(),
+            mac: self.mac.clone().unwrap_or_default(),
+            model: self.model.to_string(),
+            network_group: self.network_group.clone().unwrap_or_default(),
+        }
+    }
+}
+
+fn validate_ifname(ifname: &str) -> Result<()> {
+    let valid = !ifname.is_empty()
+        && ifname.len() <= MAX_IFNAME_LEN
+        && ifname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!("Invalid interface name: {ifname}");
+    }
+    Ok(())
+}
+
+fn resolve_nic(nic: &pb::NicConfig, cvm_config: &CvmConfig) -> Result<NicConfig> {
+    let backend: NicBackend = nic.backend.parse()?;
+    let ifname = (!nic.ifname.is_empty()).then(|| nic.ifname.clone());
+    match (backend, &ifname) {
+        (NicBackend::Tap, Some(ifname)) => validate_ifname(ifname)?,
+        (NicBackend::Tap, None) => bail!("A tap NIC requires an interface name"),
+        (_, Some(_)) => bail!("An interface name is only supported with the tap backend"),
+        (_, None) => {}
+    }
+    if backend == NicBackend::Bridge && !matches!(cvm_config.networking, Networking::Bridge(_)) {
+        bail!("A bridge NIC requires bridge networking");
+    }
+    let network_group = match nic.network_group.as_str() {
+        "" => None,
+        _ if backend != NicBackend::Bridge => {
+            bail!("A network group is only supported with the bridge backend")
+        }
+        group => {
+            validate_network_group(group)?;
+            Some(group.to_string())
+        }
+    };
+    Ok(NicConfig {
+        backend,
+        ifname,
+        mac: match nic.mac.as_str() {
+            "" => None,
+            mac => Some(parse_mac(mac)?),
+        },
+        model: match nic.model.as_str() {
+            "" => NicModel::default(),
+            model => model.parse()?,
+        },
+        network_group,
+    })
+}
+
+/// The additional NICs of a VM config.
+pub fn resolve_nics(nics: &[pb::NicConfig], cvm_config: &CvmConfig) -> Result<Vec<NicConfig>> {
+    if nics.len() > MAX_NICS {
+        bail!("At most {MAX_NICS} additional NICs are supported");
+    }
+    let mut resolved: Vec<NicConfig> = Vec::new();
+    for (i, nic) in nics.iter().enumerate() {
+        let nic = resolve_nic(nic, cvm_config).with_context(|| format!("NIC {}", i + 1))?;
+        if let Some(mac) = &nic.mac {
+            if resolved.iter().any(|n| n.mac.as_ref() == Some(mac)) {
+                bail!("NIC {}: duplicate MAC address {mac}", i + 1);
+            }
+        }
+        if let Some(ifname) = &nic.ifname {
+            if resolved.iter().any(|n| n.ifname.as_ref() == Some(ifname)) {
+                bail!("NIC {}: duplicate interface {ifname}", i + 1);
+            }
+        }
+        resolved.push(nic);
+    }
+    Ok(resolved)
+}
+
+impl Manifest {
+    /// MAC addresses of all NICs of the VM, the first NIC first.
+    pub fn macs(&self) -> Vec<String> {
+        let nic_macs = self.nics.iter().enumerate().map(|(i, nic)| {
+            nic.mac
+                .clone()
+                .unwrap_or_else(|| derived_mac(&format!("{}/net{}", self.id, i + 1)))
+        });
+        std::iter::once(self.mac()).chain(nic_macs).collect()
+    }
+
+    /// Network groups of the bridges the VM attaches to.
+    pub fn network_groups(&self) -> BTreeSet<String> {
+        let nic_groups = self
+            .nics
+            .iter()
+            .filter(|nic| nic.backend == NicBackend::Bridge)
+            .filter_map(|nic| nic.network_group.as_ref().or(self.network_group.as_ref()));
+        self.network_group
+            .iter()
+            .chain(nic_groups)
+            .cloned()
+            .collect()
+    }
+
+    /// `-netdev` and `-device` arguments of the additional NICs.
+    pub fn nic_args(&self, networking: &Networking) -> Result<Vec<String>> {
+        let macs = self.macs();
+        let mut args = vec![];
+        for (i, nic) in self.nics.iter().enumerate() {
+            let index = i + 1;
+            let netdev = match nic.backend {
+                NicBackend::User => format!("user,id=net{index}"),
+                NicBackend::Tap => format!(
+                    "tap,id=net{index},ifname={},script=no,downscript=no",
+                    nic.ifname.as_deref().unwrap_or_default()
+                ),
+                NicBackend::Bridge => {
+                    let Networking::Bridge(cfg) = networking else {
+                        bail!("NIC {index}: a bridge NIC requires bridge networking");
+                    };
+                    let group = nic.network_group.as_ref().or(self.network_group.as_ref());
+                    format!(
+                        "bridge,id=net{index},br={},helper={}",
+                        group_bridge(cfg, group.map(String::as_str)),
+                        cfg.bridge_helper
+                    )
+                }
+            };
+            args.extend([
+                "-netdev".into(),
+                netdev,
+                "-device".into(),
+                format!(
+                    "{},netdev=net{index},mac={}",
+                    nic.model.qemu_device(),
+                    macs[index]
+                ),
+            ]);
+        }
+        Ok(args)
+    }
+}
+
+impl AppState {
+    /// The VM other than `except` with a NIC on the host tap interface `ifname`.
+    pub(crate) fn tap_owner(&self, ifname: &str, except: &str) -> Option<&str> {
+        self.iter_vms()
+            .map(|vm| &vm.config.manifest)
+            .find(|m| {
+                m.id != except
+                    && m.nics
+                        .iter()
+                        .any(|nic| nic.ifname.as_deref() == Some(ifname))
+            })
+            .map(|m| m.id.as_str())
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host PCI devices passed through to CVMs with VFIO.
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use super::AppState;
+
+/// Normalize a PCI address to the full `DDDD:BB:DD.F` form, adding the default domain if missing.
+fn normalize_pci_address(addr: &str) -> Result<String> {
+    let addr = addr.trim().to_lowercase();
+    let full = if addr.matches(':').count() == 1 {
+        format!("0000:{addr}")
+    } else {
+        addr
+    };
+    let valid = match full.split_once('.') {
+        Some((prefix, function)) => {
+            let parts: Vec<&str> = prefix.split(':').collect();
+            parts.len() == 3
+                && [4, 2, 2]
+                    .iter()
+                    .zip(&parts)
+                    .all(|(len, part)| part.len() == *len && is_hex(part))
+                && function.len() == 1
+                && matches!(function.as_bytes()[0], b'0'..=b'7')
+        }
+        None => false,
+    };
+    if !valid {
+        bail!("Invalid PCI address: {full}");
+    }
+    Ok(full)
+}
+
+fn is_hex(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn resolve_pci_devices(addrs: &[String]) -> Result<Vec<String>> {
+    let mut resolved: Vec<String> = Vec::new();
+    for addr in addrs {
+        let addr = normalize_pci_address(addr)?;
+        if resolved.contains(&addr) {
+            bail!("Duplicate PCI device: {addr}");
+        }
+        resolved.push(addr);
+    }
+    Ok(resolved)
+}
+
+/// The kernel driver the host PCI device is bound to, if any.
+pub fn pci_driver(addr: &str) -> Option<String> {
+    let link = Path::new("/sys/bus/pci/devices")
+        .join(addr)
+        .join("driver")
+        .read_link()
+        .ok()?;
+    Some(link.file_name()?.to_string_lossy().to_string())
+}
+
+/// Devices in `addrs` that are not bound to `vfio-pci`, with the driver currently in use.
+pub fn devices_not_bound_to_vfio(addrs: &[String]) -> Vec<(String, Option<String>)> {
+    addrs
+        .iter()
+        .filter_map(|addr| {
+            let driver = pci_driver(addr);
+            (driver.as_deref() != Some("vfio-pci")).then(|| (addr.clone(), driver))
+        })
+        .collect()
+}
+
+impl AppState {
+    /// The VM other than `except` that claims the PCI device.
+    pub(crate) fn pci_device_owner(&self, addr: &str, except: &str) -> Option<&str> {
+        self.iter_vms()
+            .map(|vm| &vm.config.manifest)
+            .find(|m| m.id != except && m.pci_devices.iter().any(|d| d == addr))
+            .map(|m| m.id.as_str())
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attestation certificate chain and firmware versions of the TEE platform of the host.
+//!
+//! The kernel does not expose the platform certificates (TDX PCK, SEV-SNP VCEK/ASK/ARK), so
+//! the chain comes from a file or command of `platform_certs`, typically filled from the PCCS
+//! or the AMD KDS by the host provisioning. Both change rarely and are cached until SIGHUP.
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use tracing::{info, warn};
+
+use crate::config::PlatformCertsConfig;
+
+const TDX_MODULE_DIR: &str = "/sys/firmware/tdx/tdx_module";
+const SEV_SNP_PARAM: &str = "/sys/module/kvm_amd/parameters/sev_snp";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// Split a PEM bundle into its certificates, in file order.
+fn split_pem(bundle: &str) -> Vec<String> {
+    bundle
+        .split_inclusive(PEM_END)
+        .filter_map(|chunk| {
+            let start = chunk.find("-----BEGIN CERTIFICATE-----")?;
+            chunk
+                .ends_with(PEM_END)
+                .then(|| format!("{}\n", &chunk[start..]))
+        })
+        .collect()
+}
+
+fn read_cert_chain(config: &PlatformCertsConfig) -> Result<Vec<String>> {
+    let bundle = match (config.file.is_empty(), config.command.is_empty()) {
+        (true, true) => return Ok(vec![]),
+        (false, true) => fs::read_to_string(&config.file)?,
+        (true, false) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&config.command)
+                .output()
+                .context("Failed to run the certificate command")?;
+            if !output.status.success() {
+                bail!(
+                    "Certificate command failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            String::from_utf8(output.stdout).context("Certificate command printed invalid PEM")?
+        }
+        _ => bail!("Only one of platform_certs.file and platform_certs.command may be set"),
+    };
+    let certs = split_pem(&bundle);
+    if certs.is_empty() {
+        bail!("No PEM certificate found");
+    }
+    Ok(certs)
+}
+
+fn is_enabled(param: &str) -> bool {
+    fs::read_to_string(param).is_ok_and(|v| matches!(v.trim(), "Y" | "1"))
+}
+
+/// Versions of the TEE firmware and CPU microcode, as far as the kernel exposes them.
+fn firmware_versions() -> BTreeMap<String, String> {
+    let mut versions = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir(TDX_MODULE_DIR) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Ok(value) = fs::read_to_string(entry.path()) {
+                versions.insert(format!("tdx_module.{name}"), value.trim().to_string());
+            }
+        }
+    }
+    let microcode = fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|cpuinfo| {
+            cpuinfo
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim() == "microcode")
+                .map(|(_, value)| value.trim().to_string())
+        });
+    if let Some(microcode) = microcode {
+        versions.insert("microcode".into(), microcode);
+    }
+    versions
+}
+
+fn probe(config: &PlatformCertsConfig, tdx: bool) -> pb::PlatformCertificates {
+    let platform = if tdx {
+        "tdx"
+    } else if is_enabled(SEV_SNP_PARAM) {
+        "sev-snp"
+    } else {
+        ""
+    };
+    let (certificates, error) = match read_cert_chain(config) {
+        Ok(certs) => (certs, String::new()),
+        Err(err) => {
+            warn!("Failed to read the platform certificates: {err:?}");
+            (vec![], format!("{err:#}"))
+        }
+    };
+    pb::PlatformCertificates {
+        platform: platform.into(),
+        certificates,
+        firmware_versions: firmware_versions().into_iter().collect(),
+        error,
+        fetched_at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+    }
+}
+
+/// Platform certificates read on first use and kept until [`Self::invalidate`].
+pub struct PlatformCertCache {
+    config: PlatformCertsConfig,
+    cached: Mutex<Option<Arc<pb::PlatformCertificates>>>,
+}
+
+impl PlatformCertCache {
+    pub fn new(config: PlatformCertsConfig) -> Self {
+        Self {
+            config,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The cached certificates, read again if they were invalidated. `tdx` comes from the host
+    /// capabilities.
+    pub async fn get(&self, tdx: bool) -> Arc<pb::PlatformCertificates> {
+        if let Some(certs) = &*self.cached.lock().unwrap() {
+            return certs.clone();
+        }
+        let config = self.config.clone();
+        let certs = match tokio::task::spawn_blocking(move || probe(&config, tdx)).await {
+            Ok(certs) => Arc::new(certs),
+            Err(err) => {
+                warn!("Platform certificate probe panicked: {err}");
+                return Arc::new(pb::PlatformCertificates {
+                    error: "Probe panicked".into(),
+                    ..Default::default()
+                });
+            }
+        };
+        // Failures are not cached, the source may be fixed without a SIGHUP
+        if certs.error.is_empty() {
+            *self.cached.lock().unwrap() = Some(certs.clone());
+        }
+        certs
+    }
+
+    /// Drop the cached certificates so the next [`Self::get`] reads them again.
+    pub fn invalidate(&self) {
+        info!("Platform certificates will be read again");
+        *self.cached.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Registry of the host ports claimed by VMs and by the VMM itself.
+//!
+//! Every feature binding a host port claims it here first, so overlaps are rejected up front
+//! with both claimants named instead of surfacing as a failed bind inside QEMU.
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use anyhow::{Context, Result};
+use rocket::figment::Figment;
+
+use super::display::port_available;
+use super::{App, AppState, VmmError};
+use crate::config::{Config, Protocol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HostPort {
+    pub protocol: Protocol,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl HostPort {
+    pub fn tcp(address: IpAddr, port: u16) -> Self {
+        Self {
+            protocol: Protocol::Tcp,
+            address,
+            port,
+        }
+    }
+
+    /// Whether binding both would conflict. The unspecified address overlaps every address.
+    fn overlaps(&self, other: &HostPort) -> bool {
+        self.protocol == other.protocol
+            && self.port == other.port
+            && (self.address == other.address
+                || self.address.is_unspecified()
+                || other.address.is_unspecified())
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} port {}:{}",
+            self.protocol.as_str(),
+            self.address,
+            self.port
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PortClaimant {
+    /// VM id, `None` for the VMM itself
+    owner: Option<String>,
+    purpose: &'static str,
+}
+
+impl PortClaimant {
+    pub fn vm(id: &str, purpose: &'static str) -> Self {
+        Self {
+            owner: Some(id.to_string()),
+            purpose,
+        }
+    }
+
+    pub fn vmm(purpose: &'static str) -> Self {
+        Self {
+            owner: None,
+            purpose,
+        }
+    }
+}
+
+impl fmt::Display for PortClaimant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.owner {
+            Some(id) => write!(f, "VM {id} ({})", self.purpose),
+            None => write!(f, "the VMM ({})", self.purpose),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PortRegistry {
+    claims: Vec<(HostPort, PortClaimant)>,
+}
+
+impl PortRegistry {
+    fn check(&self, port: &HostPort, claimant: &PortClaimant) -> Result<()> {
+        match self.claims.iter().find(|(p, _)| p.overlaps(port)) {
+            Some((_, owner)) => Err(VmmError::PortConflict {
+                port: port.to_string(),
+                claimant: claimant.to_string(),
+                owner: owner.to_string(),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Claim all ports or none of them.
+    pub fn claim_all(&mut self, ports: Vec<(HostPort, PortClaimant)>) -> Result<()> {
+        for (i, (port, claimant)) in ports.iter().enumerate() {
+            self.check(port, claimant)?;
+            if let Some((_, other)) = ports[..i].iter().find(|(p, _)| p.overlaps(port)) {
+                return Err(VmmError::PortConflict {
+                    port: port.to_string(),
+                    claimant: claimant.to_string(),
+                    owner: other.to_string(),
+                }
+                .into());
+            }
+        }
+        self.claims.extend(ports);
+        Ok(())
+    }
+
+    /// Claim the first port of `range` that is neither claimed nor bound on the host.
+    /// `preferred` is tried first if it lies in the range.
+    pub fn allocate(
+        &mut self,
+        protocol: Protocol,
+        address: IpAddr,
+        range: RangeInclusive<u16>,
+        preferred: Option<u16>,
+        claimant: PortClaimant,
+    ) -> Result<u16> {
+        let port = preferred
+            .into_iter()
+            .chain(range.clone())
+            .filter(|p| range.contains(p))
+            .map(|port| HostPort {
+                protocol,
+                address,
+                port,
+            })
+            .find(|p| self.check(p, &claimant).is_ok() && port_available(address, p.port))
+            .with_context(|| format!("No free port for {claimant}"))?;
+        self.claims.push((port, claimant));
+        Ok(port.port)
+    }
+
+    /// Ports overlapping `protocol` on `address` claimed by anyone but VM `except`.
+    pub fn taken(&self, protocol: Protocol, address: IpAddr, except: &str) -> Vec<u16> {
+        self.claims
+            .iter()
+            .filter(|(_, c)| c.owner.as_deref() != Some(except))
+            .filter(|(p, _)| {
+                p.overlaps(&HostPort {
+                    port: p.port,
+                    protocol,
+                    address,
+                })
+            })
+            .map(|(p, _)| p.port)
+            .collect()
+    }
+
+    /// Release the ports of VM `id`, only those claimed for `purpose` if given.
+    pub fn release(&mut self, id: &str, purpose: Option<&str>) {
+        self.claims.retain(|(_, c)| {
+            c.owner.as_deref() != Some(id) || purpose.is_some_and(|p| p != c.purpose)
+        });
+    }
+}
+
+/// Ports the VMM listens on itself, from the rocket config of its two servers.
+pub(crate) fn vmm_ports(config: &Config, figment: &Figment) -> Vec<(HostPort, PortClaimant)> {
+    let mut ports = vec![];
+    let default_port = figment.extract_inner::<u16>("port").unwrap_or(8000);
+    for listener in config.api_listeners(figment) {
+        if let Ok(address) = listener.address.parse::<IpAddr>() {
+            let port = listener.port.unwrap_or(default_port);
+            ports.push((HostPort::tcp(address, port), PortClaimant::vmm("API")));
+        }
+    }
+    if let (Ok(address), Ok(port)) = (
+        config.host_api.address.parse::<IpAddr>(),
+        u16::try_from(config.host_api.port),
+    ) {
+        ports.push((HostPort::tcp(address, port), PortClaimant::vmm("host API")));
+    }
+    ports
+}
+
+impl AppState {
+    /// Check VM `id` could claim `port` without claiming it.
+    pub(crate) fn check_vm_port(
+        &self,
+        id: &str,
+        port: &HostPort,
+        purpose: &'static str,
+    ) -> Result<()> {
+        self.ports.check(port, &PortClaimant::vm(id, purpose))
+    }
+
+    /// Replace the runtime port claims of a VM about to be launched.
+    pub(crate) fn claim_vm_ports(
+        &mut self,
+        id: &str,
+        ports: impl IntoIterator<Item = (HostPort, &'static str)>,
+    ) -> Result<()> {
+        self.ports.release(id, Some("display"));
+        self.ports.release(id, Some("port mapping"));
+        self.ports.claim_all(
+            ports
+                .into_iter()
+                .map(|(port, purpose)| (port, PortClaimant::vm(id, purpose)))
+                .collect(),
+        )
+    }
+}
+
+impl App {
+    /// Release the ports claimed by a VM that is no longer running.
+    pub(crate) fn release_vm_ports(&self, id: &str) {
+        self.lock().ports.release(id, None);
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end reachability check of a guest service over vsock.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use tokio_vsock::{VsockAddr, VsockStream};
+
+use super::App;
+
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl App {
+    /// Open a vsock connection to `port` of running VM `id`, reporting whether the guest
+    /// accepted it and how long it took.
+    pub async fn probe_guest(
+        &self,
+        id: &str,
+        port: u32,
+        timeout: Option<Duration>,
+    ) -> Result<pb::ProbeGuestResponse> {
+        let cid = self.lock().get(id).context("VM not found")?.config.cid;
+        if !self.is_running(id).await? {
+            bail!("VM {id} is not running");
+        }
+        let timeout = timeout.unwrap_or(DEFAULT_PROBE_TIMEOUT);
+        let started = Instant::now();
+        let result =
+            tokio::time::timeout(timeout, VsockStream::connect(VsockAddr::new(cid, port))).await;
+        let latency_us = started.elapsed().as_micros() as u64;
+        let error = match result {
+            Ok(Ok(_stream)) => String::new(),
+            Ok(Err(err)) => format!("Failed to connect to vsock port {port}: {err}"),
+            Err(_) => format!("No answer on vsock port {port} within {timeout:?}"),
+        };
+        Ok(pb::ProbeGuestResponse {
+            reachable: error.is_empty(),
+            latency_us,
+            error,
+        })
+    }
+}
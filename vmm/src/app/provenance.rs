@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the definition of a VM came from, to know where to change it.
+//!
+//! Every VM is loaded from the manifest in its work dir, which was written for an API call,
+//! from the inventory or for an adopted QEMU. The manifest records which, and the time it was
+//! loaded at tells whether it was edited on disk since.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::{App, VmWorkDir};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VmOrigin {
+    /// Created or replaced through the API
+    Api,
+    /// Declared by the inventory at `url`
+    Inventory { url: String },
+    /// A QEMU launched outside the VMM, adopted while running as `pid`
+    Adopted { pid: u32 },
+}
+
+impl VmOrigin {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VmOrigin::Api => "api",
+            VmOrigin::Inventory { .. } => "inventory",
+            VmOrigin::Adopted { .. } => "adopted",
+        }
+    }
+}
+
+/// Modification time of the manifest in `workdir`, `None` if it can not be read.
+pub fn manifest_mtime(workdir: &VmWorkDir) -> Option<SystemTime> {
+    fs_err::metadata(workdir.manifest_path())
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl App {
+    /// Where the definition of VM `id` came from and whether it changed on disk since loaded.
+    pub fn vm_source(&self, id: &str) -> Result<pb::VmSource> {
+        let (origin, loaded_at, loaded_mtime) = {
+            let state = self.lock();
+            let vm = state.get(id).context("VM not found")?;
+            (
+                vm.config.manifest.origin.clone().unwrap_or(VmOrigin::Api),
+                vm.loaded_at,
+                vm.manifest_mtime,
+            )
+        };
+        let work_dir = self.work_dir(id);
+        let mtime = manifest_mtime(&work_dir);
+        Ok(pb::VmSource {
+            id: id.to_string(),
+            kind: origin.kind().into(),
+            url: match &origin {
+                VmOrigin::Inventory { url } => url.clone(),
+                _ => String::new(),
+            },
+            adopted_pid: match origin {
+                VmOrigin::Adopted { pid } => Some(pid),
+                _ => None,
+            },
+            manifest_path: work_dir.manifest_path().display().to_string(),
+            loaded_at_ms: unix_ms(loaded_at),
+            modified_at_ms: mtime.map(unix_ms),
+            modified_since_load: mtime != loaded_mtime,
+        })
+    }
+}
@@ -5,18 +5,36 @@
 //! QEMU related code
 use crate::{
     app::Manifest,
-    config::{CvmConfig, GatewayConfig, Networking, PasstNetworking, ProcessAnnotation, Protocol},
+    config::{
+        CvmConfig, GatewayConfig, LogLimitsConfig, Networking, PasstNetworking, ProcessAnnotation,
+        Protocol,
+    },
+};
+use std::{
+    collections::HashMap,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
 };
-use std::{collections::HashMap, os::unix::fs::PermissionsExt};
 use std::{
     fs::Permissions,
+    io::{Read, Seek, SeekFrom, Write},
     ops::Deref,
     path::{Path, PathBuf},
     process::Command,
     time::{Duration, SystemTime},
 };
 
-use super::{image::Image, GpuConfig, VmState};
+use super::{
+    attestation::{attestation_status, AttestationStatus, Readiness},
+    balloon::BALLOON_ID,
+    cpu::format_cpu_list,
+    image::Image,
+    measurement::check_cmdline,
+    network_group::group_bridge,
+    restart::{exit_code, ExitClass},
+    user_net::guest_addresses,
+    DiskConfig, DisplayEndpoint, GpuConfig, VmState, WatchdogAction, DEFAULT_MACHINE_TYPE,
+    QMP_STARTUP_WINDOW,
+};
 use anyhow::{bail, Context, Result};
 use base64::prelude::*;
 use bon::Builder;
@@ -29,7 +47,8 @@ use dstack_vmm_rpc as pb;
 use fs_err as fs;
 use serde::{Deserialize, Serialize};
 use serde_human_bytes as hex_bytes;
-use supervisor_client::supervisor::{ProcessConfig, ProcessInfo};
+use supervisor_client::supervisor::{ProcessConfig, ProcessInfo, ProcessStatus};
+use tracing::warn;
 
 #[derive(Debug, Deserialize)]
 pub struct InstanceInfo {
@@ -39,6 +58,77 @@ pub struct InstanceInfo {
     pub app_id: Vec<u8>,
 }
 
+/// The inputs of the MRCONFIGID of a VM.
+pub struct MrConfigInputs {
+    /// Raw app compose file
+    pub compose: Vec<u8>,
+    /// App id from the instance info, the truncated compose hash is used if empty
+    pub app_id: Vec<u8>,
+}
+
+impl MrConfigInputs {
+    pub fn read(workdir: &VmWorkDir) -> Result<Self> {
+        let compose = fs::read(workdir.app_compose_path()).context("Failed to read compose")?;
+        let app_compose: AppCompose =
+            serde_json::from_slice(&compose).context("Failed to get app compose")?;
+        let app_id = if app_compose.key_provider_id.is_empty() {
+            vec![]
+        } else {
+            workdir
+                .instance_info()
+                .context("Failed to get instance info")?
+                .app_id
+        };
+        Ok(Self { compose, app_id })
+    }
+
+    fn mr_config_id(&self) -> Result<String> {
+        use sha2::Digest;
+        let app_compose: AppCompose =
+            serde_json::from_slice(&self.compose).context("Failed to get app compose")?;
+        let compose_hash: [u8; 32] = sha2::Sha256::new_with_prefix(&self.compose)
+            .finalize()
+            .into();
+        let mr_config = if app_compose.key_provider_id.is_empty() {
+            MrConfig::V1 {
+                compose_hash: &compose_hash,
+            }
+        } else {
+            let app_id = if self.app_id.is_empty() {
+                &compose_hash[..20]
+            } else {
+                &self.app_id
+            };
+            MrConfig::V2 {
+                compose_hash: &compose_hash,
+                app_id: &app_id.try_into().context("Invalid app ID")?,
+                key_provider: app_compose.key_provider(),
+                key_provider_id: &app_compose.key_provider_id,
+            }
+        };
+        Ok(BASE64_STANDARD.encode(mr_config.to_mr_config_id()))
+    }
+}
+
+/// A file or directory the launch of a VM creates besides its process.
+#[derive(Debug, Serialize)]
+pub struct SideFile {
+    /// `disk`, `dir`, `log`, `pty`, `pidfile`, `socket` or `config`
+    pub kind: &'static str,
+    pub path: PathBuf,
+    pub description: String,
+}
+
+impl SideFile {
+    pub fn new(kind: &'static str, path: PathBuf, description: impl Into<String>) -> Self {
+        Self {
+            kind,
+            path,
+            description: description.into(),
+        }
+    }
+}
+
 pub struct VmInfo {
     pub manifest: Manifest,
     pub workdir: PathBuf,
@@ -51,6 +141,20 @@ pub struct VmInfo {
     pub shutdown_progress: String,
     pub image_version: String,
     pub gateway_enabled: bool,
+    pub restart_failures: u32,
+    pub crash_looping: bool,
+    /// Exit code of QEMU if it exited on its own, `None` if killed by a signal
+    pub exit_code: Option<i32>,
+    /// How the exit was classified by the exit codes of the VM
+    pub exit_class: Option<ExitClass>,
+    pub display: Option<DisplayEndpoint>,
+    pub unresponsive_for: Option<Duration>,
+    /// The NIC link of the running VM is down
+    pub network_disabled: bool,
+    pub attestation_status: AttestationStatus,
+    /// Why the last attestation verification failed or is unsupported
+    pub attestation_error: Option<String>,
+    pub readiness: Readiness,
 }
 
 #[derive(Debug, Builder)]
@@ -93,9 +197,13 @@ fn create_hd(
 }
 
 impl VmInfo {
-    pub fn to_pb(&self, gw: &GatewayConfig, brief: bool) -> pb::VmInfo {
+    pub fn to_pb(&self, gw: &GatewayConfig, networking: &Networking, brief: bool) -> pb::VmInfo {
         let workdir = VmWorkDir::new(&self.workdir);
+        let (guest_address, gateway_address) = guest_addresses(networking, &self.manifest);
         pb::VmInfo {
+            guest_address,
+            gateway_address,
+            previous_image: self.manifest.previous_image.clone(),
             id: self.manifest.id.clone(),
             name: self.manifest.name.clone(),
             status: self.status.into(),
@@ -104,6 +212,19 @@ impl VmInfo {
             boot_error: self.boot_error.clone(),
             shutdown_progress: self.shutdown_progress.clone(),
             image_version: self.image_version.clone(),
+            restart_failures: self.restart_failures,
+            crash_looping: self.crash_looping,
+            exit_code: self.exit_code,
+            exit_classification: self.exit_class.map(|c| c.as_str().into()),
+            display: self.display.as_ref().map(|d| d.to_pb()),
+            network_disabled: self.network_disabled,
+            attestation_status: self.attestation_status.as_str().into(),
+            readiness: self.readiness.as_str().into(),
+            attestation_error: self.attestation_error.clone(),
+            signed_by: self.manifest.signed_by.clone().unwrap_or_default(),
+            unresponsive_for: self
+                .unresponsive_for
+                .map(|d| humantime::format_duration(d).to_string()),
             configuration: if brief {
                 None
             } else {
@@ -158,6 +279,55 @@ impl VmInfo {
                     kms_urls,
                     gateway_urls,
                     stopped,
+                    disks: self.manifest.disks.iter().map(|d| d.to_pb()).collect(),
+                    kernel: self.manifest.kernel.clone(),
+                    initrd: self.manifest.initrd.clone(),
+                    cmdline: self.manifest.cmdline.clone(),
+                    expected_cmdline: self.manifest.expected_cmdline.clone(),
+                    qemu_name: self.manifest.qemu_name.clone(),
+                    pci_devices: self.manifest.pci_devices.clone(),
+                    rtc: self.manifest.rtc.map(|rtc| pb::RtcConfig {
+                        base: rtc.base.as_str().into(),
+                        clock: rtc.clock.as_str().into(),
+                    }),
+                    network: self.manifest.network.as_ref().map(|n| pb::NetworkConfig {
+                        dns: n.dns.iter().map(|ip| ip.to_string()).collect(),
+                        hostname: n.hostname.clone().unwrap_or_default(),
+                        mac: n.mac.clone().unwrap_or_default(),
+                        net: n.addressing.map(|a| a.net.to_string()).unwrap_or_default(),
+                        gateway: n
+                            .addressing
+                            .map(|a| a.gateway.to_string())
+                            .unwrap_or_default(),
+                        guest_address: n
+                            .addressing
+                            .map(|a| a.guest.to_string())
+                            .unwrap_or_default(),
+                    }),
+                    display: self.manifest.display.as_ref().map(|d| d.to_pb()),
+                    cpu: self.manifest.cpu.as_ref().map(|c| c.to_pb()),
+                    restart_policy: self.manifest.restart_policy.map(|p| p.to_string()),
+                    exit_codes: self.manifest.exit_codes.as_ref().map(|c| pb::ExitCodes {
+                        success: c.success.clone(),
+                        failure: c.failure.clone(),
+                    }),
+                    watchdog: self.manifest.watchdog.map(|w| pb::WatchdogConfig {
+                        timeout_secs: w.timeout,
+                        action: w.action.as_str().into(),
+                    }),
+                    boot_timeout_secs: self.manifest.boot_timeout,
+                    max_lifetime_secs: self.manifest.max_lifetime,
+                    network_group: self.manifest.network_group.clone(),
+                    nics: self.manifest.nics.iter().map(|n| n.to_pb()).collect(),
+                    signature: vec![],
+                    hooks: self.manifest.hooks.as_ref().map(|h| h.to_pb()),
+                    scheduling: self.manifest.scheduling.as_ref().map(|s| s.to_pb()),
+                    guest_agent: self.manifest.guest_agent,
+                    memory_options: self.manifest.memory_options.map(|m| m.to_pb()),
+                    machine: self.manifest.machine.as_ref().map(|m| m.to_pb()),
+                    qmp_events: self.manifest.qmp_events.as_ref().map(|q| q.to_pb()),
+                    watchdog_device: self.manifest.watchdog_device.map(|w| w.to_pb()),
+                    test_determinism: self.manifest.test_determinism.as_ref().map(|d| d.to_pb()),
                 })
             },
             app_url: self
@@ -178,7 +348,14 @@ impl VmInfo {
 }
 
 impl VmState {
-    pub fn merged_info(&self, proc_state: Option<&ProcessInfo>, workdir: &VmWorkDir) -> VmInfo {
+    /// Info of the VM with the state of its process. With `attestation_gated` the VM is only
+    /// ready once its attestation is verified.
+    pub fn merged_info(
+        &self,
+        proc_state: Option<&ProcessInfo>,
+        workdir: &VmWorkDir,
+        attestation_gated: bool,
+    ) -> VmInfo {
         fn truncate(d: Duration) -> Duration {
             Duration::from_secs(d.as_secs())
         }
@@ -187,7 +364,15 @@ impl VmState {
             None => false,
         };
         let started = workdir.started().unwrap_or(false);
+        // Until QMP is up the VM can not be managed yet
+        let starting = is_running
+            && self.state.qmp_expected
+            && !workdir.qmp_socket().exists()
+            && proc_state
+                .and_then(|info| info.state.started_at)
+                .is_some_and(|t| t.elapsed().unwrap_or_default() < QMP_STARTUP_WINDOW);
         let status = match (started, is_running) {
+            (true, true) if starting => "starting",
             (true, true) => "running",
             (true, false) => "exited",
             (false, true) => "stopping",
@@ -206,7 +391,25 @@ impl VmState {
         let uptime = display_ts(proc_state.and_then(|info| info.state.started_at.as_ref()));
         let exited_at = display_ts(proc_state.and_then(|info| info.state.stopped_at.as_ref()));
         let instance_id = workdir.instance_info().ok().map(|info| info.instance_id);
+        let exit_status = proc_state
+            .map(|info| &info.state.status)
+            .filter(|_| status == "exited");
+        let exit_code = match exit_status {
+            Some(ProcessStatus::Exited(raw)) => exit_code(*raw),
+            _ => None,
+        };
+        let exit_class = exit_status.and_then(|status| {
+            let exit_codes = self.config.manifest.exit_codes.clone().unwrap_or_default();
+            exit_codes.classify(status)
+        });
+        let attestation_status = attestation_status(self.state.attestation.as_ref(), is_running);
         VmInfo {
+            readiness: Readiness::new(
+                is_running && status == "running",
+                &self.state.boot_progress,
+                attestation_status,
+                attestation_gated,
+            ),
             manifest: self.config.manifest.clone(),
             workdir: workdir.path().to_path_buf(),
             instance_id,
@@ -218,11 +421,197 @@ impl VmState {
             shutdown_progress: self.state.shutdown_progress.clone(),
             image_version: self.config.image.info.version.clone(),
             gateway_enabled: self.config.gateway_enabled,
+            restart_failures: self.state.restart.failures(),
+            crash_looping: self.state.restart.crash_looping(),
+            exit_code,
+            exit_class,
+            display: is_running.then_some(self.state.display).flatten(),
+            unresponsive_for: self
+                .state
+                .unresponsive_since
+                .filter(|_| {
+                    let policy = self.config.manifest.watchdog;
+                    policy.is_some_and(|p| p.action != WatchdogAction::Log)
+                })
+                .map(|t| truncate(t.elapsed())),
+            network_disabled: is_running && self.state.network_disabled,
+            attestation_status,
+            attestation_error: self
+                .state
+                .attestation
+                .as_ref()
+                .and_then(|c| c.error())
+                .map(Into::into),
+        }
+    }
+}
+
+/// Kernel, initrd and cmdline the VM is booted with.
+pub struct BootSpec {
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    pub cmdline: Option<String>,
+}
+
+/// Whether `name` is listed in `allowed`, where a trailing `*` matches any suffix.
+fn env_allowed(name: &str, allowed: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
+
+/// Expand `${NAME}` references to the environment variables in `allowed`, `$$` being a
+/// literal `$`.
+fn interpolate_env(s: &str, allowed: &[String]) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(after) = after.strip_prefix('{') else {
+            output.push('$');
+            rest = after;
+            continue;
+        };
+        let end = after
+            .find('}')
+            .with_context(|| format!("Unterminated variable reference in {s:?}"))?;
+        let name = &after[..end];
+        if !env_allowed(name, allowed) {
+            bail!("Environment variable {name} is not in cvm.boot_env_allowlist");
         }
+        let value = std::env::var(name)
+            .with_context(|| format!("Environment variable {name} is not set"))?;
+        output.push_str(&value);
+        rest = &after[end + 1..];
     }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Sanitize a name for `-name`, so it can not inject QEMU options.
+fn qemu_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "dstack-vm".into()
+    } else {
+        name
+    }
+}
+
+fn resolve_boot_path(path: &str, allowed: &[String]) -> Result<PathBuf> {
+    let path = PathBuf::from(interpolate_env(path, allowed)?);
+    if !path.is_absolute() {
+        bail!("Boot file path must be absolute: {}", path.display());
+    }
+    Ok(path)
 }
 
 impl VmConfig {
+    /// Resolve the boot files, honoring the direct kernel boot settings of the manifest.
+    pub fn boot_spec(&self, cfg: &CvmConfig) -> Result<BootSpec> {
+        let manifest = &self.manifest;
+        let allowed = &cfg.boot_env_allowlist;
+        let (kernel, initrd) = match &manifest.kernel {
+            Some(kernel) => (
+                resolve_boot_path(kernel, allowed)?,
+                manifest
+                    .initrd
+                    .as_deref()
+                    .map(|initrd| resolve_boot_path(initrd, allowed))
+                    .transpose()?,
+            ),
+            None => (self.image.kernel.clone(), Some(self.image.initrd.clone())),
+        };
+        let cmdline = match &manifest.cmdline {
+            Some(cmdline) => Some(interpolate_env(cmdline, allowed)?),
+            None => self.image.info.cmdline.clone(),
+        };
+        Ok(BootSpec {
+            kernel,
+            initrd,
+            cmdline,
+        })
+    }
+
+    /// Validate the boot settings and make sure the boot files exist and are allowed.
+    pub fn validate_boot(&self, cfg: &CvmConfig) -> Result<BootSpec> {
+        let manifest = &self.manifest;
+        if manifest.kernel.is_none() && manifest.initrd.is_some() {
+            bail!("Custom initrd requires a custom kernel");
+        }
+        if manifest.kernel.is_some() && self.image.hda.is_some() {
+            bail!("Direct kernel boot can not be combined with a disk boot image");
+        }
+        let boot = self.boot_spec(cfg)?;
+        if let Some(expected) = &manifest.expected_cmdline {
+            check_cmdline(expected, boot.cmdline.as_deref().unwrap_or_default())?;
+        }
+        if let Some(determinism) = &manifest.test_determinism {
+            determinism.check_allowed(cfg, &self.image.info)?;
+        }
+        // Before probing for the files, so configs can not tell which host files exist
+        let image_files = [&self.image.hda, &self.image.rootfs, &self.image.bios];
+        for path in std::iter::once(&boot.kernel)
+            .chain(&boot.initrd)
+            .chain(image_files.into_iter().flatten())
+        {
+            cfg.check_image_root(path)?;
+        }
+        if !boot.kernel.exists() {
+            bail!("Kernel does not exist: {}", boot.kernel.display());
+        }
+        if let Some(initrd) = &boot.initrd {
+            if !initrd.exists() {
+                bail!("Initrd does not exist: {}", initrd.display());
+            }
+        }
+        Ok(boot)
+    }
+
+    /// Whether the guest agent channel is attached: allowed by the host and asked for by the VM.
+    pub fn guest_agent_enabled(&self, cfg: &CvmConfig) -> bool {
+        cfg.guest_agent && self.manifest.guest_agent
+    }
+
+    /// Whether the data disk is a qcow2 image in the workdir rather than network storage.
+    fn has_local_hda(&self) -> bool {
+        self.manifest
+            .disk("hd1")
+            .is_none_or(|disk| disk.source.is_none())
+    }
+
+    fn drive_opts(&self, drive: &str) -> String {
+        match self.manifest.disk(drive) {
+            Some(disk) => disk.drive_opts(),
+            None => DiskConfig::new(drive).drive_opts(),
+        }
+    }
+
+    fn device_opts(&self, drive: &str) -> String {
+        self.manifest
+            .disk(drive)
+            .map(|disk| disk.device_opts())
+            .unwrap_or_default()
+    }
+
     fn config_passt(&self, workdir: &VmWorkDir, netcfg: &PasstNetworking) -> Result<ProcessConfig> {
         let PasstNetworking {
             passt_exec,
@@ -328,35 +717,150 @@ impl VmConfig {
         Ok(process_config)
     }
 
+    /// Whether the app compose is measured into MRCONFIGID.
+    pub fn uses_mr_config_id(&self, cfg: &CvmConfig) -> bool {
+        let img_ver = self.image.info.version_tuple().unwrap_or_default();
+        cfg.use_mrconfigid && img_ver >= (0, 5, 2)
+    }
+
+    /// Files created in the workdir by [`Self::config_qemu`] or by QEMU and its helpers once
+    /// launched. The shared files written by the VMM are not included.
+    pub fn side_files(&self, workdir: impl AsRef<Path>, cfg: &CvmConfig) -> Vec<SideFile> {
+        let workdir = VmWorkDir::new(workdir);
+        let hda = match &self.image.hda {
+            Some(base) => format!(
+                "qcow2 overlay of {}, {}G",
+                base.display(),
+                self.manifest.disk_size
+            ),
+            None => format!("qcow2 data disk, {}G", self.manifest.disk_size),
+        };
+        let mut files = vec![];
+        if self.has_local_hda() {
+            files.push(SideFile::new("disk", workdir.hda_path(), hda));
+        }
+        files.extend([
+            SideFile::new("dir", workdir.shared_dir(), "shared with the guest over 9p"),
+            SideFile::new("log", workdir.serial_file(), "serial console log"),
+            SideFile::new("pty", workdir.serial_pty(), "serial console pty link"),
+            SideFile::new("log", workdir.stdout_file(), "QEMU stdout"),
+            SideFile::new("log", workdir.stderr_file(), "QEMU stderr"),
+            SideFile::new("pidfile", workdir.pid_file(), "QEMU pid"),
+        ]);
+        if cfg.qmp_socket {
+            files.push(SideFile::new("socket", workdir.qmp_socket(), "QMP"));
+        }
+        if cfg.qmp_events {
+            files.push(SideFile::new(
+                "socket",
+                workdir.qmp_events_socket(),
+                "QMP event monitor",
+            ));
+        }
+        if cfg.hmp_socket {
+            files.push(SideFile::new("socket", workdir.hmp_socket(), "HMP"));
+        }
+        if self.guest_agent_enabled(cfg) {
+            files.push(SideFile::new("socket", workdir.qga_socket(), "guest agent"));
+        }
+        if let Networking::Passt(_) = &cfg.networking {
+            files.push(SideFile::new(
+                "socket",
+                workdir.passt_socket(),
+                "passt network stream",
+            ));
+            files.push(SideFile::new("log", workdir.passt_stdout(), "passt stdout"));
+            files.push(SideFile::new("log", workdir.passt_stderr(), "passt stderr"));
+        }
+        files
+    }
+
+    /// Create the data disk and shared directory and build the processes of the VM.
     pub fn config_qemu(
         &self,
         workdir: impl AsRef<Path>,
         cfg: &CvmConfig,
         gpus: &GpuConfig,
+        display: Option<&DisplayEndpoint>,
     ) -> Result<Vec<ProcessConfig>> {
+        // Fail before creating anything
+        let boot = self.validate_boot(cfg)?;
         let workdir = VmWorkDir::new(workdir);
-        let serial_file = workdir.serial_file();
-        let serial_pty = workdir.serial_pty();
-        let shared_dir = workdir.shared_dir();
         let disk_size = format!("{}G", self.manifest.disk_size);
         let hda_path = workdir.hda_path();
-        if !hda_path.exists() {
-            create_hd(&hda_path, self.image.hda.as_ref(), &disk_size)?;
-        }
-        if !cfg.user.is_empty() {
-            fs_err::set_permissions(&hda_path, Permissions::from_mode(0o660))?;
+        if self.has_local_hda() {
+            if !hda_path.exists() {
+                create_hd(&hda_path, self.image.hda.as_ref(), &disk_size)?;
+            }
+            if !cfg.user.is_empty() {
+                fs_err::set_permissions(&hda_path, Permissions::from_mode(0o660))?;
+            }
         }
-
+        let shared_dir = workdir.shared_dir();
         if !shared_dir.exists() {
             fs::create_dir_all(&shared_dir)?;
         }
+        let mr_config = if self.uses_mr_config_id(cfg) {
+            Some(MrConfigInputs::read(&workdir)?)
+        } else {
+            None
+        };
+        let mut processes = self.render_qemu(
+            workdir.path(),
+            cfg,
+            &boot,
+            gpus,
+            display,
+            mr_config.as_ref(),
+        )?;
+        if let Some(config) = self.split_long_argv(&mut processes, &workdir, cfg) {
+            fs::write(workdir.qemu_config_file(), config)?;
+        }
+        Ok(processes)
+    }
+
+    /// Build the processes of the VM without touching the filesystem besides reading the boot
+    /// files. `boot` is the result of [`Self::validate_boot`], `mr_config` is required if the
+    /// compose is measured into MRCONFIGID.
+    pub fn render_qemu(
+        &self,
+        workdir: impl AsRef<Path>,
+        cfg: &CvmConfig,
+        boot: &BootSpec,
+        gpus: &GpuConfig,
+        display: Option<&DisplayEndpoint>,
+        mr_config: Option<&MrConfigInputs>,
+    ) -> Result<Vec<ProcessConfig>> {
+        let workdir = VmWorkDir::new(workdir);
+        let serial_file = workdir.serial_file();
+        let serial_pty = workdir.serial_pty();
+        let shared_dir = workdir.shared_dir();
+        let hda_path = workdir.hda_path();
         let qemu = &cfg.qemu_path;
         let mut smp = self.manifest.vcpu.max(1);
         let mut mem = self.manifest.memory;
         let mut command = Command::new(qemu);
+        let name = qemu_name(
+            self.manifest
+                .qemu_name
+                .as_deref()
+                .unwrap_or(&self.manifest.name),
+        );
+        command
+            .arg("-name")
+            .arg(format!("guest={name},process={name}"));
         command.arg("-accel").arg("kvm");
-        command.arg("-cpu").arg("host");
-        command.arg("-nographic");
+        command
+            .arg("-cpu")
+            .arg(format!("host{}", cfg.tsc.cpu_opts()));
+        match display {
+            Some(display) => {
+                command.args(display.qemu_args());
+            }
+            None => {
+                command.arg("-nographic");
+            }
+        }
         command.arg("-nodefaults");
         command.arg("-chardev").arg(format!(
             "pty,id=com0,path={},logfile={}",
@@ -370,11 +874,44 @@ impl VmConfig {
                 workdir.qmp_socket().display()
             ));
         }
+        if cfg.qmp_events {
+            command.arg("-chardev").arg(format!(
+                "socket,id=qmpev0,path={},server=on,wait=off",
+                workdir.qmp_events_socket().display()
+            ));
+            command.arg("-mon").arg("chardev=qmpev0,mode=control");
+            if let Some(events) = &self.manifest.qmp_events {
+                command.args(events.qemu_args());
+            }
+        }
+        if let Some(watchdog) = &self.manifest.watchdog_device {
+            command.args(watchdog.qemu_args());
+        }
+        if cfg.hmp_socket {
+            command.arg("-monitor").arg(format!(
+                "unix:{},server,wait=off",
+                workdir.hmp_socket().display()
+            ));
+        }
+        if self.guest_agent_enabled(cfg) {
+            command.arg("-chardev").arg(format!(
+                "socket,id=qga0,path={},server=on,wait=off",
+                workdir.qga_socket().display()
+            ));
+            command
+                .arg("-device")
+                .arg("virtio-serial-pci,id=qga-serial0");
+            command
+                .arg("-device")
+                .arg("virtserialport,chardev=qga0,name=org.qemu.guest_agent.0");
+        }
         if let Some(bios) = &self.image.bios {
             command.arg("-bios").arg(bios);
         }
-        command.arg("-kernel").arg(&self.image.kernel);
-        command.arg("-initrd").arg(&self.image.initrd);
+        command.arg("-kernel").arg(&boot.kernel);
+        if let Some(initrd) = &boot.initrd {
+            command.arg("-initrd").arg(initrd);
+        }
         if cfg.qemu_hotplug_off {
             command.args([
                 "-global",
@@ -390,7 +927,16 @@ impl VmConfig {
                 ),
             ]);
         }
-        if let Some(rootfs) = &self.image.rootfs {
+        for id in self.manifest.iothread_ids() {
+            command.arg("-object").arg(format!("iothread,id={id}"));
+        }
+        let rootfs_blockdev = self
+            .manifest
+            .disk("hd0")
+            .and_then(|disk| disk.blockdev_args(true));
+        if let Some(args) = rootfs_blockdev {
+            command.args(args);
+        } else if let Some(rootfs) = &self.image.rootfs {
             let ext = rootfs
                 .extension()
                 .unwrap_or_default()
@@ -402,10 +948,14 @@ impl VmConfig {
                 }
                 "verity" => {
                     command.arg("-drive").arg(format!(
-                        "file={},if=none,id=hd0,format=raw,readonly=on",
-                        rootfs.display()
+                        "file={},if=none,id=hd0,format=raw,readonly=on{}",
+                        rootfs.display(),
+                        self.drive_opts("hd0")
+                    ));
+                    command.arg("-device").arg(format!(
+                        "virtio-blk-pci,drive=hd0{}",
+                        self.device_opts("hd0")
                     ));
-                    command.arg("-device").arg("virtio-blk-pci,drive=hd0");
                 }
                 _ => {
                     bail!("Unsupported rootfs type: {ext}");
@@ -413,17 +963,38 @@ impl VmConfig {
             }
         }
         let mut processes = vec![];
-        command
-            .arg("-drive")
-            .arg(format!("file={},if=none,id=hd1", hda_path.display()))
-            .arg("-device")
-            .arg("virtio-blk-pci,drive=hd1");
+        match self
+            .manifest
+            .disk("hd1")
+            .and_then(|disk| disk.blockdev_args(false))
+        {
+            Some(args) => {
+                command.args(args);
+            }
+            None => {
+                command
+                    .arg("-drive")
+                    .arg(format!(
+                        "file={},if=none,id=hd1{}",
+                        hda_path.display(),
+                        self.drive_opts("hd1")
+                    ))
+                    .arg("-device")
+                    .arg(format!(
+                        "virtio-blk-pci,drive=hd1{}",
+                        self.device_opts("hd1")
+                    ));
+            }
+        }
         let netdev = match &cfg.networking {
             Networking::User(netcfg) => {
+                let addressing = self.manifest.network.as_ref().and_then(|n| n.addressing);
                 let mut netdev = format!(
-                    "user,id=net0,net={},dhcpstart={},restrict={}",
-                    netcfg.net,
-                    netcfg.dhcp_start,
+                    "user,id=net0,{},restrict={}",
+                    match addressing {
+                        Some(addressing) => addressing.qemu_opts(),
+                        None => format!("net={},dhcpstart={}", netcfg.net, netcfg.dhcp_start),
+                    },
                     if netcfg.restrict { "yes" } else { "no" }
                 );
                 for pm in &self.manifest.port_map {
@@ -435,6 +1006,9 @@ impl VmConfig {
                         pm.to
                     ));
                 }
+                if let Some(network) = &self.manifest.network {
+                    netdev.push_str(&network.slirp_opts());
+                }
                 netdev
             }
             Networking::Passt(netcfg) => {
@@ -447,47 +1021,42 @@ impl VmConfig {
                     workdir.passt_socket().display()
                 )
             }
+            Networking::Bridge(netcfg) => format!(
+                "bridge,id=net0,br={},helper={}",
+                group_bridge(netcfg, self.manifest.network_group.as_deref()),
+                netcfg.bridge_helper
+            ),
             Networking::Custom(netcfg) => netcfg.netdev.clone(),
         };
         command.arg("-netdev").arg(netdev);
-        command.arg("-device").arg("virtio-net-pci,netdev=net0");
-
-        command
-            .arg("-machine")
-            .arg("q35,kernel-irqchip=split,confidential-guest-support=tdx,hpet=off");
+        command.arg("-device").arg(format!(
+            "virtio-net-pci,netdev=net0,mac={}",
+            self.manifest.mac()
+        ));
+        command.args(self.manifest.nic_args(&cfg.networking)?);
+
+        let machine = self
+            .manifest
+            .machine
+            .as_ref()
+            .map_or(DEFAULT_MACHINE_TYPE.to_string(), |m| m.qemu_name());
+        command.arg("-machine").arg(format!(
+            "{machine},kernel-irqchip=split,confidential-guest-support=tdx,hpet=off"
+        ));
+        if let Some(rtc) = &self.manifest.rtc {
+            command.arg("-rtc").arg(rtc.qemu_opts());
+        }
+        if let Some(determinism) = &self.manifest.test_determinism {
+            warn!(
+                "VM {} runs with test determinism settings, not for production",
+                self.manifest.id
+            );
+            command.args(determinism.qemu_args());
+        }
 
-        let img_ver = self.image.info.version_tuple().unwrap_or_default();
-        let support_mr_config_id = img_ver >= (0, 5, 2);
-        let tdx_object = if cfg.use_mrconfigid && support_mr_config_id {
-            let app_compose = workdir.app_compose().context("Failed to get app compose")?;
-            let compose_hash = workdir
-                .app_compose_hash()
-                .context("Failed to get compose hash")?;
-            let mr_config = if app_compose.key_provider_id.is_empty() {
-                MrConfig::V1 {
-                    compose_hash: &compose_hash,
-                }
-            } else {
-                let instance_info = workdir
-                    .instance_info()
-                    .context("Failed to get instance info")?;
-                let app_id = if instance_info.app_id.is_empty() {
-                    &compose_hash[..20]
-                } else {
-                    &instance_info.app_id
-                };
-
-                let key_provider = app_compose.key_provider();
-                let key_provider_id = &app_compose.key_provider_id;
-                MrConfig::V2 {
-                    compose_hash: &compose_hash,
-                    app_id: &app_id.try_into().context("Invalid app ID")?,
-                    key_provider,
-                    key_provider_id,
-                }
-            };
-            let mrconfigid = BASE64_STANDARD.encode(mr_config.to_mr_config_id());
-            format!("tdx-guest,id=tdx,mrconfigid={mrconfigid}")
+        let tdx_object = if self.uses_mr_config_id(cfg) {
+            let mr_config = mr_config.context("Missing the inputs of MRCONFIGID")?;
+            format!("tdx-guest,id=tdx,mrconfigid={}", mr_config.mr_config_id()?)
         } else {
             "tdx-guest,id=tdx".to_string()
         };
@@ -496,6 +1065,11 @@ impl VmConfig {
         command
             .arg("-device")
             .arg(format!("vhost-vsock-pci,guest-cid={}", self.cid));
+        if cfg.balloon.enabled {
+            command
+                .arg("-device")
+                .arg(format!("virtio-balloon-pci,id={BALLOON_ID}"));
+        }
 
         let ro = if self.image.info.shared_ro {
             "on"
@@ -608,8 +1182,27 @@ impl VmConfig {
                 }
             }
         }
+
+        // Configure passthrough PCI devices
+        if !self.manifest.pci_devices.is_empty() {
+            if gpus.gpus.is_empty() {
+                command.arg("-object").arg("iommufd,id=iommufd0");
+            }
+            for slot in &self.manifest.pci_devices {
+                command.arg("-device").arg(format!(
+                    "pcie-root-port,id=pci.{dev_num},bus=pcie.0,chassis={dev_num}",
+                ));
+                command.arg("-device").arg(format!(
+                    "vfio-pci,host={slot},bus=pci.{dev_num},iommufd=iommufd0",
+                ));
+                dev_num += 1;
+            }
+        }
         command.arg("-smp").arg(smp.to_string());
         command.arg("-m").arg(format!("{}M", mem));
+        if let Some(options) = &self.manifest.memory_options {
+            command.args(options.qemu_args(hugepages));
+        }
 
         // NUMA pinning if requested
         let mut numa_cpus = None;
@@ -623,9 +1216,13 @@ impl VmConfig {
                 numa_cpus = Some(cpus);
             }
         }
+        let cpu = self.manifest.cpu.clone().unwrap_or_default();
+        if !cpu.affinity.is_empty() {
+            numa_cpus = Some(format_cpu_list(&cpu.affinity));
+        }
 
         // Add kernel command line
-        if let Some(cmdline) = &self.image.info.cmdline {
+        if let Some(cmdline) = &boot.cmdline {
             command.arg("-append").arg(cmdline);
         }
 
@@ -644,7 +1241,7 @@ impl VmConfig {
         cmd_args.push(qemu.to_string_lossy().to_string());
         cmd_args.extend(args);
 
-        // If we have NUMA pinning, we'll need to wrap the command with taskset
+        // If we have NUMA pinning or CPU affinity, we'll need to wrap the command with taskset
         if let Some(cpus) = numa_cpus {
             cmd_args.splice(0..0, ["taskset", "-c", &cpus].into_iter().map(|s| s.into()));
         }
@@ -655,6 +1252,7 @@ impl VmConfig {
                 ["sudo", "-u", &cfg.user].into_iter().map(|s| s.into()),
             );
         }
+        cpu.wrap_command(&mut cmd_args, &format!("dstack-vm-{}", self.manifest.id));
 
         let command = cmd_args.remove(0);
         let note = ProcessAnnotation {
@@ -806,13 +1404,6 @@ impl VmWorkDir {
         self.shared_dir().join(APP_COMPOSE)
     }
 
-    pub fn app_compose_hash(&self) -> Result<[u8; 32]> {
-        use sha2::Digest;
-        let compose_path = self.app_compose_path();
-        let compose = fs::read(compose_path).context("Failed to read compose")?;
-        Ok(sha2::Sha256::new_with_prefix(&compose).finalize().into())
-    }
-
     pub fn user_config_path(&self) -> PathBuf {
         self.shared_dir().join(USER_CONFIG)
     }
@@ -841,6 +1432,25 @@ impl VmWorkDir {
         self.workdir.join("stderr.log")
     }
 
+    /// The last `lines` lines QEMU wrote to stderr, within `limits`.
+    pub fn stderr_tail(&self, lines: usize, limits: &LogLimitsConfig) -> Result<Vec<String>> {
+        tail_lines(&self.stderr_file(), lines, limits)
+    }
+
+    /// The last `lines` lines of the serial console log, within `limits`.
+    pub fn serial_tail(&self, lines: usize, limits: &LogLimitsConfig) -> Result<Vec<String>> {
+        tail_lines(&self.serial_file(), lines, limits)
+    }
+
+    /// Diagnostics bundles of boots that timed out and of `CollectDiagnostics`.
+    pub fn diagnostics_dir(&self) -> PathBuf {
+        self.workdir.join("diagnostics")
+    }
+
+    pub fn qemu_config_file(&self) -> PathBuf {
+        self.workdir.join("qemu.cfg")
+    }
+
     pub fn pid_file(&self) -> PathBuf {
         self.workdir.join("qemu.pid")
     }
@@ -849,10 +1459,32 @@ impl VmWorkDir {
         self.workdir.join("hda.img")
     }
 
+    /// Name of the data disk snapshot last restored by `RestoreSnapshot`.
+    pub fn restored_snapshot_file(&self) -> PathBuf {
+        self.workdir.join("restored-snapshot")
+    }
+
+    /// Memory and device state saved by `CheckpointVm`.
+    pub fn checkpoint_file(&self) -> PathBuf {
+        self.workdir.join("checkpoint.mem")
+    }
+
     pub fn qmp_socket(&self) -> PathBuf {
         self.workdir.join("qmp.sock")
     }
 
+    pub fn qmp_events_socket(&self) -> PathBuf {
+        self.workdir.join("qmp-events.sock")
+    }
+
+    pub fn hmp_socket(&self) -> PathBuf {
+        self.workdir.join("hmp.sock")
+    }
+
+    pub fn qga_socket(&self) -> PathBuf {
+        self.workdir.join("qga.sock")
+    }
+
     pub fn passt_socket(&self) -> PathBuf {
         self.workdir.join("passt.sock")
     }
@@ -874,6 +1506,57 @@ impl VmWorkDir {
     }
 }
 
+/// Marker standing in for log data dropped to stay within the limits.
+pub const TRUNCATED_MARKER: &str = "[... truncated ...]";
+
+/// Cut a log line longer than `max_bytes`, marking the cut and keeping its line ending.
+pub fn truncate_line(line: &str, max_bytes: usize) -> String {
+    let (body, ending) = match line.strip_suffix('\n') {
+        Some(body) => (body, "\n"),
+        None => (line, ""),
+    };
+    if max_bytes == 0 || body.len() <= max_bytes {
+        return line.to_string();
+    }
+    let mut end = max_bytes;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{TRUNCATED_MARKER}{ending}", &body[..end])
+}
+
+/// The last `lines` lines of a log file, reading at most `limits.tail_max_bytes` from its end.
+/// A marker line is put first if the file had more to give than the limits allowed.
+fn tail_lines(path: &Path, lines: usize, limits: &LogLimitsConfig) -> Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let lines = lines.min(limits.tail_max_lines);
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let offset = len.saturating_sub(limits.tail_max_bytes);
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![];
+    file.read_to_end(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    let mut all: Vec<&str> = text.lines().collect();
+    if offset > 0 && !all.is_empty() {
+        // The first line is likely cut in the middle
+        all.remove(0);
+    }
+    let skip = all.len().saturating_sub(lines);
+    let mut tail = Vec::with_capacity(all.len() - skip + 1);
+    if offset > 0 && skip == 0 {
+        tail.push(TRUNCATED_MARKER.to_string());
+    }
+    tail.extend(
+        all[skip..]
+            .iter()
+            .map(|line| truncate_line(line, limits.max_line_bytes)),
+    );
+    Ok(tail)
+}
+
 impl VmWorkDir {
     pub fn instance_info(&self) -> Result<InstanceInfo> {
         let info_file = self.instance_info_path();
@@ -886,4 +1569,128 @@ impl VmWorkDir {
         let compose: AppCompose = serde_json::from_str(&fs::read_to_string(compose_file)?)?;
         Ok(compose)
     }
+
+    /// Copies of the inputs of a one-shot run.
+    pub fn one_shot_archive_dir(&self) -> PathBuf {
+        self.workdir.join("one-shot")
+    }
+
+    pub fn guest_api_token_path(&self) -> PathBuf {
+        self.workdir.join(".guest-api-token")
+    }
+
+    /// The token the guest uses to authenticate to the guest API, generated on first use or
+    /// if the token file is empty.
+    pub fn guest_api_token(&self) -> Result<String> {
+        let token_path = self.guest_api_token_path();
+        if token_path.exists() {
+            let token = fs::read_to_string(&token_path).context("Failed to read guest token")?;
+            let token = token.trim();
+            if !token.is_empty() {
+                return Ok(token.to_string());
+            }
+        }
+        self.rotate_guest_api_token()
+    }
+
+    /// Replace the guest API token with a fresh one.
+    pub fn rotate_guest_api_token(&self) -> Result<String> {
+        let token_path = self.guest_api_token_path();
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        // Created private and renamed over the old file, so the token is never readable by others
+        let tmp_path = token_path.with_extension("tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path).context("Failed to remove stale guest token")?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .context("Failed to create guest token")?;
+        file.write_all(token.as_bytes())
+            .and_then(|()| file.sync_all())
+            .context("Failed to write guest token")?;
+        fs::rename(&tmp_path, &token_path).context("Failed to write guest token")?;
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn interpolates_env() {
+        std::env::set_var("DSTACK_VMM_TEST_BOOT_DIR", "/opt/boot");
+        let allowed = allowed(&["DSTACK_VMM_TEST_BOOT_DIR"]);
+        assert_eq!(
+            interpolate_env("${DSTACK_VMM_TEST_BOOT_DIR}/bzImage", &allowed).unwrap(),
+            "/opt/boot/bzImage"
+        );
+        assert_eq!(
+            interpolate_env(
+                "a=${DSTACK_VMM_TEST_BOOT_DIR} b=${DSTACK_VMM_TEST_BOOT_DIR}",
+                &allowed
+            )
+            .unwrap(),
+            "a=/opt/boot b=/opt/boot"
+        );
+        assert_eq!(
+            interpolate_env("console=ttyS0 quiet", &[]).unwrap(),
+            "console=ttyS0 quiet"
+        );
+    }
+
+    #[test]
+    fn interpolate_env_escapes() {
+        assert_eq!(interpolate_env("$$", &[]).unwrap(), "$");
+        assert_eq!(interpolate_env("$${HOME}", &[]).unwrap(), "${HOME}");
+        assert_eq!(interpolate_env("a$$$$b", &[]).unwrap(), "a$$b");
+        // A `$` not followed by `{` is kept
+        assert_eq!(interpolate_env("cost=$5 $", &[]).unwrap(), "cost=$5 $");
+    }
+
+    #[test]
+    fn interpolate_env_errors() {
+        let allowed = allowed(&["DSTACK_VMM_TEST_*"]);
+        let err = interpolate_env("${DSTACK_VMM_TEST_UNSET}/bzImage", &allowed).unwrap_err();
+        assert!(err.to_string().contains("DSTACK_VMM_TEST_UNSET is not set"));
+        let err = interpolate_env("/boot/${DSTACK_VMM_TEST_DIR", &allowed).unwrap_err();
+        assert!(err.to_string().contains("Unterminated"));
+        assert!(interpolate_env("${}", &allowed).is_err());
+    }
+
+    #[test]
+    fn interpolate_env_only_expands_allowed_variables() {
+        std::env::set_var("DSTACK_VMM_TEST_SECRET", "hunter2");
+        std::env::set_var("DSTACK_VMM_TEST_BOOT_ROOT", "/opt/boot");
+        let err = interpolate_env("key=${DSTACK_VMM_TEST_SECRET}", &[]).unwrap_err();
+        assert!(err.to_string().contains("not in cvm.boot_env_allowlist"));
+        assert!(!err.to_string().contains("hunter2"));
+
+        let allowed = allowed(&["DSTACK_VMM_TEST_BOOT_*", "HOME"]);
+        assert!(interpolate_env("${DSTACK_VMM_TEST_SECRET}", &allowed).is_err());
+        assert!(interpolate_env("${HOMEDIR}", &allowed).is_err());
+        assert_eq!(
+            interpolate_env("${DSTACK_VMM_TEST_BOOT_ROOT}/bzImage", &allowed).unwrap(),
+            "/opt/boot/bzImage"
+        );
+    }
+
+    #[test]
+    fn sanitizes_qemu_name() {
+        assert_eq!(qemu_name("web-1_prod.v2"), "web-1_prod.v2");
+        assert_eq!(
+            qemu_name("my vm,debug-threads=on"),
+            "my_vm_debug-threads_on"
+        );
+        assert_eq!(qemu_name("vm\nx"), "vm_x");
+        assert_eq!(qemu_name("vé"), "v_");
+        assert_eq!(qemu_name(""), "dstack-vm");
+    }
 }
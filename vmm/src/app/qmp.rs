@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal QMP client used to talk to running QEMU instances.
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+const QMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a command without a reply on success is given to fail.
+const NO_REPLY_WAIT: Duration = Duration::from_secs(1);
+
+/// How long after launch QEMU may take to open its QMP socket.
+pub const QMP_STARTUP_WINDOW: Duration = Duration::from_secs(10);
+
+pub struct QmpClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl QmpClient {
+    /// Connect to the QMP socket and negotiate capabilities.
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let stream = timeout(QMP_TIMEOUT, UnixStream::connect(path))
+            .await
+            .context("Timed out connecting to QMP socket")?
+            .with_context(|| format!("Failed to connect to QMP socket {}", path.display()))?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let greeting = client.read_message().await?;
+        if greeting.get("QMP").is_none() {
+            bail!("Unexpected QMP greeting: {greeting}");
+        }
+        client.execute("qmp_capabilities", None).await?;
+        Ok(client)
+    }
+
+    /// Connect to a QEMU guest agent socket. The agent speaks QMP without a greeting, replies
+    /// left over from an earlier client are skipped with `guest-sync`.
+    pub async fn connect_guest_agent(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let stream = timeout(QMP_TIMEOUT, UnixStream::connect(path))
+            .await
+            .context("Timed out connecting to guest agent socket")?
+            .with_context(|| {
+                format!("Failed to connect to guest agent socket {}", path.display())
+            })?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let id = rand::random::<u32>() as u64;
+        client.send("guest-sync", Some(json!({ "id": id }))).await?;
+        loop {
+            let message = client
+                .read_message()
+                .await
+                .context("Guest agent did not answer, is it running in the guest?")?;
+            if message.get("return").and_then(Value::as_u64) == Some(id) {
+                return Ok(client);
+            }
+        }
+    }
+
+    async fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<()> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut buf = serde_json::to_vec(&request)?;
+        buf.push(b'\n');
+        self.writer
+            .write_all(&buf)
+            .await
+            .context("Failed to write to QMP socket")
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        let n = timeout(QMP_TIMEOUT, self.reader.read_line(&mut line))
+            .await
+            .context("Timed out reading from QMP socket")?
+            .context("Failed to read from QMP socket")?;
+        if n == 0 {
+            bail!("QMP connection closed");
+        }
+        serde_json::from_str(&line).context("Invalid QMP message")
+    }
+
+    /// Wait for the next asynchronous event, however long it takes. Only for a monitor no
+    /// commands are sent on after the capabilities negotiation.
+    pub async fn next_event(&mut self) -> Result<Value> {
+        loop {
+            let mut line = String::new();
+            let n = self
+                .reader
+                .read_line(&mut line)
+                .await
+                .context("Failed to read from QMP socket")?;
+            if n == 0 {
+                bail!("QMP connection closed");
+            }
+            let message: Value = serde_json::from_str(&line).context("Invalid QMP message")?;
+            if message.get("event").is_some() {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Execute a QMP command and return its `return` value.
+    pub async fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        self.send(command, arguments).await?;
+        loop {
+            let message = self.read_message().await?;
+            if let Some(ret) = message.get("return") {
+                return Ok(ret.clone());
+            }
+            if let Some(err) = message.get("error") {
+                let desc = err
+                    .get("desc")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error");
+                bail!("QMP command {command} failed: {desc}");
+            }
+            // Asynchronous events may be interleaved with command responses, skip them.
+        }
+    }
+
+    /// Execute a guest agent command that only replies if it fails, such as `guest-shutdown`.
+    pub async fn execute_no_reply(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<()> {
+        self.send(command, arguments).await?;
+        let mut line = String::new();
+        let Ok(Ok(n)) = timeout(NO_REPLY_WAIT, self.reader.read_line(&mut line)).await else {
+            return Ok(());
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        let message: Value = serde_json::from_str(&line).context("Invalid QMP message")?;
+        if let Some(err) = message.get("error") {
+            let desc = err
+                .get("desc")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            bail!("Command {command} failed: {desc}");
+        }
+        Ok(())
+    }
+}
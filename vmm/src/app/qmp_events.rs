@@ -0,0 +1,149 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forwarding of the QMP events of running VMs as VM events.
+//!
+//! Each QEMU gets a second QMP monitor used only to listen for events, so the watcher never
+//! holds the command monitor. Notable events become `vm.*` events in `GetVmEvents` and the
+//! webhooks, other events a VM subscribes to are forwarded as `vm.qmp_event`.
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use super::{App, QmpClient};
+
+/// Events forwarded for VMs that do not choose their own.
+const DEFAULT_EVENTS: &[&str] = &["GUEST_PANICKED", "BLOCK_IO_ERROR", "RESET", "SHUTDOWN"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QmpEventsConfig {
+    /// QMP events to forward, [`DEFAULT_EVENTS`] if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    /// Attach a pvpanic device and make QEMU exit with a failure when the guest panics, so
+    /// the restart policy of the VM applies
+    #[serde(default)]
+    pub restart_on_panic: bool,
+}
+
+impl QmpEventsConfig {
+    pub fn to_pb(&self) -> pb::QmpEventsConfig {
+        pb::QmpEventsConfig {
+            events: self.events.clone(),
+            restart_on_panic: self.restart_on_panic,
+        }
+    }
+
+    fn forwards(&self, event: &str) -> bool {
+        // Only raised by VMs with a watchdog device, which always want to know it fired
+        if event == "WATCHDOG" {
+            return true;
+        }
+        match self.events.is_empty() {
+            true => DEFAULT_EVENTS.contains(&event),
+            false => self.events.iter().any(|e| e == event),
+        }
+    }
+
+    /// QEMU arguments for a guest panic to be reported and, if asked, to end QEMU.
+    pub fn qemu_args(&self) -> Vec<&'static str> {
+        let mut args = vec!["-device", "pvpanic-pci"];
+        if self.restart_on_panic {
+            args.extend(["-action", "panic=exit-failure"]);
+        }
+        args
+    }
+}
+
+pub fn resolve_qmp_events(config: &pb::QmpEventsConfig) -> Result<QmpEventsConfig> {
+    for event in &config.events {
+        if event.is_empty()
+            || !event
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            bail!("Invalid QMP event name: {event}");
+        }
+    }
+    Ok(QmpEventsConfig {
+        events: config.events.clone(),
+        restart_on_panic: config.restart_on_panic,
+    })
+}
+
+/// The VM event a QMP event is reported as.
+fn vm_event_name(event: &str) -> &'static str {
+    match event {
+        "GUEST_PANICKED" => "vm.guest_panicked",
+        "BLOCK_IO_ERROR" => "vm.block_io_error",
+        "RESET" => "vm.reset",
+        "SHUTDOWN" => "vm.guest_shutdown",
+        "WATCHDOG" => "vm.watchdog_fired",
+        _ => "vm.qmp_event",
+    }
+}
+
+impl App {
+    /// Start a QMP event watcher for each running VM that has none.
+    pub(crate) async fn watch_qmp_events(&self) -> Result<()> {
+        let running = self
+            .list_processes()
+            .await?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .map(|p| p.config.id)
+            .collect::<Vec<_>>();
+        for id in running {
+            {
+                let mut state = self.lock();
+                if state.get(&id).is_none() || !state.qmp_watchers.insert(id.clone()) {
+                    continue;
+                }
+            }
+            let app = self.clone();
+            tokio::spawn(async move {
+                // Ends when QEMU exits and closes the monitor
+                if let Err(err) = app.forward_qmp_events(&id).await {
+                    debug!("QMP event watcher of VM {id} stopped: {err:#}");
+                }
+                app.lock().qmp_watchers.remove(&id);
+            });
+        }
+        Ok(())
+    }
+
+    /// Forward the QMP events of VM `id` until its QEMU exits.
+    async fn forward_qmp_events(&self, id: &str) -> Result<()> {
+        let mut qmp = QmpClient::connect(self.work_dir(id).qmp_events_socket()).await?;
+        loop {
+            let message = qmp.next_event().await?;
+            let event = message
+                .get("event")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let config = self
+                .lock()
+                .get(id)
+                .and_then(|vm| vm.config.manifest.qmp_events.clone())
+                .unwrap_or_default();
+            if !config.forwards(event) {
+                continue;
+            }
+            let data = message.get("data").cloned().unwrap_or(Value::Null);
+            match event {
+                "GUEST_PANICKED" => warn!("Guest of VM {id} panicked: {data}"),
+                "BLOCK_IO_ERROR" => warn!("Block I/O error on VM {id}: {data}"),
+                "WATCHDOG" => warn!("Watchdog of VM {id} fired: {data}"),
+                _ => info!("VM {id} QMP event {event}: {data}"),
+            }
+            self.emit_event(
+                vm_event_name(event),
+                Some(id),
+                json!({ "qmp_event": event, "data": data }),
+            );
+        }
+    }
+}
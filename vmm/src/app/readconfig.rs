@@ -0,0 +1,332 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Moving options out of a QEMU command line too long to launch.
+//!
+//! A VM with many devices can get a command line over what `execve` takes. Above
+//! `cvm.qemu_max_argv_bytes` the `-device`, `-object`, `-netdev`, `-chardev` and `-drive`
+//! options are written to `qemu.cfg` in the VM work dir instead, which QEMU reads with
+//! `-readconfig`. An option group is only moved if all its options can be expressed in the
+//! config file, so the order of the options within a group is kept.
+use std::path::Path;
+
+use supervisor_client::supervisor::ProcessConfig;
+use tracing::info;
+
+use super::{VmConfig, VmWorkDir};
+use crate::config::CvmConfig;
+
+/// Options `-readconfig` can take, with their config group and the key of their leading
+/// implied value.
+const MOVABLE: &[(&str, &str, Option<&str>)] = &[
+    ("-device", "device", Some("driver")),
+    ("-object", "object", Some("qom-type")),
+    ("-netdev", "netdev", Some("type")),
+    ("-chardev", "chardev", Some("backend")),
+    ("-drive", "drive", None),
+];
+
+/// Longest key, id and value the QEMU config parser reads.
+const MAX_NAME_LEN: usize = 63;
+const MAX_VALUE_LEN: usize = 1023;
+
+/// Bytes the arguments of `process` take on the stack of the new process.
+pub fn argv_bytes(process: &ProcessConfig) -> usize {
+    std::iter::once(&process.command)
+        .chain(&process.args)
+        .map(|arg| arg.len() + 1)
+        .sum()
+}
+
+/// Whether the VM process reads part of its options from a config file.
+pub fn uses_readconfig(process: &ProcessConfig) -> bool {
+    process.args.iter().any(|arg| arg == "-readconfig")
+}
+
+/// Split QemuOpts `key=value,...` into its parts, `,,` being a literal comma.
+fn split_opts(opts: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut chars = opts.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != ',' {
+            part.push(c);
+        } else if chars.peek() == Some(&',') {
+            chars.next();
+            part.push(',');
+        } else {
+            parts.push(std::mem::take(&mut part));
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+/// The config file section of `opts` of the option `flag`, `None` if it can not be expressed.
+fn section(flag: &str, opts: &str) -> Option<String> {
+    let (_, group, implied) = MOVABLE.iter().find(|(f, _, _)| *f == flag)?;
+    if opts.starts_with('{') {
+        return None;
+    }
+    let mut id = None;
+    let mut entries = vec![];
+    for (i, part) in split_opts(opts).into_iter().enumerate() {
+        let (key, value) = match (part.split_once('='), implied) {
+            (Some((key, value)), _) => (key.to_string(), value.to_string()),
+            (None, Some(implied)) if i == 0 => (implied.to_string(), part),
+            (None, _) => (part, "on".to_string()),
+        };
+        if key.is_empty()
+            || key.len() > MAX_NAME_LEN
+            || value.len() > MAX_VALUE_LEN
+            || value.contains('"')
+        {
+            return None;
+        }
+        if key == "id" {
+            if value.len() > MAX_NAME_LEN {
+                return None;
+            }
+            id = Some(value);
+        } else {
+            entries.push((key, value));
+        }
+    }
+    let mut section = match id {
+        Some(id) => format!("\n[{group} \"{id}\"]\n"),
+        None => format!("\n[{group}]\n"),
+    };
+    for (key, value) in entries {
+        section.push_str(&format!("  {key} = \"{value}\"\n"));
+    }
+    Some(section)
+}
+
+/// Move the options of the QEMU command line of `process` into a config file read from
+/// `path` if the arguments take more than `max_bytes`. Returns the contents of the file.
+pub fn split_long_argv(
+    process: &mut ProcessConfig,
+    qemu: &str,
+    path: &Path,
+    max_bytes: usize,
+) -> Option<String> {
+    if max_bytes == 0 || argv_bytes(process) <= max_bytes {
+        return None;
+    }
+    // Wrappers such as taskset come before the QEMU binary
+    let start = process
+        .args
+        .iter()
+        .position(|arg| arg == qemu)
+        .map_or(0, |i| i + 1);
+    let qemu_args = &process.args[start..];
+    let mut sections = vec![];
+    let mut i = 0;
+    while i < qemu_args.len() {
+        let flag = &qemu_args[i];
+        match qemu_args.get(i + 1) {
+            Some(opts) if MOVABLE.iter().any(|(f, _, _)| f == flag) => {
+                sections.push((i, flag.as_str(), section(flag, opts)));
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    let movable = |flag: &str| {
+        sections
+            .iter()
+            .filter(|(_, f, _)| *f == flag)
+            .all(|(_, _, section)| section.is_some())
+    };
+    let mut config = String::from("# Options of the QEMU command line, read with -readconfig\n");
+    let mut moved = vec![];
+    for (i, flag, section) in &sections {
+        if let (true, Some(section)) = (movable(flag), section) {
+            config.push_str(section);
+            moved.extend([*i, *i + 1]);
+        }
+    }
+    if moved.is_empty() {
+        return None;
+    }
+    let mut args = process.args[..start].to_vec();
+    args.extend(
+        qemu_args
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !moved.contains(i))
+            .map(|(_, arg)| arg.clone()),
+    );
+    args.extend(["-readconfig".into(), path.display().to_string()]);
+    process.args = args;
+    Some(config)
+}
+
+impl VmConfig {
+    /// Move options of the VM process among `processes` into the QEMU config file of
+    /// `workdir` if its command line is longer than `cvm.qemu_max_argv_bytes`. Returns the
+    /// contents of the file to write.
+    pub fn split_long_argv(
+        &self,
+        processes: &mut [ProcessConfig],
+        workdir: &VmWorkDir,
+        cfg: &CvmConfig,
+    ) -> Option<String> {
+        let process = processes.iter_mut().find(|p| p.id == self.manifest.id)?;
+        let bytes = argv_bytes(process);
+        let config = split_long_argv(
+            process,
+            &cfg.qemu_path.to_string_lossy(),
+            &workdir.qemu_config_file(),
+            cfg.qemu_max_argv_bytes,
+        )?;
+        info!(
+            "Command line of VM {} takes {bytes} bytes, over {}, moved options to {}",
+            self.manifest.id,
+            cfg.qemu_max_argv_bytes,
+            workdir.qemu_config_file().display()
+        );
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QEMU: &str = "/usr/bin/qemu-system-x86_64";
+
+    fn process(args: &[&str]) -> ProcessConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "vm-1",
+            "command": "/usr/bin/taskset",
+            "args": args,
+        }))
+        .unwrap()
+    }
+
+    type Opts = Vec<(String, String)>;
+
+    fn parse_opts(flag: &str, opts: &str) -> Opts {
+        let implied = MOVABLE.iter().find(|(f, _, _)| *f == flag).unwrap().2;
+        let mut opts = split_opts(opts)
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| match (part.split_once('='), implied) {
+                (Some((key, value)), _) => (key.to_string(), value.to_string()),
+                (None, Some(implied)) if i == 0 => (implied.to_string(), part),
+                (None, _) => (part, "on".to_string()),
+            })
+            .collect::<Vec<_>>();
+        opts.sort();
+        opts
+    }
+
+    /// Options as QEMU sees them, reading the sections of `config` for `-readconfig`. Options
+    /// are grouped by flag, keeping their order within a group.
+    fn options(args: &[String], config: &str) -> Vec<(String, Opts)> {
+        let mut options = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-readconfig" {
+                args.next();
+                for line in config.lines().map(str::trim) {
+                    if let Some(header) = line.strip_prefix('[') {
+                        let header = header.strip_suffix(']').unwrap();
+                        let (group, id) = match header.split_once(' ') {
+                            Some((group, id)) => (group, Some(id.trim_matches('"'))),
+                            None => (header, None),
+                        };
+                        let opts: Opts = id
+                            .map(|id| ("id".to_string(), id.to_string()))
+                            .into_iter()
+                            .collect();
+                        options.push((format!("-{group}"), opts));
+                    } else if let Some((key, value)) = line.split_once(" = ") {
+                        let opts: &mut Opts = &mut options.last_mut().unwrap().1;
+                        opts.push((key.to_string(), value.trim_matches('"').to_string()));
+                        opts.sort();
+                    }
+                }
+            } else if MOVABLE.iter().any(|(f, _, _)| f == arg) {
+                let opts = args.next().unwrap();
+                options.push((arg.clone(), parse_opts(arg, opts)));
+            } else {
+                options.push((arg.clone(), vec![]));
+            }
+        }
+        options.sort_by(|a, b| a.0.cmp(&b.0));
+        options
+    }
+
+    #[test]
+    fn keeps_short_command_lines() {
+        let mut process = process(&["-c", "0", QEMU, "-device", "virtio-rng-pci"]);
+        let args = process.args.clone();
+        let max = argv_bytes(&process);
+        assert!(split_long_argv(&mut process, QEMU, Path::new("/vm/qemu.cfg"), max).is_none());
+        assert!(split_long_argv(&mut process, QEMU, Path::new("/vm/qemu.cfg"), 0).is_none());
+        assert_eq!(process.args, args);
+    }
+
+    #[test]
+    fn moved_options_round_trip() {
+        let mut process = process(&[
+            "-c",
+            "0-1",
+            QEMU,
+            "-machine",
+            "q35",
+            "-netdev",
+            "user,id=net0,hostfwd=tcp::8080-:80",
+            "-device",
+            "virtio-net-pci,netdev=net0,mac=02:00:00:00:00:01",
+            "-drive",
+            "file=/vm/a,,b.img,if=none,id=hd0,readonly",
+            "-device",
+            "virtio-blk-pci,drive=hd0",
+            "-object",
+            "memory-backend-memfd,id=mem,size=2G,share=on",
+            "-nographic",
+        ]);
+        let original = process.args.clone();
+        let config = split_long_argv(&mut process, QEMU, Path::new("/vm/qemu.cfg"), 64).unwrap();
+        assert_eq!(
+            process.args,
+            [
+                "-c",
+                "0-1",
+                QEMU,
+                "-machine",
+                "q35",
+                "-nographic",
+                "-readconfig",
+                "/vm/qemu.cfg"
+            ]
+        );
+        assert!(config.contains("[drive \"hd0\"]\n  file = \"/vm/a,b.img\"\n"));
+        assert_eq!(options(&process.args, &config), options(&original, ""));
+    }
+
+    #[test]
+    fn keeps_groups_the_config_can_not_express() {
+        let mut process = process(&[
+            QEMU,
+            "-device",
+            "virtio-rng-pci",
+            "-device",
+            r#"{"driver":"virtio-blk-pci","drive":"hd0"}"#,
+            "-chardev",
+            "socket,id=qmp,path=/vm/qmp.sock,server=on,wait=off",
+        ]);
+        let original = process.args.clone();
+        let config = split_long_argv(&mut process, QEMU, Path::new("/vm/qemu.cfg"), 1).unwrap();
+        assert!(!config.contains("[device"));
+        assert_eq!(&process.args[..5], &original[..5]);
+        assert_eq!(
+            options(&process.args[5..], &config),
+            options(&original[5..], "")
+        );
+    }
+}
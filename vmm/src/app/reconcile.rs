@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic reconciliation of the supervisor processes with the VMs the VMM knows.
+//!
+//! The supervisor and the VMM restart independently, and afterwards they can disagree about
+//! what runs. A running process of a VM the VMM has not loaded is picked up if the VM has a work
+//! dir, and a VM marked started that the supervisor has no process for is reported as exited,
+//! so auto-restart can act. Other mismatches are only reported.
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::App;
+use crate::config::ProcessAnnotation;
+
+/// Outcome of a reconciliation run.
+#[derive(Debug, Clone, Default)]
+pub struct ReconcileReport {
+    pub finished_at: Option<SystemTime>,
+    /// Running processes of unloaded VMs that were loaded
+    pub adopted: Vec<String>,
+    /// Started VMs without a supervisor process, reported as exited
+    pub missing: Vec<String>,
+    /// Running processes without a VM work dir, left alone
+    pub orphaned: Vec<String>,
+    /// Running processes whose CID differs from the CID of their VM
+    pub cid_mismatches: Vec<String>,
+    /// Why the run failed, the other fields are empty then
+    pub error: Option<String>,
+}
+
+impl ReconcileReport {
+    pub fn to_pb(&self) -> pb::ReconcileStatus {
+        pb::ReconcileStatus {
+            finished_at_ms: self
+                .finished_at
+                .map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64),
+            adopted: self.adopted.clone(),
+            missing: self.missing.clone(),
+            orphaned: self.orphaned.clone(),
+            cid_mismatches: self.cid_mismatches.clone(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+impl App {
+    /// Reconcile the supervisor processes with the loaded VMs and keep the report.
+    pub(crate) async fn reconcile_supervisor(&self) {
+        let mut report = match self.try_reconcile_supervisor().await {
+            Ok(report) => report,
+            Err(err) => {
+                warn!("Failed to reconcile VMs with the supervisor: {err:?}");
+                ReconcileReport {
+                    error: Some(format!("{err:#}")),
+                    ..Default::default()
+                }
+            }
+        };
+        report.finished_at = Some(SystemTime::now());
+        self.lock().last_reconcile = report;
+    }
+
+    pub fn last_reconcile(&self) -> ReconcileReport {
+        self.lock().last_reconcile.clone()
+    }
+
+    async fn try_reconcile_supervisor(&self) -> Result<ReconcileReport> {
+        let processes = self.list_processes().await?;
+        let known = self
+            .lock()
+            .iter_vms()
+            .map(|vm| (vm.config.manifest.id.clone(), vm.config.cid))
+            .collect::<HashMap<_, _>>();
+        let mut report = ReconcileReport::default();
+        let mut supervised = HashSet::new();
+        for process in processes {
+            let note: ProcessAnnotation =
+                serde_json::from_str(&process.config.note).unwrap_or_default();
+            if !note.is_cvm() {
+                continue;
+            }
+            let id = process.config.id.clone();
+            supervised.insert(id.clone());
+            if !process.state.status.is_running() {
+                continue;
+            }
+            match known.get(&id) {
+                Some(cid) => {
+                    if process.config.cid.is_some_and(|c| c != *cid) {
+                        warn!(
+                            "VM {id} runs with CID {:?} but is loaded with CID {cid}",
+                            process.config.cid
+                        );
+                        report.cid_mismatches.push(id);
+                    }
+                }
+                None if self.work_dir(&id).manifest().is_ok() => {
+                    match self.load_supervised(&id, process.config.cid).await {
+                        Ok(()) => {
+                            info!("Loaded VM {id}, which the supervisor runs but was not loaded");
+                            self.emit_event(
+                                "vm.adopt",
+                                Some(&id),
+                                json!({ "pid": process.state.pid, "reconciled": true }),
+                            );
+                            report.adopted.push(id);
+                        }
+                        Err(err) => {
+                            warn!("Failed to load VM {id} run by the supervisor: {err:?}");
+                            report.orphaned.push(id);
+                        }
+                    }
+                }
+                None => {
+                    warn!("The supervisor runs process {id}, which has no VM work dir");
+                    report.orphaned.push(id);
+                }
+            }
+        }
+
+        for id in known.keys().filter(|id| !supervised.contains(*id)) {
+            if !self.work_dir(id).started().unwrap_or(false) {
+                continue;
+            }
+            let newly_reported = self.lock().get_mut(id).is_some_and(|vm| {
+                vm.state.incoming_migration.is_none() && vm.state.restart.mark_exit_reported()
+            });
+            if newly_reported {
+                warn!("VM {id} is started but the supervisor has no process for it");
+                self.emit_event(
+                    "vm.exit",
+                    Some(id),
+                    json!({ "error": "no supervisor process", "missing": true }),
+                );
+            }
+            report.missing.push(id.clone());
+        }
+        Ok(report)
+    }
+
+    /// Load the VM of a running supervisor process, keeping the CID the process runs with.
+    async fn load_supervised(&self, id: &str, cid: Option<u32>) -> Result<()> {
+        let mut cids = HashMap::new();
+        if let Some(cid) = cid {
+            self.lock()
+                .cid_pool
+                .occupy(cid)
+                .with_context(|| format!("CID {cid} is in use by another VM"))?;
+            cids.insert(id.to_string(), cid);
+        }
+        let result = self.load_vm(self.work_dir(id).path(), &cids, false).await;
+        if result.is_err() {
+            if let Some(cid) = cid {
+                self.lock().cid_pool.free(cid);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,107 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Crash and restart statistics of the VMs over a recent window, from the event buffers.
+//!
+//! A failure is an exit of a VM that was meant to be running, reported as `vm.exit`. A guest
+//! panic is counted on its own, as the exit it may lead to is a failure already. Only the
+//! events still held in the per VM buffers are counted, a VM whose buffer dropped events of the
+//! window is reported as truncated, and the events of removed VMs are no longer counted.
+//! The mean time between failures is the window over the failures in it, for the fleet the
+//! window of each VM over the failures of all.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+
+use super::App;
+
+fn vm_stats(
+    id: &str,
+    events: &[(u64, String)],
+    truncated: bool,
+    window: Duration,
+) -> pb::VmReliabilityStats {
+    let mut stats = pb::VmReliabilityStats {
+        id: id.to_string(),
+        truncated,
+        ..Default::default()
+    };
+    for (timestamp_ms, event) in events {
+        match event.as_str() {
+            "vm.exit" => {
+                stats.failures += 1;
+                stats.last_failure_ms = Some(*timestamp_ms);
+            }
+            "vm.restart" => stats.restarts += 1,
+            "vm.crash_loop" => stats.crash_loops += 1,
+            "vm.guest_panicked" => stats.guest_panics += 1,
+            _ => {}
+        }
+    }
+    stats.mtbf_secs = (stats.failures > 0).then(|| window.as_secs() / stats.failures as u64);
+    stats
+}
+
+impl App {
+    /// Failures, restarts and crash loops of VMs `ids`, all if empty, and of all of them
+    /// together over the last `window`.
+    pub fn reliability_stats(
+        &self,
+        ids: &[String],
+        window: Duration,
+    ) -> Result<pb::ReliabilityStats> {
+        if window.is_zero() {
+            bail!("The window must not be empty");
+        }
+        let ids = match ids {
+            [] => {
+                let mut ids = self
+                    .lock()
+                    .iter_vms()
+                    .map(|vm| vm.config.manifest.id.clone())
+                    .collect::<Vec<_>>();
+                ids.sort();
+                ids
+            }
+            ids => {
+                let state = self.lock();
+                if let Some(id) = ids.iter().find(|id| state.get(id).is_none()) {
+                    bail!("VM not found: {id}");
+                }
+                ids.to_vec()
+            }
+        };
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let since_ms = now_ms.saturating_sub(window.as_millis() as u64);
+        let vms = ids
+            .iter()
+            .map(|id| {
+                let (events, truncated) = self.events.vm_events_since(id, since_ms);
+                vm_stats(id, &events, truncated, window)
+            })
+            .collect::<Vec<_>>();
+
+        let mut fleet = pb::VmReliabilityStats::default();
+        for vm in &vms {
+            fleet.failures += vm.failures;
+            fleet.restarts += vm.restarts;
+            fleet.crash_loops += vm.crash_loops;
+            fleet.guest_panics += vm.guest_panics;
+            fleet.last_failure_ms = fleet.last_failure_ms.max(vm.last_failure_ms);
+            fleet.truncated |= vm.truncated;
+        }
+        fleet.mtbf_secs = (fleet.failures > 0)
+            .then(|| window.as_secs() * vms.len() as u64 / fleet.failures as u64);
+        Ok(pb::ReliabilityStats {
+            window_secs: window.as_secs(),
+            since_ms,
+            vms,
+            fleet: Some(fleet),
+        })
+    }
+}
@@ -0,0 +1,179 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Relaunching a VM with a new launch config, keeping its id, name and disks.
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use dstack_types::shared_filenames::{APP_COMPOSE, ENCRYPTED_ENV, INSTANCE_INFO, USER_CONFIG};
+use dstack_vmm_rpc::VmConfiguration;
+use fs_err as fs;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use super::{App, Manifest, VmWorkDir};
+
+/// How long the new QEMU has to stay up for the replacement to count as launched.
+const LAUNCH_GRACE: Duration = Duration::from_secs(3);
+const SHARED_FILES: &[&str] = &[APP_COMPOSE, ENCRYPTED_ENV, USER_CONFIG, INSTANCE_INFO];
+
+/// The manifest and shared files of a VM, to restore if the replacement fails.
+struct Snapshot {
+    manifest: Manifest,
+    shared_files: Vec<(&'static str, Option<Vec<u8>>)>,
+}
+
+impl Snapshot {
+    fn take(work_dir: &VmWorkDir) -> Result<Self> {
+        let manifest = work_dir.manifest().context("Failed to read manifest")?;
+        let shared_dir = work_dir.shared_dir();
+        let shared_files = SHARED_FILES
+            .iter()
+            .map(|name| (*name, fs::read(shared_dir.join(name)).ok()))
+            .collect();
+        Ok(Self {
+            manifest,
+            shared_files,
+        })
+    }
+
+    fn restore(&self, work_dir: &VmWorkDir) -> Result<()> {
+        work_dir
+            .put_manifest(&self.manifest)
+            .context("Failed to write manifest")?;
+        let shared_dir = work_dir.shared_dir();
+        for (name, contents) in &self.shared_files {
+            let path = shared_dir.join(name);
+            match contents {
+                Some(contents) => fs::write(&path, contents)?,
+                None if path.exists() => fs::remove_file(&path)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl App {
+    /// Replace the launch config of VM `id` with `manifest` and `config`, keeping its id,
+    /// creation time and disks. A running VM is stopped and relaunched with the new config,
+    /// and relaunched with its old config if the new one fails to come up.
+    pub async fn replace_vm(
+        &self,
+        id: &str,
+        mut manifest: Manifest,
+        config: &VmConfiguration,
+    ) -> Result<()> {
+        let work_dir = self.work_dir(id);
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let snapshot = Snapshot::take(&work_dir)?;
+        if manifest.disk_size != snapshot.manifest.disk_size {
+            bail!("Cannot change the disk size by replacing a VM, use ResizeVm");
+        }
+        manifest.id = id.to_string();
+        manifest.created_at_ms = snapshot.manifest.created_at_ms;
+        let errors = self
+            .validate_manifest(&manifest)
+            .into_iter()
+            .filter(|f| f.severity == "error")
+            .map(|f| match f.field.as_str() {
+                "" => f.message,
+                field => format!("{field}: {}", f.message),
+            })
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            bail!("Invalid VM config: {}", errors.join("; "));
+        }
+
+        let was_running = self.is_running(id).await?;
+        if was_running {
+            info!("Stopping VM {id} to replace its config");
+            self.stop_vm(id).await.context("Failed to stop VM")?;
+        }
+        if let Err(err) = self
+            .launch_replacement(&work_dir, &manifest, config, was_running)
+            .await
+        {
+            error!("Failed to launch the new config of VM {id}, restoring the old one: {err:?}");
+            let outcome = match self
+                .rollback_replacement(&work_dir, &snapshot, was_running)
+                .await
+            {
+                Ok(()) => "the old config was restored",
+                Err(err) => {
+                    error!("Failed to restore the old config of VM {id}: {err:?}");
+                    "restoring the old config failed too"
+                }
+            };
+            return Err(err.context(format!("Failed to launch the new config, {outcome}")));
+        }
+        self.emit_event(
+            "vm.replace",
+            Some(id),
+            json!({ "name": manifest.name, "relaunched": was_running }),
+        );
+        Ok(())
+    }
+
+    async fn launch_replacement(
+        &self,
+        work_dir: &VmWorkDir,
+        manifest: &Manifest,
+        config: &VmConfiguration,
+        start: bool,
+    ) -> Result<()> {
+        let id = &manifest.id;
+        work_dir
+            .put_manifest(manifest)
+            .context("Failed to write manifest")?;
+        // Drop the files of the old config the new one does not replace
+        let shared_dir = work_dir.shared_dir();
+        for name in [ENCRYPTED_ENV, USER_CONFIG] {
+            let path = shared_dir.join(name);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        self.prepare_work_dir(id, config, &manifest.app_id)?;
+        self.load_vm(work_dir, &Default::default(), false)
+            .await
+            .context("Failed to load VM")?;
+        if !start {
+            return Ok(());
+        }
+        self.start_vm(id).await?;
+        tokio::time::sleep(LAUNCH_GRACE).await;
+        if !self.is_running(id).await? {
+            let stderr = work_dir
+                .stderr_tail(20, &self.config.log_limits)
+                .unwrap_or_default();
+            bail!("QEMU exited right after launch: {}", stderr.join("\n"));
+        }
+        Ok(())
+    }
+
+    async fn rollback_replacement(
+        &self,
+        work_dir: &VmWorkDir,
+        snapshot: &Snapshot,
+        start: bool,
+    ) -> Result<()> {
+        let id = &snapshot.manifest.id;
+        if start {
+            if let Err(err) = self.stop_vm(id).await {
+                warn!("Failed to stop the new launch of VM {id}: {err:?}");
+            }
+        }
+        snapshot.restore(work_dir)?;
+        self.load_vm(work_dir, &Default::default(), false)
+            .await
+            .context("Failed to load VM")?;
+        if start {
+            self.start_vm(id).await?;
+        }
+        Ok(())
+    }
+}
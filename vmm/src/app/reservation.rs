@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reservations of a VM id, name and resources ahead of the VM creation.
+//!
+//! Expired reservations are dropped whenever reservations are consulted, so their resources are
+//! free again without a background task.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use tracing::info;
+
+use super::{App, AppState, VmmError};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+const MAX_TTL: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Reservation {
+    id: String,
+    name: String,
+    vcpu: u32,
+    /// MB
+    memory: u32,
+    /// GB
+    disk_size: u32,
+    expires_at: SystemTime,
+}
+
+impl Reservation {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_pb(&self) -> pb::VmReservation {
+        pb::VmReservation {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            vcpu: self.vcpu,
+            memory: self.memory,
+            disk_size: self.disk_size,
+            expires_at_ms: self
+                .expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+
+    /// Check a VM config fits into the reservation.
+    fn check_fits(&self, config: &pb::VmConfiguration) -> Result<()> {
+        if !config.name.is_empty() && config.name != self.name {
+            bail!(
+                "VM name {} does not match the reserved name {}",
+                config.name,
+                self.name
+            );
+        }
+        for (what, requested, reserved) in [
+            ("vCPUs", config.vcpu, self.vcpu),
+            ("MB of memory", config.memory, self.memory),
+            ("GB of disk", config.disk_size, self.disk_size),
+        ] {
+            if requested > reserved {
+                bail!("VM requests {requested} {what}, only {reserved} are reserved");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AppState {
+    fn purge_expired_reservations(&mut self) {
+        let now = SystemTime::now();
+        self.reservations.retain(|id, r| {
+            let live = r.expires_at > now;
+            if !live {
+                info!("Reservation {id} expired");
+            }
+            live
+        });
+    }
+}
+
+impl App {
+    pub fn reserve_vm(&self, request: pb::ReserveVmRequest) -> Result<pb::VmReservation> {
+        self.ensure_not_draining()?;
+        if request.name.is_empty() {
+            bail!("Reservation name is required");
+        }
+        let ttl = match request.ttl_secs {
+            0 => DEFAULT_TTL,
+            secs => Duration::from_secs(secs),
+        };
+        if ttl > MAX_TTL {
+            bail!("Reservation TTL must not exceed {}s", MAX_TTL.as_secs());
+        }
+        let cfg = &self.config.cvm;
+        let mut state = self.lock();
+        state.purge_expired_reservations();
+        if state.reservations.values().any(|r| r.name == request.name)
+            || state
+                .vms
+                .values()
+                .any(|vm| vm.config.manifest.name == request.name)
+        {
+            bail!("VM name {} is already in use", request.name);
+        }
+        let vms = state.vms.values().map(|vm| &vm.config.manifest);
+        let reservations = state.reservations.values();
+        let vcpu = vms.clone().map(|m| m.vcpu as u64).sum::<u64>()
+            + reservations.clone().map(|r| r.vcpu as u64).sum::<u64>();
+        let memory = vms.map(|m| m.memory as u64).sum::<u64>()
+            + reservations.map(|r| r.memory as u64).sum::<u64>();
+        for (what, used, requested, max) in [
+            ("vCPUs", vcpu, request.vcpu, cfg.max_allocable_vcpu),
+            (
+                "MB of memory",
+                memory,
+                request.memory,
+                cfg.max_allocable_memory_in_mb,
+            ),
+        ] {
+            if max > 0 && used + requested as u64 > max as u64 {
+                return Err(VmmError::ResourceExhausted(format!(
+                    "{requested} {what} requested, {} of {max} available",
+                    (max as u64).saturating_sub(used)
+                ))
+                .into());
+            }
+        }
+        let reservation = Reservation {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: request.name,
+            vcpu: request.vcpu,
+            memory: request.memory,
+            disk_size: request.disk_size,
+            expires_at: SystemTime::now() + ttl,
+        };
+        info!(
+            "Reserved VM {} ({}) for {}s",
+            reservation.id,
+            reservation.name,
+            ttl.as_secs()
+        );
+        let response = reservation.to_pb();
+        state
+            .reservations
+            .insert(reservation.id.clone(), reservation);
+        Ok(response)
+    }
+
+    /// Take a live reservation for `config`, failing if the config exceeds it.
+    ///
+    /// The reservation is removed so concurrent commits cannot both use it. Give it back with
+    /// [`Self::restore_reservation`] if the VM could not be created.
+    pub(crate) fn claim_reservation(
+        &self,
+        id: &str,
+        config: &pb::VmConfiguration,
+    ) -> Result<Reservation> {
+        let mut state = self.lock();
+        state.purge_expired_reservations();
+        let reservation = state
+            .reservations
+            .remove(id)
+            .context("Reservation not found or expired")?;
+        if let Err(err) = reservation.check_fits(config) {
+            state.reservations.insert(id.to_string(), reservation);
+            return Err(err);
+        }
+        Ok(reservation)
+    }
+
+    pub(crate) fn restore_reservation(&self, reservation: Reservation) {
+        self.lock()
+            .reservations
+            .insert(reservation.id.clone(), reservation);
+    }
+
+    pub fn cancel_reservation(&self, id: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.purge_expired_reservations();
+        state
+            .reservations
+            .remove(id)
+            .context("Reservation not found or expired")?;
+        info!("Reservation {id} cancelled");
+        Ok(())
+    }
+
+    /// Fail if `name` is held by a live reservation.
+    pub(crate) fn ensure_name_not_reserved(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.purge_expired_reservations();
+        if state.reservations.values().any(|r| r.name == name) {
+            bail!("VM name {name} is reserved");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,640 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Auto-restart of exited VMs with crash-loop backoff.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use supervisor_client::supervisor::ProcessStatus;
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+use super::App;
+use crate::config::AutoRestartConfig;
+
+/// Lines of QEMU stderr logged when a VM is found exited.
+const EXIT_STDERR_LINES: usize = 20;
+
+/// Bounds of the auto-restart pacing set at runtime.
+const MAX_INTERVAL: u64 = 3600;
+const MAX_JITTER: u64 = 3600;
+const MAX_START_CONCURRENCY: u32 = 64;
+
+/// Pacing of auto-restart, from `cvm.auto_restart` until changed with `SetAutoRestartParams`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoRestartParams {
+    /// Seconds between checks for exited VMs, also the first restart backoff
+    pub interval: u64,
+    pub jitter: u64,
+    pub start_concurrency: u32,
+}
+
+impl AutoRestartParams {
+    pub fn from_config(cfg: &AutoRestartConfig) -> Self {
+        Self {
+            interval: cfg.interval.max(1),
+            jitter: cfg.jitter,
+            start_concurrency: cfg.start_concurrency.max(1),
+        }
+    }
+
+    pub fn to_pb(&self) -> pb::AutoRestartParams {
+        pb::AutoRestartParams {
+            interval: self.interval,
+            jitter: self.jitter,
+            start_concurrency: self.start_concurrency,
+        }
+    }
+
+    /// These params with the values set in `request` replaced.
+    fn updated(&self, request: &pb::SetAutoRestartParamsRequest) -> Result<Self> {
+        if request
+            .interval
+            .is_some_and(|n| !(1..=MAX_INTERVAL).contains(&n))
+        {
+            bail!("Interval must be within 1-{MAX_INTERVAL} seconds");
+        }
+        if request.jitter.is_some_and(|n| n > MAX_JITTER) {
+            bail!("Jitter must be within 0-{MAX_JITTER} seconds");
+        }
+        if request
+            .start_concurrency
+            .is_some_and(|n| !(1..=MAX_START_CONCURRENCY).contains(&n))
+        {
+            bail!("Start concurrency must be within 1-{MAX_START_CONCURRENCY}");
+        }
+        Ok(Self {
+            interval: request.interval.unwrap_or(self.interval),
+            jitter: request.jitter.unwrap_or(self.jitter),
+            start_concurrency: request.start_concurrency.unwrap_or(self.start_concurrency),
+        })
+    }
+}
+
+/// When an exited VM is restarted, following the Docker restart policies.
+///
+/// A VM stopped by an operator is never restarted while the VMM is running. `always` differs
+/// from `unless-stopped` in also starting such VMs when the VMM itself starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RestartPolicy {
+    Always,
+    /// Only restart if QEMU exited with an error, giving up after `max_retries` restarts in a
+    /// row (0 falls back to `cvm.auto_restart.max_attempts`)
+    OnFailure {
+        max_retries: u32,
+    },
+    #[default]
+    UnlessStopped,
+    No,
+}
+
+impl RestartPolicy {
+    fn should_restart(&self, status: Option<&ProcessStatus>, exit_codes: &ExitCodes) -> bool {
+        match self {
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+            RestartPolicy::OnFailure { .. } => match status {
+                Some(status) => exit_codes.classify(status) != Some(ExitClass::Success),
+                None => true,
+            },
+            RestartPolicy::No => false,
+        }
+    }
+
+    fn max_retries(&self, default: u32) -> u32 {
+        match self {
+            RestartPolicy::OnFailure { max_retries } if *max_retries > 0 => *max_retries,
+            _ => default,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "always" => RestartPolicy::Always,
+            "on-failure" => RestartPolicy::OnFailure { max_retries: 0 },
+            "unless-stopped" => RestartPolicy::UnlessStopped,
+            "no" => RestartPolicy::No,
+            _ => match s.strip_prefix("on-failure:") {
+                Some(n) => RestartPolicy::OnFailure {
+                    max_retries: n
+                        .parse()
+                        .with_context(|| format!("Invalid max retries: {n}"))?,
+                },
+                None => bail!("Invalid restart policy: {s}"),
+            },
+        })
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::OnFailure { max_retries: 0 } => write!(f, "on-failure"),
+            RestartPolicy::OnFailure { max_retries } => write!(f, "on-failure:{max_retries}"),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+            RestartPolicy::No => write!(f, "no"),
+        }
+    }
+}
+
+impl TryFrom<String> for RestartPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<RestartPolicy> for String {
+    fn from(policy: RestartPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+/// Exit codes of QEMU counted as a clean exit or a failure by the `on-failure` policy.
+///
+/// A code listed in `failure` is a failure. Otherwise a code listed in `success` is a clean
+/// exit, and any other code is a failure unless `failure` is set. QEMU killed by a signal or
+/// failing to be waited on is always a failure.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitCodes {
+    /// Clean exits, `[0]` if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub success: Vec<i32>,
+    /// Failures, taking precedence over `success`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitClass {
+    Success,
+    Failure,
+}
+
+impl ExitClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitClass::Success => "success",
+            ExitClass::Failure => "failure",
+        }
+    }
+}
+
+impl ExitCodes {
+    pub fn validate(&self) -> Result<()> {
+        for code in self.success.iter().chain(&self.failure) {
+            if !(0..=255).contains(code) {
+                bail!("Invalid exit code: {code}, must be within 0-255");
+            }
+        }
+        Ok(())
+    }
+
+    /// Classify how QEMU exited, `None` if it is running or was stopped by the VMM.
+    pub fn classify(&self, status: &ProcessStatus) -> Option<ExitClass> {
+        let code = match status {
+            ProcessStatus::Running | ProcessStatus::Stopped => return None,
+            ProcessStatus::Error(_) => return Some(ExitClass::Failure),
+            ProcessStatus::Exited(raw) => match exit_code(*raw) {
+                Some(code) => code,
+                None => return Some(ExitClass::Failure),
+            },
+        };
+        let success = match self.success.is_empty() {
+            true => code == 0,
+            false => self.success.contains(&code),
+        };
+        let class = if self.failure.contains(&code) {
+            ExitClass::Failure
+        } else if success || !self.failure.is_empty() {
+            ExitClass::Success
+        } else {
+            ExitClass::Failure
+        };
+        Some(class)
+    }
+}
+
+/// Exit code of a raw wait status as reported by the supervisor, `None` if the process was
+/// killed by a signal.
+pub fn exit_code(raw: i32) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    std::process::ExitStatus::from_raw(raw).code()
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RestartState {
+    /// Auto-restarts since the VM last stayed up for `max_backoff`
+    failures: u32,
+    last_attempt: Option<Instant>,
+    /// No auto-restart is attempted before this
+    next_attempt: Option<Instant>,
+    /// Auto-restart gave up on the VM
+    crash_looping: bool,
+    /// The `vm.exit` webhook was sent for the current exit
+    exit_reported: bool,
+}
+
+impl RestartState {
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn crash_looping(&self) -> bool {
+        self.crash_looping
+    }
+
+    /// Mark the current exit as reported, returns false if it already was.
+    pub(crate) fn mark_exit_reported(&mut self) -> bool {
+        !std::mem::replace(&mut self.exit_reported, true)
+    }
+
+    fn is_clear(&self) -> bool {
+        self.failures == 0 && !self.crash_looping
+    }
+
+    /// The VM is running, forget its restarts once it stayed up for `reset_after` since the
+    /// last one.
+    fn running(&mut self, now: Instant, reset_after: Duration) {
+        self.exit_reported = false;
+        let stable = self
+            .last_attempt
+            .is_some_and(|t| now.duration_since(t) >= reset_after);
+        if stable && !self.is_clear() {
+            *self = RestartState::default();
+        }
+    }
+
+    /// Whether the backoff of the last restart has passed.
+    fn due(&self, now: Instant) -> bool {
+        self.next_attempt.is_none_or(|t| now >= t)
+    }
+
+    /// Give up on the VM if it already used its `max_attempts` restarts, 0 for no limit.
+    fn give_up(&mut self, max_attempts: u32) -> bool {
+        if max_attempts > 0 && self.failures >= max_attempts {
+            self.crash_looping = true;
+        }
+        self.crash_looping
+    }
+
+    /// Count a restart made `delay` after `now`, returns its attempt number.
+    fn record_attempt(
+        &mut self,
+        now: Instant,
+        delay: Duration,
+        params: &AutoRestartParams,
+        max_backoff: u64,
+    ) -> u32 {
+        self.failures += 1;
+        self.last_attempt = Some(now + delay);
+        self.next_attempt = Some(now + delay + restart_backoff(params, self.failures, max_backoff));
+        self.failures
+    }
+}
+
+/// Delay before the next restart after `failures` restarts in a row, doubling from the
+/// interval up to `max_backoff` seconds.
+fn restart_backoff(params: &AutoRestartParams, failures: u32, max_backoff: u64) -> Duration {
+    let backoff = params
+        .interval
+        .saturating_mul(1 << failures.saturating_sub(1).min(16));
+    Duration::from_secs(backoff.min(max_backoff))
+}
+
+/// Random delay within the jitter window.
+fn restart_jitter(params: &AutoRestartParams) -> Duration {
+    let jitter_ms = params.jitter.saturating_mul(1000);
+    if jitter_ms == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms))
+}
+
+fn exit_details(status: &ProcessStatus) -> Value {
+    match status {
+        ProcessStatus::Exited(code) => json!({ "exit_status": code }),
+        ProcessStatus::Error(err) => json!({ "error": err }),
+        _ => json!({}),
+    }
+}
+
+impl App {
+    pub(crate) async fn try_restart_exited_vms(&self) -> Result<()> {
+        if self.is_draining() {
+            info!("Host is draining, skip restarting exited VMs");
+            return Ok(());
+        }
+        if self.maintenance_mode() {
+            info!("Maintenance mode is on, skip restarting exited VMs");
+            return Ok(());
+        }
+        let processes = self
+            .list_processes()
+            .await?
+            .into_iter()
+            .map(|v| (v.config.id, (v.state.status, v.state.started_at)))
+            .collect::<HashMap<_, _>>();
+        let cfg = &self.config.cvm.auto_restart;
+        let reset_after = Duration::from_secs(cfg.max_backoff);
+        let now = Instant::now();
+        let mut exited_vms = vec![];
+        let mut expired_vms = vec![];
+        let params = self.auto_restart_params();
+        {
+            let mut state = self.lock();
+            for vm in state.vms.values_mut() {
+                let id = &vm.config.manifest.id;
+                let (status, launched_at) = match processes.get(id) {
+                    Some((status, launched_at)) => (Some(status), *launched_at),
+                    None => (None, None),
+                };
+                let lifetime_expired = vm
+                    .lifetime_deadline(launched_at)
+                    .filter(|deadline| *deadline <= SystemTime::now())
+                    .map(|deadline| vm.lifetime_details(deadline));
+                let restart = &mut vm.state.restart;
+                if status.is_some_and(|s| s.is_running()) {
+                    restart.running(now, reset_after);
+                    continue;
+                }
+                if !self.work_dir(id).started().unwrap_or(false) {
+                    continue;
+                }
+                if let Some(status) = status {
+                    if restart.mark_exit_reported() {
+                        self.emit_event("vm.exit", Some(id), exit_details(status));
+                    }
+                }
+                if restart.crash_looping || vm.state.incoming_migration.is_some() {
+                    continue;
+                }
+                // A VM that ran out of its lifetime stays stopped
+                if let Some(details) = lifetime_expired {
+                    expired_vms.push((id.clone(), details));
+                    continue;
+                }
+                let policy = vm.config.manifest.restart_policy.unwrap_or_default();
+                let exit_codes = vm.config.manifest.exit_codes.clone().unwrap_or_default();
+                if !policy.should_restart(status, &exit_codes) {
+                    continue;
+                }
+                if !restart.due(now) {
+                    continue;
+                }
+                if restart.give_up(policy.max_retries(cfg.max_attempts)) {
+                    error!(
+                        "VM {id} is crash looping after {} restarts, auto-restart disabled for it",
+                        restart.failures
+                    );
+                    self.emit_event(
+                        "vm.crash_loop",
+                        Some(id),
+                        json!({ "restarts": restart.failures }),
+                    );
+                    continue;
+                }
+                let delay = restart_jitter(&params);
+                let attempt = restart.record_attempt(now, delay, &params, cfg.max_backoff);
+                exited_vms.push((id.clone(), attempt, delay));
+            }
+        }
+        for (id, details) in expired_vms {
+            self.keep_expired_vm_stopped(&id, details);
+        }
+        // Spread the restarts of VMs that exited together over the jitter window, starting at
+        // most `start_concurrency` of them at a time
+        let semaphore = Arc::new(Semaphore::new(params.start_concurrency as usize));
+        let mut tasks = tokio::task::JoinSet::new();
+        for (id, attempt, delay) in exited_vms {
+            let app = self.clone();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                if !delay.is_zero() {
+                    tokio::time::sleep_until((now + delay).into()).await;
+                }
+                let Ok(_permit) = semaphore.acquire().await else {
+                    return;
+                };
+                if app.is_draining() || app.maintenance_mode() {
+                    info!("Auto-restart paused, skip restarting VM {id}");
+                    return;
+                }
+                // The VM may have been stopped or started by an operator meanwhile
+                if !app.work_dir(&id).started().unwrap_or(false)
+                    || app.is_running(&id).await.unwrap_or(false)
+                {
+                    return;
+                }
+                app.restart_exited_vm(&id, attempt).await;
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    async fn restart_exited_vm(&self, id: &str, attempt: u32) {
+        match self
+            .work_dir(id)
+            .stderr_tail(EXIT_STDERR_LINES, &self.config.log_limits)
+        {
+            Ok(tail) if !tail.is_empty() => {
+                warn!("VM {id} exited, QEMU stderr:\n{}", tail.join("\n"));
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Failed to read QEMU stderr of VM {id}: {err:?}"),
+        }
+        info!("Restarting VM {id}");
+        self.emit_event("vm.restart", Some(id), json!({ "attempt": attempt }));
+        if let Err(err) = self.start_vm(id).await {
+            error!("Failed to restart VM {id}: {err:?}");
+        }
+    }
+
+    pub fn auto_restart_params(&self) -> AutoRestartParams {
+        self.lock().auto_restart_params
+    }
+
+    /// Change the auto-restart pacing until the VMM restarts, leaving unset values as they
+    /// are. Returns the params now in effect.
+    pub fn set_auto_restart_params(
+        &self,
+        request: &pb::SetAutoRestartParamsRequest,
+    ) -> Result<AutoRestartParams> {
+        let params = {
+            let mut state = self.lock();
+            let params = state.auto_restart_params.updated(request)?;
+            state.auto_restart_params = params;
+            params
+        };
+        info!("Auto-restart params set to {params:?}");
+        Ok(params)
+    }
+
+    pub fn maintenance_mode(&self) -> bool {
+        self.lock().maintenance_mode
+    }
+
+    /// Pause or resume auto-restart of all VMs until the VMM restarts.
+    pub fn set_maintenance_mode(&self, enabled: bool) {
+        let previous = std::mem::replace(&mut self.lock().maintenance_mode, enabled);
+        if previous != enabled {
+            warn!(
+                "Maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+            self.emit_event("host.maintenance", None, json!({ "enabled": enabled }));
+        }
+    }
+
+    /// Reset the restart state of a VM, or all VMs if `id` is `None`, so the next
+    /// auto-restart tick retries them. Returns the number of VMs that had any state.
+    pub fn clear_restart_state(&self, id: Option<&str>) -> Result<u32> {
+        let mut state = self.lock();
+        let mut cleared = 0;
+        let mut clear = |restart: &mut RestartState| {
+            if !restart.is_clear() {
+                cleared += 1;
+            }
+            *restart = RestartState::default();
+        };
+        match id {
+            Some(id) => clear(&mut state.get_mut(id).context("VM not found")?.state.restart),
+            None => state
+                .vms
+                .values_mut()
+                .for_each(|vm| clear(&mut vm.state.restart)),
+        }
+        Ok(cleared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(interval: u64, jitter: u64) -> AutoRestartParams {
+        AutoRestartParams {
+            interval,
+            jitter,
+            start_concurrency: 1,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_interval() {
+        let params = params(10, 0);
+        let secs = (1..=5)
+            .map(|failures| restart_backoff(&params, failures, 3600).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(secs, [10, 20, 40, 80, 160]);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let params = params(10, 0);
+        assert_eq!(restart_backoff(&params, 6, 300), Duration::from_secs(300));
+        assert_eq!(
+            restart_backoff(&params, 1000, 300),
+            Duration::from_secs(300)
+        );
+        let params = AutoRestartParams {
+            interval: u64::MAX,
+            ..params
+        };
+        assert_eq!(
+            restart_backoff(&params, 1000, 300),
+            Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_the_window() {
+        assert_eq!(restart_jitter(&params(10, 0)), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(restart_jitter(&params(10, 2)) <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn attempts_wait_for_the_backoff() {
+        let params = params(10, 0);
+        let now = Instant::now();
+        let mut state = RestartState::default();
+        assert!(state.due(now));
+        assert_eq!(state.record_attempt(now, Duration::ZERO, &params, 300), 1);
+        assert!(!state.due(now + Duration::from_secs(9)));
+        assert!(state.due(now + Duration::from_secs(10)));
+
+        let now = now + Duration::from_secs(10);
+        let delay = Duration::from_secs(5);
+        assert_eq!(state.record_attempt(now, delay, &params, 300), 2);
+        assert!(!state.due(now + Duration::from_secs(24)));
+        assert!(state.due(now + Duration::from_secs(25)));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let params = params(10, 0);
+        let now = Instant::now();
+        let mut state = RestartState::default();
+        for _ in 0..3 {
+            assert!(!state.give_up(3));
+            state.record_attempt(now, Duration::ZERO, &params, 300);
+        }
+        assert!(state.give_up(3));
+        assert!(state.crash_looping());
+
+        let mut state = RestartState::default();
+        for _ in 0..10 {
+            state.record_attempt(now, Duration::ZERO, &params, 300);
+        }
+        assert!(!state.give_up(0));
+    }
+
+    #[test]
+    fn resets_after_a_stable_run() {
+        let params = params(10, 0);
+        let reset_after = Duration::from_secs(300);
+        let now = Instant::now();
+        let mut state = RestartState::default();
+        state.record_attempt(now, Duration::ZERO, &params, 300);
+        state.record_attempt(now, Duration::ZERO, &params, 300);
+        assert!(state.mark_exit_reported());
+
+        state.running(now + Duration::from_secs(299), reset_after);
+        assert_eq!(state.failures(), 2);
+        assert!(state.mark_exit_reported());
+
+        state.running(now + reset_after, reset_after);
+        assert!(state.is_clear());
+        assert!(state.due(now + reset_after));
+    }
+
+    #[test]
+    fn crash_loop_resets_after_a_stable_run() {
+        let params = params(10, 0);
+        let now = Instant::now();
+        let mut state = RestartState::default();
+        state.record_attempt(now, Duration::ZERO, &params, 300);
+        assert!(state.give_up(1));
+        state.running(now + Duration::from_secs(300), Duration::from_secs(300));
+        assert!(!state.crash_looping());
+        assert_eq!(state.failures(), 0);
+    }
+}
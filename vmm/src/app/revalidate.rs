@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic re-validation of the stored configs of existing VMs.
+//!
+//! Configs are checked when a VM is created, but the files and devices they depend on can be
+//! removed or edited out of band afterwards. The checks only read, running VMs are left alone.
+//! A config that broke is reported with a `vm.config_invalid` event before its next launch
+//! fails, and with `vm.config_valid` once it is fixed.
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::cpu::format_cpu_list;
+use super::image::Image;
+use super::pci::devices_not_bound_to_vfio;
+use super::{App, VmConfig};
+
+impl App {
+    /// Problems with the on-disk config of a VM that would fail or break its next launch.
+    fn config_problems(&self, id: &str) -> Vec<String> {
+        let workdir = self.work_dir(id);
+        let manifest = match workdir.manifest() {
+            Ok(manifest) => manifest,
+            Err(err) => return vec![format!("{err:#}")],
+        };
+        let mut problems = vec![];
+        match Image::load(self.config.image_path.join(&manifest.image)) {
+            Ok(image) => {
+                let vm_config = VmConfig {
+                    manifest: manifest.clone(),
+                    image,
+                    cid: 0,
+                    workdir: workdir.path().to_path_buf(),
+                    gateway_enabled: false,
+                };
+                if let Err(err) = vm_config.validate_boot(&self.config.cvm) {
+                    problems.push(format!("{err:#}"));
+                }
+            }
+            Err(err) => problems.push(format!("Image {}: {err:#}", manifest.image)),
+        }
+        if let Some(cpu) = &manifest.cpu {
+            match cpu.offline_cores() {
+                Ok(offline) if !offline.is_empty() => problems.push(format!(
+                    "Cores {} of the CPU affinity are not online",
+                    format_cpu_list(&offline)
+                )),
+                Ok(_) => {}
+                Err(err) => problems.push(format!("{err:#}")),
+            }
+        }
+        for (addr, driver) in devices_not_bound_to_vfio(&manifest.pci_devices) {
+            let driver = driver.as_deref().unwrap_or("no driver");
+            problems.push(format!(
+                "PCI device {addr} is bound to {driver}, not vfio-pci"
+            ));
+        }
+        let state = self.lock();
+        for addr in &manifest.pci_devices {
+            if let Some(owner) = state.pci_device_owner(addr, id) {
+                problems.push(format!("PCI device {addr} is also claimed by VM {owner}"));
+            }
+        }
+        if let Some(port) = manifest.display.map(|d| d.port).filter(|p| *p != 0) {
+            if let Some(owner) = state.display_port_owner(port, id) {
+                problems.push(format!("Display port {port} is also claimed by VM {owner}"));
+            }
+        }
+        if let Some(addressing) = manifest.network.as_ref().and_then(|n| n.addressing) {
+            if let Some(owner) = state.user_net_owner(&addressing, id) {
+                problems.push(format!(
+                    "Network {} overlaps the network of VM {owner}",
+                    addressing.net
+                ));
+            }
+        }
+        if let Err(err) = manifest.nic_args(&self.config.cvm.networking) {
+            problems.push(format!("{err:#}"));
+        }
+        for ifname in manifest.nics.iter().filter_map(|n| n.ifname.as_ref()) {
+            if let Some(owner) = state.tap_owner(ifname, id) {
+                problems.push(format!("Tap interface {ifname} is also used by VM {owner}"));
+            }
+        }
+        problems
+    }
+
+    /// Validate the configs of all VMs again and report those that broke or were fixed since
+    /// the last run.
+    pub(crate) fn revalidate_configs(&self) {
+        let ids = self.lock().vms.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            let problems = self.config_problems(&id);
+            let previous = {
+                let mut state = self.lock();
+                let Some(vm) = state.get_mut(&id) else {
+                    continue;
+                };
+                std::mem::replace(&mut vm.state.config_problems, problems.clone())
+            };
+            if problems == previous {
+                continue;
+            }
+            if problems.is_empty() {
+                info!("Config of VM {id} is valid again");
+                self.emit_event("vm.config_valid", Some(&id), json!({}));
+            } else {
+                warn!(
+                    "Config of VM {id} is no longer valid: {}",
+                    problems.join("; ")
+                );
+                self.emit_event(
+                    "vm.config_invalid",
+                    Some(&id),
+                    json!({ "problems": problems }),
+                );
+            }
+        }
+    }
+
+    /// VMs whose config failed the last re-validation.
+    pub fn invalid_config_count(&self) -> usize {
+        self.lock()
+            .iter_vms()
+            .filter(|vm| !vm.state.config_problems.is_empty())
+            .count()
+    }
+}
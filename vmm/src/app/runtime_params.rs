@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Settings of a running VM tuned over QMP in one batch.
+//!
+//! The settings of a batch are applied one after the other. If one fails, those applied before
+//! it are set back to the values they had, so a batch is applied as a whole or not at all as
+//! far as QEMU lets the old values be restored.
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use super::balloon::MB;
+use super::disk::DISK_IDS;
+use super::net_link::NETDEV_ID;
+use super::{App, IoThrottle, QmpClient};
+
+/// A QMP-backed setting of a running VM.
+#[derive(Debug, Clone)]
+enum Tunable {
+    IoThrottle(String, IoThrottle),
+    /// Balloon target in MB
+    BalloonTarget(u32),
+    NetworkEnabled(bool),
+    /// Migration bandwidth limit in bytes per second
+    MigrationMaxBandwidth(u64),
+}
+
+impl Tunable {
+    fn name(&self) -> String {
+        match self {
+            Tunable::IoThrottle(disk, _) => format!("the I/O throttle of {disk}"),
+            Tunable::BalloonTarget(_) => "the balloon target".into(),
+            Tunable::NetworkEnabled(_) => "the NIC link".into(),
+            Tunable::MigrationMaxBandwidth(_) => "the migration bandwidth".into(),
+        }
+    }
+
+    async fn apply(&self, qmp: &mut QmpClient) -> Result<()> {
+        let (command, args) = match self {
+            Tunable::IoThrottle(disk, throttle) => {
+                ("block_set_io_throttle", throttle.to_qmp_args(disk))
+            }
+            Tunable::BalloonTarget(target_mb) => {
+                ("balloon", json!({ "value": *target_mb as u64 * MB }))
+            }
+            Tunable::NetworkEnabled(enabled) => {
+                ("set_link", json!({ "name": NETDEV_ID, "up": enabled }))
+            }
+            Tunable::MigrationMaxBandwidth(bandwidth) => (
+                "migrate-set-parameters",
+                json!({ "max-bandwidth": bandwidth }),
+            ),
+        };
+        qmp.execute(command, Some(args))
+            .await
+            .with_context(|| format!("Failed to set {}", self.name()))?;
+        Ok(())
+    }
+}
+
+/// The I/O throttles of the attached disks.
+async fn io_throttles(qmp: &mut QmpClient) -> Result<Vec<(String, IoThrottle)>> {
+    let blocks = qmp
+        .execute("query-block", None)
+        .await
+        .context("Failed to query the disks")?;
+    Ok(blocks
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|block| {
+            let device = block.get("device").and_then(Value::as_str)?;
+            let inserted = block.get("inserted")?;
+            (!device.is_empty()).then(|| (device.to_string(), IoThrottle::from_qmp(inserted)))
+        })
+        .collect())
+}
+
+async fn migration_max_bandwidth(qmp: &mut QmpClient) -> Result<u64> {
+    qmp.execute("query-migrate-parameters", None)
+        .await
+        .context("Failed to query the migration parameters")?
+        .get("max-bandwidth")
+        .and_then(Value::as_u64)
+        .context("Invalid query-migrate-parameters response")
+}
+
+impl App {
+    /// Apply a batch of settings to a running VM, rolling back the applied ones if one fails,
+    /// and return the effective settings.
+    pub async fn set_vm_runtime_params(
+        &self,
+        request: pb::SetVmRuntimeParamsRequest,
+    ) -> Result<pb::VmRuntimeParams> {
+        let id = request.id.as_str();
+        let (memory, balloon_target, network_disabled) = {
+            let state = self.lock();
+            let vm = state.get(id).context("VM not found")?;
+            (
+                vm.config.manifest.memory,
+                vm.state.balloon_target,
+                vm.state.network_disabled,
+            )
+        };
+        let work_dir = self.work_dir(id);
+        let mut manifest = work_dir.manifest().context("Failed to read manifest")?;
+
+        let mut changes = vec![];
+        for entry in &request.io_throttles {
+            let disk = entry.disk.as_str();
+            if !DISK_IDS.contains(&disk) {
+                bail!("Unknown disk: {disk}");
+            }
+            if manifest.disk(disk).is_some_and(|d| d.source.is_some()) {
+                bail!("Disk {disk} has a network source, which cannot be throttled");
+            }
+            if changes
+                .iter()
+                .any(|c| matches!(c, Tunable::IoThrottle(d, _) if d == disk))
+            {
+                bail!("Disk {disk} is given more than once");
+            }
+            let throttle = entry.throttle.as_ref().map(Into::into).unwrap_or_default();
+            changes.push(Tunable::IoThrottle(disk.to_string(), throttle));
+        }
+        if let Some(target_mb) = request.balloon_target_mb {
+            if !self.config.cvm.balloon.enabled {
+                bail!("Balloon device is disabled");
+            }
+            if target_mb == 0 || target_mb > memory {
+                bail!("Balloon target must be between 1 and {memory} MB");
+            }
+            changes.push(Tunable::BalloonTarget(target_mb));
+        }
+        if let Some(enabled) = request.network_enabled {
+            changes.push(Tunable::NetworkEnabled(enabled));
+        }
+        if let Some(bandwidth) = request.migration_max_bandwidth {
+            changes.push(Tunable::MigrationMaxBandwidth(bandwidth));
+        }
+        if changes.is_empty() {
+            bail!("No runtime parameters given");
+        }
+        if !self.is_running(id).await? {
+            bail!("VM is not running");
+        }
+
+        let mut qmp = self.qmp(id).await?;
+        let current_throttles = io_throttles(&mut qmp).await?;
+        let mut previous = vec![];
+        for change in &changes {
+            previous.push(match change {
+                Tunable::IoThrottle(disk, _) => {
+                    let (_, throttle) = current_throttles
+                        .iter()
+                        .find(|(device, _)| device == disk)
+                        .with_context(|| format!("Disk {disk} is not attached"))?;
+                    Tunable::IoThrottle(disk.clone(), *throttle)
+                }
+                Tunable::BalloonTarget(_) => {
+                    Tunable::BalloonTarget(balloon_target.unwrap_or(memory))
+                }
+                Tunable::NetworkEnabled(_) => Tunable::NetworkEnabled(!network_disabled),
+                Tunable::MigrationMaxBandwidth(_) => {
+                    Tunable::MigrationMaxBandwidth(migration_max_bandwidth(&mut qmp).await?)
+                }
+            });
+        }
+        for (applied, change) in changes.iter().enumerate() {
+            if let Err(err) = change.apply(&mut qmp).await {
+                for undo in previous[..applied].iter().rev() {
+                    if let Err(err) = undo.apply(&mut qmp).await {
+                        error!("Failed to roll back {} of VM {id}: {err:?}", undo.name());
+                    }
+                }
+                return Err(
+                    err.context(format!("Rolled back the {applied} settings applied before"))
+                );
+            }
+        }
+        info!("Applied {} runtime settings to VM {id}", changes.len());
+
+        if let Some(vm) = self.lock().get_mut(id) {
+            for change in &changes {
+                match change {
+                    Tunable::BalloonTarget(target_mb) => {
+                        vm.state.balloon_target = (*target_mb != memory).then_some(*target_mb);
+                    }
+                    Tunable::NetworkEnabled(enabled) => vm.state.network_disabled = !enabled,
+                    _ => {}
+                }
+            }
+        }
+        if let Some(enabled) = request.network_enabled {
+            self.emit_event("vm.network", Some(id), json!({ "enabled": enabled }));
+        }
+        if !request.io_throttles.is_empty() {
+            for change in &changes {
+                if let Tunable::IoThrottle(disk, throttle) = change {
+                    manifest.disk_mut(disk).throttle = (!throttle.is_empty()).then_some(*throttle);
+                }
+            }
+            work_dir
+                .put_manifest(&manifest)
+                .context("Failed to write manifest")?;
+            self.load_vm(&work_dir, &Default::default(), false)
+                .await
+                .context("Failed to reload VM")?;
+        }
+        self.vm_runtime_params(id, &mut qmp).await
+    }
+
+    async fn vm_runtime_params(
+        &self,
+        id: &str,
+        qmp: &mut QmpClient,
+    ) -> Result<pb::VmRuntimeParams> {
+        let io_throttles = io_throttles(qmp)
+            .await?
+            .into_iter()
+            .map(|(disk, throttle)| pb::DiskIoThrottle {
+                disk,
+                throttle: Some((&throttle).into()),
+            })
+            .collect();
+        let migration_max_bandwidth = migration_max_bandwidth(qmp).await?;
+        let state = self.lock();
+        let vm = state.get(id).context("VM not found")?;
+        Ok(pb::VmRuntimeParams {
+            id: id.to_string(),
+            io_throttles,
+            balloon_target_mb: self
+                .config
+                .cvm
+                .balloon
+                .enabled
+                .then(|| vm.state.balloon_target.unwrap_or(vm.config.manifest.memory)),
+            network_enabled: !vm.state.network_disabled,
+            migration_max_bandwidth,
+        })
+    }
+}
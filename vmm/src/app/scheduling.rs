@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scheduling hints of VMs, for external schedulers placing VMs across hosts.
+//!
+//! The VMM does not place VMs itself. Hints are stored with the VM, reported by
+//! `GetHostCapacity` and `ExportFleet`, and checked by `CheckFit`. Creating a VM that breaks
+//! them is not refused.
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::cpu::{format_cpu_list, CpuConfig};
+use super::validate::validation_error;
+use super::{App, Manifest};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct SchedulingHints {
+    /// Labels no other VM on the same host may carry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anti_affinity: Vec<String>,
+    /// The vCPUs run on host cores of their own, given by the CPU affinity of the VM
+    #[serde(default)]
+    pub dedicated_cores: bool,
+    /// The VM needs a host that can run TDX guests
+    #[serde(default)]
+    pub require_tee: bool,
+}
+
+impl SchedulingHints {
+    pub fn to_pb(&self) -> pb::SchedulingHints {
+        pb::SchedulingHints {
+            anti_affinity: self.anti_affinity.clone(),
+            dedicated_cores: self.dedicated_cores,
+            require_tee: self.require_tee,
+        }
+    }
+}
+
+/// Hints of a config, `None` if it sets none. Dedicated cores need a CPU affinity with a core
+/// per vCPU.
+pub fn resolve_scheduling(
+    hints: &pb::SchedulingHints,
+    cpu: Option<&CpuConfig>,
+    vcpu: u32,
+) -> Result<Option<SchedulingHints>> {
+    let mut anti_affinity = BTreeSet::new();
+    for label in &hints.anti_affinity {
+        if label.is_empty() || label.chars().any(char::is_whitespace) {
+            bail!("Invalid anti-affinity label: {label:?}");
+        }
+        anti_affinity.insert(label.clone());
+    }
+    if hints.dedicated_cores {
+        let cores = cpu.map_or(0, |cpu| cpu.affinity.len());
+        if cores < vcpu as usize {
+            bail!("Dedicated cores need a CPU affinity of at least {vcpu} cores, got {cores}");
+        }
+    }
+    let hints = SchedulingHints {
+        anti_affinity: anti_affinity.into_iter().collect(),
+        dedicated_cores: hints.dedicated_cores,
+        require_tee: hints.require_tee,
+    };
+    Ok((hints != SchedulingHints::default()).then_some(hints))
+}
+
+fn affinity(manifest: &Manifest) -> &[u32] {
+    manifest.cpu.as_ref().map_or(&[], |cpu| &cpu.affinity)
+}
+
+fn is_dedicated(manifest: &Manifest) -> bool {
+    manifest
+        .scheduling
+        .as_ref()
+        .is_some_and(|h| h.dedicated_cores)
+}
+
+impl App {
+    /// Whether a VM of `manifest` would fit on this host next to the existing VMs: resources,
+    /// TEE support, anti-affinity labels and dedicated cores. Returns the reasons it does not.
+    pub async fn check_fit(&self, manifest: &Manifest) -> Vec<pb::ValidationFinding> {
+        let hints = manifest.scheduling.clone().unwrap_or_default();
+        let mut findings = self.allocation_findings(manifest);
+        if hints.require_tee && self.capabilities.get().await.tdx != Some(true) {
+            findings.push(validation_error(
+                "scheduling.require_tee",
+                "Host can not run TDX guests",
+            ));
+        }
+        if let Some(cpu) = &manifest.cpu {
+            match cpu.offline_cores() {
+                Ok(offline) if !offline.is_empty() => findings.push(validation_error(
+                    "cpu.affinity",
+                    format!("Cores {} are not online", format_cpu_list(&offline)),
+                )),
+                Ok(_) => {}
+                Err(err) => findings.push(validation_error("cpu.affinity", format!("{err:#}"))),
+            }
+        }
+        let state = self.lock();
+        for vm in state.vms.values() {
+            let other = &vm.config.manifest;
+            if other.id == manifest.id {
+                continue;
+            }
+            let labels = other
+                .scheduling
+                .as_ref()
+                .map(|h| &h.anti_affinity[..])
+                .unwrap_or_default();
+            for label in hints.anti_affinity.iter().filter(|l| labels.contains(l)) {
+                findings.push(validation_error(
+                    "scheduling.anti_affinity",
+                    format!("VM {} also carries the label {label}", other.id),
+                ));
+            }
+            // Cores of a dedicated VM are shared with no one, unpinned VMs can not be kept off
+            if !hints.dedicated_cores && !is_dedicated(other) {
+                continue;
+            }
+            let shared = affinity(manifest)
+                .iter()
+                .filter(|core| affinity(other).contains(core))
+                .copied()
+                .collect::<Vec<_>>();
+            if !shared.is_empty() {
+                findings.push(validation_error(
+                    "scheduling.dedicated_cores",
+                    format!(
+                        "Cores {} are also used by VM {}",
+                        format_cpu_list(&shared),
+                        other.id
+                    ),
+                ));
+            }
+        }
+        findings
+    }
+
+    /// Resources and hints of each VM and the cores held by dedicated VMs, for `HostCapacity`.
+    pub(super) fn add_placements(&self, capacity: &mut pb::HostCapacity, running: &[String]) {
+        let state = self.lock();
+        let mut dedicated = BTreeSet::new();
+        let mut vms = state
+            .vms
+            .values()
+            .map(|vm| &vm.config.manifest)
+            .collect::<Vec<_>>();
+        vms.sort_by_key(|m| m.created_at_ms);
+        for manifest in vms {
+            if is_dedicated(manifest) {
+                dedicated.extend(affinity(manifest));
+            }
+            capacity.vms.push(pb::VmPlacement {
+                id: manifest.id.clone(),
+                name: manifest.name.clone(),
+                vcpu: manifest.vcpu,
+                memory_mb: manifest.memory,
+                running: running.contains(&manifest.id),
+                scheduling: manifest.scheduling.as_ref().map(|h| h.to_pb()),
+            });
+        }
+        capacity.dedicated_cores = format_cpu_list(&dedicated.into_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,102 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Random access to the serial console log of a VM.
+//!
+//! QEMU appends to `serial.log` in the VM work dir. Segments rotated out of it by an external
+//! log rotation, uncompressed as `serial.log.1` (newest) to `serial.log.N`, are read as if
+//! they preceded it, so one offset space covers the whole history. Offsets shift when the log
+//! is rotated again, the segment boundaries of each read tell when that happened.
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+
+use super::App;
+
+/// Rotated segments looked for at most.
+const MAX_SEGMENTS: usize = 100;
+
+struct Segment {
+    path: PathBuf,
+    start: u64,
+    size: u64,
+}
+
+/// The segments of the serial log at `path`, oldest first.
+fn segments(path: &Path) -> Vec<Segment> {
+    let mut paths = (1..=MAX_SEGMENTS)
+        .map(|n| PathBuf::from(format!("{}.{n}", path.display())))
+        .take_while(|p| p.exists())
+        .collect::<Vec<_>>();
+    paths.reverse();
+    paths.push(path.to_path_buf());
+    let mut start = 0;
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let size = fs::metadata(&path).ok()?.len();
+            let segment = Segment { path, start, size };
+            start += size;
+            Some(segment)
+        })
+        .collect()
+}
+
+impl App {
+    /// Read up to `length` bytes of the serial log of VM `id` from `offset`, at most
+    /// `log_limits.tail_max_bytes` and all of them if 0.
+    pub fn read_serial_log(
+        &self,
+        id: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<pb::SerialLogChunk> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let max = self.config.log_limits.tail_max_bytes;
+        let length = match length {
+            0 => max,
+            n => n.min(max),
+        };
+        let segments = segments(&self.work_dir(id).serial_file());
+        let total_size = segments.last().map_or(0, |s| s.start + s.size);
+        let end = offset.saturating_add(length).min(total_size);
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        for segment in &segments {
+            let segment_end = segment.start + segment.size;
+            if segment_end <= offset || segment.start >= end {
+                continue;
+            }
+            let from = offset.max(segment.start) - segment.start;
+            let to = end.min(segment_end) - segment.start;
+            let mut file = fs::File::open(&segment.path)?;
+            file.seek(SeekFrom::Start(from))?;
+            // The segment may be shorter by now if it was rotated meanwhile
+            file.take(to - from).read_to_end(&mut data)?;
+        }
+        Ok(pb::SerialLogChunk {
+            offset,
+            eof: offset + data.len() as u64 >= total_size,
+            data,
+            total_size,
+            segments: segments
+                .iter()
+                .map(|s| pb::SerialLogSegment {
+                    name: s
+                        .path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    start: s.start,
+                    size: s.size,
+                })
+                .collect(),
+        })
+    }
+}
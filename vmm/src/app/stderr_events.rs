@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Warnings and errors QEMU writes to its stderr, reported as VM events.
+//!
+//! The supervisor appends the QEMU stderr of a VM to `stderr.log`. Each scan reads the lines
+//! written since the last one and reports the diagnostics among them as `vm.qemu_warning` and
+//! `vm.qemu_error` events, with a category telling what part of QEMU failed. What a QEMU wrote
+//! before the VMM started is not reported, a QEMU launched by it is scanned from its first line.
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+use fs_err as fs;
+use serde_json::json;
+use tracing::{info, warn};
+
+use super::qemu::truncate_line;
+use super::App;
+
+/// Lines that are no problem of the VM.
+const IGNORED: &[&str] = &["terminating on signal"];
+
+/// Categories of diagnostics by the lowercase text they contain, the first match wins.
+const CATEGORIES: &[(&str, &[&str])] = &[
+    ("migration", &["migration", "migrate", "-incoming"]),
+    (
+        "device",
+        &["-device ", "vfio", "device initialization failed"],
+    ),
+    (
+        "block",
+        &["-drive ", "-blockdev ", "block", "qcow2", "i/o error"],
+    ),
+    ("network", &["-netdev ", "tap", "bridge helper"]),
+    (
+        "memory",
+        &[
+            "-object memory",
+            "cannot allocate memory",
+            "hugepage",
+            "mlock",
+        ],
+    ),
+    ("accel", &["kvm", "tdx", "sev-"]),
+];
+
+/// Text of a line telling it reports an error, when not prefixed with the QEMU binary.
+const ERROR_MARKERS: &[&str] = &["error", "failed", "cannot", "unable to"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+/// Severity and category of a stderr line, `None` if it is no diagnostic.
+fn classify(line: &str) -> Option<(Severity, &'static str)> {
+    let lower = line.to_lowercase();
+    if lower.trim().is_empty() || IGNORED.iter().any(|p| lower.contains(p)) {
+        return None;
+    }
+    let severity = if lower.contains("warning:") {
+        Severity::Warning
+    } else if lower.starts_with("qemu") || ERROR_MARKERS.iter().any(|m| lower.contains(m)) {
+        Severity::Error
+    } else {
+        return None;
+    };
+    let category = CATEGORIES
+        .iter()
+        .find(|(_, patterns)| patterns.iter().any(|p| lower.contains(p)))
+        .map_or("other", |(category, _)| category);
+    Some((severity, category))
+}
+
+/// Complete lines of `path` from `offset` on, and the offset after the last of them.
+/// At most `max_bytes` are read, skipping older output.
+fn read_new_lines(path: &Path, offset: u64, max_bytes: u64) -> Result<(Vec<String>, u64)> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    // Truncated or rotated
+    let offset = if len < offset { 0 } else { offset };
+    let start = offset.max(len.saturating_sub(max_bytes));
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = vec![];
+    file.by_ref().take(len - start).read_to_end(&mut buf)?;
+    let Some(end) = buf.iter().rposition(|b| *b == b'\n') else {
+        return Ok((vec![], start));
+    };
+    let text = String::from_utf8_lossy(&buf[..end]);
+    let mut lines = text.lines().map(String::from).collect::<Vec<_>>();
+    if start > offset && !lines.is_empty() {
+        // The first line is likely cut in the middle
+        lines.remove(0);
+    }
+    Ok((lines, start + end as u64 + 1))
+}
+
+impl App {
+    /// Report the warnings and errors the QEMU of each VM wrote since the last scan.
+    pub(crate) fn scan_stderr_events(&self) {
+        let vms = self
+            .lock()
+            .iter_vms()
+            .map(|vm| (vm.config.manifest.id.clone(), vm.state.stderr_offset))
+            .collect::<Vec<_>>();
+        for (id, offset) in vms {
+            let path = self.work_dir(&id).stderr_file();
+            let Ok(len) = fs::metadata(&path).map(|m| m.len()) else {
+                continue;
+            };
+            let Some(offset) = offset else {
+                // Output from before the VMM started is not reported
+                self.set_stderr_offset(&id, len);
+                continue;
+            };
+            if offset == len {
+                continue;
+            }
+            let (lines, new_offset) =
+                match read_new_lines(&path, offset, self.config.log_limits.tail_max_bytes) {
+                    Ok(read) => read,
+                    Err(err) => {
+                        warn!("Failed to read the QEMU stderr of VM {id}: {err:?}");
+                        continue;
+                    }
+                };
+            self.set_stderr_offset(&id, new_offset);
+            self.report_stderr_lines(&id, &lines);
+        }
+    }
+
+    fn set_stderr_offset(&self, id: &str, offset: u64) {
+        if let Some(vm) = self.lock().get_mut(id) {
+            vm.state.stderr_offset = Some(offset);
+        }
+    }
+
+    fn report_stderr_lines(&self, id: &str, lines: &[String]) {
+        let max = self.config.stderr_events.max_per_scan;
+        let max_line_bytes = self.config.log_limits.max_line_bytes;
+        let mut reported = 0;
+        for line in lines {
+            let Some((severity, category)) = classify(line) else {
+                continue;
+            };
+            if max != 0 && reported >= max {
+                info!("QEMU of VM {id}: {line}");
+                continue;
+            }
+            reported += 1;
+            let event = match severity {
+                Severity::Warning => "vm.qemu_warning",
+                Severity::Error => "vm.qemu_error",
+            };
+            let message = truncate_line(line, max_line_bytes);
+            self.emit_event(
+                event,
+                Some(id),
+                json!({ "category": category, "message": message }),
+            );
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stopping VMs gracefully, with a forced stop as the fallback.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use tracing::{info, warn};
+
+use super::App;
+
+/// How a `StopVm` call ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopPath {
+    /// QEMU was not running
+    NotRunning,
+    /// The guest powered off within the timeout
+    Graceful,
+    /// QEMU was killed
+    Forced,
+    /// The guest did not power off within the timeout and the VM was left running
+    TimedOut,
+}
+
+impl StopPath {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopPath::NotRunning => "not_running",
+            StopPath::Graceful => "graceful",
+            StopPath::Forced => "forced",
+            StopPath::TimedOut => "timed_out",
+        }
+    }
+}
+
+impl App {
+    /// Ask the guest of VM `id` to power off, through its guest agent or else an ACPI power
+    /// button press over QMP.
+    async fn request_power_off(&self, id: &str) -> Result<()> {
+        let Err(err) = async { self.guest_agent_client(id)?.shutdown().await }.await else {
+            return Ok(());
+        };
+        let mut qmp = self
+            .qmp(id)
+            .await
+            .with_context(|| format!("Guest agent shutdown failed: {err:#}"))?;
+        qmp.execute("system_powerdown", None)
+            .await
+            .with_context(|| format!("Guest agent shutdown failed: {err:#}"))?;
+        Ok(())
+    }
+
+    /// Ask the guest of VM `id` to power off and wait up to `timeout` for QEMU to exit.
+    pub(crate) async fn power_off_guest(&self, id: &str, timeout: Duration) -> Result<()> {
+        let graceful = async {
+            self.request_power_off(id).await?;
+            while self.is_running(id).await? {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            anyhow::Ok(())
+        };
+        match tokio::time::timeout(timeout, graceful).await {
+            Ok(result) => result,
+            Err(_) => bail!("timed out after {timeout:?}"),
+        }
+    }
+
+    /// Stop VM `id`, gracefully if `timeout` is non-zero.
+    ///
+    /// A guest that does not power off within `timeout` is stopped forcibly if `force` is set,
+    /// otherwise the VM is left running as it was.
+    pub async fn stop_vm_with(
+        &self,
+        id: &str,
+        force: bool,
+        timeout: Duration,
+    ) -> Result<pb::StopVmResponse> {
+        if self.lock().get(id).is_none() {
+            bail!("VM not found: {id}");
+        }
+        let started = Instant::now();
+        let mut error = String::new();
+        let path = if !self.is_running(id).await? {
+            self.stop_vm(id).await?;
+            StopPath::NotRunning
+        } else if timeout.is_zero() {
+            self.stop_vm(id).await?;
+            StopPath::Forced
+        } else {
+            // Cleared first, so the guest powering off is not taken for a crash
+            self.set_started(id, false)?;
+            match self.power_off_guest(id, timeout).await {
+                Ok(()) => {
+                    self.stop_vm(id).await?;
+                    StopPath::Graceful
+                }
+                Err(err) if force => {
+                    warn!("Graceful shutdown of VM {id} failed ({err:#}), stopping it");
+                    error = format!("{err:#}");
+                    self.stop_vm(id).await?;
+                    StopPath::Forced
+                }
+                Err(err) => {
+                    // The guest may still have powered off right after the timeout
+                    if self.is_running(id).await? {
+                        warn!("Graceful shutdown of VM {id} failed ({err:#}), leaving it running");
+                        error = format!("{err:#}");
+                        self.set_started(id, true)?;
+                        StopPath::TimedOut
+                    } else {
+                        self.stop_vm(id).await?;
+                        StopPath::Graceful
+                    }
+                }
+            }
+        };
+        let duration = started.elapsed();
+        info!("Stop of VM {id} ended {} after {duration:?}", path.as_str());
+        Ok(pb::StopVmResponse {
+            path: path.as_str().into(),
+            error,
+            duration_ms: duration.as_millis() as u64,
+        })
+    }
+}
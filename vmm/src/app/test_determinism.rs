@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Settings making a VM more reproducible for test runs: a fixed seed for the randomness QEMU
+//! provides and a fixed start of the RTC.
+//!
+//! Both are unsafe outside of tests, a known seed makes the guest randomness from QEMU
+//! predictable and the RTC lies about the time. VMs with them are only accepted by hosts with
+//! `cvm.allow_test_determinism` and only launched from dev images. A TDX guest also draws
+//! entropy from the CPU, which the seed does not cover.
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+use super::ImageInfo;
+use crate::config::CvmConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TestDeterminism {
+    /// Seed of the QEMU pseudo-random number generator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rng_seed: Option<u64>,
+    /// UTC time the RTC starts at, `YYYY-MM-DDTHH:MM:SS`. It then runs with the guest clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtc_start: Option<String>,
+}
+
+impl TestDeterminism {
+    pub fn to_pb(&self) -> pb::TestDeterminismConfig {
+        pb::TestDeterminismConfig {
+            rng_seed: self.rng_seed,
+            rtc_start: self.rtc_start.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Fail unless the host and the image of the VM allow these settings.
+    pub fn check_allowed(&self, cfg: &CvmConfig, image: &ImageInfo) -> Result<()> {
+        if !cfg.allow_test_determinism {
+            bail!("Test determinism settings require cvm.allow_test_determinism");
+        }
+        if !image.is_dev {
+            bail!("Test determinism settings are only allowed with dev images");
+        }
+        Ok(())
+    }
+
+    pub fn qemu_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(seed) = self.rng_seed {
+            args.extend(["-seed".into(), seed.to_string()]);
+        }
+        if let Some(start) = &self.rtc_start {
+            args.extend(["-rtc".into(), format!("base={start},clock=vm")]);
+        }
+        args
+    }
+}
+
+fn parse_rtc_start(start: &str) -> Result<String> {
+    let time: SystemTime = humantime::parse_rfc3339_weak(start)
+        .with_context(|| format!("Invalid RTC start: {start}, expected YYYY-MM-DDTHH:MM:SS"))?;
+    let formatted = humantime::format_rfc3339_seconds(time).to_string();
+    Ok(formatted.trim_end_matches('Z').to_string())
+}
+
+/// The test determinism settings of a VM config, `None` if it sets none.
+pub fn resolve_test_determinism(
+    config: &pb::TestDeterminismConfig,
+    cvm_config: &CvmConfig,
+    has_rtc: bool,
+) -> Result<Option<TestDeterminism>> {
+    let rtc_start = match config.rtc_start.as_str() {
+        "" => None,
+        start => Some(parse_rtc_start(start)?),
+    };
+    if config.rng_seed.is_none() && rtc_start.is_none() {
+        return Ok(None);
+    }
+    if !cvm_config.allow_test_determinism {
+        bail!("Test determinism settings require cvm.allow_test_determinism");
+    }
+    if rtc_start.is_some() && has_rtc {
+        bail!("An RTC start can not be combined with RTC settings");
+    }
+    Ok(Some(TestDeterminism {
+        rng_seed: config.rng_seed,
+        rtc_start,
+    }))
+}
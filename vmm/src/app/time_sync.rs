@@ -0,0 +1,89 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resync of the guest clock with the host.
+//!
+//! The guest clock falls behind while a VM is paused, e.g. for a checkpoint, a restore or a
+//! migration, and drifts over long runs. QMP alone can not set it, so the guest agent of the VM
+//! does, either to the host time or, with `cvm.time_sync.method = "rtc"`, from the RTC after
+//! its tick reinjection is reset over QMP.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use super::App;
+use crate::config::TimeSyncMethod;
+
+impl App {
+    /// Set the guest clock of VM `id` from the host and report how far off it was.
+    pub async fn sync_guest_time(&self, id: &str) -> Result<pb::GuestTimeSync> {
+        let method = self.config.cvm.time_sync.method;
+        let mut agent = self.guest_agent(id).await?;
+        let guest_ns = agent
+            .execute("guest-get-time", None)
+            .await
+            .context("Failed to get the guest time")?
+            .as_i64()
+            .context("Invalid guest-get-time response")?;
+        let host_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64;
+        let args = match method {
+            TimeSyncMethod::Agent => Some(json!({ "time": host_ns })),
+            TimeSyncMethod::Rtc => {
+                let mut qmp = self.qmp(id).await?;
+                qmp.execute("rtc-reset-reinjection", None)
+                    .await
+                    .context("Failed to reset the RTC reinjection")?;
+                None
+            }
+        };
+        agent
+            .execute("guest-set-time", args)
+            .await
+            .context("Failed to set the guest time")?;
+        let offset_ms = (guest_ns - host_ns) / 1_000_000;
+        info!(
+            "Synced the guest time of VM {id} with {}, it was {offset_ms} ms off",
+            method.as_str()
+        );
+        self.emit_event(
+            "vm.time_sync",
+            Some(id),
+            json!({ "method": method.as_str(), "offset_ms": offset_ms }),
+        );
+        Ok(pb::GuestTimeSync {
+            method: method.as_str().into(),
+            offset_ms,
+        })
+    }
+
+    /// Resync the guest clock of VM `id` in the background after `operation` paused it, if
+    /// `cvm.time_sync.after_resume` is set and the VM has a guest agent.
+    pub(crate) fn sync_guest_time_after(&self, id: &str, operation: &str) {
+        if !self.config.cvm.time_sync.after_resume || !self.config.cvm.guest_agent {
+            return;
+        }
+        let has_agent = self
+            .lock()
+            .get(id)
+            .is_some_and(|vm| vm.config.manifest.guest_agent);
+        if !has_agent {
+            debug!("VM {id} has no guest agent, not syncing its time after {operation}");
+            return;
+        }
+        let app = self.clone();
+        let id = id.to_string();
+        let operation = operation.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = app.sync_guest_time(&id).await {
+                warn!("Failed to sync the guest time of VM {id} after {operation}: {err:#}");
+            }
+        });
+    }
+}
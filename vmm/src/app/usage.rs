@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sampling of the CPU and memory usage of the host and the QEMU processes.
+//!
+//! QEMU processes are sampled from `/proc`; only the balloon size is queried over QMP, and
+//! VMs whose QMP does not answer quickly are reported without it.
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use serde_json::Value;
+use tokio::time::timeout;
+
+use super::balloon::meminfo_mb;
+use super::App;
+use crate::config::ProcessAnnotation;
+
+/// Clock ticks per second of `/proc/<pid>/stat`, `USER_HZ` is 100 on all Linux targets.
+const CLOCK_TICKS: f64 = 100.0;
+const QMP_SAMPLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// CPU time in clock ticks and resident memory in kB of a process.
+pub(super) fn process_stats(pid: u32) -> Option<(u64, u64)> {
+    let stat = fs_err::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may contain spaces, fields are counted after its closing parenthesis
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let status = fs_err::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let rss_kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rest| rest.split_whitespace().next()?.parse().ok())
+        .unwrap_or_default();
+    Some((utime + stime, rss_kb))
+}
+
+/// Busy and total jiffies of all host CPUs.
+fn host_cpu_times() -> Option<(u64, u64)> {
+    let stat = fs_err::read_to_string("/proc/stat").ok()?;
+    let values = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .map(|v| v.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let total = values.iter().sum::<u64>();
+    // idle and iowait
+    let idle =
+        values.get(3).copied().unwrap_or_default() + values.get(4).copied().unwrap_or_default();
+    Some((total - idle, total))
+}
+
+/// Previous samples, CPU usage is the change between two of them.
+#[derive(Default)]
+pub struct UsageSampler {
+    processes: HashMap<u32, (Instant, u64)>,
+    host: Option<(u64, u64)>,
+}
+
+impl UsageSampler {
+    fn cpu_percent(&mut self, pid: u32, ticks: u64) -> Option<f64> {
+        let now = Instant::now();
+        let previous = self.processes.insert(pid, (now, ticks))?;
+        let elapsed = now.duration_since(previous.0).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        Some(ticks.saturating_sub(previous.1) as f64 / CLOCK_TICKS / elapsed * 100.0)
+    }
+
+    fn host_cpu_percent(&mut self) -> Option<f64> {
+        let (busy, total) = host_cpu_times()?;
+        let (last_busy, last_total) = self.host.replace((busy, total))?;
+        let total = total.saturating_sub(last_total);
+        (total > 0).then(|| busy.saturating_sub(last_busy) as f64 / total as f64 * 100.0)
+    }
+}
+
+impl App {
+    async fn balloon_actual_mb(&self, id: &str) -> Result<u32> {
+        let mut qmp = timeout(QMP_SAMPLE_TIMEOUT, self.qmp(id))
+            .await
+            .context("QMP timed out")??;
+        let actual = timeout(QMP_SAMPLE_TIMEOUT, qmp.execute("query-balloon", None))
+            .await
+            .context("QMP timed out")??
+            .get("actual")
+            .and_then(Value::as_u64)
+            .context("Invalid query-balloon response")?;
+        Ok((actual / 1024 / 1024) as u32)
+    }
+
+    /// Take one sample of the host and the running VMs.
+    pub async fn sample_resource_usage(
+        &self,
+        sampler: &mut UsageSampler,
+    ) -> Result<pb::ResourceUsage> {
+        let processes = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .filter(|p| {
+                serde_json::from_str::<ProcessAnnotation>(&p.config.note)
+                    .unwrap_or_default()
+                    .is_cvm()
+            })
+            .collect::<Vec<_>>();
+        let meminfo = fs_err::read_to_string("/proc/meminfo").unwrap_or_default();
+        let mut usage = pb::ResourceUsage {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            host_cpu_percent: sampler.host_cpu_percent(),
+            host_total_memory_mb: meminfo_mb(&meminfo, "MemTotal"),
+            host_available_memory_mb: meminfo_mb(&meminfo, "MemAvailable"),
+            ..Default::default()
+        };
+        let query_balloon = self.config.cvm.qmp_socket && self.config.cvm.balloon.enabled;
+        let mut live_pids = vec![];
+        for process in processes {
+            let id = process.config.id;
+            let Some((name, vcpu, memory_mb)) = self.lock().get(&id).map(|vm| {
+                let m = &vm.config.manifest;
+                (m.name.clone(), m.vcpu, m.memory)
+            }) else {
+                continue;
+            };
+            let mut vm = pb::VmResourceUsage {
+                id: id.clone(),
+                name,
+                vcpu,
+                memory_mb,
+                ..Default::default()
+            };
+            if let Some(pid) = process.state.pid {
+                if let Some((ticks, rss_kb)) = process_stats(pid) {
+                    live_pids.push(pid);
+                    vm.cpu_percent = sampler.cpu_percent(pid, ticks);
+                    vm.rss_mb = rss_kb / 1024;
+                }
+            }
+            if query_balloon {
+                match self.balloon_actual_mb(&id).await {
+                    Ok(actual) => {
+                        vm.balloon_actual_mb = Some(actual);
+                        vm.qmp_responsive = true;
+                    }
+                    Err(_) => vm.qmp_responsive = false,
+                }
+            }
+            usage.vms_rss_mb += vm.rss_mb;
+            if let Some(cpu) = vm.cpu_percent {
+                *usage.vms_cpu_percent.get_or_insert(0.0) += cpu;
+            }
+            usage.vms.push(vm);
+        }
+        sampler.processes.retain(|pid, _| live_pids.contains(pid));
+        Ok(usage)
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pinned addressing of the user-mode (slirp) network of a VM.
+//!
+//! Every VM gets a slirp stack of its own, by default all on the `cvm.networking` network. A VM
+//! can pin its own network, gateway and guest address instead, so the guest address is known
+//! before it boots. Pinned networks of different VMs may not overlap, so that an address names
+//! one guest of the host.
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Context, Result};
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+
+use super::{AppState, Manifest};
+use crate::config::Networking;
+
+/// Offsets in the network of the addresses slirp uses by default.
+const DEFAULT_GATEWAY_OFFSET: u32 = 2;
+const DEFAULT_DNS_OFFSET: u32 = 3;
+const DEFAULT_GUEST_OFFSET: u32 = 15;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserNetAddressing {
+    pub net: Ipv4Net,
+    /// Address of the host in the network
+    pub gateway: Ipv4Addr,
+    /// Start of the DHCP range, leased to the single NIC of the guest
+    pub guest: Ipv4Addr,
+}
+
+impl UserNetAddressing {
+    /// Options of the `-netdev user` argument, replacing the host-wide `net` and `dhcpstart`.
+    pub fn qemu_opts(&self) -> String {
+        format!(
+            "net={},host={},dhcpstart={}",
+            self.net, self.gateway, self.guest
+        )
+    }
+
+    pub fn overlaps(&self, other: &UserNetAddressing) -> bool {
+        self.net.contains(&other.net.network()) || other.net.contains(&self.net.network())
+    }
+
+    /// Whether `addr` may be handed to a VM of the network.
+    fn is_usable(&self, addr: Ipv4Addr) -> bool {
+        self.net.contains(&addr) && addr != self.net.network() && addr != self.net.broadcast()
+    }
+}
+
+fn offset_addr(net: &Ipv4Net, offset: u32) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(net.network()).saturating_add(offset))
+}
+
+/// The pinned addressing of a VM config, `None` if `net` is empty.
+pub fn resolve_user_net(
+    net: &str,
+    gateway: &str,
+    guest: &str,
+    dns: Option<Ipv4Addr>,
+) -> Result<Option<UserNetAddressing>> {
+    if net.is_empty() {
+        if !gateway.is_empty() || !guest.is_empty() {
+            bail!("A gateway or guest address requires a pinned network");
+        }
+        return Ok(None);
+    }
+    let net: Ipv4Net = net
+        .parse()
+        .with_context(|| format!("Invalid network: {net}"))?;
+    if net.trunc() != net {
+        bail!("Network {net} has host bits set, expected {}", net.trunc());
+    }
+    if net.prefix_len() > 28 {
+        bail!("Network {net} is too small, at most /28 is supported");
+    }
+    let parse_or = |addr: &str, name: &str, offset: u32| -> Result<Ipv4Addr> {
+        match addr {
+            "" => Ok(offset_addr(&net, offset)),
+            addr => addr
+                .parse()
+                .with_context(|| format!("Invalid {name} address: {addr}")),
+        }
+    };
+    let addressing = UserNetAddressing {
+        net,
+        gateway: parse_or(gateway, "gateway", DEFAULT_GATEWAY_OFFSET)?,
+        guest: parse_or(guest, "guest", DEFAULT_GUEST_OFFSET)?,
+    };
+    for (name, addr) in [("Gateway", addressing.gateway), ("Guest", addressing.guest)] {
+        if !addressing.is_usable(addr) {
+            bail!("{name} address {addr} is not a host address of {net}");
+        }
+    }
+    let dns = dns.unwrap_or(offset_addr(&net, DEFAULT_DNS_OFFSET));
+    if !addressing.is_usable(dns) {
+        bail!("DNS address {dns} is not a host address of {net}");
+    }
+    if addressing.gateway == addressing.guest || addressing.guest == dns {
+        bail!(
+            "Guest address {} collides with the gateway or DNS address",
+            addressing.guest
+        );
+    }
+    if addressing.gateway == dns {
+        bail!(
+            "Gateway address {} is also the DNS address",
+            addressing.gateway
+        );
+    }
+    Ok(Some(addressing))
+}
+
+/// Address of the guest and of its gateway, if the networking mode makes them known.
+pub fn guest_addresses(
+    networking: &Networking,
+    manifest: &Manifest,
+) -> (Option<String>, Option<String>) {
+    match networking {
+        Networking::User(cfg) => {
+            let pinned = manifest.network.as_ref().and_then(|n| n.addressing);
+            if let Some(addressing) = pinned {
+                return (
+                    Some(addressing.guest.to_string()),
+                    Some(addressing.gateway.to_string()),
+                );
+            }
+            let gateway = cfg
+                .net
+                .parse::<Ipv4Net>()
+                .ok()
+                .map(|net| offset_addr(&net, DEFAULT_GATEWAY_OFFSET).to_string());
+            (Some(cfg.dhcp_start.clone()), gateway)
+        }
+        Networking::Passt(cfg) => (
+            Some(cfg.address.clone()).filter(|a| !a.is_empty()),
+            Some(cfg.gateway.clone()).filter(|a| !a.is_empty()),
+        ),
+        Networking::Bridge(_) | Networking::Custom(_) => (None, None),
+    }
+}
+
+impl AppState {
+    /// The VM other than `except` whose pinned user-mode network overlaps `addressing`.
+    pub(crate) fn user_net_owner(
+        &self,
+        addressing: &UserNetAddressing,
+        except: &str,
+    ) -> Option<&str> {
+        self.iter_vms()
+            .map(|vm| &vm.config.manifest)
+            .find(|m| {
+                m.id != except
+                    && m.network
+                        .as_ref()
+                        .and_then(|n| n.addressing)
+                        .is_some_and(|a| a.overlaps(addressing))
+            })
+            .map(|m| m.id.as_str())
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Side-effect free checks of a VM config against the live host, for `ValidateVm`.
+use dstack_vmm_rpc as pb;
+
+use super::image::{check_image_name, Image};
+use super::memory::check_memlock;
+use super::pci::devices_not_bound_to_vfio;
+use super::ports::HostPort;
+use super::{App, Manifest, VmConfig};
+
+pub fn validation_error(field: &str, message: impl Into<String>) -> pb::ValidationFinding {
+    pb::ValidationFinding {
+        severity: "error".into(),
+        field: field.into(),
+        message: message.into(),
+    }
+}
+
+pub fn validation_warning(field: &str, message: impl Into<String>) -> pb::ValidationFinding {
+    pb::ValidationFinding {
+        severity: "warning".into(),
+        field: field.into(),
+        message: message.into(),
+    }
+}
+
+impl App {
+    /// Check a manifest built from a valid config against host state: image and boot files,
+    /// resources, and the MACs, PCI devices and ports claimed by other VMs.
+    pub fn validate_manifest(&self, manifest: &Manifest) -> Vec<pb::ValidationFinding> {
+        let mut findings = vec![];
+        if let Err(err) = self.ensure_not_draining() {
+            findings.push(validation_error("", err.to_string()));
+        }
+        if let Err(err) = self.ensure_name_not_reserved(&manifest.name) {
+            findings.push(validation_error("name", err.to_string()));
+        }
+        if let Err(err) = self.ensure_mac_unused(manifest) {
+            findings.push(validation_error("network.mac", err.to_string()));
+        }
+        let image = check_image_name(&manifest.image)
+            .and_then(|_| Image::load(self.config.image_path.join(&manifest.image)));
+        match image {
+            Ok(image) => {
+                let vm_config = VmConfig {
+                    manifest: manifest.clone(),
+                    image,
+                    cid: 0,
+                    workdir: self.work_dir(&manifest.id).path().to_path_buf(),
+                    gateway_enabled: false,
+                };
+                if let Err(err) = vm_config.validate_boot(&self.config.cvm) {
+                    findings.push(validation_error("kernel", format!("{err:#}")));
+                }
+            }
+            Err(err) => findings.push(validation_error("image", format!("{err:#}"))),
+        }
+        for (addr, driver) in devices_not_bound_to_vfio(&manifest.pci_devices) {
+            let driver = driver.as_deref().unwrap_or("no driver");
+            findings.push(validation_warning(
+                "pci_devices",
+                format!("PCI device {addr} is bound to {driver}, not vfio-pci"),
+            ));
+        }
+
+        if manifest.memory_options.is_some_and(|m| m.lock) {
+            if let Err(err) = check_memlock(manifest.memory) {
+                findings.push(validation_warning(
+                    "memory_options.lock",
+                    format!("{err:#}"),
+                ));
+            }
+        }
+        if let Some(machine) = &manifest.machine {
+            let capabilities = self.capabilities.cached();
+            if let Some(machines) = capabilities.as_ref().and_then(|c| c.machines.as_ref()) {
+                match machine.check(machines) {
+                    Ok(Some(outdated)) => {
+                        findings.push(validation_warning("machine.version", outdated))
+                    }
+                    Ok(None) => {}
+                    Err(err) => findings.push(validation_error("machine", err.to_string())),
+                }
+            }
+        }
+        findings.extend(self.allocation_findings(manifest));
+
+        let state = self.lock();
+        for addr in &manifest.pci_devices {
+            if let Some(owner) = state.pci_device_owner(addr, &manifest.id) {
+                findings.push(validation_error(
+                    "pci_devices",
+                    format!("PCI device {addr} is already claimed by VM {owner}"),
+                ));
+            }
+        }
+        for ifname in manifest.nics.iter().filter_map(|n| n.ifname.as_ref()) {
+            if let Some(owner) = state.tap_owner(ifname, &manifest.id) {
+                findings.push(validation_error(
+                    "nics",
+                    format!("Tap interface {ifname} is already used by VM {owner}"),
+                ));
+            }
+        }
+        if let Some(port) = manifest.display.map(|d| d.port).filter(|p| *p != 0) {
+            if let Some(owner) = state.display_port_owner(port, &manifest.id) {
+                findings.push(validation_error(
+                    "display.port",
+                    format!("Display port {port} is already claimed by VM {owner}"),
+                ));
+            }
+        }
+        for (i, pm) in manifest.port_map.iter().enumerate() {
+            let port = HostPort {
+                protocol: pm.protocol,
+                address: pm.address,
+                port: pm.from,
+            };
+            if let Err(err) = state.check_vm_port(&manifest.id, &port, "port mapping") {
+                findings.push(validation_error(&format!("ports[{i}]"), err.to_string()));
+            }
+        }
+        findings
+    }
+
+    /// vCPUs and memory of the VM against `max_allocable_*`, counting all other VMs.
+    pub(super) fn allocation_findings(&self, manifest: &Manifest) -> Vec<pb::ValidationFinding> {
+        let mut findings = vec![];
+        let cfg = &self.config.cvm;
+        let state = self.lock();
+        let others = state
+            .vms
+            .values()
+            .map(|vm| &vm.config.manifest)
+            .filter(|m| m.id != manifest.id);
+        let vcpu = others.clone().map(|m| m.vcpu as u64).sum::<u64>();
+        let memory = others.map(|m| m.memory as u64).sum::<u64>();
+        for (field, used, requested, max) in [
+            ("vcpu", vcpu, manifest.vcpu, cfg.max_allocable_vcpu),
+            (
+                "memory",
+                memory,
+                manifest.memory,
+                cfg.max_allocable_memory_in_mb,
+            ),
+        ] {
+            if max > 0 && used + requested as u64 > max as u64 {
+                findings.push(validation_error(
+                    field,
+                    format!(
+                        "{requested} requested, {} of {max} available",
+                        (max as u64).saturating_sub(used)
+                    ),
+                ));
+            }
+        }
+        findings
+    }
+}
@@ -0,0 +1,123 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Preloading of image files into the host page cache, so the first boot from a large image
+//! does not wait on cold reads.
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use dstack_vmm_rpc as pb;
+use tracing::{info, warn};
+
+use super::image::check_image_name;
+use super::{App, Image};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+struct Warmup {
+    bytes: u64,
+    complete: bool,
+}
+
+/// Read `files` in order until `max_bytes` are read or `deadline` passes.
+fn read_files(files: &[PathBuf], max_bytes: u64, deadline: Instant) -> Result<Warmup> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut bytes = 0u64;
+    for path in files {
+        let mut file = fs_err::File::open(path)?;
+        loop {
+            if bytes >= max_bytes || Instant::now() >= deadline {
+                return Ok(Warmup {
+                    bytes,
+                    complete: false,
+                });
+            }
+            let want = (max_bytes - bytes).min(CHUNK_SIZE as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            bytes += n as u64;
+        }
+    }
+    Ok(Warmup {
+        bytes,
+        complete: true,
+    })
+}
+
+/// Files of the image, smallest first so a bounded warmup covers as many as possible.
+fn image_files(image: &Image) -> Vec<PathBuf> {
+    let mut files = [
+        Some(image.kernel.clone()),
+        Some(image.initrd.clone()),
+        image.bios.clone(),
+        image.rootfs.clone(),
+        image.hda.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    files.sort_by_key(|f| f.metadata().map(|m| m.len()).unwrap_or_default());
+    files
+}
+
+impl App {
+    /// Read the files of image `name` into the page cache, bounded by `cvm.image_warmup`.
+    pub async fn warm_image(&self, name: &str) -> Result<pb::WarmImageResponse> {
+        check_image_name(name)?;
+        let image = Image::load(self.config.image_path.join(name))
+            .with_context(|| format!("Failed to load image {name}"))?;
+        let cfg = &self.config.cvm.image_warmup;
+        let files = image_files(&image);
+        let max_bytes = cfg.max_size_mb * 1024 * 1024;
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(cfg.timeout);
+        let warmup = tokio::task::spawn_blocking(move || read_files(&files, max_bytes, deadline))
+            .await
+            .context("Warmup task panicked")??;
+        let elapsed = started.elapsed();
+        info!(
+            "Warmed image {name}: {} MB in {}ms{}",
+            warmup.bytes / 1024 / 1024,
+            elapsed.as_millis(),
+            if warmup.complete { "" } else { " (partial)" }
+        );
+        self.lock()
+            .warmed_images
+            .insert(name.to_string(), Instant::now());
+        Ok(pb::WarmImageResponse {
+            bytes_read: warmup.bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            complete: warmup.complete,
+        })
+    }
+
+    /// Warm the image of a VM about to launch unless disabled or warmed recently.
+    pub(crate) async fn warm_image_for_launch(&self, image: &str) {
+        let cfg = &self.config.cvm.image_warmup;
+        if !cfg.enabled {
+            return;
+        }
+        {
+            let mut state = self.lock();
+            let recent = state
+                .warmed_images
+                .get(image)
+                .is_some_and(|at| at.elapsed() < Duration::from_secs(cfg.rewarm_after));
+            if recent {
+                return;
+            }
+            // Claim the warmup so VMs launched in a burst do not all read the image
+            state
+                .warmed_images
+                .insert(image.to_string(), Instant::now());
+        }
+        if let Err(err) = self.warm_image(image).await {
+            warn!("Failed to warm image {image}: {err:?}");
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of hung guests from missed heartbeats.
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, warn};
+
+use super::App;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogAction {
+    /// Only log the missed heartbeats
+    #[default]
+    Log,
+    /// Also report the guest as unresponsive in the VM info
+    Event,
+    /// Also run `cvm.watchdog.hook`
+    Hook,
+    /// Also restart the VM
+    Restart,
+}
+
+impl WatchdogAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchdogAction::Log => "log",
+            WatchdogAction::Event => "event",
+            WatchdogAction::Hook => "hook",
+            WatchdogAction::Restart => "restart",
+        }
+    }
+}
+
+impl std::str::FromStr for WatchdogAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "" | "log" => WatchdogAction::Log,
+            "event" => WatchdogAction::Event,
+            "hook" => WatchdogAction::Hook,
+            "restart" => WatchdogAction::Restart,
+            _ => bail!("Invalid watchdog action: {s}"),
+        })
+    }
+}
+
+/// What to do when a guest stops sending heartbeats. Guests that never sent a heartbeat,
+/// such as those running older images, are not watched.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchdogPolicy {
+    /// Seconds without a heartbeat before the action fires
+    pub timeout: u64,
+    #[serde(default)]
+    pub action: WatchdogAction,
+}
+
+impl App {
+    /// Fire the watchdog action of running guests that missed their heartbeats. Each action
+    /// fires once until the guest sends a heartbeat again.
+    pub(crate) async fn check_guest_heartbeats(&self) -> Result<()> {
+        let running = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .filter(|p| p.state.status.is_running())
+            .map(|p| p.config.id)
+            .collect::<BTreeSet<_>>();
+        let now = Instant::now();
+        let mut fired = vec![];
+        {
+            let mut state = self.lock();
+            for vm in state.vms.values_mut() {
+                let manifest = &vm.config.manifest;
+                let Some(policy) = manifest.watchdog else {
+                    continue;
+                };
+                if policy.timeout == 0 || !running.contains(&manifest.id) {
+                    continue;
+                }
+                let Some(last) = vm.state.last_heartbeat else {
+                    continue;
+                };
+                if vm.state.unresponsive_since.is_some() {
+                    continue;
+                }
+                let missed = now.duration_since(last);
+                if missed < Duration::from_secs(policy.timeout) {
+                    continue;
+                }
+                vm.state.unresponsive_since = Some(last);
+                fired.push((manifest.id.clone(), manifest.name.clone(), policy, missed));
+            }
+        }
+        for (id, name, policy, missed) in fired {
+            let missed = humantime::format_duration(Duration::from_secs(missed.as_secs()));
+            warn!(
+                "VM {id} missed heartbeats for {missed}, action: {}",
+                policy.action.as_str()
+            );
+            if policy.action != WatchdogAction::Log {
+                self.emit_event(
+                    "vm.unresponsive",
+                    Some(&id),
+                    json!({ "missed_heartbeats_for": missed.to_string(), "action": policy.action.as_str() }),
+                );
+            }
+            let result = match policy.action {
+                WatchdogAction::Log | WatchdogAction::Event => Ok(()),
+                WatchdogAction::Hook => self.run_watchdog_hook(&id, &name, &missed.to_string()),
+                WatchdogAction::Restart => self.restart_unresponsive_vm(&id).await,
+            };
+            if let Err(err) = result {
+                error!("Watchdog action for VM {id} failed: {err:?}");
+            }
+        }
+        Ok(())
+    }
+
+    fn run_watchdog_hook(&self, id: &str, name: &str, missed: &str) -> Result<()> {
+        let hook = &self.config.cvm.watchdog.hook;
+        if hook.is_empty() {
+            bail!("cvm.watchdog.hook is not configured");
+        }
+        // The hook is fire-and-forget, a slow hook must not stall the watchdog.
+        tokio::process::Command::new(hook)
+            .env("DSTACK_VM_ID", id)
+            .env("DSTACK_VM_NAME", name)
+            .env("DSTACK_MISSED_HEARTBEATS_FOR", missed)
+            .spawn()
+            .with_context(|| format!("Failed to run watchdog hook {hook}"))?;
+        Ok(())
+    }
+
+    async fn restart_unresponsive_vm(&self, id: &str) -> Result<()> {
+        self.ensure_not_draining()?;
+        self.supervisor.stop(id).await?;
+        // A VM that never exits must not stall the checks of the other VMs
+        let grace = Duration::from_secs(self.config.cvm.watchdog.restart_timeout);
+        let stopped = async {
+            while self.is_running(id).await? {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            anyhow::Ok(())
+        };
+        match tokio::time::timeout(grace, stopped).await {
+            Ok(result) => result?,
+            Err(_) => bail!("VM {id} did not stop within {grace:?}, not restarting it"),
+        }
+        self.start_vm(id).await
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulated hardware watchdog of a VM, which recovers a hung guest without the host-side
+//! heartbeat watchdog. QEMU reports it firing as the `WATCHDOG` QMP event.
+use anyhow::{bail, Result};
+use dstack_vmm_rpc as pb;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogModel {
+    /// Intel 6300ESB, a PCI device
+    I6300esb,
+    /// iBASE 700, an ISA device
+    Ib700,
+}
+
+impl WatchdogModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchdogModel::I6300esb => "i6300esb",
+            WatchdogModel::Ib700 => "ib700",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogDeviceAction {
+    /// Reset the guest
+    #[default]
+    Reset,
+    /// Exit QEMU, so the restart policy of the VM applies
+    Poweroff,
+    /// Pause the guest for inspection
+    Pause,
+    /// Only report it
+    None,
+}
+
+impl WatchdogDeviceAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchdogDeviceAction::Reset => "reset",
+            WatchdogDeviceAction::Poweroff => "poweroff",
+            WatchdogDeviceAction::Pause => "pause",
+            WatchdogDeviceAction::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for WatchdogDeviceAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "" | "reset" => WatchdogDeviceAction::Reset,
+            "poweroff" => WatchdogDeviceAction::Poweroff,
+            "pause" => WatchdogDeviceAction::Pause,
+            "none" => WatchdogDeviceAction::None,
+            _ => bail!("Invalid watchdog device action: {s}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchdogDevice {
+    pub model: WatchdogModel,
+    /// What QEMU does when the guest stops petting the watchdog
+    #[serde(default)]
+    pub action: WatchdogDeviceAction,
+}
+
+impl WatchdogDevice {
+    pub fn to_pb(&self) -> pb::WatchdogDeviceConfig {
+        pb::WatchdogDeviceConfig {
+            model: self.model.as_str().into(),
+            action: self.action.as_str().into(),
+        }
+    }
+
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-device".into(),
+            self.model.as_str().into(),
+            "-action".into(),
+            format!("watchdog={}", self.action.as_str()),
+        ]
+    }
+}
+
+/// The watchdog device of a VM config, `None` if the model is empty.
+pub fn resolve_watchdog_device(
+    config: &pb::WatchdogDeviceConfig,
+) -> Result<Option<WatchdogDevice>> {
+    let model = match config.model.as_str() {
+        "" => {
+            if !config.action.is_empty() {
+                bail!("Watchdog device action requires a model");
+            }
+            return Ok(None);
+        }
+        "i6300esb" => WatchdogModel::I6300esb,
+        "ib700" => WatchdogModel::Ib700,
+        other => bail!("Invalid watchdog device model: {other}, expected i6300esb or ib700"),
+    };
+    Ok(Some(WatchdogDevice {
+        model,
+        action: config.action.parse()?,
+    }))
+}
@@ -2,23 +2,76 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::IpAddr, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{bail, Context, Result};
-use load_config::load_config;
+use load_config::{apply_profile, load_config_with_includes};
 use path_absolutize::Absolutize;
-use rocket::figment::Figment;
+use ra_rpc::limits::RpcLimits;
+use rocket::figment::{Figment, Source};
 use serde::{Deserialize, Serialize};
 
 use lspci::{lspci_filtered, Device};
-use tracing::info;
+use tracing::{info, warn};
 
 pub const DEFAULT_CONFIG: &str = include_str!("../vmm.toml");
-pub fn load_config_figment(config_file: Option<&str>) -> Figment {
-    load_config("vmm", DEFAULT_CONFIG, config_file, false)
+/// Environment variable selecting the config profile if `--profile` is not given.
+pub const PROFILE_ENV: &str = "DSTACK_VMM_PROFILE";
+
+/// Load the layered config, merging the files listed under `include` in each config file,
+/// then the selected profile over the result.
+pub fn load_config_figment(config_file: Option<&str>, profile: Option<&str>) -> Result<Figment> {
+    let figment = load_config_with_includes("vmm", DEFAULT_CONFIG, config_file, false)?;
+    match profile {
+        Some(profile) => apply_profile(figment, profile),
+        None => Ok(figment),
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A config key that was renamed or removed.
+struct Deprecation {
+    /// The version the key was deprecated in
+    since: &'static str,
+    key: &'static str,
+    /// The key to use instead, `None` if the key no longer has any effect
+    replacement: Option<&'static str>,
+}
+
+const DEPRECATIONS: &[Deprecation] = &[Deprecation {
+    since: "0.5.0",
+    key: "cvm.tproxy_urls",
+    replacement: Some("cvm.gateway_urls"),
+}];
+
+/// Log a warning for each deprecated key set in `figment` and return the keys found.
+pub fn check_deprecated_keys(figment: &Figment) -> Vec<&'static str> {
+    let mut found = vec![];
+    for deprecation in DEPRECATIONS {
+        if figment.find_value(deprecation.key).is_err() {
+            continue;
+        }
+        let Deprecation {
+            since,
+            key,
+            replacement,
+        } = deprecation;
+        match replacement {
+            Some(replacement) => {
+                warn!("Config key `{key}` is deprecated since {since}, use `{replacement}` instead")
+            }
+            None => warn!("Config key `{key}` is deprecated since {since} and has no effect"),
+        }
+        found.push(*key);
+    }
+    found
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Protocol {
     Tcp,
@@ -70,6 +123,23 @@ pub struct PortMappingConfig {
 pub struct AutoRestartConfig {
     pub enabled: bool,
     pub interval: u64,
+    /// Restarts in a row before a VM is considered crash looping, 0 means unlimited
+    #[serde(default)]
+    pub max_attempts: u32,
+    /// Upper bound of the backoff between restarts in seconds. A VM that stays up this long
+    /// has its failure count reset.
+    #[serde(default)]
+    pub max_backoff: u64,
+    /// Start with auto-restart paused, toggled at runtime with `SetMaintenanceMode`
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    /// Seconds over which the restarts due in the same check are randomly spread, 0 to restart
+    /// them right away
+    #[serde(default)]
+    pub jitter: u64,
+    /// VMs restarted at the same time, 0 is taken as 1
+    #[serde(default)]
+    pub start_concurrency: u32,
 }
 
 impl PortMappingConfig {
@@ -100,11 +170,52 @@ pub struct CvmConfig {
     pub cid_pool_size: u32,
     /// Port mapping configuration
     pub port_mapping: PortMappingConfig,
-    /// Max allocable resources. Not yet implement fully, only for inspect API `GetMeta`
+    /// Max allocable resources, reported by `GetMeta` and enforced for `ReserveVm`. 0 means
+    /// unlimited
     pub max_allocable_vcpu: u32,
     pub max_allocable_memory_in_mb: u32,
+    /// Maximum number of running VMs, 0 means unlimited
+    #[serde(default)]
+    pub max_vms: u32,
+    /// Directories the image files, COW bases and direct boot kernels of VMs must be under.
+    /// Not restricted if empty
+    #[serde(default)]
+    pub allowed_image_roots: Vec<PathBuf>,
+    /// Environment variables of the VMM that `${NAME}` in the kernel, initrd and cmdline of
+    /// VMs can expand, a trailing `*` matching a prefix. None if empty
+    #[serde(default)]
+    pub boot_env_allowlist: Vec<String>,
+    /// Largest disk in MB `PrepareImage` downloads, 0 for no limit
+    #[serde(default)]
+    pub max_image_download_mb: u64,
+    /// Seconds boot secrets stay retrievable after the first fetch, 0 to deliver them once
+    #[serde(default)]
+    pub boot_secret_window: u64,
+    /// VMs whose data disk key is provided by the host as a boot secret
+    #[serde(default)]
+    pub disk_keys: Vec<DiskKeyConfig>,
+    /// Seconds host capability probes are cached, 0 to keep them until SIGHUP
+    #[serde(default)]
+    pub capabilities_refresh: u64,
     /// Enable qmp socket
     pub qmp_socket: bool,
+    /// Enable the human monitor socket of each VM and the `HmpCommand` RPC. HMP commands are
+    /// unrestricted, only enable this for debugging
+    #[serde(default)]
+    pub hmp_socket: bool,
+    /// Attach a second QMP monitor to each VM and forward its QMP events as VM events
+    #[serde(default)]
+    pub qmp_events: bool,
+    /// Seconds between looks for running VMs whose QMP events are not watched yet
+    #[serde(default)]
+    pub qmp_events_interval: u64,
+    /// Accept VMs with a fixed RNG seed or RTC start, for reproducible test runs only
+    #[serde(default)]
+    pub allow_test_determinism: bool,
+    /// Attach a QEMU guest agent channel to VMs that ask for one and enable the guest agent
+    /// RPCs, which are also restricted to `auth.admin_tokens`
+    #[serde(default)]
+    pub guest_agent: bool,
     /// GPU configuration
     pub gpu: GpuConfig,
     /// Use sudo to run the VM
@@ -134,9 +245,281 @@ pub struct CvmConfig {
     pub qemu_pci_hole64_size: u64,
     /// QEMU hotplug_off
     pub qemu_hotplug_off: bool,
+    /// Longest QEMU command line in bytes, 0 for no limit. Longer ones are shortened by moving
+    /// options into a `-readconfig` file in the VM work dir
+    pub qemu_max_argv_bytes: usize,
 
     /// Networking configuration
     pub networking: Networking,
+
+    /// Port range for auto-allocated VNC/SPICE display ports
+    #[serde(default)]
+    pub display: DisplayPortsConfig,
+
+    /// Guest heartbeat watchdog configuration
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+
+    /// Per-VM lifecycle hook configuration
+    #[serde(default)]
+    pub lifecycle_hooks: LifecycleHooksConfig,
+
+    /// Incoming live migration configuration
+    #[serde(default)]
+    pub migration: MigrationConfig,
+
+    /// Memory balloon configuration
+    #[serde(default)]
+    pub balloon: BalloonConfig,
+
+    /// Sandboxing of one-shot launches
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+
+    /// Page cache preloading of images before launching VMs
+    #[serde(default)]
+    pub image_warmup: ImageWarmupConfig,
+
+    /// Guest TSC flags
+    #[serde(default)]
+    pub tsc: TscConfig,
+
+    /// Resync of the guest clock with the host
+    #[serde(default)]
+    pub time_sync: TimeSyncConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeSyncMethod {
+    /// Set the guest clock to the host time with the guest agent
+    #[default]
+    Agent,
+    /// Reset the RTC tick reinjection over QMP and have the guest agent reload the guest clock
+    /// from the RTC
+    Rtc,
+}
+
+impl TimeSyncMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeSyncMethod::Agent => "agent",
+            TimeSyncMethod::Rtc => "rtc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TimeSyncConfig {
+    #[serde(default)]
+    pub method: TimeSyncMethod,
+    /// Resync the guest clock of VMs with a guest agent after they were paused: resumed after
+    /// a checkpoint, restored from one or migrated in
+    #[serde(default)]
+    pub after_resume: bool,
+}
+
+/// TSC exposed to the guests.
+///
+/// Under TDX the TDX module always reports an invariant TSC to the TD and runs it at the
+/// frequency chosen at TD creation, the host frequency unless pinned. `invariant` is then only
+/// informative, pinning `frequency` keeps the guest TSC the same across hosts and needs TSC
+/// scaling on the host. SEV guests are not supported by the VMM.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TscConfig {
+    /// Expose the invariant TSC CPUID flag (`+invtsc`). Needs a host TSC that is constant and
+    /// keeps running in deep C-states
+    #[serde(default)]
+    pub invariant: bool,
+    /// Guest TSC frequency in Hz (`tsc-frequency`), the host frequency if 0
+    #[serde(default)]
+    pub frequency: u64,
+}
+
+impl CvmConfig {
+    /// Fail if `allowed_image_roots` is set and `path`, with symlinks resolved, is under none
+    /// of them.
+    pub fn check_image_root(&self, path: &Path) -> Result<()> {
+        if self.allowed_image_roots.is_empty() {
+            return Ok(());
+        }
+        let resolved = match fs_err::canonicalize(path) {
+            Ok(resolved) => resolved,
+            Err(_) => path.absolutize()?.to_path_buf(),
+        };
+        if self
+            .allowed_image_roots
+            .iter()
+            .any(|root| resolved.starts_with(root))
+        {
+            return Ok(());
+        }
+        bail!("{} is outside the allowed image roots", resolved.display())
+    }
+}
+
+impl TscConfig {
+    /// Options appended to the `-cpu` argument.
+    pub fn cpu_opts(&self) -> String {
+        let mut opts = String::new();
+        if self.invariant {
+            opts.push_str(",+invtsc");
+        }
+        if self.frequency > 0 {
+            opts.push_str(&format!(",tsc-frequency={}", self.frequency));
+        }
+        opts
+    }
+}
+
+/// Source of the data disk key of a VM, delivered to the guest at boot and never stored in
+/// its work dir. Exactly one of `key_file` and `key_command` is set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiskKeyConfig {
+    /// Name of the VM
+    pub vm: String,
+    /// File holding the key
+    #[serde(default)]
+    pub key_file: String,
+    /// Shell command printing the key, e.g. fetching it from a secret store. Redacted in the
+    /// effective config, as it may hold credentials
+    #[serde(default)]
+    pub key_command: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageWarmupConfig {
+    /// Read the image of a VM into the page cache before launching it
+    pub enabled: bool,
+    /// Upper bound of the bytes read per image
+    pub max_size_mb: u64,
+    /// Seconds a warmup may take before the launch goes ahead
+    pub timeout: u64,
+    /// Seconds after which an image warmed before is read again on launch
+    pub rewarm_after: u64,
+}
+
+impl Default for ImageWarmupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size_mb: 4096,
+            timeout: 30,
+            rewarm_after: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct SandboxConfig {
+    /// Run one-shot QEMU in new mount and PID namespaces, chrooted into a root holding only the
+    /// files the VM refers to
+    #[serde(default)]
+    pub enabled: bool,
+    /// Also run it in a new network namespace
+    #[serde(default)]
+    pub isolate_network: bool,
+    /// Host paths bound read-only into the sandbox besides the system directories and the
+    /// files on the QEMU command line
+    #[serde(default)]
+    pub extra_paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BalloonConfig {
+    /// Add a virtio-balloon device to launched VMs
+    pub enabled: bool,
+    /// Seconds between guest memory statistics updates, 0 to not collect them
+    pub stats_interval: u64,
+}
+
+impl Default for BalloonConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stats_interval: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MigrationConfig {
+    /// Address incoming migration streams are accepted on
+    pub listen_address: IpAddr,
+    /// Ports allocated to incoming migrations
+    pub port_start: u16,
+    pub port_end: u16,
+    /// Seconds an accepted migration may take before the target VM is removed, and saving or
+    /// restoring a memory checkpoint may take
+    pub timeout: u64,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: Ipv4Addr::UNSPECIFIED.into(),
+            port_start: 16000,
+            port_end: 16099,
+            timeout: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LifecycleHooksConfig {
+    /// Allow VM configs to declare host commands run around their lifecycle
+    pub enabled: bool,
+    /// Seconds a hook may run unless the VM config sets its own timeout
+    pub timeout: u64,
+    /// Upper bound of the timeout a VM config may set
+    pub max_timeout: u64,
+}
+
+impl Default for LifecycleHooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: 30,
+            max_timeout: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchdogConfig {
+    /// Seconds between heartbeat and boot timeout checks
+    pub interval: u64,
+    /// Command run by the `hook` watchdog action, with `DSTACK_VM_ID`, `DSTACK_VM_NAME` and
+    /// `DSTACK_MISSED_HEARTBEATS_FOR` set
+    #[serde(default)]
+    pub hook: String,
+    /// Seconds the `restart` watchdog action waits for QEMU to exit before giving up on the VM
+    pub restart_timeout: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            hook: String::new(),
+            restart_timeout: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DisplayPortsConfig {
+    pub port_start: u16,
+    pub port_end: u16,
+}
+
+impl Default for DisplayPortsConfig {
+    fn default() -> Self {
+        Self {
+            port_start: 5900,
+            port_end: 5999,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -184,6 +567,28 @@ pub struct AuthConfig {
     pub enabled: bool,
     /// The API tokens
     pub tokens: Vec<String>,
+    /// Tokens limited to reading and streaming, refused every mutating method even on
+    /// endpoints without token auth
+    #[serde(default)]
+    pub observer_tokens: Vec<String>,
+    /// The only tokens accepted by admin methods (the guest agent RPCs), which are refused to
+    /// everyone if empty
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    /// Refuse VM definitions without a valid signature
+    #[serde(default)]
+    pub require_signed_configs: bool,
+    /// Keys accepted for VM definition signatures
+    #[serde(default)]
+    pub signing_keys: Vec<SigningKey>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningKey {
+    /// Reported as the signer of VMs verified with this key
+    pub name: String,
+    /// Hex encoded Ed25519 public key
+    pub public_key: String,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -194,6 +599,13 @@ pub struct SupervisorConfig {
     pub log_file: String,
     pub detached: bool,
     pub auto_start: bool,
+    /// Seconds a call to the supervisor may take, 0 to wait forever
+    #[serde(default)]
+    pub timeout: u64,
+    /// Seconds to keep retrying the connection at startup before starting the supervisor or
+    /// giving up, 0 to try once
+    #[serde(default)]
+    pub connect_timeout: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -226,8 +638,358 @@ pub struct Config {
     /// Host API configuration
     pub host_api: HostApiConfig,
 
+    /// Guest callback API configuration
+    #[serde(default)]
+    pub guest_callback: GuestCallbackConfig,
+
     /// Key provider configuration
     pub key_provider: KeyProviderConfig,
+
+    /// Serve the read-only status page at `/ui`
+    #[serde(default)]
+    pub status_ui: bool,
+
+    /// Webhooks notified of lifecycle events
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+
+    /// Push of the `/metrics` values to a StatsD server
+    #[serde(default)]
+    pub statsd: StatsdConfig,
+
+    /// Endpoints serving the external API. The top-level `address` and `port` are used if empty
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// In-memory history of lifecycle events
+    #[serde(default)]
+    pub events: EventsConfig,
+
+    /// Bounds of the VM logs read into memory
+    #[serde(default)]
+    pub log_limits: LogLimitsConfig,
+
+    /// External service declaring the VMs of this host
+    #[serde(default)]
+    pub inventory: InventoryConfig,
+
+    /// Host smoke test run by `dstack-vmm selftest`
+    #[serde(default)]
+    pub selftest: SelftestConfig,
+
+    /// Timeouts and request size limits of the RPC methods
+    #[serde(default)]
+    pub rpc_limits: RpcLimits,
+
+    /// RPC methods available on the server
+    #[serde(default)]
+    pub rpc: RpcConfig,
+
+    /// Periodic re-validation of the stored VM configs
+    #[serde(default)]
+    pub revalidation: RevalidationConfig,
+
+    /// Periodic reconciliation of the supervisor processes with the loaded VMs
+    #[serde(default)]
+    pub reconcile: ReconcileConfig,
+
+    /// Periodic pruning of old data disk snapshots
+    #[serde(default)]
+    pub snapshot_retention: SnapshotRetentionConfig,
+
+    /// Periodic verification of the attestation of running VMs
+    #[serde(default)]
+    pub attestation: AttestationConfig,
+
+    /// Source of the attestation certificate chain of the TEE platform
+    #[serde(default)]
+    pub platform_certs: PlatformCertsConfig,
+
+    /// Free space required in the run dir to launch a VM
+    #[serde(default)]
+    pub disk_space: DiskSpaceConfig,
+
+    /// Warnings and errors of the QEMU stderr of the VMs reported as events
+    #[serde(default)]
+    pub stderr_events: StderrEventsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct StderrEventsConfig {
+    /// Seconds between scans of the new QEMU stderr output of the VMs, 0 to disable
+    #[serde(default)]
+    pub interval: u64,
+    /// Events reported per VM and scan, the rest is only logged. 0 for no limit
+    #[serde(default)]
+    pub max_per_scan: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiskSpaceConfig {
+    /// Bytes that must be available in the run dir before a VM is launched, 0 to not check
+    pub min_free_bytes: u64,
+}
+
+impl Default for DiskSpaceConfig {
+    fn default() -> Self {
+        Self {
+            min_free_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct PlatformCertsConfig {
+    /// PEM file with the certificate chain, leaf first
+    #[serde(default)]
+    pub file: String,
+    /// Shell command printing the PEM certificate chain, leaf first. Redacted in the effective
+    /// config, as it may hold credentials
+    #[serde(default)]
+    pub command: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct RevalidationConfig {
+    /// Seconds between re-validations of the stored VM configs, 0 to disable
+    #[serde(default)]
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct SnapshotRetentionConfig {
+    /// Seconds between prunings, 0 to disable
+    #[serde(default)]
+    pub interval: u64,
+    /// Snapshots of each VM kept regardless of age, 0 for no count rule
+    #[serde(default)]
+    pub keep_last: u32,
+    /// Seconds after which a snapshot is pruned, 0 for no age rule
+    #[serde(default)]
+    pub max_age: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct AttestationConfig {
+    /// Seconds between verifications of the running VMs, 0 to only verify on request
+    #[serde(default)]
+    pub interval: u64,
+    /// Seconds after which a passed verification is reported stale, 0 to never
+    #[serde(default)]
+    pub max_age: u64,
+    /// Hex MRTDs a quote must have one of, any if empty
+    #[serde(default)]
+    pub expected_mrtd: Vec<String>,
+    /// Hex OS image hashes a quote must have one of, any if empty
+    #[serde(default)]
+    pub expected_os_image_hash: Vec<String>,
+    /// Report a VM ready only once its attestation is verified, not as soon as its guest is
+    #[serde(default)]
+    pub gate_readiness: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct RpcConfig {
+    /// Methods refused to every caller, by name without the service prefix
+    #[serde(default)]
+    pub disabled_methods: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ReconcileConfig {
+    /// Seconds between reconciliations with the supervisor, 0 to disable
+    #[serde(default)]
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventsConfig {
+    /// Events kept across all VMs and the host
+    pub capacity: usize,
+    /// Events kept per VM
+    pub per_vm_capacity: usize,
+    /// Bytes of events kept across all VMs and the host, 0 for no limit
+    pub max_bytes: usize,
+    /// Bytes of events kept per VM, 0 for no limit
+    pub per_vm_max_bytes: usize,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            per_vm_capacity: 100,
+            max_bytes: 1024 * 1024,
+            per_vm_max_bytes: 128 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogLimitsConfig {
+    /// Trailing bytes of a log file read for a tail (stderr, serial console, diagnostics), and
+    /// the largest range of the serial log read at once
+    pub tail_max_bytes: u64,
+    /// Lines a tail returns at most
+    pub tail_max_lines: usize,
+    /// Lines the `/logs` stream replays before following
+    pub stream_max_lines: usize,
+    /// Longest line returned by tails and streams, longer lines are cut
+    pub max_line_bytes: usize,
+}
+
+impl Default for LogLimitsConfig {
+    fn default() -> Self {
+        Self {
+            tail_max_bytes: 1024 * 1024,
+            tail_max_lines: 10000,
+            stream_max_lines: 10000,
+            max_line_bytes: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    /// IP address, or `unix:<path>` for a Unix domain socket
+    pub address: String,
+    /// TCP port, the top-level `port` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// TLS of the endpoint, the top-level `tls` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ListenerTlsConfig>,
+    /// Require API tokens on this endpoint, `auth.enabled` if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerTlsConfig {
+    pub certs: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Config {
+    /// The endpoints of the external API, falling back to the top-level rocket address.
+    pub fn api_listeners(&self, figment: &Figment) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            address: figment
+                .extract_inner("address")
+                .unwrap_or_else(|_| "127.0.0.1".into()),
+            port: figment.extract_inner("port").ok(),
+            tls: None,
+            auth: None,
+        }]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdConfig {
+    /// `host:port` of the StatsD server, disabled if empty
+    #[serde(default)]
+    pub address: String,
+    /// Seconds between pushes
+    pub interval: u64,
+    /// Prepended to every metric name
+    #[serde(default)]
+    pub prefix: String,
+    /// Send labels as DogStatsD tags instead of appending them to the metric name
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            interval: 10,
+            prefix: String::new(),
+            dogstatsd: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SelftestConfig {
+    /// VM configuration booted by `dstack-vmm selftest` if none is given
+    #[serde(default)]
+    pub vm_config: String,
+    /// Seconds the self-test VM has to report ready
+    pub timeout: u64,
+}
+
+impl Default for SelftestConfig {
+    fn default() -> Self {
+        Self {
+            vm_config: String::new(),
+            timeout: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InventoryConfig {
+    /// URL returning the JSON list of VMs this host should run, disabled if empty
+    #[serde(default)]
+    pub url: String,
+    /// Sent as a bearer token in the Authorization header if not empty
+    #[serde(default)]
+    pub token: String,
+    /// Seconds a fetch may take
+    pub timeout: u64,
+    /// Seconds a fetched list is reused before fetching it again
+    pub cache_ttl: u64,
+    /// Seconds between reconciliations after the one at startup, 0 to reconcile at startup only
+    pub interval: u64,
+    /// Remove the VMs that are not in the inventory instead of only warning about them
+    #[serde(default)]
+    pub prune: bool,
+}
+
+impl Default for InventoryConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            token: String::new(),
+            timeout: 10,
+            cache_ttl: 30,
+            interval: 60,
+            prune: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    /// Retries of a failed delivery, with exponential backoff
+    pub max_retries: u32,
+    #[serde(default)]
+    pub endpoints: Vec<WebhookEndpoint>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            endpoints: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// Key to sign the body with HMAC-SHA256, unsigned if empty
+    #[serde(default)]
+    pub secret: String,
+    /// Events to deliver, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
@@ -248,7 +1010,17 @@ impl ProcessAnnotation {
 }
 
 impl Config {
-    pub fn abs_path(self) -> Result<Self> {
+    pub fn abs_path(mut self) -> Result<Self> {
+        // Resolved like the paths checked against them
+        self.cvm.allowed_image_roots = self
+            .cvm
+            .allowed_image_roots
+            .iter()
+            .map(|root| match fs_err::canonicalize(root) {
+                Ok(root) => Ok(root),
+                Err(_) => Ok(root.absolutize()?.to_path_buf()),
+            })
+            .collect::<Result<_>>()?;
         Ok(Self {
             image_path: self.image_path.absolutize()?.to_path_buf(),
             run_path: self.run_path.absolutize()?.to_path_buf(),
@@ -262,6 +1034,7 @@ impl Config {
 pub enum Networking {
     User(UserNetworking),
     Passt(PasstNetworking),
+    Bridge(BridgeNetworking),
     Custom(CustomNetworking),
 }
 
@@ -292,6 +1065,18 @@ pub struct PasstNetworking {
     pub ipv4_only: bool,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BridgeNetworking {
+    /// Bridge of VMs without a network group
+    pub bridge: String,
+    /// `qemu-bridge-helper` attaching the tap devices, its ACL must allow the group bridges
+    pub bridge_helper: String,
+    /// Bridge of each network group, `dstack-<hash of group>` for groups not listed.
+    /// Missing group bridges are created when a VM of the group starts.
+    #[serde(default)]
+    pub group_bridges: BTreeMap<String, String>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CustomNetworking {
     pub netdev: String,
@@ -301,6 +1086,30 @@ pub struct CustomNetworking {
 pub struct HostApiConfig {
     pub address: String,
     pub port: u32,
+    /// Maximum concurrent connections to the vsock host API, 0 for unlimited
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Maximum concurrent connections to the vsock host API from a single VM, 0 for unlimited
+    #[serde(default)]
+    pub max_connections_per_cid: usize,
+}
+
+/// The vsock listener serving only the calls guests make on their own behalf.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GuestCallbackConfig {
+    /// Serve the guest callback API and tell guests its URL in the sys-config
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub address: String,
+    #[serde(default)]
+    pub port: u32,
+    /// Maximum concurrent connections to the guest callback API, 0 for unlimited
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Maximum concurrent connections to the guest callback API from a single VM, 0 for unlimited
+    #[serde(default)]
+    pub max_connections_per_cid: usize,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -365,3 +1174,181 @@ impl Config {
         Ok(me)
     }
 }
+
+#[cfg(test)]
+impl Config {
+    /// The default config, for tests.
+    pub(crate) fn for_test() -> Self {
+        use rocket::figment::providers::{Format, Toml};
+        Figment::from(Toml::string(DEFAULT_CONFIG))
+            .extract()
+            .expect("valid default config")
+    }
+}
+
+/// Config keys holding secrets, redacted in [`effective_config`].
+/// A `*` segment matches every element of an array.
+const SECRET_KEYS: &[&str] = &[
+    "auth.admin_tokens",
+    "auth.observer_tokens",
+    "auth.tokens",
+    "cvm.disk_keys.*.key_command",
+    "cvm.tmp_ca_key",
+    "inventory.token",
+    "platform_certs.command",
+    "secret_key",
+    "webhook.endpoints.*.secret",
+];
+
+/// The fully resolved config with secrets redacted.
+pub struct EffectiveConfig {
+    pub config: serde_json::Value,
+    /// Dotted key path of each leaf value mapped to where it came from
+    pub sources: BTreeMap<String, String>,
+}
+
+/// Resolve the merged config from `figment`, recording the provider of each value.
+///
+/// Values filled in by [`Config::extract_or_default`] after merging are reported as `computed`.
+pub fn effective_config(figment: &Figment, config: &Config) -> Result<EffectiveConfig> {
+    let mut value: serde_json::Value = figment.extract().context("Failed to extract config")?;
+    let mut sources = BTreeMap::new();
+    collect_sources(figment, "", &value, &mut sources);
+    let computed = [
+        ("image_path", &config.image_path),
+        ("run_path", &config.run_path),
+        ("cvm.qemu_path", &config.cvm.qemu_path),
+    ];
+    for (key, path) in computed {
+        let path = serde_json::Value::String(path.display().to_string());
+        let Some(slot) = value.pointer_mut(&json_pointer(key)) else {
+            continue;
+        };
+        if *slot != path {
+            *slot = path;
+            sources.insert(key.to_string(), "computed".to_string());
+        }
+    }
+    redact_secrets(&mut value);
+    Ok(EffectiveConfig {
+        config: value,
+        sources,
+    })
+}
+
+fn redact_secrets(value: &mut serde_json::Value) {
+    for key in SECRET_KEYS {
+        let path = key.split('.').collect::<Vec<_>>();
+        redact(value, &path);
+    }
+}
+
+fn redact(value: &mut serde_json::Value, path: &[&str]) {
+    let Some((first, rest)) = path.split_first() else {
+        let is_empty = match value {
+            serde_json::Value::Null => true,
+            serde_json::Value::String(s) => s.is_empty(),
+            serde_json::Value::Array(a) => a.is_empty(),
+            _ => false,
+        };
+        if !is_empty {
+            *value = serde_json::Value::String(crate::app::REDACTED.to_string());
+        }
+        return;
+    };
+    match (value, *first) {
+        (serde_json::Value::Array(items), "*") => {
+            for item in items {
+                redact(item, rest);
+            }
+        }
+        (serde_json::Value::Object(map), key) => {
+            if let Some(child) = map.get_mut(key) {
+                redact(child, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn json_pointer(key: &str) -> String {
+    format!("/{}", key.replace('.', "/"))
+}
+
+fn collect_sources(
+    figment: &Figment,
+    prefix: &str,
+    value: &serde_json::Value,
+    sources: &mut BTreeMap<String, String>,
+) {
+    if let serde_json::Value::Object(map) = value {
+        for (key, value) in map {
+            let key = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{prefix}.{key}")
+            };
+            collect_sources(figment, &key, value, sources);
+        }
+        return;
+    }
+    let source = match figment.find_metadata(prefix) {
+        Some(metadata) => match &metadata.source {
+            Some(Source::File(path)) => format!("file:{}", path.display()),
+            Some(Source::Code(_)) => "default".to_string(),
+            Some(Source::Custom(source)) => source.clone(),
+            _ if metadata.name.to_lowercase().contains("env") => "env".to_string(),
+            _ => metadata.name.to_string(),
+        },
+        None => "unknown".to_string(),
+    };
+    sources.insert(prefix.to_string(), source);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_secrets() {
+        let mut value = json!({
+            "auth": { "admin_tokens": ["admin"], "observer_tokens": [] },
+            "webhook": {
+                "endpoints": [
+                    { "url": "https://a.example", "secret": "s3cret" },
+                    { "url": "https://b.example", "secret": "" },
+                ],
+            },
+            "inventory": { "url": "https://inventory.example", "token": "bearer" },
+            "cvm": {
+                "disk_keys": [
+                    { "vm": "app", "key_file": "/keys/app.key", "key_command": "" },
+                    { "vm": "db", "key_file": "", "key_command": "vault read -token=t db" },
+                ],
+            },
+            "platform_certs": { "file": "", "command": "curl -H 'Authorization: t' https://pccs" },
+        });
+        redact_secrets(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "auth": { "admin_tokens": "<redacted>", "observer_tokens": [] },
+                "webhook": {
+                    "endpoints": [
+                        { "url": "https://a.example", "secret": "<redacted>" },
+                        { "url": "https://b.example", "secret": "" },
+                    ],
+                },
+                "inventory": { "url": "https://inventory.example", "token": "<redacted>" },
+                "cvm": {
+                    "disk_keys": [
+                        { "vm": "app", "key_file": "/keys/app.key", "key_command": "" },
+                        { "vm": "db", "key_file": "", "key_command": "<redacted>" },
+                    ],
+                },
+                "platform_certs": { "file": "", "command": "<redacted>" },
+            })
+        );
+    }
+}
@@ -3,16 +3,66 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::App as AppState;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use guest_api::{
+    client::DefaultClient as GuestClient,
     proxied_guest_api_server::{ProxiedGuestApiRpc, ProxiedGuestApiServer},
     GuestInfo, Id, ListContainersResponse, NetworkInformation, SystemInfo,
 };
+use ra_rpc::rocket_helper::deps::{PrpcHandler, RpcRequest, RpcResponse};
 use ra_rpc::{CallContext, RpcCall};
+use rocket::{
+    data::Data,
+    get,
+    http::Status,
+    post,
+    request::{FromRequest, Outcome},
+    Request, Route, State,
+};
+use rocket_apitoken::Authorized;
 use std::ops::Deref;
 
+/// Header carrying the per-VM token passed to the guest in its sys-config.
+pub const GUEST_TOKEN_HEADER: &str = "X-Dstack-Guest-Token";
+
+/// The authenticated caller of the guest API.
+#[derive(Debug, Clone)]
+pub enum GuestCaller {
+    /// An operator authenticated with the external API token
+    Operator,
+    /// A guest authenticated with its per-VM token, limited to its own VM id
+    Guest(String),
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GuestCaller {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(token) = request.headers().get_one(GUEST_TOKEN_HEADER) {
+            let Some(app) = request.rocket().state::<AppState>() else {
+                return Outcome::Error((Status::InternalServerError, ()));
+            };
+            return match app.vm_id_by_guest_token(token) {
+                Some(vm_id) => Outcome::Success(GuestCaller::Guest(vm_id)),
+                None => Outcome::Error((Status::Unauthorized, ())),
+            };
+        }
+        match request.guard::<Authorized>().await {
+            Outcome::Success(_) => Outcome::Success(GuestCaller::Operator),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+pub struct GuestCallState {
+    app: AppState,
+    caller: GuestCaller,
+}
+
 pub struct GuestApiHandler {
     state: AppState,
+    caller: GuestCaller,
 }
 
 impl Deref for GuestApiHandler {
@@ -23,36 +73,92 @@ impl Deref for GuestApiHandler {
     }
 }
 
-impl RpcCall<AppState> for GuestApiHandler {
+impl GuestApiHandler {
+    fn guest_client(&self, id: &str) -> Result<GuestClient> {
+        if let GuestCaller::Guest(vm_id) = &self.caller {
+            if vm_id != id {
+                bail!("Access denied to vm {id}");
+            }
+        }
+        self.guest_agent_client(id)
+    }
+}
+
+impl RpcCall<GuestCallState> for GuestApiHandler {
     type PrpcService = ProxiedGuestApiServer<Self>;
 
-    fn construct(context: CallContext<'_, AppState>) -> Result<Self> {
+    fn construct(context: CallContext<'_, GuestCallState>) -> Result<Self> {
         Ok(Self {
-            state: context.state.clone(),
+            state: context.state.app.clone(),
+            caller: context.state.caller.clone(),
         })
     }
 }
 
+#[post("/<method>", data = "<data>")]
+async fn prpc_post<'a: 'd, 'd>(
+    app: &'a State<AppState>,
+    caller: GuestCaller,
+    method: &'a str,
+    rpc_request: RpcRequest<'a>,
+    data: Data<'d>,
+) -> RpcResponse {
+    let state = GuestCallState {
+        app: app.inner().clone(),
+        caller,
+    };
+    PrpcHandler::builder()
+        .state(&state)
+        .request(rpc_request)
+        .method(method)
+        .data(data)
+        .build()
+        .handle::<GuestApiHandler>()
+        .await
+}
+
+#[get("/<method>")]
+async fn prpc_get(
+    app: &State<AppState>,
+    caller: GuestCaller,
+    method: &str,
+    rpc_request: RpcRequest<'_>,
+) -> RpcResponse {
+    let state = GuestCallState {
+        app: app.inner().clone(),
+        caller,
+    };
+    PrpcHandler::builder()
+        .state(&state)
+        .request(rpc_request)
+        .method(method)
+        .build()
+        .handle::<GuestApiHandler>()
+        .await
+}
+
+pub fn routes() -> Vec<Route> {
+    rocket::routes![prpc_post, prpc_get]
+}
+
 impl ProxiedGuestApiRpc for GuestApiHandler {
     async fn info(self, request: Id) -> Result<GuestInfo> {
-        self.guest_agent_client(&request.id)?.info().await
+        self.guest_client(&request.id)?.info().await
     }
 
     async fn sys_info(self, request: Id) -> Result<SystemInfo> {
-        self.guest_agent_client(&request.id)?.sys_info().await
+        self.guest_client(&request.id)?.sys_info().await
     }
 
     async fn network_info(self, request: Id) -> Result<NetworkInformation> {
-        self.guest_agent_client(&request.id)?.network_info().await
+        self.guest_client(&request.id)?.network_info().await
     }
 
     async fn list_containers(self, request: Id) -> Result<ListContainersResponse> {
-        self.guest_agent_client(&request.id)?
-            .list_containers()
-            .await
+        self.guest_client(&request.id)?.list_containers().await
     }
 
     async fn shutdown(self, request: Id) -> Result<()> {
-        self.guest_agent_client(&request.id)?.shutdown().await
+        self.guest_client(&request.id)?.shutdown().await
     }
 }
@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The guest callback API: the few calls a guest makes on its own behalf, served on their own
+//! vsock listener so no operator or host API method is reachable from it.
+use anyhow::{bail, Result};
+use host_api::{
+    guest_callback_server::{GuestCallbackRpc, GuestCallbackServer},
+    BootSecrets, FetchBootSecretRequest, GuestCallbackRequest,
+};
+use ra_rpc::{CallContext, RemoteEndpoint, RpcCall};
+
+use crate::app::App;
+
+/// Boot progress of a guest that finished booting.
+const BOOT_DONE: &str = "done";
+
+pub struct GuestCallbackHandler {
+    cid: u32,
+    app: App,
+}
+
+impl RpcCall<App> for GuestCallbackHandler {
+    type PrpcService = GuestCallbackServer<Self>;
+
+    fn construct(context: CallContext<'_, App>) -> Result<Self> {
+        let Some(RemoteEndpoint::Vsock { cid, .. }) = context.remote_endpoint else {
+            bail!("invalid remote endpoint: {:?}", context.remote_endpoint);
+        };
+        Ok(Self {
+            cid,
+            app: context.state.clone(),
+        })
+    }
+}
+
+impl GuestCallbackRpc for GuestCallbackHandler {
+    async fn heartbeat(self, request: GuestCallbackRequest) -> Result<()> {
+        self.app.authenticate_guest(self.cid, &request.token)?;
+        self.app
+            .vm_event_report(self.cid, "heartbeat", String::new())
+    }
+
+    async fn ready(self, request: GuestCallbackRequest) -> Result<()> {
+        self.app.authenticate_guest(self.cid, &request.token)?;
+        self.app
+            .vm_event_report(self.cid, "boot.progress", BOOT_DONE.to_string())
+    }
+
+    async fn fetch_boot_secret(self, request: FetchBootSecretRequest) -> Result<BootSecrets> {
+        let secrets = self.app.fetch_boot_secrets(self.cid, &request.token)?;
+        Ok(BootSecrets {
+            secrets: secrets.into_iter().collect(),
+        })
+    }
+}
@@ -5,7 +5,8 @@
 use anyhow::{bail, Context, Result};
 use host_api::{
     host_api_server::{HostApiRpc, HostApiServer},
-    GetSealingKeyRequest, GetSealingKeyResponse, HostInfo, Notification,
+    BootSecrets, FetchBootSecretRequest, GetSealingKeyRequest, GetSealingKeyResponse, HostInfo,
+    Notification,
 };
 use ra_rpc::{CallContext, RemoteEndpoint, RpcCall};
 use rocket_vsock_listener::VsockEndpoint;
@@ -60,4 +61,13 @@ impl HostApiRpc for HostApiHandler {
             provider_quote: response.provider_quote,
         })
     }
+
+    async fn fetch_boot_secret(self, request: FetchBootSecretRequest) -> Result<BootSecrets> {
+        let secrets = self
+            .app
+            .fetch_boot_secrets(self.endpoint.cid, &request.token)?;
+        Ok(BootSecrets {
+            secrets: secrets.into_iter().collect(),
+        })
+    }
 }
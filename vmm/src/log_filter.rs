@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The global `tracing` subscriber with a filter that can be replaced at runtime.
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Install the global subscriber, filtered by `RUST_LOG` or `info` if unset.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .init();
+    let _ = FILTER.set(handle);
+}
+
+fn handle() -> Result<&'static reload::Handle<EnvFilter, Registry>> {
+    FILTER.get().context("Log filter is not initialized")
+}
+
+/// The active filter directives.
+pub fn current() -> Result<String> {
+    handle()?
+        .with_current(|filter| filter.to_string())
+        .context("Failed to read log filter")
+}
+
+/// Replace the filter with `EnvFilter` directives such as `info,dstack_vmm=debug`.
+pub fn set(directives: &str) -> Result<()> {
+    if directives.trim().is_empty() {
+        bail!("Empty log filter");
+    }
+    let filter = EnvFilter::builder()
+        .parse(directives)
+        .context("Invalid log filter")?;
+    handle()?
+        .reload(filter)
+        .context("Failed to apply log filter")
+}
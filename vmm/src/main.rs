@@ -4,14 +4,15 @@
 
 use std::{path::Path, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use app::App;
 use clap::{Args as ClapArgs, Parser, Subcommand};
-use config::Config;
-use guest_api_service::GuestApiHandler;
+use config::{Config, ListenerConfig};
+use guest_callback_service::GuestCallbackHandler;
 use host_api_service::HostApiHandler;
-use main_service::RpcHandler;
+use main_service::{RpcHandler, ADMIN_METHODS, READ_ONLY_METHODS};
 use path_absolutize::Absolutize;
+use ra_rpc::access::{AdminTokens, DisabledMethods, ObserverTokens};
 use rocket::{
     fairing::AdHoc,
     figment::{providers::Serialized, Figment},
@@ -20,15 +21,24 @@ use rocket::{
 use rocket_apitoken::ApiToken;
 use rocket_vsock_listener::VsockListener;
 use supervisor_client::SupervisorClient;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing::{error, info};
 
 mod app;
 mod config;
 mod guest_api_service;
+mod guest_callback_service;
 mod host_api_service;
+mod log_filter;
 mod main_routes;
 mod main_service;
+mod metrics;
 mod one_shot;
+#[cfg(feature = "profiling")]
+mod profile;
+mod selftest;
+mod status_page;
+mod webhook;
 
 const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 const GIT_REV: &str = git_version::git_version!(
@@ -47,6 +57,12 @@ struct Args {
     /// Path to the configuration file
     #[arg(short, long)]
     config: Option<String>,
+    /// Config profile to merge over the config, defaults to `$DSTACK_VMM_PROFILE`
+    #[arg(long)]
+    profile: Option<String>,
+    /// Fail on deprecated config keys instead of warning about them
+    #[arg(long)]
+    strict_config: bool,
     /// Subcommand to run
     #[command(subcommand)]
     command: Option<Command>,
@@ -59,6 +75,36 @@ enum Command {
     Serve,
     /// One-shot VM execution mode for debugging
     Run(RunArgs),
+    /// Print the processes and files a one-shot run would create, without creating anything
+    Render(RenderArgs),
+    /// Boot a known-good VM, wait for it to report ready, query its guest agent over vsock and
+    /// check its attestation, then remove it. Serves the host API meanwhile, so run it while
+    /// the VMM is not serving on this host
+    Selftest(SelftestArgs),
+}
+
+#[derive(ClapArgs)]
+struct SelftestArgs {
+    /// VM configuration file path (default: `selftest.vm_config`)
+    vm_config: Option<String>,
+    /// Time the VM has to report ready (e.g. 90s, 5m, default: `selftest.timeout`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// Keep the VM when a check fails
+    #[arg(long)]
+    keep: bool,
+}
+
+#[derive(ClapArgs)]
+struct RenderArgs {
+    /// VM configuration file path
+    vm_config: String,
+    /// Working directory the paths are rendered for (default: in the current directory)
+    #[arg(long)]
+    workdir: Option<String>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: one_shot::RenderFormat,
 }
 
 #[derive(ClapArgs)]
@@ -71,19 +117,117 @@ struct RunArgs {
     /// Dry run: only output QEMU command without executing
     #[arg(long)]
     dry_run: bool,
+    /// Fail the dry run if passthrough PCI devices are not bound to vfio-pci, the host lacks
+    /// KVM, TDX or the CPU flags of the TSC settings, a network disk source is unreachable, or
+    /// RLIMIT_MEMLOCK is too low for locked VM memory
+    #[arg(long, requires = "dry_run")]
+    strict: bool,
+    /// Write the QEMU launch of the dry run as a systemd service unit to this file
+    #[arg(long, requires = "dry_run", value_name = "FILE")]
+    emit_systemd: Option<String>,
+    /// Kill QEMU if the VM is not running within this duration (e.g. 90s, 5m)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    launch_timeout: Option<Duration>,
+    /// Don't copy the VM config and the effective VMM config into the workdir
+    #[arg(long)]
+    no_archive_config: bool,
+    /// Keep the working directory when QEMU exits cleanly. By default it is removed then, and
+    /// kept when the run fails
+    #[arg(long, conflicts_with = "clean_workdir_on_exit")]
+    keep_workdir: bool,
+    /// Remove the working directory when the run ends, even on failure, after a dry run or if
+    /// it existed before
+    #[arg(long)]
+    clean_workdir_on_exit: bool,
+    /// Run QEMU in new mount and PID namespaces, chrooted into a root holding only the files
+    /// the VM refers to. Needs root or CAP_SYS_ADMIN
+    #[arg(long)]
+    sandbox: bool,
+    /// Also run QEMU in a new network namespace, cutting it off from host networking
+    #[arg(long)]
+    sandbox_net: bool,
+}
+
+/// Figment of an external API endpoint, the top-level config with the endpoint settings merged.
+fn listener_figment(figment: &Figment, listener: &ListenerConfig) -> Figment {
+    let mut settings = serde_json::Map::new();
+    settings.insert("address".into(), listener.address.clone().into());
+    if let Some(port) = listener.port {
+        settings.insert("port".into(), port.into());
+    }
+    if let Some(tls) = &listener.tls {
+        settings.insert(
+            "tls".into(),
+            serde_json::json!({ "certs": tls.certs, "key": tls.key }),
+        );
+    }
+    figment.clone().merge(Serialized::defaults(settings))
 }
 
-async fn run_external_api(app: App, figment: Figment, api_auth: ApiToken) -> Result<()> {
+/// Serve the external API on all configured endpoints until one of them fails.
+async fn run_external_api(app: App, figment: Figment) -> Result<()> {
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in app.config.api_listeners(&figment) {
+        let auth = listener.auth.unwrap_or(app.config.auth.enabled);
+        // The routes outside prpc only read, observers may use all of them
+        let tokens = app
+            .config
+            .auth
+            .tokens
+            .iter()
+            .chain(&app.config.auth.observer_tokens)
+            .chain(&app.config.auth.admin_tokens)
+            .cloned()
+            .collect();
+        let api_auth = ApiToken::new(tokens, auth);
+        servers.spawn(serve_external_api(
+            app.clone(),
+            listener_figment(&figment, &listener),
+            api_auth,
+            auth,
+        ));
+    }
+    while let Some(result) = servers.join_next().await {
+        result.context("External API task panicked")??;
+    }
+    Ok(())
+}
+
+async fn serve_external_api(
+    app: App,
+    figment: Figment,
+    api_auth: ApiToken,
+    auth: bool,
+) -> Result<()> {
     let external_api = rocket::custom(figment)
         .mount("/", main_routes::routes())
-        .mount("/guest", ra_rpc::prpc_routes!(App, GuestApiHandler))
+        .mount("/guest", guest_api_service::routes())
         .mount("/api", ra_rpc::prpc_routes!(App, HostApiHandler))
         .mount(
             "/prpc",
             ra_rpc::prpc_routes!(App, RpcHandler, trim: "Teepod."),
         )
+        .manage(
+            app.config
+                .rpc_limits
+                .clone()
+                .with_interruptible(READ_ONLY_METHODS.iter().copied()),
+        )
+        .manage(app.rpc_concurrency.clone())
+        .manage(ObserverTokens::new(
+            app.config.auth.observer_tokens.clone(),
+            READ_ONLY_METHODS.iter().copied(),
+        ))
+        .manage(AdminTokens::new(
+            app.config.auth.admin_tokens.clone(),
+            ADMIN_METHODS.iter().copied(),
+        ))
+        .manage(DisabledMethods::new(
+            app.config.rpc.disabled_methods.clone(),
+        ))
         .manage(app)
         .manage(api_auth)
+        .attach(metrics::RpcLatencyFairing)
         .attach(AdHoc::on_response("Add app rev header", |_req, res| {
             Box::pin(async move {
                 res.set_raw_header("X-App-Version", app_version());
@@ -95,19 +239,33 @@ async fn run_external_api(app: App, figment: Figment, api_auth: ApiToken) -> Res
             })
         }));
 
-    let _ = external_api
-        .launch()
+    let ignite = external_api
+        .ignite()
+        .await
+        .map_err(|err| anyhow!("Failed to ignite rocket: {err}"))?;
+    let endpoint = DefaultListener::bind_endpoint(&ignite)
+        .map_err(|err| anyhow!("Invalid external API endpoint: {err}"))?;
+    let listener = DefaultListener::bind(&ignite)
+        .await
+        .map_err(|err| anyhow!("Failed to bind external API on {endpoint}: {err}"))?;
+    let auth = if auth { "token auth" } else { "no auth" };
+    info!("External API listening on {endpoint} ({auth})");
+    ignite
+        .launch_on(listener)
         .await
         .map_err(|err| anyhow!(err.to_string()))?;
     Ok(())
 }
 
 async fn run_host_api(app: App, figment: Figment) -> Result<()> {
+    let vsock_stats = app.vsock_stats.clone();
+    let limits = app.config.host_api.clone();
     let figment = figment
         .clone()
         .merge(Serialized::defaults(figment.find_value("host_api")?));
     let rocket = rocket::custom(figment)
         .mount("/api", ra_rpc::prpc_routes!(App, HostApiHandler))
+        .manage(app.config.rpc_limits.clone())
         .manage(app);
     let ignite = rocket
         .ignite()
@@ -123,7 +281,9 @@ async fn run_host_api(app: App, figment: Figment) -> Result<()> {
             .map_err(|err| anyhow!(err.to_string()))?;
     } else {
         let listener = VsockListener::bind_rocket(&ignite)
-            .map_err(|err| anyhow!("Failed to bind host API : {err}"))?;
+            .map_err(|err| anyhow!("Failed to bind host API : {err}"))?
+            .with_stats(vsock_stats)
+            .with_limits(limits.max_connections, limits.max_connections_per_cid);
         ignite
             .launch_on(listener)
             .await
@@ -132,52 +292,216 @@ async fn run_host_api(app: App, figment: Figment) -> Result<()> {
     Ok(())
 }
 
+/// Serve the guest callback API, which only answers guests that present their own token.
+async fn run_guest_callback_api(app: App, figment: Figment) -> Result<()> {
+    let limits = app.config.guest_callback.clone();
+    let figment = figment
+        .clone()
+        .merge(Serialized::defaults(figment.find_value("guest_callback")?));
+    let rocket = rocket::custom(figment)
+        .mount("/api", ra_rpc::prpc_routes!(App, GuestCallbackHandler))
+        .manage(app.config.rpc_limits.clone())
+        .manage(app);
+    let ignite = rocket
+        .ignite()
+        .await
+        .map_err(|err| anyhow!("Failed to ignite rocket: {err}"))?;
+    let listener = VsockListener::bind_rocket(&ignite)
+        .map_err(|err| anyhow!("Failed to bind guest callback API: {err}"))?
+        .with_limits(limits.max_connections, limits.max_connections_per_cid);
+    info!("Guest callback API listening on vsock port {}", limits.port);
+    ignite
+        .launch_on(listener)
+        .await
+        .map_err(|err| anyhow!(err.to_string()))?;
+    Ok(())
+}
+
 async fn auto_restart_task(app: App) {
     if !app.config.cvm.auto_restart.enabled {
         info!("Auto restart CVMs is disabled");
         return;
     }
-    let mut interval =
-        tokio::time::interval(Duration::from_secs(app.config.cvm.auto_restart.interval));
     loop {
         info!("Checking for exited VMs");
         if let Err(err) = app.try_restart_exited_vms().await {
             error!("Failed to restart exited VMs: {err:?}");
         }
+        // Read on every round, so a new interval set at runtime applies to the next check
+        let interval = app.auto_restart_params().interval;
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+async fn watchdog_task(app: App) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(app.config.cvm.watchdog.interval.max(1)));
+    loop {
         interval.tick().await;
+        if let Err(err) = app.check_guest_heartbeats().await {
+            error!("Failed to check guest heartbeats: {err:?}");
+        }
+        if let Err(err) = app.check_boot_timeouts().await {
+            error!("Failed to check boot timeouts: {err:?}");
+        }
+        if let Err(err) = app.check_vm_lifetimes().await {
+            error!("Failed to check VM lifetimes: {err:?}");
+        }
+        app.purge_expired_boot_secrets();
+        if let Err(err) = app.check_stopped_vms().await {
+            error!("Failed to check stopped VMs: {err:?}");
+        }
+    }
+}
+
+async fn inventory_task(app: App) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(app.config.inventory.interval.max(1)));
+    // The first tick completes immediately, the startup reconciliation already ran
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = app.reconcile_inventory().await {
+            error!("Failed to reconcile VMs with the inventory: {err:?}");
+        }
+    }
+}
+
+async fn revalidation_task(app: App) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(app.config.revalidation.interval.max(1)));
+    loop {
+        interval.tick().await;
+        app.revalidate_configs();
+    }
+}
+
+async fn qmp_events_task(app: App) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        app.config.cvm.qmp_events_interval.max(1),
+    ));
+    loop {
+        interval.tick().await;
+        if let Err(err) = app.watch_qmp_events().await {
+            error!("Failed to watch QMP events: {err:?}");
+        }
+    }
+}
+
+async fn reconcile_task(app: App) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(app.config.reconcile.interval.max(1)));
+    loop {
+        interval.tick().await;
+        app.reconcile_supervisor().await;
+    }
+}
+
+async fn snapshot_retention_task(app: App) {
+    let policy = app.config.snapshot_retention.clone();
+    let mut interval = tokio::time::interval(Duration::from_secs(policy.interval.max(1)));
+    loop {
+        interval.tick().await;
+        app.apply_snapshot_retention(&policy).await;
+    }
+}
+
+async fn attestation_task(app: App) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(app.config.attestation.interval.max(1)));
+    loop {
+        interval.tick().await;
+        app.verify_attestations().await;
+    }
+}
+
+async fn stderr_events_task(app: App) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        app.config.stderr_events.interval.max(1),
+    ));
+    loop {
+        interval.tick().await;
+        app.scan_stderr_events();
+    }
+}
+
+/// Probe the host capabilities and read the platform certificates again whenever the VMM
+/// receives SIGHUP.
+async fn sighup_task(app: App) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Failed to listen for SIGHUP: {err}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        app.capabilities.invalidate();
+        app.platform_certs.invalidate();
+        app.capabilities.get().await;
     }
 }
 
 #[rocket::main]
 async fn main() -> Result<()> {
-    {
-        use tracing_subscriber::{fmt, EnvFilter};
-        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-        fmt().with_env_filter(filter).init();
-    }
+    log_filter::init();
 
     let args = Args::parse();
-    let figment = config::load_config_figment(args.config.as_deref());
+    let profile = args.profile.clone().or_else(|| {
+        std::env::var(config::PROFILE_ENV)
+            .ok()
+            .filter(|p| !p.is_empty())
+    });
+    let figment = config::load_config_figment(args.config.as_deref(), profile.as_deref())
+        .context("Failed to load config")?;
+    let deprecated = config::check_deprecated_keys(&figment);
+    if args.strict_config && !deprecated.is_empty() {
+        bail!("Deprecated config keys in use: {}", deprecated.join(", "));
+    }
     let config = Config::extract_or_default(&figment)?.abs_path()?;
 
     // Handle commands
-    match args.command.unwrap_or_default() {
+    let selftest = match args.command.unwrap_or_default() {
         Command::Run(run_args) => {
             // One-shot VM execution mode
-            return one_shot::run_one_shot(
-                &run_args.vm_config,
-                config,
-                run_args.workdir,
-                run_args.dry_run,
-            )
-            .await;
+            let options = one_shot::OneShotOptions {
+                workdir: run_args.workdir,
+                dry_run: run_args.dry_run,
+                strict: run_args.strict,
+                emit_systemd: run_args.emit_systemd,
+                launch_timeout: run_args.launch_timeout,
+                archive_config: !run_args.no_archive_config,
+                sandbox: run_args.sandbox || run_args.sandbox_net,
+                sandbox_net: run_args.sandbox_net,
+                workdir_retention: if run_args.keep_workdir {
+                    one_shot::WorkdirRetention::Keep
+                } else if run_args.clean_workdir_on_exit {
+                    one_shot::WorkdirRetention::Clean
+                } else {
+                    one_shot::WorkdirRetention::Auto
+                },
+            };
+            return one_shot::run_one_shot(&run_args.vm_config, config, &figment, options).await;
         }
+        Command::Render(render_args) => {
+            return one_shot::render(
+                &render_args.vm_config,
+                &config,
+                render_args.workdir.as_deref(),
+                render_args.format,
+            );
+        }
+        Command::Selftest(selftest_args) => Some(selftest::SelftestOptions {
+            vm_config: selftest_args.vm_config,
+            timeout: selftest_args.timeout,
+            keep: selftest_args.keep,
+        }),
         Command::Serve => {
             // Default server mode - continue to main server logic
+            None
         }
-    }
+    };
 
-    let api_auth = ApiToken::new(config.auth.tokens.clone(), config.auth.enabled);
     let supervisor = {
         let cfg = &config.supervisor;
         let abs_exe = Path::new(&cfg.exe).absolutize()?;
@@ -188,18 +512,60 @@ async fn main() -> Result<()> {
             &cfg.log_file,
             cfg.detached,
             cfg.auto_start,
+            Duration::from_secs(cfg.connect_timeout),
         )
         .await
         .context("Failed to connect to supervisor")?
+        .with_timeout(Duration::from_secs(cfg.timeout))
     };
-    let state = app::App::new(config, supervisor);
+    let state = app::App::new(config, figment.clone(), supervisor);
+    if let Some(options) = selftest {
+        tokio::select! {
+            result = selftest::run(state.clone(), options) => return result,
+            result = run_host_api(state, figment) => {
+                result.context("Failed to run host API")?;
+                bail!("Host API stopped during the self-test");
+            }
+        }
+    }
     state.reload_vms().await.context("Failed to reload VMs")?;
     tokio::spawn(auto_restart_task(state.clone()));
+    tokio::spawn(watchdog_task(state.clone()));
+    tokio::spawn(sighup_task(state.clone()));
+    if !state.config.inventory.url.is_empty() && state.config.inventory.interval > 0 {
+        tokio::spawn(inventory_task(state.clone()));
+    }
+    if !state.config.statsd.address.is_empty() {
+        tokio::spawn(metrics::statsd_task(state.clone()));
+    }
+    if state.config.revalidation.interval > 0 {
+        tokio::spawn(revalidation_task(state.clone()));
+    }
+    if state.config.cvm.qmp_events {
+        tokio::spawn(qmp_events_task(state.clone()));
+    }
+    if state.config.reconcile.interval > 0 {
+        tokio::spawn(reconcile_task(state.clone()));
+    }
+    let retention = &state.config.snapshot_retention;
+    if retention.interval > 0 && (retention.keep_last > 0 || retention.max_age > 0) {
+        tokio::spawn(snapshot_retention_task(state.clone()));
+    }
+    if state.config.attestation.interval > 0 {
+        tokio::spawn(attestation_task(state.clone()));
+    }
+    if state.config.stderr_events.interval > 0 {
+        tokio::spawn(stderr_events_task(state.clone()));
+    }
 
+    let guest_callback = state.config.guest_callback.enabled;
     tokio::select! {
-        result = run_external_api(state.clone(), figment.clone(), api_auth) => {
+        result = run_external_api(state.clone(), figment.clone()) => {
             result.context("Failed to run external API")?;
         }
+        result = run_guest_callback_api(state.clone(), figment.clone()), if guest_callback => {
+            result.context("Failed to run guest callback API")?;
+        }
         result = run_host_api(state, figment) => {
             result.context("Failed to run host API")?;
         }
@@ -2,12 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::app::App;
+use crate::app::{truncate_line, App, UsageSampler};
 use anyhow::Result;
+use dstack_vmm_rpc::StatusRequest;
 use fs_err as fs;
 use rocket::{
     get,
-    http::ContentType,
+    http::{Accept, ContentType},
     response::{status::Custom, stream::TextStream},
     routes, Route, State,
 };
@@ -46,6 +47,13 @@ async fn res(path: &str) -> Result<(ContentType, String), Custom<String>> {
 static STREAM_CREATED_COUNTER: AtomicUsize = AtomicUsize::new(0);
 static STREAM_DROPPED_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+/// Log streams currently open.
+pub fn active_log_streams() -> usize {
+    STREAM_CREATED_COUNTER
+        .load(Ordering::Relaxed)
+        .saturating_sub(STREAM_DROPPED_COUNTER.load(Ordering::Relaxed))
+}
+
 struct StreamCounter {
     id: usize,
 }
@@ -85,6 +93,7 @@ fn vm_logs(
     ch: Option<&str>,
 ) -> TextStream![String] {
     let workdir = app.work_dir(&id);
+    let limits = app.config.log_limits.clone();
     let ch = ch.unwrap_or("serial").to_string();
     TextStream! {
         let log_file = match ch.as_str() {
@@ -99,9 +108,11 @@ fn vm_logs(
 
         let counter = StreamCounter::new();
 
-        const DEFAULT_TAIL_LINES: usize = 10000;
+        let num_lines = lines
+            .unwrap_or(limits.stream_max_lines)
+            .min(limits.stream_max_lines);
         let tailer_result = tailf::Options::builder()
-            .num_lines(lines.or(Some(DEFAULT_TAIL_LINES)))
+            .num_lines(Some(num_lines))
             .follow(follow)
             .build()
             .tail(log_file);
@@ -134,8 +145,9 @@ fn vm_logs(
             match next {
                 Ok(Some(line)) => {
                     let line_str = String::from_utf8_lossy(&line);
+                    let line_str = truncate_line(&line_str, limits.max_line_bytes);
                     if ansi {
-                        yield line_str.to_string();
+                        yield line_str;
                     } else {
                         yield strip_ansi_escapes::strip_str(&line_str);
                     }
@@ -152,6 +164,122 @@ fn vm_logs(
     }
 }
 
+/// Stream resource usage samples as JSON lines, one every `interval` seconds.
+#[get("/resource-usage?<interval>")]
+fn resource_usage(
+    _auth: Authorized,
+    app: &State<App>,
+    interval: Option<u64>,
+) -> TextStream![String] {
+    let app = app.inner().clone();
+    let interval = Duration::from_secs(interval.unwrap_or(5).max(1));
+    TextStream! {
+        let _counter = StreamCounter::new();
+        let mut sampler = UsageSampler::default();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match app.sample_resource_usage(&mut sampler).await {
+                Ok(usage) => match serde_json::to_string(&usage) {
+                    Ok(line) => yield line + "\n",
+                    Err(err) => yield format!("{{\"error\":{:?}}}\n", err.to_string()),
+                },
+                Err(err) => yield format!("{{\"error\":{:?}}}\n", format!("{err:#}")),
+            }
+        }
+    }
+}
+
+/// The compiled proto definitions of the RPC services, a serialized `FileDescriptorSet`.
+#[get("/service-descriptor")]
+fn service_descriptor(_auth: Authorized) -> (ContentType, &'static [u8]) {
+    (ContentType::Binary, dstack_vmm_rpc::FILE_DESCRIPTOR_SET)
+}
+
+/// Prometheus text format, or OpenMetrics if the scraper accepts it.
+#[get("/metrics")]
+async fn metrics(
+    _auth: Authorized,
+    app: &State<App>,
+    accept: Option<&Accept>,
+) -> (ContentType, String) {
+    let metrics = crate::metrics::collect(app).await;
+    let openmetrics = accept.is_some_and(|accept| {
+        accept
+            .iter()
+            .any(|media| media.top() == "application" && media.sub() == "openmetrics-text")
+    });
+    if openmetrics {
+        let content_type = ContentType::parse_flexible(crate::metrics::OPENMETRICS_CONTENT_TYPE)
+            .unwrap_or(ContentType::Plain);
+        return (content_type, crate::metrics::render_openmetrics(&metrics));
+    }
+    (
+        ContentType::Plain,
+        crate::metrics::render_prometheus(&metrics),
+    )
+}
+
+#[get("/ui")]
+async fn status_ui(
+    _auth: Authorized,
+    app: &State<App>,
+) -> Result<(ContentType, String), Custom<String>> {
+    if !app.config.status_ui {
+        return Err(Custom(
+            rocket::http::Status::NotFound,
+            "Not found".to_string(),
+        ));
+    }
+    let status = app
+        .list_vms(StatusRequest::default())
+        .await
+        .map_err(|err| {
+            Custom(
+                rocket::http::Status::InternalServerError,
+                format!("{err:?}"),
+            )
+        })?;
+    let page = crate::status_page::render(&status);
+    Ok((ContentType::HTML, page))
+}
+
+#[cfg(feature = "profiling")]
+#[get("/debug/profile?<seconds>&<frequency>&<flamegraph>")]
+async fn debug_profile(
+    _auth: Authorized,
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    flamegraph: Option<bool>,
+) -> Result<(ContentType, Vec<u8>), Custom<String>> {
+    let options = crate::profile::ProfileOptions {
+        duration: Duration::from_secs(seconds.unwrap_or(10)),
+        frequency: frequency.unwrap_or(99),
+        flamegraph: flamegraph.unwrap_or(false),
+    };
+    let content_type = if options.flamegraph {
+        ContentType::SVG
+    } else {
+        ContentType::Binary
+    };
+    let profile = crate::profile::capture(options)
+        .await
+        .map_err(|err| Custom(rocket::http::Status::BadRequest, format!("{err:?}")))?;
+    Ok((content_type, profile))
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![index, res, vm_logs]
+    #[allow(unused_mut)]
+    let mut routes = routes![
+        index,
+        res,
+        vm_logs,
+        resource_usage,
+        service_descriptor,
+        metrics,
+        status_ui
+    ];
+    #[cfg(feature = "profiling")]
+    routes.extend(routes![debug_profile]);
+    routes
 }
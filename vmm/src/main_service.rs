@@ -2,26 +2,58 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::net::IpAddr;
 use std::ops::Deref;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, bail, Context, Result};
 use dstack_types::AppCompose;
 use dstack_vmm_rpc as rpc;
 use dstack_vmm_rpc::vmm_server::{VmmRpc, VmmServer};
 use dstack_vmm_rpc::{
-    AppId, ComposeHash as RpcComposeHash, GatewaySettings, GetInfoResponse, GetMetaResponse, Id,
-    ImageInfo as RpcImageInfo, ImageListResponse, KmsSettings, ListGpusResponse, PublicKeyResponse,
-    ResizeVmRequest, ResourcesSettings, StatusRequest, StatusResponse, UpgradeAppRequest,
-    VersionResponse, VmConfiguration,
+    AdoptVmRequest, AppId, AttestationReport, AutoRestartParams, BalloonInfo, CheckpointInfo,
+    CheckpointVmRequest, CleanupNetworkDevicesRequest, CleanupNetworkDevicesResponse,
+    ClearRestartStateRequest, ClearRestartStateResponse, CollectDiagnosticsRequest,
+    CommitVmRequest, ComposeHash as RpcComposeHash, ConfigValueSource, DiagnosticsBundle,
+    DiffVmConfigRequest, DrainHostRequest, DrainStatus, EffectiveConfigResponse,
+    ExtendVmLifetimeRequest, ExtendVmLifetimeResponse, FleetExport, GatewaySettings,
+    GetInfoResponse, GetLaunchDigestRequest, GetMetaResponse, GetReliabilityStatsRequest,
+    GetVmDiskStatsResponse, GetVmEventsRequest, GetVmStderrRequest, GuestAgentExecRequest,
+    GuestAgentExecResponse, GuestAgentPingResponse, GuestTimeSync, HmpCommandRequest,
+    HmpCommandResponse, HostCapacity, HostInfo, Id, ImageInfo as RpcImageInfo, ImageListResponse,
+    IncomingMigration, KmsSettings, LaunchDigestResponse, ListGpusResponse, LogLevel,
+    MaintenanceMode, ManagedNetworkDevicesResponse, PlatformCertificates, PrepareImageRequest,
+    PrepareImageResponse, ProbeGuestRequest, ProbeGuestResponse, ProvisionBootSecretsRequest,
+    PruneSnapshotsRequest, PruneSnapshotsResponse, PublicKeyResponse, ReadSerialLogRequest,
+    RebootGuestRequest, RebootGuestResponse, ReconcileStatus, ReliabilityStats, ReplaceVmRequest,
+    ReserveVmRequest, ResizeVmRequest, ResourceUsage, ResourcesSettings, RestoreSnapshotRequest,
+    RestoreSnapshotResponse, RotateVmTokenRequest, SerialLogChunk, ServiceDescriptor,
+    ServiceMethods, SetAutoRestartParamsRequest, SetBalloonTargetRequest, SetVmIoThrottleRequest,
+    SetVmNetworkEnabledRequest, SetVmRuntimeParamsRequest, StatusRequest, StatusResponse,
+    StopVmRequest, StopVmResponse, SwapBootDiskRequest, SwapBootDiskResponse, UpgradeAppRequest,
+    ValidationFinding, VersionResponse, VmConfigDiff, VmConfigDrive, VmConfiguration,
+    VmEventsResponse, VmFit, VmMeasurements, VmNetStats, VmNetworkState, VmReservation,
+    VmRuntimeParams, VmSource, VmStderrResponse, VmTokenFingerprint, VmTokenResponse, VmValidation,
+    VsockCidConnections, VsockConnectionStats, VsockStatsResponse, WarmImageRequest,
+    WarmImageResponse,
 };
 use fs_err as fs;
 use ra_rpc::{CallContext, RpcCall};
 use tracing::{info, warn};
 
-use crate::app::{App, AttachMode, GpuConfig, GpuSpec, Manifest, PortMapping, VmWorkDir};
+use crate::app::{
+    parse_mac, resolve_cpu, resolve_disks, resolve_display, resolve_hooks, resolve_machine,
+    resolve_nics, resolve_pci_devices, resolve_qmp_events, resolve_scheduling,
+    resolve_test_determinism, resolve_user_net, resolve_watchdog_device, token_fingerprint,
+    upgrade_signed_message, validate_network_group, validation_error, verify_config_signature,
+    vm_config_signed_message, AdoptSource, App, AttachMode, ExitCodes, GpuConfig, GpuSpec,
+    IoThrottle, Manifest, MemoryOptions, PortMapping, RestartPolicy, RtcBase, RtcClock, RtcConfig,
+    UsageSampler, VmNetworkConfig, VmWorkDir, WatchdogAction, WatchdogPolicy,
+};
+use crate::config::{effective_config, Networking};
+use crate::log_filter;
 
-fn hex_sha256(data: &str) -> String {
+fn hex_sha256(data: impl AsRef<[u8]>) -> String {
     use sha2::Digest;
     let mut hasher = sha2::Sha256::new();
     hasher.update(data);
@@ -124,6 +156,222 @@ pub fn resolve_gpus(gpu_cfg: &rpc::GpuConfig) -> Result<GpuConfig> {
     }
 }
 
+fn resolve_rtc(rtc: &rpc::RtcConfig) -> Result<RtcConfig> {
+    let base = match rtc.base.as_str() {
+        "" | "utc" => RtcBase::Utc,
+        "localtime" => RtcBase::Localtime,
+        base => bail!("Invalid RTC base: {base}"),
+    };
+    let clock = match rtc.clock.as_str() {
+        "" | "host" => RtcClock::Host,
+        "vm" => RtcClock::Vm,
+        "rt" => RtcClock::Rt,
+        clock => bail!("Invalid RTC clock: {clock}"),
+    };
+    Ok(RtcConfig { base, clock })
+}
+
+fn resolve_exit_codes(exit_codes: &rpc::ExitCodes) -> Result<ExitCodes> {
+    let exit_codes = ExitCodes {
+        success: exit_codes.success.clone(),
+        failure: exit_codes.failure.clone(),
+    };
+    exit_codes.validate()?;
+    Ok(exit_codes)
+}
+
+fn resolve_network(
+    network: &rpc::NetworkConfig,
+    cvm_config: &crate::config::CvmConfig,
+) -> Result<VmNetworkConfig> {
+    let user_mode = matches!(cvm_config.networking, Networking::User(_));
+    if !user_mode && !network.dns.is_empty() {
+        bail!("DNS settings are only supported with user-mode networking");
+    }
+    let mac = match network.mac.as_str() {
+        "" => None,
+        mac => Some(parse_mac(mac)?),
+    };
+    let mut dns: Vec<IpAddr> = Vec::new();
+    for addr in &network.dns {
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid DNS address: {addr}"))?;
+        if dns.iter().any(|d| d.is_ipv4() == addr.is_ipv4()) {
+            bail!("At most one IPv4 and one IPv6 DNS address are supported");
+        }
+        dns.push(addr);
+    }
+    let hostname = match network.hostname.as_str() {
+        "" => None,
+        hostname => {
+            let valid = hostname.len() <= 253
+                && hostname.split('.').all(|label| {
+                    !label.is_empty()
+                        && label.len() <= 63
+                        && !label.starts_with('-')
+                        && !label.ends_with('-')
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                });
+            if !valid {
+                bail!("Invalid hostname: {hostname}");
+            }
+            Some(hostname.to_string())
+        }
+    };
+    let addressing = resolve_user_net(
+        &network.net,
+        &network.gateway,
+        &network.guest_address,
+        dns.iter().find_map(|d| match d {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        }),
+    )?;
+    if !user_mode && addressing.is_some() {
+        bail!("Pinned network addressing is only supported with user-mode networking");
+    }
+    Ok(VmNetworkConfig {
+        dns,
+        hostname,
+        mac,
+        addressing,
+    })
+}
+
+fn resolve_port_mapping(
+    p: &rpc::PortMapping,
+    cvm_config: &crate::config::CvmConfig,
+) -> Result<PortMapping> {
+    let pm_cfg = &cvm_config.port_mapping;
+    let from = p.host_port.try_into().context("Invalid host port")?;
+    let to = p.vm_port.try_into().context("Invalid vm port")?;
+    if !pm_cfg.is_allowed(&p.protocol, from) {
+        bail!("Port mapping is not allowed for {}:{}", p.protocol, from);
+    }
+    let protocol = p.protocol.parse().context("Invalid protocol")?;
+    let address = if !p.host_address.is_empty() {
+        p.host_address.parse().context("Invalid host address")?
+    } else {
+        pm_cfg.address
+    };
+    Ok(PortMapping {
+        address,
+        protocol,
+        from,
+        to,
+    })
+}
+
+/// Check each field of `request` on its own, so that every invalid field is reported.
+fn field_findings(
+    request: &VmConfiguration,
+    cvm_config: &crate::config::CvmConfig,
+) -> Vec<ValidationFinding> {
+    fn ok<T>(result: Result<T>) -> Result<()> {
+        result.map(|_| ())
+    }
+    let mut checks = vec![
+        ("name".to_string(), validate_label(&request.name)),
+        (
+            "compose_file".into(),
+            ok(serde_json::from_str::<AppCompose>(&request.compose_file)
+                .context("Invalid compose file")),
+        ),
+        ("disks".into(), ok(resolve_disks(&request.disks))),
+        ("nics".into(), ok(resolve_nics(&request.nics, cvm_config))),
+        (
+            "pci_devices".into(),
+            ok(resolve_pci_devices(&request.pci_devices)),
+        ),
+    ];
+    if !(request.ports.is_empty() || cvm_config.port_mapping.enabled) {
+        checks.push(("ports".into(), Err(anyhow!("Port mapping is disabled"))));
+    }
+    for (i, port) in request.ports.iter().enumerate() {
+        checks.push((
+            format!("ports[{i}]"),
+            ok(resolve_port_mapping(port, cvm_config)),
+        ));
+    }
+    if let Some(gpus) = &request.gpus {
+        checks.push((
+            "gpus".into(),
+            ok(resolve_gpus_with_config(gpus, cvm_config)),
+        ));
+    }
+    if let Some(rtc) = &request.rtc {
+        checks.push(("rtc".into(), ok(resolve_rtc(rtc))));
+    }
+    if let Some(network) = &request.network {
+        checks.push(("network".into(), ok(resolve_network(network, cvm_config))));
+    }
+    if let Some(display) = &request.display {
+        checks.push(("display".into(), ok(resolve_display(display))));
+    }
+    if let Some(policy) = &request.restart_policy {
+        checks.push(("restart_policy".into(), ok(policy.parse::<RestartPolicy>())));
+    }
+    if let Some(exit_codes) = &request.exit_codes {
+        checks.push(("exit_codes".into(), ok(resolve_exit_codes(exit_codes))));
+    }
+    if let Some(watchdog) = request.watchdog.as_ref().filter(|w| w.timeout_secs > 0) {
+        checks.push((
+            "watchdog.action".into(),
+            ok(watchdog.action.parse::<WatchdogAction>()),
+        ));
+    }
+    if let Some(group) = request.network_group.as_ref().filter(|g| !g.is_empty()) {
+        checks.push(("network_group".into(), validate_network_group(group)));
+    }
+    if let Some(hooks) = &request.hooks {
+        checks.push(("hooks".into(), ok(resolve_hooks(hooks, cvm_config))));
+    }
+    if let Some(cpu) = &request.cpu {
+        checks.push(("cpu".into(), ok(resolve_cpu(cpu, request.pin_numa))));
+    }
+    if let Some(machine) = &request.machine {
+        checks.push(("machine".into(), ok(resolve_machine(machine))));
+    }
+    if let Some(qmp_events) = &request.qmp_events {
+        checks.push(("qmp_events".into(), ok(resolve_qmp_events(qmp_events))));
+    }
+    if let Some(watchdog_device) = &request.watchdog_device {
+        checks.push((
+            "watchdog_device".into(),
+            ok(resolve_watchdog_device(watchdog_device)),
+        ));
+    }
+    if let Some(determinism) = &request.test_determinism {
+        checks.push((
+            "test_determinism".into(),
+            ok(resolve_test_determinism(
+                determinism,
+                cvm_config,
+                request.rtc.is_some(),
+            )),
+        ));
+    }
+    if let Some(hints) = &request.scheduling {
+        let cpu = request
+            .cpu
+            .as_ref()
+            .and_then(|cpu| resolve_cpu(cpu, request.pin_numa).ok().flatten());
+        checks.push((
+            "scheduling".into(),
+            ok(resolve_scheduling(hints, cpu.as_ref(), request.vcpu)),
+        ));
+    }
+    checks
+        .into_iter()
+        .filter_map(|(field, result)| {
+            result
+                .err()
+                .map(|err| validation_error(&field, format!("{err:#}")))
+        })
+        .collect()
+}
+
 // Shared function to create manifest from VM configuration
 pub fn create_manifest_from_vm_config(
     request: VmConfiguration,
@@ -131,32 +379,13 @@ pub fn create_manifest_from_vm_config(
 ) -> Result<Manifest> {
     validate_label(&request.name)?;
 
-    let pm_cfg = &cvm_config.port_mapping;
-    if !(request.ports.is_empty() || pm_cfg.enabled) {
+    if !(request.ports.is_empty() || cvm_config.port_mapping.enabled) {
         bail!("Port mapping is disabled");
     }
     let port_map = request
         .ports
         .iter()
-        .map(|p| {
-            let from = p.host_port.try_into().context("Invalid host port")?;
-            let to = p.vm_port.try_into().context("Invalid vm port")?;
-            if !pm_cfg.is_allowed(&p.protocol, from) {
-                bail!("Port mapping is not allowed for {}:{}", p.protocol, from);
-            }
-            let protocol = p.protocol.parse().context("Invalid protocol")?;
-            let address = if !p.host_address.is_empty() {
-                p.host_address.parse().context("Invalid host address")?
-            } else {
-                pm_cfg.address
-            };
-            Ok(PortMapping {
-                address,
-                protocol,
-                from,
-                to,
-            })
-        })
+        .map(|p| resolve_port_mapping(p, cvm_config))
         .collect::<Result<Vec<_>>>()?;
 
     let app_id = match &request.app_id {
@@ -172,6 +401,89 @@ pub fn create_manifest_from_vm_config(
         Some(gpus) => resolve_gpus_with_config(gpus, cvm_config)?,
         None => GpuConfig::default(),
     };
+    let disks = resolve_disks(&request.disks)?;
+    let nics = resolve_nics(&request.nics, cvm_config)?;
+    let pci_devices = resolve_pci_devices(&request.pci_devices)?;
+    let rtc = request.rtc.as_ref().map(resolve_rtc).transpose()?;
+    let network = request
+        .network
+        .as_ref()
+        .map(|network| resolve_network(network, cvm_config))
+        .transpose()?;
+    let display = request
+        .display
+        .as_ref()
+        .map(resolve_display)
+        .transpose()?
+        .flatten();
+    let restart_policy = request
+        .restart_policy
+        .as_deref()
+        .map(str::parse::<RestartPolicy>)
+        .transpose()?;
+    let exit_codes = request
+        .exit_codes
+        .as_ref()
+        .map(resolve_exit_codes)
+        .transpose()?;
+    let watchdog = request
+        .watchdog
+        .as_ref()
+        .filter(|w| w.timeout_secs > 0)
+        .map(|w| {
+            anyhow::Ok(WatchdogPolicy {
+                timeout: w.timeout_secs,
+                action: w.action.parse()?,
+            })
+        })
+        .transpose()?;
+    let network_group = request
+        .network_group
+        .clone()
+        .filter(|g| !g.is_empty())
+        .map(|g| validate_network_group(&g).map(|_| g))
+        .transpose()?;
+    let hooks = request
+        .hooks
+        .as_ref()
+        .map(|hooks| resolve_hooks(hooks, cvm_config))
+        .transpose()?
+        .flatten();
+    let cpu = request
+        .cpu
+        .as_ref()
+        .map(|cpu| resolve_cpu(cpu, request.pin_numa))
+        .transpose()?
+        .flatten();
+    let scheduling = request
+        .scheduling
+        .as_ref()
+        .map(|hints| resolve_scheduling(hints, cpu.as_ref(), request.vcpu))
+        .transpose()?
+        .flatten();
+    let test_determinism = request
+        .test_determinism
+        .as_ref()
+        .map(|d| resolve_test_determinism(d, cvm_config, rtc.is_some()))
+        .transpose()?
+        .flatten();
+    let machine = request
+        .machine
+        .as_ref()
+        .map(resolve_machine)
+        .transpose()?
+        .flatten();
+    let qmp_events = request
+        .qmp_events
+        .as_ref()
+        .map(resolve_qmp_events)
+        .transpose()?;
+    let watchdog_device = request
+        .watchdog_device
+        .as_ref()
+        .map(resolve_watchdog_device)
+        .transpose()?
+        .flatten();
 
     Ok(Manifest::builder()
         .id(id)
@@ -185,9 +497,40 @@ pub fn create_manifest_from_vm_config(
         .created_at_ms(now)
         .hugepages(request.hugepages)
         .pin_numa(request.pin_numa)
+        .guest_agent(request.guest_agent)
+        .maybe_memory_options(
+            request
+                .memory_options
+                .as_ref()
+                .and_then(MemoryOptions::from_pb),
+        )
+        .maybe_machine(machine)
+        .maybe_qmp_events(qmp_events)
         .gpus(gpus)
         .kms_urls(request.kms_urls.clone())
         .gateway_urls(request.gateway_urls.clone())
+        .disks(disks)
+        .maybe_kernel(request.kernel.clone())
+        .maybe_initrd(request.initrd.clone())
+        .maybe_cmdline(request.cmdline.clone())
+        .maybe_expected_cmdline(request.expected_cmdline.clone())
+        .maybe_qemu_name(request.qemu_name.clone().filter(|n| !n.is_empty()))
+        .pci_devices(pci_devices)
+        .maybe_rtc(rtc)
+        .maybe_network(network)
+        .maybe_display(display)
+        .maybe_cpu(cpu)
+        .maybe_restart_policy(restart_policy)
+        .maybe_exit_codes(exit_codes)
+        .maybe_watchdog(watchdog)
+        .maybe_watchdog_device(watchdog_device)
+        .maybe_boot_timeout(request.boot_timeout_secs.filter(|t| *t > 0))
+        .maybe_max_lifetime(request.max_lifetime_secs.filter(|t| *t > 0))
+        .maybe_network_group(network_group)
+        .nics(nics)
+        .maybe_hooks(hooks)
+        .maybe_scheduling(scheduling)
+        .maybe_test_determinism(test_determinism)
         .build())
 }
 
@@ -195,45 +538,108 @@ impl RpcHandler {
     fn resolve_gpus(&self, gpu_cfg: &rpc::GpuConfig) -> Result<GpuConfig> {
         resolve_gpus_with_config(gpu_cfg, &self.app.config.cvm)
     }
+
+    /// Create a VM, with the id of the committed reservation if any. An `incoming` VM is left
+    /// stopped for [`App::prepare_incoming_migration`].
+    async fn create_vm_with_id(
+        &self,
+        request: VmConfiguration,
+        id: Option<String>,
+        incoming: bool,
+    ) -> Result<Id> {
+        self.app.ensure_not_draining()?;
+        let signed_by = verify_config_signature(
+            &self.app.config.auth,
+            &vm_config_signed_message(&request),
+            &request.signature,
+        )?;
+        let mut manifest = create_manifest_from_vm_config(request.clone(), &self.app.config.cvm)?;
+        if let Some(key) = &signed_by {
+            info!("VM {} config verified with signing key {key}", manifest.id);
+        }
+        manifest.signed_by = signed_by;
+        if let Some(id) = id {
+            manifest.id = id;
+        }
+        let id = manifest.id.clone();
+        let start = !request.stopped && !incoming;
+        self.app.create_vm(manifest, &request, start).await?;
+        Ok(Id { id })
+    }
 }
 
 impl VmmRpc for RpcHandler {
     async fn create_vm(self, request: VmConfiguration) -> Result<Id> {
-        let manifest = create_manifest_from_vm_config(request.clone(), &self.app.config.cvm)?;
-        let id = manifest.id.clone();
-        let app_id = manifest.app_id.clone();
-        let vm_work_dir = self.app.work_dir(&id);
-        vm_work_dir
-            .put_manifest(&manifest)
-            .context("Failed to write manifest")?;
-        let work_dir = self.prepare_work_dir(&id, &request, &app_id)?;
-        if let Err(err) = vm_work_dir.set_started(!request.stopped) {
-            warn!("Failed to set started: {}", err);
-        }
+        self.app.ensure_name_not_reserved(&request.name)?;
+        self.create_vm_with_id(request, None, false).await
+    }
 
-        let result = self
+    async fn reserve_vm(self, request: ReserveVmRequest) -> Result<VmReservation> {
+        self.app.reserve_vm(request)
+    }
+
+    async fn commit_vm(self, request: CommitVmRequest) -> Result<Id> {
+        let mut config = request.configuration.context("Missing VM configuration")?;
+        let reservation = self
             .app
-            .load_vm(&work_dir, &Default::default(), false)
-            .await
-            .context("Failed to load VM");
-        let result = match result {
-            Ok(()) => {
-                if !request.stopped {
-                    self.app.start_vm(&id).await
-                } else {
-                    Ok(())
-                }
-            }
-            Err(err) => Err(err),
-        };
-        if let Err(err) = result {
-            if let Err(err) = fs::remove_dir_all(&work_dir) {
-                warn!("Failed to remove work dir: {}", err);
+            .claim_reservation(&request.reservation_id, &config)?;
+        if config.name.is_empty() {
+            config.name = reservation.name().to_string();
+        }
+        let result = self
+            .create_vm_with_id(config, Some(reservation.id().to_string()), false)
+            .await;
+        if result.is_err() {
+            self.app.restore_reservation(reservation);
+        }
+        result
+    }
+
+    async fn cancel_reservation(self, request: Id) -> Result<()> {
+        self.app.cancel_reservation(&request.id)
+    }
+
+    async fn prepare_incoming_migration(
+        self,
+        request: VmConfiguration,
+    ) -> Result<IncomingMigration> {
+        self.app.ensure_name_not_reserved(&request.name)?;
+        let Id { id } = self.create_vm_with_id(request, None, true).await?;
+        match self.app.prepare_incoming_migration(&id).await {
+            Ok(uri) => Ok(IncomingMigration { id, uri }),
+            Err(err) => {
+                self.app.abort_incoming_migration(&id).await;
+                Err(err.context("Failed to prepare incoming migration"))
             }
-            return Err(err);
         }
+    }
 
-        Ok(Id { id })
+    async fn accept_migration(self, request: Id) -> Result<()> {
+        self.app.accept_migration(&request.id).await
+    }
+
+    async fn adopt_vm(self, request: AdoptVmRequest) -> Result<Id> {
+        self.app.ensure_not_draining()?;
+        let config = request.configuration.context("Missing VM configuration")?;
+        self.app.ensure_name_not_reserved(&config.name)?;
+        let signed_by = verify_config_signature(
+            &self.app.config.auth,
+            &vm_config_signed_message(&config),
+            &config.signature,
+        )?;
+        let mut manifest = create_manifest_from_vm_config(config.clone(), &self.app.config.cvm)?;
+        manifest.id = request.id.clone();
+        manifest.signed_by = signed_by;
+        let source = AdoptSource {
+            pid: request.pid,
+            qmp_socket: request.qmp_socket.into(),
+            serial: request.serial_socket.into(),
+            serial_log: Some(request.serial_log)
+                .filter(|p| !p.is_empty())
+                .map(Into::into),
+        };
+        self.app.adopt_vm(manifest, &config, source).await?;
+        Ok(Id { id: request.id })
     }
 
     async fn start_vm(self, request: Id) -> Result<()> {
@@ -244,12 +650,15 @@ impl VmmRpc for RpcHandler {
         Ok(())
     }
 
-    async fn stop_vm(self, request: Id) -> Result<()> {
+    async fn stop_vm(self, request: StopVmRequest) -> Result<StopVmResponse> {
         self.app
-            .stop_vm(&request.id)
+            .stop_vm_with(
+                &request.id,
+                request.force,
+                Duration::from_secs(request.timeout_secs as u64),
+            )
             .await
-            .context("Failed to stop VM")?;
-        Ok(())
+            .context("Failed to stop VM")
     }
 
     async fn remove_vm(self, request: Id) -> Result<()> {
@@ -280,7 +689,33 @@ impl VmmRpc for RpcHandler {
         })
     }
 
+    async fn replace_vm(self, request: ReplaceVmRequest) -> Result<()> {
+        let config = request.configuration.context("Missing VM configuration")?;
+        let signed_by = verify_config_signature(
+            &self.app.config.auth,
+            &vm_config_signed_message(&config),
+            &config.signature,
+        )?;
+        let mut manifest = create_manifest_from_vm_config(config.clone(), &self.app.config.cvm)?;
+        if let Some(key) = &signed_by {
+            info!(
+                "VM {} replacement verified with signing key {key}",
+                request.id
+            );
+        }
+        manifest.signed_by = signed_by;
+        self.app
+            .replace_vm(&request.id, manifest, &config)
+            .await
+            .context("Failed to replace VM")
+    }
+
     async fn upgrade_app(self, request: UpgradeAppRequest) -> Result<Id> {
+        let signed_by = verify_config_signature(
+            &self.app.config.auth,
+            &upgrade_signed_message(&request),
+            &request.signature,
+        )?;
         let new_id = if !request.compose_file.is_empty() {
             // check the compose file is valid
             let _app_compose: AppCompose =
@@ -308,6 +743,10 @@ impl VmmRpc for RpcHandler {
         }
         let vm_work_dir = self.app.work_dir(&request.id);
         let mut manifest = vm_work_dir.manifest().context("Failed to read manifest")?;
+        if let Some(key) = &signed_by {
+            info!("VM {} upgrade verified with signing key {key}", request.id);
+        }
+        manifest.signed_by = signed_by;
         if let Some(gpus) = request.gpus {
             manifest.gpus = Some(self.resolve_gpus(&gpus)?);
         }
@@ -363,6 +802,11 @@ impl VmmRpc for RpcHandler {
         }
     }
 
+    #[tracing::instrument(skip(self, request), fields(image = %request.image))]
+    async fn prepare_image(self, request: PrepareImageRequest) -> Result<PrepareImageResponse> {
+        self.app.prepare_image(request).await
+    }
+
     #[tracing::instrument(skip(self, request), fields(id = request.id))]
     async fn resize_vm(self, request: ResizeVmRequest) -> Result<()> {
         info!("Resizing VM: {:?}", request);
@@ -393,6 +837,9 @@ impl VmmRpc for RpcHandler {
             if disk_size < manifest.disk_size {
                 bail!("Cannot shrink disk size");
             }
+            if manifest.disk("hd1").is_some_and(|d| d.source.is_some()) {
+                bail!("Cannot resize a data disk on network storage");
+            }
             manifest.disk_size = disk_size;
 
             // Run qemu-img resize to resize the disk
@@ -464,6 +911,8 @@ impl VmmRpc for RpcHandler {
                 max_allocable_vcpu: self.app.config.cvm.max_allocable_vcpu,
                 max_allocable_memory_in_mb: self.app.config.cvm.max_allocable_memory_in_mb,
             }),
+            maintenance_mode: self.app.maintenance_mode(),
+            disabled_methods: self.app.config.rpc.disabled_methods.clone(),
         })
     }
 
@@ -484,8 +933,512 @@ impl VmmRpc for RpcHandler {
         let hash = hex_sha256(&request.compose_file);
         Ok(RpcComposeHash { hash })
     }
+
+    async fn set_vm_io_throttle(self, request: SetVmIoThrottleRequest) -> Result<()> {
+        let throttle: IoThrottle = request
+            .throttle
+            .as_ref()
+            .map(Into::into)
+            .unwrap_or_default();
+        self.app
+            .set_vm_io_throttle(&request.id, &request.disk, throttle)
+            .await
+            .context("Failed to set I/O throttle")
+    }
+
+    async fn get_vm_disk_stats(self, request: Id) -> Result<GetVmDiskStatsResponse> {
+        let disks = self.app.vm_disk_stats(&request.id).await?;
+        Ok(GetVmDiskStatsResponse { disks })
+    }
+
+    async fn get_vm_net_stats(self, request: Id) -> Result<VmNetStats> {
+        let nics = self.app.vm_net_stats(&request.id).await?;
+        Ok(VmNetStats { nics })
+    }
+
+    async fn probe_guest(self, request: ProbeGuestRequest) -> Result<ProbeGuestResponse> {
+        if request.port == 0 {
+            bail!("Missing port");
+        }
+        let timeout =
+            (request.timeout_ms > 0).then(|| Duration::from_millis(request.timeout_ms.into()));
+        self.app
+            .probe_guest(&request.id, request.port, timeout)
+            .await
+    }
+
+    async fn provision_boot_secrets(self, request: ProvisionBootSecretsRequest) -> Result<()> {
+        self.app
+            .provision_boot_secrets(&request.id, request.secrets.into_iter().collect())
+    }
+
+    async fn get_vm_stderr(self, request: GetVmStderrRequest) -> Result<VmStderrResponse> {
+        const DEFAULT_LINES: usize = 100;
+        let lines = match request.lines {
+            0 => DEFAULT_LINES,
+            n => n as usize,
+        };
+        let lines = self.app.vm_stderr(&request.id, lines)?;
+        Ok(VmStderrResponse { lines })
+    }
+
+    async fn read_serial_log(self, request: ReadSerialLogRequest) -> Result<SerialLogChunk> {
+        self.app
+            .read_serial_log(&request.id, request.offset, request.length)
+    }
+
+    async fn collect_diagnostics(
+        self,
+        request: CollectDiagnosticsRequest,
+    ) -> Result<DiagnosticsBundle> {
+        let path = self.app.collect_diagnostics(&request.id).await?;
+        let data = if request.include_data {
+            fs::read(&path)?
+        } else {
+            vec![]
+        };
+        Ok(DiagnosticsBundle {
+            path: path.display().to_string(),
+            data,
+        })
+    }
+
+    async fn rotate_vm_token(self, request: RotateVmTokenRequest) -> Result<VmTokenResponse> {
+        let token = self
+            .app
+            .rotate_vm_token(&request.id, request.revoke_previous)?;
+        Ok(VmTokenResponse {
+            fingerprint: token_fingerprint(&token),
+            token,
+        })
+    }
+
+    async fn get_vm_token_fingerprint(self, request: Id) -> Result<VmTokenFingerprint> {
+        Ok(VmTokenFingerprint {
+            fingerprint: self.app.vm_token_fingerprint(&request.id)?,
+        })
+    }
+
+    async fn export_fleet(self) -> Result<FleetExport> {
+        self.app.export_fleet().await
+    }
+
+    async fn get_vsock_stats(self) -> Result<VsockStatsResponse> {
+        let stats = self.app.vsock_stats.snapshot();
+        Ok(VsockStatsResponse {
+            bound: stats.endpoint.is_some(),
+            cid: stats.endpoint.map(|e| e.cid).unwrap_or_default(),
+            port: stats.endpoint.map(|e| e.port).unwrap_or_default(),
+            accepted: stats.accepted,
+            errors: stats.errors,
+            active: stats.connections.len() as u64,
+            bytes_read: stats.bytes_read,
+            bytes_written: stats.bytes_written,
+            connections: stats
+                .connections
+                .iter()
+                .map(|c| VsockConnectionStats {
+                    cid: c.peer.cid,
+                    port: c.peer.port,
+                    bytes_read: c.bytes_read,
+                    bytes_written: c.bytes_written,
+                })
+                .collect(),
+            rejected: stats.rejected,
+            max_connections: stats.max_connections as u64,
+            max_connections_per_cid: stats.max_connections_per_cid as u64,
+            connections_by_cid: stats
+                .connections_by_cid()
+                .into_iter()
+                .map(|(cid, active)| VsockCidConnections {
+                    cid,
+                    active: active as u64,
+                })
+                .collect(),
+        })
+    }
+
+    async fn clear_restart_state(
+        self,
+        request: ClearRestartStateRequest,
+    ) -> Result<ClearRestartStateResponse> {
+        let id = match request.id.as_str() {
+            "all" => None,
+            id => Some(id),
+        };
+        let cleared = self.app.clear_restart_state(id)?;
+        Ok(ClearRestartStateResponse { cleared })
+    }
+
+    async fn drain_host(self, request: DrainHostRequest) -> Result<DrainStatus> {
+        self.app.drain_host(request).await
+    }
+
+    async fn undrain_host(self) -> Result<DrainStatus> {
+        Ok(self.app.undrain_host())
+    }
+
+    async fn get_drain_status(self) -> Result<DrainStatus> {
+        Ok(self.app.drain_status())
+    }
+
+    async fn get_log_level(self) -> Result<LogLevel> {
+        Ok(LogLevel {
+            filter: log_filter::current()?,
+        })
+    }
+
+    async fn set_log_level(self, request: LogLevel) -> Result<LogLevel> {
+        let previous = log_filter::current()?;
+        log_filter::set(&request.filter)?;
+        let filter = log_filter::current()?;
+        warn!("Log filter changed from {previous:?} to {filter:?}");
+        Ok(LogLevel { filter })
+    }
+
+    async fn get_host_info(self) -> Result<HostInfo> {
+        Ok(self.app.capabilities.get().await.to_pb())
+    }
+
+    async fn get_platform_certificates(self) -> Result<PlatformCertificates> {
+        let tdx = self.app.capabilities.get().await.tdx == Some(true);
+        Ok((*self.app.platform_certs.get(tdx).await).clone())
+    }
+
+    async fn get_balloon_info(self, request: Id) -> Result<BalloonInfo> {
+        self.app.get_balloon_info(&request.id).await
+    }
+
+    async fn set_balloon_target(self, request: SetBalloonTargetRequest) -> Result<BalloonInfo> {
+        let info = self
+            .app
+            .set_balloon_target(&request.id, request.target_mb)
+            .await?;
+        info!(
+            "Balloon target of VM {} set to {} MB",
+            request.id, request.target_mb
+        );
+        Ok(info)
+    }
+
+    async fn get_host_capacity(self) -> Result<HostCapacity> {
+        self.app.host_capacity().await
+    }
+
+    async fn guest_agent_ping(self, request: Id) -> Result<GuestAgentPingResponse> {
+        self.app.guest_agent_ping(&request.id).await
+    }
+
+    async fn guest_agent_exec(
+        self,
+        request: GuestAgentExecRequest,
+    ) -> Result<GuestAgentExecResponse> {
+        self.app.guest_agent_exec(&request).await
+    }
+
+    async fn check_fit(self, request: VmConfiguration) -> Result<VmFit> {
+        let mut findings = field_findings(&request, &self.app.config.cvm);
+        if findings.is_empty() {
+            match create_manifest_from_vm_config(request, &self.app.config.cvm) {
+                Ok(manifest) => findings.extend(self.app.check_fit(&manifest).await),
+                Err(err) => findings.push(validation_error("", format!("{err:#}"))),
+            }
+        }
+        Ok(VmFit {
+            fits: findings.is_empty(),
+            findings,
+        })
+    }
+
+    async fn set_vm_network_enabled(
+        self,
+        request: SetVmNetworkEnabledRequest,
+    ) -> Result<VmNetworkState> {
+        let state = self
+            .app
+            .set_vm_network_enabled(&request.id, request.enabled)
+            .await?;
+        info!(
+            "Network of VM {} {}",
+            request.id,
+            if request.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+        Ok(state)
+    }
+
+    async fn diff_vm_config(self, request: DiffVmConfigRequest) -> Result<VmConfigDiff> {
+        let config = request.configuration.context("Missing configuration")?;
+        let desired = create_manifest_from_vm_config(config.clone(), &self.app.config.cvm)?;
+        self.app.diff_vm_config(&request.id, &desired, &config)
+    }
+
+    async fn hmp_command(self, request: HmpCommandRequest) -> Result<HmpCommandResponse> {
+        let output = self.app.hmp_command(&request.id, &request.command).await?;
+        Ok(HmpCommandResponse { output })
+    }
+
+    async fn get_vm_measurements(self, request: Id) -> Result<VmMeasurements> {
+        self.app.vm_measurements(&request.id)
+    }
+
+    async fn validate_vm(self, request: VmConfiguration) -> Result<VmValidation> {
+        let mut findings = field_findings(&request, &self.app.config.cvm);
+        if let Err(err) = verify_config_signature(
+            &self.app.config.auth,
+            &vm_config_signed_message(&request),
+            &request.signature,
+        ) {
+            findings.push(validation_error("signature", format!("{err:#}")));
+        }
+        if findings.is_empty() {
+            match create_manifest_from_vm_config(request, &self.app.config.cvm) {
+                Ok(manifest) => findings.extend(self.app.validate_manifest(&manifest)),
+                Err(err) => findings.push(validation_error("", format!("{err:#}"))),
+            }
+        }
+        Ok(VmValidation {
+            valid: !findings.iter().any(|f| f.severity == "error"),
+            findings,
+        })
+    }
+
+    async fn get_reconcile_status(self) -> Result<ReconcileStatus> {
+        Ok(self.app.last_reconcile().to_pb())
+    }
+
+    async fn checkpoint_vm(self, request: CheckpointVmRequest) -> Result<CheckpointInfo> {
+        self.app.checkpoint_vm(&request.id, request.pause).await
+    }
+
+    async fn restore_checkpoint(self, request: Id) -> Result<CheckpointInfo> {
+        self.app.restore_checkpoint(&request.id).await
+    }
+
+    async fn get_vm_config_drive(self, request: Id) -> Result<VmConfigDrive> {
+        self.app.vm_config_drive(&request.id)
+    }
+
+    async fn prune_snapshots(
+        self,
+        request: PruneSnapshotsRequest,
+    ) -> Result<PruneSnapshotsResponse> {
+        self.app
+            .prune_snapshots(
+                &request.ids,
+                request.keep_last,
+                request.max_age_secs,
+                request.dry_run,
+            )
+            .await
+    }
+
+    async fn verify_attestation(self, request: Id) -> Result<AttestationReport> {
+        self.app.verify_attestation(&request.id).await
+    }
+
+    async fn restore_snapshot(
+        self,
+        request: RestoreSnapshotRequest,
+    ) -> Result<RestoreSnapshotResponse> {
+        self.app
+            .restore_snapshot(&request.id, &request.snapshot_name, request.force)
+            .await
+    }
+
+    async fn swap_boot_disk(self, request: SwapBootDiskRequest) -> Result<SwapBootDiskResponse> {
+        self.app
+            .swap_boot_disk(
+                &request.id,
+                &request.image,
+                request.revert,
+                request.reboot.unwrap_or(true),
+            )
+            .await
+    }
+
+    async fn get_launch_digest(
+        self,
+        request: GetLaunchDigestRequest,
+    ) -> Result<LaunchDigestResponse> {
+        self.app.launch_digest(&request.id, request.spec)
+    }
+
+    async fn set_vm_runtime_params(
+        self,
+        request: SetVmRuntimeParamsRequest,
+    ) -> Result<VmRuntimeParams> {
+        self.app.set_vm_runtime_params(request).await
+    }
+
+    async fn sync_guest_time(self, request: Id) -> Result<GuestTimeSync> {
+        self.app.sync_guest_time(&request.id).await
+    }
+
+    async fn get_vm_source(self, request: Id) -> Result<VmSource> {
+        self.app.vm_source(&request.id)
+    }
+
+    async fn list_managed_network_devices(self) -> Result<ManagedNetworkDevicesResponse> {
+        Ok(ManagedNetworkDevicesResponse {
+            devices: self.app.managed_network_devices().await?,
+        })
+    }
+
+    async fn cleanup_orphaned_network_devices(
+        self,
+        request: CleanupNetworkDevicesRequest,
+    ) -> Result<CleanupNetworkDevicesResponse> {
+        self.app
+            .cleanup_orphaned_network_devices(request.dry_run)
+            .await
+    }
+
+    async fn get_reliability_stats(
+        self,
+        request: GetReliabilityStatsRequest,
+    ) -> Result<ReliabilityStats> {
+        self.app
+            .reliability_stats(&request.ids, Duration::from_secs(request.window_secs))
+    }
+
+    async fn reboot_guest(self, request: RebootGuestRequest) -> Result<RebootGuestResponse> {
+        self.app
+            .reboot_guest(
+                &request.id,
+                request.hard,
+                Duration::from_secs(request.wait_secs.into()),
+            )
+            .await
+    }
+
+    async fn extend_vm_lifetime(
+        self,
+        request: ExtendVmLifetimeRequest,
+    ) -> Result<ExtendVmLifetimeResponse> {
+        self.app
+            .extend_vm_lifetime(&request.id, Duration::from_secs(request.extra_secs))
+            .await
+    }
+
+    async fn get_service_descriptor(self) -> Result<ServiceDescriptor> {
+        let services = rpc::service_methods()
+            .context("Failed to decode the service descriptor")?
+            .into_iter()
+            .map(|(name, methods)| ServiceMethods { name, methods })
+            .collect();
+        Ok(ServiceDescriptor {
+            file_descriptor_set: rpc::FILE_DESCRIPTOR_SET.to_vec(),
+            sha256: hex_sha256(rpc::FILE_DESCRIPTOR_SET),
+            services,
+        })
+    }
+
+    async fn get_resource_usage(self) -> Result<ResourceUsage> {
+        self.app
+            .sample_resource_usage(&mut UsageSampler::default())
+            .await
+    }
+
+    async fn warm_image(self, request: WarmImageRequest) -> Result<WarmImageResponse> {
+        self.app.warm_image(&request.image).await
+    }
+
+    async fn get_vm_events(self, request: GetVmEventsRequest) -> Result<VmEventsResponse> {
+        let id = (!request.id.is_empty()).then_some(request.id.as_str());
+        Ok(VmEventsResponse {
+            events: self
+                .app
+                .events
+                .query(id, request.since_seq, request.limit as usize),
+        })
+    }
+
+    async fn get_effective_config(self) -> Result<EffectiveConfigResponse> {
+        let effective = effective_config(&self.app.figment, &self.app.config)?;
+        Ok(EffectiveConfigResponse {
+            config_json: serde_json::to_string_pretty(&effective.config)?,
+            sources: effective
+                .sources
+                .into_iter()
+                .map(|(key, source)| ConfigValueSource { key, source })
+                .collect(),
+            maintenance_mode: self.app.maintenance_mode(),
+            auto_restart_params: Some(self.app.auto_restart_params().to_pb()),
+        })
+    }
+
+    async fn set_auto_restart_params(
+        self,
+        request: SetAutoRestartParamsRequest,
+    ) -> Result<AutoRestartParams> {
+        Ok(self.app.set_auto_restart_params(&request)?.to_pb())
+    }
+
+    async fn set_maintenance_mode(self, request: MaintenanceMode) -> Result<MaintenanceMode> {
+        self.app.set_maintenance_mode(request.enabled);
+        Ok(MaintenanceMode {
+            enabled: self.app.maintenance_mode(),
+        })
+    }
+}
+
+/// The RPC method an external API path calls, `None` for other paths and unknown methods.
+pub fn prpc_method(path: &str) -> Option<&'static str> {
+    let method = path.strip_prefix("/prpc/")?;
+    let method = method.rsplit('.').next().unwrap_or(method);
+    <VmmServer<RpcHandler>>::supported_methods()
+        .iter()
+        .map(|m| m.rsplit('.').next().unwrap_or(m))
+        .find(|m| *m == method)
 }
 
+/// Methods refused to all but admin tokens, as they reach into the guest.
+pub const ADMIN_METHODS: &[&str] = &["GuestAgentExec", "GuestAgentPing"];
+
+/// Methods an observer token may call, the ones that neither change nor create state.
+pub const READ_ONLY_METHODS: &[&str] = &[
+    "CheckFit",
+    "DiffVmConfig",
+    "ExportFleet",
+    "GetAppEnvEncryptPubKey",
+    "GetBalloonInfo",
+    "GetComposeHash",
+    "GetDrainStatus",
+    "GetEffectiveConfig",
+    "GetHostCapacity",
+    "GetHostInfo",
+    "GetInfo",
+    "GetLaunchDigest",
+    "GetLogLevel",
+    "GetMeta",
+    "GetPlatformCertificates",
+    "GetReconcileStatus",
+    "GetReliabilityStats",
+    "GetResourceUsage",
+    "GetServiceDescriptor",
+    "GetVmConfigDrive",
+    "GetVmDiskStats",
+    "GetVmEvents",
+    "GetVmMeasurements",
+    "GetVmNetStats",
+    "GetVmSource",
+    "GetVmStderr",
+    "GetVmTokenFingerprint",
+    "GetVsockStats",
+    "ListGpus",
+    "ListImages",
+    "ListManagedNetworkDevices",
+    "ProbeGuest",
+    "ReadSerialLog",
+    "Status",
+    "ValidateVm",
+    "Version",
+];
+
 impl RpcCall<App> for RpcHandler {
     type PrpcService = VmmServer<Self>;
 
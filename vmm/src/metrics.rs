@@ -0,0 +1,600 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Metrics exposed at `/metrics` in the Prometheus text format, optionally pushed to StatsD.
+//!
+//! Scrapers asking for `application/openmetrics-text` get the OpenMetrics format instead, with
+//! exemplars on the RPC latency histogram. An exemplar carries the trace id of the W3C
+//! `traceparent` header of the call it was taken from, linking a bucket to one of its traces.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+use crate::app::App;
+use crate::config::StatsdConfig;
+use crate::main_service::prpc_method;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// An observation of a sample, linking it to the trace it was made in.
+#[derive(Debug, Clone)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub span_id: String,
+    pub value: f64,
+    /// Seconds since UNIX epoch
+    pub timestamp: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Appended to the metric name, `_bucket`, `_sum` or `_count` for histograms
+    pub suffix: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+    /// Only rendered in the OpenMetrics format
+    pub exemplar: Option<Exemplar>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+impl Metric {
+    pub fn counter(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: MetricKind::Counter,
+            samples: vec![],
+        }
+    }
+
+    pub fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: MetricKind::Gauge,
+            samples: vec![],
+        }
+    }
+
+    pub fn histogram(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: MetricKind::Histogram,
+            samples: vec![],
+        }
+    }
+
+    pub fn sample(mut self, labels: Vec<(&'static str, String)>, value: f64) -> Self {
+        self.samples.push(Sample {
+            suffix: "",
+            labels,
+            value,
+            exemplar: None,
+        });
+        self
+    }
+
+    /// Add an unlabeled sample.
+    pub fn value(self, value: f64) -> Self {
+        self.sample(vec![], value)
+    }
+}
+
+/// Network counters of each running VM, summed over its interfaces.
+async fn vm_network_metrics(app: &App) -> Vec<Metric> {
+    let mut metrics = [
+        Metric::counter(
+            "dstack_vmm_vm_network_received_bytes_total",
+            "Bytes received by a VM over its tap devices",
+        ),
+        Metric::counter(
+            "dstack_vmm_vm_network_transmitted_bytes_total",
+            "Bytes transmitted by a VM over its tap devices",
+        ),
+        Metric::counter(
+            "dstack_vmm_vm_network_received_packets_total",
+            "Packets received by a VM over its tap devices",
+        ),
+        Metric::counter(
+            "dstack_vmm_vm_network_transmitted_packets_total",
+            "Packets transmitted by a VM over its tap devices",
+        ),
+    ];
+    let stats = match app.all_vm_net_stats().await {
+        Ok(stats) => stats,
+        Err(err) => {
+            warn!("Failed to collect VM network stats: {err:?}");
+            return vec![];
+        }
+    };
+    for (id, nics) in stats.into_iter().filter(|(_, nics)| !nics.is_empty()) {
+        let totals = [
+            nics.iter().map(|n| n.rx_bytes).sum::<u64>(),
+            nics.iter().map(|n| n.tx_bytes).sum(),
+            nics.iter().map(|n| n.rx_packets).sum(),
+            nics.iter().map(|n| n.tx_packets).sum(),
+        ];
+        for (metric, total) in metrics.iter_mut().zip(totals) {
+            metric.samples.push(Sample {
+                suffix: "",
+                labels: vec![("vm", id.clone())],
+                value: total as f64,
+                exemplar: None,
+            });
+        }
+    }
+    metrics.into()
+}
+
+/// Upper bounds in seconds of the RPC latency buckets, `+Inf` follows.
+const RPC_LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Trace context of a call, from its W3C `traceparent` header.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    /// Parse a `version-trace_id-span_id-flags` header, `None` if it is invalid.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let is_hex = |s: &str, len: usize| {
+            s.len() == len && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        };
+        let valid = is_hex(version, 2)
+            && version != "ff"
+            && is_hex(trace_id, 32)
+            && is_hex(span_id, 16)
+            && is_hex(flags, 2)
+            && trace_id.chars().any(|c| c != '0')
+            && span_id.chars().any(|c| c != '0');
+        valid.then(|| Self {
+            trace_id: trace_id.into(),
+            span_id: span_id.into(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, the last one is `+Inf`
+    counts: Vec<u64>,
+    sum: f64,
+    /// Latest traced observation per bucket
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+/// Latency of the calls of each RPC method of the external API.
+#[derive(Debug, Default)]
+pub struct RpcLatency {
+    methods: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl RpcLatency {
+    pub fn observe(&self, method: &str, elapsed: Duration, trace: Option<TraceContext>) {
+        let value = elapsed.as_secs_f64();
+        let bucket = RPC_LATENCY_BUCKETS
+            .iter()
+            .position(|le| value <= *le)
+            .unwrap_or(RPC_LATENCY_BUCKETS.len());
+        let mut methods = self.methods.lock().unwrap();
+        let histogram = methods
+            .entry(method.to_string())
+            .or_insert_with(|| Histogram {
+                counts: vec![0; RPC_LATENCY_BUCKETS.len() + 1],
+                sum: 0.0,
+                exemplars: vec![None; RPC_LATENCY_BUCKETS.len() + 1],
+            });
+        histogram.counts[bucket] += 1;
+        histogram.sum += value;
+        if let Some(trace) = trace {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            histogram.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace.trace_id,
+                span_id: trace.span_id,
+                value,
+                timestamp,
+            });
+        }
+    }
+
+    fn metric(&self) -> Metric {
+        let mut metric = Metric::histogram(
+            "dstack_vmm_rpc_duration_seconds",
+            "Time taken by the calls of an RPC method of the external API",
+        );
+        let methods = self.methods.lock().unwrap();
+        for (method, histogram) in methods.iter() {
+            let bounds = RPC_LATENCY_BUCKETS
+                .iter()
+                .map(|le| format!("{le:?}"))
+                .chain(["+Inf".to_string()]);
+            let mut cumulative = 0;
+            for ((le, count), exemplar) in bounds.zip(&histogram.counts).zip(&histogram.exemplars) {
+                cumulative += count;
+                metric.samples.push(Sample {
+                    suffix: "_bucket",
+                    labels: vec![("method", method.clone()), ("le", le)],
+                    value: cumulative as f64,
+                    exemplar: exemplar.clone(),
+                });
+            }
+            for (suffix, value) in [("_sum", histogram.sum), ("_count", cumulative as f64)] {
+                metric.samples.push(Sample {
+                    suffix,
+                    labels: vec![("method", method.clone())],
+                    value,
+                    exemplar: None,
+                });
+            }
+        }
+        metric
+    }
+}
+
+/// Gather all metrics of the VMM.
+pub async fn collect(app: &App) -> Vec<Metric> {
+    let vsock = app.vsock_stats.snapshot();
+    let webhooks = app.webhooks.stats();
+    let events = app.events.usage();
+    let mut metrics = vec![
+        Metric::counter(
+            "dstack_vmm_vsock_accepted_total",
+            "Connections accepted by the host API vsock listener",
+        )
+        .value(vsock.accepted as f64),
+        Metric::counter(
+            "dstack_vmm_vsock_errors_total",
+            "Failed accepts on the host API vsock listener",
+        )
+        .value(vsock.errors as f64),
+        Metric::gauge(
+            "dstack_vmm_vsock_connections",
+            "Open connections on the host API vsock listener",
+        )
+        .value(vsock.connections.len() as f64),
+        Metric::counter(
+            "dstack_vmm_vsock_read_bytes_total",
+            "Bytes read from guests over the host API vsock listener",
+        )
+        .value(vsock.bytes_read as f64),
+        Metric::counter(
+            "dstack_vmm_vsock_written_bytes_total",
+            "Bytes written to guests over the host API vsock listener",
+        )
+        .value(vsock.bytes_written as f64),
+        Metric::counter(
+            "dstack_vmm_webhook_deliveries_total",
+            "Lifecycle events delivered to webhooks",
+        )
+        .value(webhooks.delivered as f64),
+        Metric::counter(
+            "dstack_vmm_webhook_failures_total",
+            "Lifecycle events that could not be delivered to webhooks after all retries",
+        )
+        .value(webhooks.failed as f64),
+        Metric::gauge(
+            "dstack_vmm_event_buffer_events",
+            "Lifecycle events held in memory, counted once per buffer holding them",
+        )
+        .value(events.events as f64),
+        Metric::gauge(
+            "dstack_vmm_event_buffer_bytes",
+            "Approximate bytes of lifecycle events held in memory",
+        )
+        .value(events.bytes as f64),
+        Metric::gauge(
+            "dstack_vmm_log_streams",
+            "Log streams open on the /logs endpoint",
+        )
+        .value(crate::main_routes::active_log_streams() as f64),
+        Metric::gauge(
+            "dstack_vmm_vm_invalid_configs",
+            "VMs whose stored config failed the last periodic re-validation",
+        )
+        .value(app.invalid_config_count() as f64),
+    ];
+    metrics.push(rpc_in_flight_metric(app));
+    metrics.push(app.rpc_latency.metric());
+    metrics.extend(vm_network_metrics(app).await);
+    metrics
+}
+
+/// Calls in progress of each RPC method with a concurrency limit.
+fn rpc_in_flight_metric(app: &App) -> Metric {
+    let mut metric = Metric::gauge(
+        "dstack_vmm_rpc_in_flight",
+        "Calls in progress of an RPC method limited by rpc_limits max_concurrent",
+    );
+    for in_flight in app.rpc_concurrency.in_flight() {
+        metric = metric.sample(vec![("method", in_flight.method)], in_flight.calls as f64);
+    }
+    metric
+}
+
+/// When a request reached the VMM, for [`RpcLatencyFairing`].
+struct RequestStart(Instant);
+
+/// Records the latency of the RPC calls of the external API in [`App::rpc_latency`].
+pub struct RpcLatencyFairing;
+
+#[rocket::async_trait]
+impl Fairing for RpcLatencyFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "RPC latency",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, _res: &mut Response<'r>) {
+        let Some(method) = prpc_method(req.uri().path().as_str()) else {
+            return;
+        };
+        let Some(app) = req.rocket().state::<App>() else {
+            return;
+        };
+        let started = req.local_cache(|| RequestStart(Instant::now()));
+        let trace = req
+            .headers()
+            .get_one("traceparent")
+            .and_then(TraceContext::from_traceparent);
+        app.rpc_latency.observe(method, started.0.elapsed(), trace);
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, String)]) -> String {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{labels}}}")
+}
+
+pub fn render_prometheus(metrics: &[Metric]) -> String {
+    let mut output = String::new();
+    for metric in metrics {
+        let _ = writeln!(output, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(output, "# TYPE {} {}", metric.name, metric.kind.as_str());
+        for sample in &metric.samples {
+            output.push_str(metric.name);
+            output.push_str(sample.suffix);
+            if !sample.labels.is_empty() {
+                output.push_str(&render_labels(&sample.labels));
+            }
+            let _ = writeln!(output, " {}", sample.value);
+        }
+    }
+    output
+}
+
+/// Content type of [`render_openmetrics`].
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Render in the OpenMetrics text format, with exemplars.
+pub fn render_openmetrics(metrics: &[Metric]) -> String {
+    let mut output = String::new();
+    for metric in metrics {
+        // Counter families are named without the `_total` of their samples
+        let (family, suffix) = match metric.kind {
+            MetricKind::Counter => (
+                metric.name.strip_suffix("_total").unwrap_or(metric.name),
+                "_total",
+            ),
+            _ => (metric.name, ""),
+        };
+        let _ = writeln!(output, "# HELP {family} {}", metric.help);
+        let _ = writeln!(output, "# TYPE {family} {}", metric.kind.as_str());
+        for sample in &metric.samples {
+            output.push_str(family);
+            output.push_str(suffix);
+            output.push_str(sample.suffix);
+            if !sample.labels.is_empty() {
+                output.push_str(&render_labels(&sample.labels));
+            }
+            let _ = write!(output, " {}", sample.value);
+            if let Some(exemplar) = &sample.exemplar {
+                let labels = [
+                    ("trace_id", exemplar.trace_id.clone()),
+                    ("span_id", exemplar.span_id.clone()),
+                ];
+                let _ = write!(
+                    output,
+                    " # {} {} {:.3}",
+                    render_labels(&labels),
+                    exemplar.value,
+                    exemplar.timestamp
+                );
+            }
+            output.push('\n');
+        }
+    }
+    output.push_str("# EOF\n");
+    output
+}
+
+/// Largest payload of a StatsD datagram, fits a typical MTU.
+const STATSD_MAX_DATAGRAM: usize = 1400;
+
+/// Encodes metrics as StatsD lines.
+///
+/// StatsD counters are increments, so the change of each counter since the previous call is
+/// sent rather than its total.
+struct StatsdEncoder {
+    prefix: String,
+    dogstatsd: bool,
+    last_counters: HashMap<String, f64>,
+}
+
+impl StatsdEncoder {
+    fn new(config: &StatsdConfig) -> Self {
+        Self {
+            prefix: config.prefix.clone(),
+            dogstatsd: config.dogstatsd,
+            last_counters: HashMap::new(),
+        }
+    }
+
+    fn encode(&mut self, metrics: &[Metric]) -> Vec<String> {
+        let mut lines = vec![];
+        for metric in metrics {
+            for sample in &metric.samples {
+                // StatsD has no buckets, a histogram is sent as its sum and count
+                if sample.suffix == "_bucket" {
+                    continue;
+                }
+                let mut name = format!("{}{}{}", self.prefix, metric.name, sample.suffix);
+                let mut tags = String::new();
+                for (key, value) in &sample.labels {
+                    if self.dogstatsd {
+                        tags.push(if tags.is_empty() { '#' } else { ',' });
+                        let _ = write!(tags, "{key}:{}", statsd_escape(value));
+                    } else {
+                        let _ = write!(name, ".{}", statsd_escape(value));
+                    }
+                }
+                let (value, kind) = match metric.kind {
+                    MetricKind::Gauge => (sample.value, "g"),
+                    MetricKind::Counter | MetricKind::Histogram => {
+                        let key = format!("{name}|{tags}");
+                        let last = self.last_counters.insert(key, sample.value);
+                        // A counter that went backwards was reset, its whole value is new
+                        let delta = match last {
+                            Some(last) if last <= sample.value => sample.value - last,
+                            _ => sample.value,
+                        };
+                        if delta == 0.0 {
+                            continue;
+                        }
+                        (delta, "c")
+                    }
+                };
+                let mut line = format!("{name}:{value}|{kind}");
+                if !tags.is_empty() {
+                    let _ = write!(line, "|{tags}");
+                }
+                lines.push(line);
+            }
+        }
+        lines
+    }
+}
+
+fn statsd_escape(value: &str) -> String {
+    value.replace([':', '|', '@', ',', '#', '\n'], "_")
+}
+
+async fn push_statsd(socket: &UdpSocket, lines: &[String]) -> std::io::Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > STATSD_MAX_DATAGRAM {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn connect_statsd(address: &str) -> Result<UdpSocket> {
+    let target = tokio::net::lookup_host(address)
+        .await
+        .with_context(|| format!("Failed to resolve StatsD address {address}"))?
+        .next()
+        .with_context(|| format!("No address found for {address}"))?;
+    let bind = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Push the metrics of [`collect`] to the configured StatsD server periodically.
+pub async fn statsd_task(app: App) {
+    let config = &app.config.statsd;
+    let mut encoder = StatsdEncoder::new(config);
+    let mut socket = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+        if socket.is_none() {
+            match connect_statsd(&config.address).await {
+                Ok(s) => socket = Some(s),
+                Err(err) => {
+                    warn!("Failed to connect to StatsD: {err:?}");
+                    continue;
+                }
+            }
+        }
+        let lines = encoder.encode(&collect(&app).await);
+        if let Some(s) = &socket {
+            if let Err(err) = push_statsd(s, &lines).await {
+                warn!("Failed to push metrics to StatsD: {err}");
+                // Resolve the address again, the server may have moved
+                socket = None;
+            }
+        }
+    }
+}
@@ -2,21 +2,183 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::app::{Image, VmConfig, VmWorkDir};
-use crate::config::Config;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::app::{
+    allocate_display, argv_bytes, check_free_space, check_memlock, devices_not_bound_to_vfio,
+    probe_qemu_aio, uses_readconfig, verify_config_signature, vm_config_signed_message, DiskAio,
+    HostCapabilities, Image, Manifest, QmpClient, VmConfig, VmWorkDir,
+};
+use crate::config::{effective_config, Config};
 use crate::main_service;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use dstack_types::AppCompose;
+use dstack_vmm_rpc::VmConfiguration;
+use rocket::figment::Figment;
+use supervisor_client::supervisor::ProcessConfig;
+use tokio::process::Child;
+
+mod render;
+mod sandbox;
+mod systemd;
+
+pub use render::{render, RenderFormat};
+
+pub struct OneShotOptions {
+    /// Working directory, created in the current directory if absent
+    pub workdir: Option<String>,
+    pub dry_run: bool,
+    /// Fail the dry run if passthrough PCI devices are not bound to vfio-pci, the host lacks
+    /// KVM, TDX or the CPU flags of the TSC settings, or a network disk source is unreachable
+    pub strict: bool,
+    /// Path to write the QEMU launch of the dry run to as a systemd unit
+    pub emit_systemd: Option<String>,
+    pub launch_timeout: Option<Duration>,
+    /// Copy the VM config and the effective VMM config into the workdir
+    pub archive_config: bool,
+    pub workdir_retention: WorkdirRetention,
+    /// Run QEMU in the sandbox, in addition to `cvm.sandbox.enabled`
+    pub sandbox: bool,
+    /// Isolate the network of the sandbox, in addition to `cvm.sandbox.isolate_network`
+    pub sandbox_net: bool,
+}
+
+/// What happens to the workdir when the run ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkdirRetention {
+    /// Remove a workdir created by this run after QEMU exited cleanly, keep it otherwise
+    #[default]
+    Auto,
+    Keep,
+    /// Always remove the workdir
+    Clean,
+}
+
+impl WorkdirRetention {
+    fn should_remove(self, created: bool, success: bool) -> bool {
+        match self {
+            WorkdirRetention::Auto => created && success,
+            WorkdirRetention::Keep => false,
+            WorkdirRetention::Clean => true,
+        }
+    }
+}
+
+fn finish_workdir(workdir: &Path, remove: bool) {
+    if !remove {
+        eprintln!("# Kept working directory {}", workdir.display());
+        return;
+    }
+    match fs_err::remove_dir_all(workdir) {
+        Ok(()) => eprintln!("# Removed working directory {}", workdir.display()),
+        Err(err) => eprintln!("# Failed to clean up working directory: {err}"),
+    }
+}
+
+/// App compose of a VM config without one.
+fn default_app_compose(vm_config: &VmConfiguration) -> String {
+    let gateway_enabled = !vm_config.gateway_urls.is_empty();
+    let kms_enabled = !vm_config.kms_urls.is_empty();
+    format!(
+        r#"{{
+"manifest_version": 1,
+"name": "{}",
+"runner": "none",
+"gateway_enabled": {},
+"tproxy_enabled": false,
+"kms_enabled": {},
+"public_logs": false,
+"public_sysinfo": false,
+"public_tcbinfo": true,
+"local_key_provider_enabled": false,
+"no_instance_id": false,
+"secure_time": true,
+"features": [],
+"allowed_envs": []
+}}"#,
+        vm_config.name, gateway_enabled, kms_enabled
+    )
+}
+
+fn one_shot_instance_info(manifest: &Manifest) -> serde_json::Value {
+    serde_json::json!({
+        "instance_id_seed": "4befb40617034796ce9aad5b07b812359d8817bd", // Use a fixed seed for one-shot
+        "instance_id": "", // Empty like in the successful VM
+        "app_id": &manifest.app_id
+    })
+}
+
+/// Sys-config of a one-shot VM. Uses manifest URLs if available, falling back to config URLs
+/// (matching VMM's sync_dynamic_config logic).
+fn one_shot_sys_config(
+    config: &Config,
+    manifest: &Manifest,
+    image: &Image,
+) -> Result<serde_json::Value> {
+    let kms_urls = if manifest.kms_urls.is_empty() {
+        config.cvm.kms_urls.clone()
+    } else {
+        manifest.kms_urls.clone()
+    };
+    let gateway_urls = if manifest.gateway_urls.is_empty() {
+        config.cvm.gateway_urls.clone()
+    } else {
+        manifest.gateway_urls.clone()
+    };
+    Ok(serde_json::json!({
+        "kms_urls": kms_urls,
+        "gateway_urls": gateway_urls,
+        "pccs_url": config.cvm.pccs_url,
+        "docker_registry": config.cvm.docker_registry,
+        "host_api_url": format!("vsock://2:{}/api", config.host_api.port),
+        "vm_config": serde_json::to_string(&dstack_types::VmConfig {
+            spec_version: 1,
+            os_image_hash: image.digest.as_ref()
+                .and_then(|d| hex::decode(d).ok())
+                .unwrap_or_default(),
+            cpu_count: manifest.vcpu,
+            memory_size: manifest.memory as u64 * 1024 * 1024,
+            qemu_single_pass_add_pages: config.cvm.qemu_single_pass_add_pages,
+            pic: config.cvm.qemu_pic,
+            pci_hole64_size: config.cvm.qemu_pci_hole64_size,
+            hugepages: manifest.hugepages,
+            num_gpus: manifest.gpus.as_ref().map_or(0, |g| g.gpus.len() as u32),
+            num_nvswitches: manifest.gpus.as_ref().map_or(0, |g| g.bridges.len() as u32),
+            hotplug_off: config.cvm.qemu_hotplug_off,
+        })?,
+    }))
+}
 
 pub async fn run_one_shot(
     vm_config_path: &str,
-    config: Config,
-    workdir_option: Option<String>,
-    dry_run: bool,
+    mut config: Config,
+    figment: &Figment,
+    options: OneShotOptions,
 ) -> Result<()> {
-    use dstack_types::AppCompose;
-    use dstack_vmm_rpc::VmConfiguration;
     use main_service::create_manifest_from_vm_config;
 
+    let OneShotOptions {
+        workdir: workdir_option,
+        dry_run,
+        strict,
+        emit_systemd,
+        launch_timeout,
+        archive_config,
+        workdir_retention,
+        sandbox,
+        sandbox_net,
+    } = options;
+    config.cvm.sandbox.enabled |= sandbox;
+    config.cvm.sandbox.isolate_network |= sandbox_net;
+
+    if launch_timeout.is_some() {
+        // The running state is detected via QMP
+        config.cvm.qmp_socket = true;
+    }
+
     // Dynamically allocate CID by scanning running QEMU processes (ps aux method)
     let mut existing_cids = Vec::new();
     if let Ok(output) = std::process::Command::new("ps").args(["aux"]).output() {
@@ -75,8 +237,19 @@ pub async fn run_one_shot(
         hex::encode(hasher.finalize())
     };
 
+    let signed_by = verify_config_signature(
+        &config.auth,
+        &vm_config_signed_message(&vm_config),
+        &vm_config.signature,
+    )
+    .with_context(|| format!("Failed to verify VM configuration: {}", vm_config_path))?;
+    if let Some(key) = &signed_by {
+        println!("# Signed by: {key}");
+    }
+
     // Create manifest using shared logic
-    let manifest = create_manifest_from_vm_config(vm_config.clone(), &config.cvm)?;
+    let mut manifest = create_manifest_from_vm_config(vm_config.clone(), &config.cvm)?;
+    manifest.signed_by = signed_by;
 
     // Load image
     let image_path = config.image_path.join(&manifest.image);
@@ -84,9 +257,11 @@ pub async fn run_one_shot(
         .with_context(|| format!("Failed to load image: {}", image_path.display()))?;
 
     // Create or use specified workdir and setup files
+    let created_workdir;
     let workdir_path = match workdir_option {
         Some(workdir_str) => {
-            let workdir_path = std::path::PathBuf::from(workdir_str);
+            let workdir_path = std::env::current_dir()?.join(workdir_str);
+            created_workdir = !workdir_path.exists();
             fs_err::create_dir_all(&workdir_path)
                 .with_context(|| format!("Failed to create workdir: {}", workdir_path.display()))?;
             workdir_path
@@ -100,6 +275,7 @@ pub async fn run_one_shot(
                 .as_secs();
             let workdir_name = format!("dstack-oneshot-{}-{}", vm_name, timestamp);
             let workdir_path = std::env::current_dir()?.join(workdir_name);
+            created_workdir = true;
             fs_err::create_dir_all(&workdir_path)
                 .with_context(|| format!("Failed to create workdir: {}", workdir_path.display()))?;
             workdir_path
@@ -118,29 +294,7 @@ pub async fn run_one_shot(
 
     // Create app compose file content and parse AppCompose instance
     let (app_compose_content, app_compose) = if vm_config.compose_file.is_empty() {
-        // Create default compose JSON directly as string
-        let gateway_enabled = !vm_config.gateway_urls.is_empty();
-        let kms_enabled = !vm_config.kms_urls.is_empty();
-
-        let default_compose = format!(
-            r#"{{
-"manifest_version": 1,
-"name": "{}",
-"runner": "none",
-"gateway_enabled": {},
-"tproxy_enabled": false,
-"kms_enabled": {},
-"public_logs": false,
-"public_sysinfo": false,
-"public_tcbinfo": true,
-"local_key_provider_enabled": false,
-"no_instance_id": false,
-"secure_time": true,
-"features": [],
-"allowed_envs": []
-}}"#,
-            vm_config.name, gateway_enabled, kms_enabled
-        );
+        let default_compose = default_app_compose(&vm_config);
 
         // Parse the default compose to get AppCompose instance for gateway_enabled() call
         let app_compose: AppCompose =
@@ -222,11 +376,7 @@ Compose file content (first 200 chars):
     // Create missing files that VMM's prepare_work_dir() and sync_dynamic_config() create
 
     // 1. Create .instance_info (needed for mrconfigid)
-    let instance_info = serde_json::json!({
-        "instance_id_seed": "4befb40617034796ce9aad5b07b812359d8817bd", // Use a fixed seed for one-shot
-        "instance_id": "", // Empty like in the successful VM
-        "app_id": &manifest.app_id
-    });
+    let instance_info = one_shot_instance_info(&manifest);
     fs_err::write(
         vm_work_dir.instance_info_path(),
         serde_json::to_string(&instance_info)?,
@@ -234,40 +384,7 @@ Compose file content (first 200 chars):
     .context("Failed to write instance info")?;
 
     // 2. Create .sys-config.json (critical for 0.5.x VMs)
-    // Use manifest URLs if available, fallback to config URLs (matching VMM's sync_dynamic_config logic)
-    let kms_urls = if manifest.kms_urls.is_empty() {
-        config.cvm.kms_urls.clone()
-    } else {
-        manifest.kms_urls.clone()
-    };
-    let gateway_urls = if manifest.gateway_urls.is_empty() {
-        config.cvm.gateway_urls.clone()
-    } else {
-        manifest.gateway_urls.clone()
-    };
-
-    let sys_config = serde_json::json!({
-        "kms_urls": kms_urls,
-        "gateway_urls": gateway_urls,
-        "pccs_url": config.cvm.pccs_url,
-        "docker_registry": config.cvm.docker_registry,
-        "host_api_url": format!("vsock://2:{}/api", config.host_api.port),
-        "vm_config": serde_json::to_string(&dstack_types::VmConfig {
-            spec_version: 1,
-            os_image_hash: image.digest.as_ref()
-                .and_then(|d| hex::decode(d).ok())
-                .unwrap_or_default(),
-            cpu_count: manifest.vcpu,
-            memory_size: manifest.memory as u64 * 1024 * 1024,
-            qemu_single_pass_add_pages: config.cvm.qemu_single_pass_add_pages,
-            pic: config.cvm.qemu_pic,
-            pci_hole64_size: config.cvm.qemu_pci_hole64_size,
-            hugepages: manifest.hugepages,
-            num_gpus: manifest.gpus.as_ref().map_or(0, |g| g.gpus.len() as u32),
-            num_nvswitches: manifest.gpus.as_ref().map_or(0, |g| g.bridges.len() as u32),
-            hotplug_off: config.cvm.qemu_hotplug_off,
-        })?
-    });
+    let sys_config = one_shot_sys_config(&config, &manifest, &image)?;
     let sys_config_path = vm_work_dir.shared_dir().join(".sys-config.json");
     fs_err::write(&sys_config_path, serde_json::to_string(&sys_config)?)
         .context("Failed to write sys config")?;
@@ -290,41 +407,181 @@ Compose file content (first 200 chars):
         gateway_enabled: app_compose.gateway_enabled(),
     };
 
+    let display = match &manifest.display {
+        Some(display) => allocate_display(display, &config.cvm.display, &[], None)
+            .context("Failed to allocate display port")?,
+        None => None,
+    };
     let process_configs = vm_builder_config
-        .config_qemu(&workdir_path, &config.cvm, &gpus)
+        .config_qemu(&workdir_path, &config.cvm, &gpus, display.as_ref())
         .context("Failed to build QEMU configuration")?;
 
+    let helper_processes = process_configs.len().saturating_sub(1);
     // Get the main QEMU process config (first in the list)
-    let process_config = process_configs
+    let mut process_config = process_configs
         .into_iter()
         .next()
         .context("No QEMU process configuration generated")?;
+    let sandbox_config = &config.cvm.sandbox;
+    if sandbox_config.enabled {
+        if dry_run {
+            if let Err(err) = sandbox::check_privileges(sandbox_config) {
+                if strict {
+                    return Err(err);
+                }
+                eprintln!("# Warning: {err:#}");
+            }
+        } else {
+            sandbox::check_privileges(sandbox_config)?;
+        }
+        process_config = sandbox::wrap(&process_config, &workdir_path, sandbox_config);
+    }
 
     // Build the QEMU command
     let mut full_command = vec![process_config.command.clone()];
     full_command.extend(process_config.args.clone());
 
+    if archive_config {
+        archive_run(&vm_work_dir, &vm_config, figment, &config, &full_command)
+            .context("Failed to archive config")?;
+    }
+
     println!("# Working directory: {}", workdir_path.display());
     println!("# Compose hash: {}", compose_hash);
     println!("# App ID: {}", manifest.app_id);
     println!("# VM ID: {}", manifest.id);
+    if let Some(display) = &display {
+        println!(
+            "# Display: {} on {}:{}",
+            display.protocol.as_str(),
+            display.address,
+            display.port
+        );
+    }
+    let launch_mode = if uses_readconfig(&process_config) {
+        format!(
+            "options in {}, the command line is over {} bytes",
+            vm_work_dir.qemu_config_file().display(),
+            config.cvm.qemu_max_argv_bytes
+        )
+    } else {
+        "command line".to_string()
+    };
+    println!(
+        "# Launch mode: {launch_mode} ({} bytes)",
+        argv_bytes(&process_config)
+    );
     println!("#");
     println!("# QEMU Command:");
     println!("{}", full_command.join(" "));
 
     if dry_run {
+        let unbound = devices_not_bound_to_vfio(&manifest.pci_devices);
+        for (addr, driver) in &unbound {
+            let driver = driver.as_deref().unwrap_or("no driver");
+            eprintln!("# Warning: PCI device {addr} is bound to {driver}, not vfio-pci");
+        }
+        if strict && !unbound.is_empty() {
+            bail!("{} PCI device(s) not bound to vfio-pci", unbound.len());
+        }
+        let capabilities = HostCapabilities::probe(&config.cvm.qemu_path);
+        println!(
+            "# QEMU version: {}",
+            capabilities.qemu_version.as_deref().unwrap_or("unknown")
+        );
+        let missing = capabilities.missing();
+        for capability in &missing {
+            eprintln!("# Warning: host is missing {capability}");
+        }
+        if strict && !missing.is_empty() {
+            bail!("Host is missing {}", missing.join(", "));
+        }
+        let missing_tsc = capabilities.missing_tsc_flags(&config.cvm.tsc);
+        if !missing_tsc.is_empty() {
+            let msg = format!(
+                "host CPU lacks {} for the TSC settings",
+                missing_tsc.join(", ")
+            );
+            if strict {
+                bail!("The {msg}");
+            }
+            eprintln!("# Warning: {msg}");
+        }
+        if let Some(machine) = &manifest.machine {
+            match &capabilities.machines {
+                Some(machines) => {
+                    if let Some(outdated) = machine.check(machines)? {
+                        if strict {
+                            bail!("{outdated}");
+                        }
+                        eprintln!("# Warning: {outdated}");
+                    }
+                }
+                None => eprintln!("# Warning: could not list the machines of the host QEMU"),
+            }
+        }
+        if let Err(err) = check_free_space(&workdir_path, config.disk_space.min_free_bytes) {
+            if strict {
+                return Err(err);
+            }
+            eprintln!("# Warning: {err:#}");
+        }
+        if manifest.memory_options.is_some_and(|m| m.lock) {
+            if let Err(err) = check_memlock(manifest.memory) {
+                if strict {
+                    return Err(err);
+                }
+                eprintln!("# Warning: {err:#}");
+            }
+        }
+        let aio_modes = manifest
+            .disks
+            .iter()
+            .filter_map(|d| d.aio)
+            .filter(|aio| *aio != DiskAio::Threads)
+            .collect::<BTreeSet<_>>();
+        for aio in aio_modes {
+            probe_qemu_aio(&config.cvm.qemu_path, &workdir_path, aio)?;
+        }
+        if strict {
+            for disk in &manifest.disks {
+                if let Some(source) = &disk.source {
+                    source
+                        .probe(disk.secret_file.as_deref())
+                        .with_context(|| format!("Disk {}", disk.id))?;
+                    println!("# Disk {}: {source} is reachable", disk.id);
+                }
+            }
+        }
+        if let Some(unit_path) = &emit_systemd {
+            let unit = systemd::render_unit(
+                &manifest,
+                &process_config,
+                config.cvm.auto_restart.max_attempts,
+            );
+            fs_err::write(unit_path, unit).context("Failed to write systemd unit")?;
+            println!("# Wrote systemd unit to {unit_path}");
+            if helper_processes > 0 {
+                eprintln!(
+                    "# Warning: {helper_processes} helper process(es) such as passt are not part of the unit"
+                );
+            }
+        }
         println!("# Dry run mode - QEMU command not executed");
         println!(
             "# To execute, run: --one-shot {} (without --dry-run)",
             vm_config_path
         );
+        if workdir_retention == WorkdirRetention::Clean {
+            finish_workdir(&workdir_path, true);
+        }
     } else {
         println!("# Executing QEMU...");
 
         // Change working directory to match supervisor process behavior
         std::env::set_current_dir(&workdir_path).context("Failed to change working directory")?;
 
-        let mut cmd = std::process::Command::new(&process_config.command);
+        let mut cmd = tokio::process::Command::new(&process_config.command);
         cmd.args(&process_config.args);
 
         // Apply environment variables from ProcessConfig
@@ -348,35 +605,142 @@ Compose file content (first 200 chars):
         cmd.stdin(std::process::Stdio::null());
 
         // Execute QEMU command
-        let status = cmd.status().context("Failed to execute QEMU command")?;
+        let mut child = cmd.spawn().context("Failed to execute QEMU command")?;
+
+        let launch = wait_for_launch(&mut child, launch_timeout, &vm_work_dir.qmp_socket()).await;
+        let status = match launch {
+            Launch::Exited(status) => status,
+            Launch::Running => {
+                println!("# VM is running");
+                child.wait().await.context("Failed to wait for QEMU")?
+            }
+            Launch::TimedOut | Launch::Interrupted => {
+                let _ = child.kill().await;
+                print_process_output(&process_config);
+                finish_workdir(
+                    &workdir_path,
+                    workdir_retention.should_remove(created_workdir, false),
+                );
+                if matches!(launch, Launch::Interrupted) {
+                    bail!("Interrupted during VM launch, QEMU killed");
+                }
+                let timeout = launch_timeout.unwrap_or_default();
+                bail!(
+                    "VM did not reach running state within {}, QEMU killed",
+                    humantime::format_duration(timeout)
+                );
+            }
+        };
 
         if status.success() {
             println!("# QEMU execution completed successfully");
         } else {
             eprintln!("# QEMU exited with status: {}", status);
+            print_process_output(&process_config);
+            eprintln!("# Try running with --dry-run to check the generated command");
+        }
+        finish_workdir(
+            &workdir_path,
+            workdir_retention.should_remove(created_workdir, status.success()),
+        );
+        if !status.success() {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
 
-            // Show output files if they exist
-            if !process_config.stdout.is_empty() {
-                if let Ok(stdout_content) = fs_err::read_to_string(&process_config.stdout) {
-                    if !stdout_content.trim().is_empty() {
-                        eprintln!("# QEMU stdout output:");
-                        eprintln!("{}", stdout_content);
-                    }
+    Ok(())
+}
+
+enum Launch {
+    /// QEMU reported the VM as running
+    Running,
+    /// QEMU exited before the VM was detected running
+    Exited(ExitStatus),
+    TimedOut,
+    Interrupted,
+}
+
+/// Wait until the VM is running, QEMU exits, the launch times out or the user hits Ctrl-C.
+///
+/// Without a timeout there is no running state detection, so Ctrl-C is honored for the whole run.
+async fn wait_for_launch(
+    child: &mut Child,
+    launch_timeout: Option<Duration>,
+    qmp_socket: &Path,
+) -> Launch {
+    let running = async {
+        match launch_timeout {
+            Some(launch_timeout) => {
+                match tokio::time::timeout(launch_timeout, wait_until_running(qmp_socket)).await {
+                    Ok(()) => Launch::Running,
+                    Err(_) => Launch::TimedOut,
                 }
             }
-            if !process_config.stderr.is_empty() {
-                if let Ok(stderr_content) = fs_err::read_to_string(&process_config.stderr) {
-                    if !stderr_content.trim().is_empty() {
-                        eprintln!("# QEMU stderr output:");
-                        eprintln!("{}", stderr_content);
-                    }
-                }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        status = child.wait() => match status {
+            Ok(status) => Launch::Exited(status),
+            Err(err) => {
+                eprintln!("# Failed to wait for QEMU: {err}");
+                Launch::Interrupted
             }
+        },
+        _ = tokio::signal::ctrl_c() => Launch::Interrupted,
+        launch = running => launch,
+    }
+}
 
-            eprintln!("# Try running with --dry-run to check the generated command");
-            std::process::exit(status.code().unwrap_or(1));
+async fn wait_until_running(qmp_socket: &Path) {
+    loop {
+        if let Ok(mut qmp) = QmpClient::connect(qmp_socket).await {
+            if let Ok(status) = qmp.execute("query-status", None).await {
+                if status.get("running").and_then(|v| v.as_bool()) == Some(true) {
+                    return;
+                }
+            }
         }
+        tokio::time::sleep(Duration::from_millis(500)).await;
     }
+}
 
+/// Save what the run was made of, so it can be reproduced after the original files change.
+fn archive_run(
+    workdir: &VmWorkDir,
+    vm_config: &dstack_vmm_rpc::VmConfiguration,
+    figment: &Figment,
+    config: &Config,
+    command: &[String],
+) -> Result<()> {
+    let dir = workdir.one_shot_archive_dir();
+    fs_err::create_dir_all(&dir)?;
+    fs_err::write(
+        dir.join("vm-config.json"),
+        serde_json::to_string_pretty(vm_config)?,
+    )?;
+    let effective = effective_config(figment, config)?;
+    fs_err::write(
+        dir.join("vmm-config.json"),
+        serde_json::to_string_pretty(&effective.config)?,
+    )?;
+    fs_err::write(dir.join("qemu-command"), command.join(" ") + "\n")?;
     Ok(())
 }
+
+fn print_process_output(process_config: &ProcessConfig) {
+    for (name, path) in [
+        ("stdout", &process_config.stdout),
+        ("stderr", &process_config.stderr),
+    ] {
+        if path.is_empty() {
+            continue;
+        }
+        if let Ok(content) = fs_err::read_to_string(path) {
+            if !content.trim().is_empty() {
+                eprintln!("# QEMU {name} output:");
+                eprintln!("{}", content);
+            }
+        }
+    }
+}
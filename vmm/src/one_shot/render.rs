@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rendering of the launch of a VM config without creating anything.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use dstack_types::shared_filenames::{APP_COMPOSE, ENCRYPTED_ENV, INSTANCE_INFO, USER_CONFIG};
+use dstack_types::AppCompose;
+use dstack_vmm_rpc::VmConfiguration;
+use serde::Serialize;
+use supervisor_client::supervisor::ProcessConfig;
+
+use super::sandbox::{self, sh_quote};
+use super::{default_app_compose, one_shot_instance_info, one_shot_sys_config};
+use crate::app::{
+    allocate_display, verify_config_signature, vm_config_signed_message, Image, MrConfigInputs,
+    SideFile, VmConfig, VmWorkDir,
+};
+use crate::config::Config;
+use crate::main_service::create_manifest_from_vm_config;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RenderFormat {
+    /// Commented list of the files followed by one command line per process
+    #[default]
+    Shell,
+    Json,
+}
+
+#[derive(Serialize)]
+struct RenderedFile {
+    #[serde(flatten)]
+    file: SideFile,
+    /// Contents of the files written by the VMM, absent for those created by QEMU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Rendered {
+    workdir: PathBuf,
+    /// Helpers such as passt, followed by the VM
+    processes: Vec<ProcessConfig>,
+    files: Vec<RenderedFile>,
+}
+
+/// Print the processes a one-shot run of `vm_config_path` in `workdir` would launch and the
+/// files it would create, without creating the workdir or anything in it.
+pub fn render(
+    vm_config_path: &str,
+    config: &Config,
+    workdir: Option<&str>,
+    format: RenderFormat,
+) -> Result<()> {
+    let vm_config_json = fs_err::read_to_string(vm_config_path)?;
+    let vm_config: VmConfiguration = serde_json::from_str(&vm_config_json)
+        .with_context(|| format!("Failed to parse VM configuration from: {vm_config_path}"))?;
+    let signed_by = verify_config_signature(
+        &config.auth,
+        &vm_config_signed_message(&vm_config),
+        &vm_config.signature,
+    )?;
+    let mut manifest = create_manifest_from_vm_config(vm_config.clone(), &config.cvm)?;
+    manifest.signed_by = signed_by;
+    let image_path = config.image_path.join(&manifest.image);
+    let image = Image::load(&image_path)
+        .with_context(|| format!("Failed to load image: {}", image_path.display()))?;
+
+    let workdir = std::env::current_dir()?.join(match workdir {
+        Some(workdir) => workdir.to_string(),
+        None => format!("dstack-oneshot-{}", manifest.name),
+    });
+    let compose = if vm_config.compose_file.is_empty() {
+        default_app_compose(&vm_config)
+    } else {
+        vm_config.compose_file.clone()
+    };
+    let app_compose: AppCompose =
+        serde_json::from_str(&compose).context("Failed to parse compose file")?;
+    let shared_dir = VmWorkDir::new(&workdir).shared_dir();
+    let mut config_files = vec![
+        (shared_dir.join(APP_COMPOSE), "app compose", compose.clone()),
+        (
+            shared_dir.join(INSTANCE_INFO),
+            "instance info",
+            one_shot_instance_info(&manifest).to_string(),
+        ),
+        (
+            shared_dir.join(".sys-config.json"),
+            "sys-config",
+            one_shot_sys_config(config, &manifest, &image)?.to_string(),
+        ),
+    ];
+    if !vm_config.user_config.is_empty() {
+        config_files.push((
+            shared_dir.join(USER_CONFIG),
+            "user config",
+            vm_config.user_config.clone(),
+        ));
+    }
+    if !vm_config.encrypted_env.is_empty() {
+        config_files.push((
+            shared_dir.join(ENCRYPTED_ENV),
+            "encrypted env, hex encoded",
+            hex::encode(&vm_config.encrypted_env),
+        ));
+    }
+
+    let gpus = manifest.gpus.clone().unwrap_or_default();
+    let display = match &manifest.display {
+        Some(display) => allocate_display(display, &config.cvm.display, &[], None)?,
+        None => None,
+    };
+    let mr_config = MrConfigInputs {
+        compose: compose.into_bytes(),
+        app_id: hex::decode(&manifest.app_id).unwrap_or_default(),
+    };
+    let vm = VmConfig {
+        manifest,
+        image,
+        cid: config.cvm.cid_start,
+        workdir: workdir.clone(),
+        gateway_enabled: app_compose.gateway_enabled(),
+    };
+    let boot = vm.validate_boot(&config.cvm)?;
+    let mut processes = vm
+        .render_qemu(
+            &workdir,
+            &config.cvm,
+            &boot,
+            &gpus,
+            display.as_ref(),
+            Some(&mr_config),
+        )
+        .context("Failed to build QEMU configuration")?;
+    let qemu_config = vm.split_long_argv(&mut processes, &VmWorkDir::new(&workdir), &config.cvm);
+    if config.cvm.sandbox.enabled {
+        if let Some(process) = processes.last_mut() {
+            *process = sandbox::wrap(process, &workdir, &config.cvm.sandbox);
+        }
+    }
+    let mut files = vm
+        .side_files(&workdir, &config.cvm)
+        .into_iter()
+        .map(|file| RenderedFile {
+            file,
+            contents: None,
+        })
+        .collect::<Vec<_>>();
+    if let Some(contents) = qemu_config {
+        files.push(RenderedFile {
+            file: SideFile::new(
+                "config",
+                VmWorkDir::new(&workdir).qemu_config_file(),
+                "QEMU options read with -readconfig, the command line is too long",
+            ),
+            contents: Some(contents),
+        });
+    }
+    files.extend(
+        config_files
+            .into_iter()
+            .map(|(path, description, contents)| RenderedFile {
+                file: SideFile::new("config", path, description),
+                contents: Some(contents),
+            }),
+    );
+    let rendered = Rendered {
+        workdir,
+        processes,
+        files,
+    };
+    match format {
+        RenderFormat::Json => println!("{}", serde_json::to_string_pretty(&rendered)?),
+        RenderFormat::Shell => print_shell(&rendered),
+    }
+    Ok(())
+}
+
+fn print_shell(rendered: &Rendered) {
+    println!("# Working directory: {}", rendered.workdir.display());
+    for RenderedFile { file, .. } in &rendered.files {
+        println!(
+            "# {} {}: {}",
+            file.kind,
+            display_path(&file.path, &rendered.workdir),
+            file.description
+        );
+    }
+    for process in &rendered.processes {
+        let command = std::iter::once(&process.command)
+            .chain(&process.args)
+            .map(|arg| sh_quote(arg))
+            .collect::<Vec<_>>();
+        println!("{}", command.join(" "));
+    }
+}
+
+/// Path relative to the workdir if inside it.
+fn display_path(path: &Path, workdir: &Path) -> String {
+    path.strip_prefix(workdir)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
@@ -0,0 +1,149 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running the one-shot QEMU in new mount and PID namespaces, chrooted into a root that only
+//! holds the files the launch refers to.
+//!
+//! The namespaces are set up with `unshare` from util-linux, which needs root or
+//! `CAP_SYS_ADMIN`.
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use supervisor_client::supervisor::ProcessConfig;
+
+use crate::config::SandboxConfig;
+
+/// Host directories bound read-only so QEMU and its libraries can run.
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib64",
+    "/etc/ld.so.cache",
+];
+
+/// Devices QEMU may open, bound if present on the host.
+const DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/kvm",
+    "/dev/vhost-vsock",
+    "/dev/vhost-net",
+    "/dev/net/tun",
+    "/dev/vfio",
+    "/dev/ptmx",
+    // Shared with the host so the serial console pty stays reachable
+    "/dev/pts",
+];
+
+pub(super) fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Absolute host paths named by the QEMU command line, including `key=/path` options.
+fn referenced_paths(process: &ProcessConfig) -> BTreeSet<PathBuf> {
+    std::iter::once(process.command.as_str())
+        .chain(process.args.iter().flat_map(|arg| arg.split(',')))
+        .map(|word| word.split_once('=').map_or(word, |(_, value)| value))
+        .filter(|word| word.starts_with('/'))
+        .map(PathBuf::from)
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// Drop paths covered by another entry, `paths` iterates parents before children.
+fn outermost(paths: BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = vec![];
+    for path in paths {
+        if !result.iter().any(|parent| path.starts_with(parent)) {
+            result.push(path);
+        }
+    }
+    result
+}
+
+/// Fail with an explanation if this process cannot create the namespaces.
+pub fn check_privileges(config: &SandboxConfig) -> Result<()> {
+    let output = Command::new("unshare")
+        .args(namespace_args(config))
+        .arg("true")
+        .output()
+        .context("Sandboxing requires the unshare command of util-linux")?;
+    if !output.status.success() {
+        bail!(
+            "Sandboxing requires root or CAP_SYS_ADMIN, unshare failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn namespace_args(config: &SandboxConfig) -> Vec<&'static str> {
+    let mut args = vec!["--mount", "--pid", "--fork", "--kill-child"];
+    if config.isolate_network {
+        args.push("--net");
+    }
+    args
+}
+
+/// Wrap the QEMU launch so it runs inside the sandbox. `workdir` is the only path bound
+/// writable, everything else the command line refers to is bound read-only.
+pub fn wrap(process: &ProcessConfig, workdir: &Path, config: &SandboxConfig) -> ProcessConfig {
+    let root = workdir.join(".sandbox-root");
+    let mut paths: BTreeSet<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+    paths.extend(config.extra_paths.iter().cloned());
+    paths.extend(referenced_paths(process));
+    paths.retain(|path| path.exists() && !path.starts_with(workdir));
+
+    let mut script = String::from(
+        r#"set -e
+root="$1"
+shift
+bind() {
+    if [ -d "$1" ]; then mkdir -p "$root$1"; else mkdir -p "$root$(dirname "$1")"; touch "$root$1"; fi
+    mount --bind "$1" "$root$1"
+    if [ "$2" = ro ]; then mount -o remount,bind,ro "$root$1"; fi
+}
+mount --make-rprivate /
+mkdir -p "$root"
+mount -t tmpfs -o mode=755 sandbox "$root"
+"#,
+    );
+    for path in outermost(paths) {
+        script.push_str(&format!("bind {} ro\n", sh_quote(&path.to_string_lossy())));
+    }
+    for device in DEVICES.iter().filter(|d| Path::new(d).exists()) {
+        script.push_str(&format!("bind {device} rw\n"));
+    }
+    script.push_str(&format!(
+        "bind {} rw\n",
+        sh_quote(&workdir.to_string_lossy())
+    ));
+    script.push_str(
+        r#"mkdir -p "$root/proc" "$root/tmp"
+mount -t proc proc "$root/proc"
+mount -t tmpfs tmpfs "$root/tmp"
+exec chroot "$root" /bin/sh -c 'cd "$0" && exec "$@"' "$@"
+"#,
+    );
+
+    let mut args: Vec<String> = namespace_args(config).into_iter().map(Into::into).collect();
+    args.extend(["--".into(), "/bin/sh".into(), "-c".into(), script]);
+    args.push("sandbox".into());
+    args.push(root.to_string_lossy().into_owned());
+    args.push(workdir.to_string_lossy().into_owned());
+    args.push(process.command.clone());
+    args.extend(process.args.iter().cloned());
+    ProcessConfig {
+        command: "unshare".into(),
+        args,
+        ..process.clone()
+    }
+}
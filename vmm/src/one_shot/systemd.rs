@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rendering of the one-shot QEMU launch as a systemd service unit.
+use std::fmt::Write;
+
+use supervisor_client::supervisor::ProcessConfig;
+
+use crate::app::{Manifest, RestartPolicy};
+
+/// Escape `%` specifiers and quote a value for a unit file setting.
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{escaped}\"")
+}
+
+/// Quote a word of `ExecStart=`, which additionally expands `$` variables.
+fn quote_exec_arg(arg: &str) -> String {
+    quote(&arg.replace('$', "$$"))
+}
+
+fn escape_path(path: &str) -> String {
+    path.replace('%', "%%")
+}
+
+/// Render a unit running QEMU in the foreground with the restart policy of the VM.
+/// `default_max_attempts` replaces a zero `max_retries` of `on-failure`.
+pub fn render_unit(
+    manifest: &Manifest,
+    process: &ProcessConfig,
+    default_max_attempts: u32,
+) -> String {
+    let (restart, max_restarts) = match manifest.restart_policy.unwrap_or_default() {
+        RestartPolicy::Always | RestartPolicy::UnlessStopped => ("always", None),
+        RestartPolicy::OnFailure { max_retries } => {
+            let max_retries = match max_retries {
+                0 => default_max_attempts,
+                n => n,
+            };
+            ("on-failure", (max_retries > 0).then_some(max_retries))
+        }
+        RestartPolicy::No => ("no", None),
+    };
+
+    let mut unit = String::new();
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(
+        unit,
+        "Description=dstack VM {} ({})",
+        manifest.name.replace('%', "%%"),
+        manifest.id
+    );
+    let _ = writeln!(unit, "Wants=network-online.target");
+    let _ = writeln!(unit, "After=network-online.target");
+    if let Some(max_restarts) = max_restarts {
+        // Count restarts over the whole lifetime of the unit, like the VMM does
+        let _ = writeln!(unit, "StartLimitIntervalSec=infinity");
+        let _ = writeln!(unit, "StartLimitBurst={}", max_restarts + 1);
+    }
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Service]");
+    let _ = writeln!(unit, "Type=simple");
+    if !process.cwd.is_empty() {
+        let _ = writeln!(unit, "WorkingDirectory={}", escape_path(&process.cwd));
+    }
+    let mut env = process.env.iter().collect::<Vec<_>>();
+    env.sort();
+    for (key, value) in env {
+        let _ = writeln!(unit, "Environment={}", quote(&format!("{key}={value}")));
+    }
+    let exec = std::iter::once(&process.command)
+        .chain(&process.args)
+        .map(|arg| quote_exec_arg(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let _ = writeln!(unit, "ExecStart={exec}");
+    if !process.stdout.is_empty() {
+        let _ = writeln!(
+            unit,
+            "StandardOutput=append:{}",
+            escape_path(&process.stdout)
+        );
+    }
+    if !process.stderr.is_empty() {
+        let _ = writeln!(
+            unit,
+            "StandardError=append:{}",
+            escape_path(&process.stderr)
+        );
+    }
+    if manifest.memory_options.is_some_and(|m| m.lock) {
+        let _ = writeln!(unit, "LimitMEMLOCK=infinity");
+    }
+    let _ = writeln!(unit, "Restart={restart}");
+    if let Some(exit_codes) = manifest
+        .exit_codes
+        .as_ref()
+        .filter(|_| restart == "on-failure")
+    {
+        // systemd always counts 0 as clean and codes in neither list as failures
+        let codes = |codes: &[i32]| {
+            codes
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        if !exit_codes.success.is_empty() {
+            let _ = writeln!(unit, "SuccessExitStatus={}", codes(&exit_codes.success));
+        }
+        if !exit_codes.failure.is_empty() {
+            let _ = writeln!(
+                unit,
+                "RestartForceExitStatus={}",
+                codes(&exit_codes.failure)
+            );
+        }
+    }
+    let _ = writeln!(unit, "RestartSec=5");
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Install]");
+    let _ = writeln!(unit, "WantedBy=multi-user.target");
+    unit
+}
@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! On-demand CPU profiles served at `/debug/profile`, built with the `profiling` feature.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use pprof::protos::Message;
+
+const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// Set while a profile is being captured, only one can run at a time.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+pub struct ProfileOptions {
+    pub duration: Duration,
+    /// Samples per second
+    pub frequency: i32,
+    /// Render an SVG flamegraph instead of a pprof protobuf
+    pub flamegraph: bool,
+}
+
+/// Sample the whole process for `duration` and return the encoded profile.
+pub async fn capture(options: ProfileOptions) -> Result<Vec<u8>> {
+    if options.duration.is_zero() || options.duration > MAX_DURATION {
+        bail!("Profile duration must be between 1s and {MAX_DURATION:?}");
+    }
+    if !(1..=1000).contains(&options.frequency) {
+        bail!("Profile frequency must be between 1 and 1000");
+    }
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        bail!("A profile is already being captured");
+    }
+    let result = tokio::task::spawn_blocking(move || capture_blocking(&options)).await;
+    PROFILING.store(false, Ordering::SeqCst);
+    result.context("Profiler task panicked")?
+}
+
+fn capture_blocking(options: &ProfileOptions) -> Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(options.frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .context("Failed to start profiler")?;
+    std::thread::sleep(options.duration);
+    let report = guard
+        .report()
+        .build()
+        .context("Failed to build profile report")?;
+    let mut buf = Vec::new();
+    if options.flamegraph {
+        report
+            .flamegraph(&mut buf)
+            .context("Failed to render flamegraph")?;
+    } else {
+        report
+            .pprof()
+            .context("Failed to convert profile")?
+            .encode(&mut buf)
+            .context("Failed to encode profile")?;
+    }
+    Ok(buf)
+}
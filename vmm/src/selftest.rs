@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Smoke test of a host: boot a known-good VM and check the path from QEMU to attestation.
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dstack_vmm_rpc::VmConfiguration;
+use guest_api::GuestInfo;
+use ra_rpc::Attestation;
+
+use crate::app::{verify_config_signature, vm_config_signed_message, App};
+use crate::main_service::create_manifest_from_vm_config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Boot progress the guest reports once it is up.
+const BOOT_DONE: &str = "done";
+
+pub struct SelftestOptions {
+    /// VM configuration file, `selftest.vm_config` if absent
+    pub vm_config: Option<String>,
+    /// Time the VM has to boot, `selftest.timeout` if absent
+    pub timeout: Option<Duration>,
+    /// Leave the VM in place when a check fails
+    pub keep: bool,
+}
+
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    /// Print the outcome of a check and return its value if it passed.
+    fn check<T>(
+        &mut self,
+        check: &str,
+        result: Result<T>,
+        detail: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("PASS {check}: {}", detail(&value));
+                Some(value)
+            }
+            Err(err) => {
+                self.failed = true;
+                println!("FAIL {check}: {err:#}");
+                None
+            }
+        }
+    }
+
+    fn skip(&self, check: &str, reason: &str) {
+        println!("SKIP {check}: {reason}");
+    }
+}
+
+/// Launch the self-test VM, wait for the guest to report ready, query its guest agent over
+/// vsock and extract its attestation, then remove the VM. Fails if any check failed.
+///
+/// The host API must be served meanwhile, the guest reports its boot progress to it.
+pub async fn run(app: App, options: SelftestOptions) -> Result<()> {
+    let config = &app.config.selftest;
+    let vm_config_path = options
+        .vm_config
+        .unwrap_or_else(|| config.vm_config.clone());
+    if vm_config_path.is_empty() {
+        bail!("No self-test VM configured, pass one or set selftest.vm_config");
+    }
+    let timeout = options
+        .timeout
+        .unwrap_or(Duration::from_secs(config.timeout));
+    let vm_config_json = fs_err::read_to_string(&vm_config_path)?;
+    let vm_config: VmConfiguration = serde_json::from_str(&vm_config_json)
+        .with_context(|| format!("Failed to parse VM configuration from: {vm_config_path}"))?;
+
+    let mut report = Report::default();
+    let Some(id) = report.check("launch", launch(&app, &vm_config).await, |id| {
+        format!("QEMU started for VM {id}")
+    }) else {
+        bail!("Self-test failed");
+    };
+    let booted = report.check("boot", wait_for_boot(&app, &id, timeout).await, |elapsed| {
+        format!("guest reported ready after {}s", elapsed.as_secs())
+    });
+    let info = match booted {
+        Some(_) => {
+            let info = match app.guest_agent_client(&id) {
+                Ok(client) => client.info().await,
+                Err(err) => Err(err),
+            };
+            report.check("guest api", info, |info| {
+                format!("guest agent {} answered over vsock", info.version)
+            })
+        }
+        None => {
+            report.skip("guest api", "the guest did not boot");
+            None
+        }
+    };
+    let tdx = app.capabilities.get().await.tdx == Some(true);
+    match info {
+        Some(info) if tdx => {
+            report.check("attestation", quote_of(&info), |len| {
+                format!("quote of {len} bytes in the app certificate")
+            });
+        }
+        Some(_) => report.skip("attestation", "the host has no TDX"),
+        None => report.skip("attestation", "the guest agent did not answer"),
+    }
+
+    if report.failed && options.keep {
+        println!("# VM {id} was kept for inspection");
+    } else {
+        report.check("teardown", teardown(&app, &id).await, |_| {
+            "VM stopped and removed".into()
+        });
+    }
+    if report.failed {
+        bail!("Self-test failed");
+    }
+    println!("# Self-test passed");
+    Ok(())
+}
+
+async fn launch(app: &App, vm_config: &VmConfiguration) -> Result<String> {
+    let signed_by = verify_config_signature(
+        &app.config.auth,
+        &vm_config_signed_message(vm_config),
+        &vm_config.signature,
+    )?;
+    let mut manifest = create_manifest_from_vm_config(vm_config.clone(), &app.config.cvm)?;
+    manifest.name = format!("selftest-{}", manifest.name);
+    manifest.signed_by = signed_by;
+    manifest.restart_policy = None;
+    let id = manifest.id.clone();
+    app.create_vm(manifest, vm_config, true).await?;
+    Ok(id)
+}
+
+/// Wait until the guest reports its boot done, returning how long it took.
+async fn wait_for_boot(app: &App, id: &str, timeout: Duration) -> Result<Duration> {
+    let started = Instant::now();
+    loop {
+        let info = app.vm_info(id).await?.context("VM not found")?;
+        if !info.boot_error.is_empty() {
+            bail!("guest reported a boot error: {}", info.boot_error);
+        }
+        if info.boot_progress == BOOT_DONE {
+            return Ok(started.elapsed());
+        }
+        if info.status == "exited" || info.status == "stopped" {
+            let stderr = app
+                .work_dir(id)
+                .stderr_tail(20, &app.config.log_limits)
+                .unwrap_or_default();
+            bail!("QEMU exited during boot: {}", stderr.join("\n"));
+        }
+        if started.elapsed() >= timeout {
+            bail!(
+                "no ready report within {}s, last progress: {}",
+                timeout.as_secs(),
+                info.boot_progress
+            );
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Length of the TDX quote embedded in the RA-TLS certificate of the guest.
+fn quote_of(info: &GuestInfo) -> Result<usize> {
+    let attestation = Attestation::from_pem(info.app_cert.as_bytes())
+        .context("Invalid app certificate")?
+        .context("The app certificate carries no attestation")?;
+    attestation.decode_quote().context("Invalid quote")?;
+    Ok(attestation.quote.len())
+}
+
+async fn teardown(app: &App, id: &str) -> Result<()> {
+    app.stop_vm(id).await.context("Failed to stop VM")?;
+    app.remove_vm(id).await.context("Failed to remove VM")
+}
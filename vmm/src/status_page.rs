@@ -0,0 +1,81 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only HTML page showing the fleet, served at `/ui`.
+use std::fmt::Write;
+
+use dstack_vmm_rpc::{StatusResponse, VmInfo};
+
+const STYLE: &str = "\
+body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;width:100%}\
+th,td{border-bottom:1px solid #ddd;padding:6px 10px;text-align:left}\
+th{background:#f4f4f4}\
+.running{color:#1a7f37}.exited{color:#cf222e}.stopped,.stopping{color:#777}";
+
+fn escape_html(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+fn render_row(output: &mut String, vm: &VmInfo) {
+    let config = vm.configuration.as_ref();
+    let resources = config
+        .map(|c| format!("{} vCPU, {} MB, {} GB", c.vcpu, c.memory, c.disk_size))
+        .unwrap_or_default();
+    let image = config.map(|c| c.image.as_str()).unwrap_or_default();
+    let uptime = match vm.status.as_str() {
+        "running" => vm.uptime.as_str(),
+        _ => "",
+    };
+    let _ = writeln!(
+        output,
+        "<tr><td>{}</td><td><code>{}</code></td><td class=\"{}\">{}</td><td>{}</td>\
+         <td>{}</td><td>{}</td><td>{}</td></tr>",
+        escape_html(&vm.name),
+        escape_html(&vm.id),
+        escape_html(&vm.status),
+        escape_html(&vm.status),
+        escape_html(&vm.boot_progress),
+        escape_html(image),
+        escape_html(&resources),
+        escape_html(uptime),
+    );
+}
+
+/// Render the VM list as a self-contained HTML page.
+pub fn render(status: &StatusResponse) -> String {
+    let mut output = String::new();
+    let title = "dstack VMM";
+    let _ = writeln!(
+        output,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>{STYLE}</style></head><body><h1>{title}</h1>"
+    );
+    let running = status
+        .vms
+        .iter()
+        .filter(|vm| vm.status == "running")
+        .count();
+    let _ = writeln!(output, "<p>{} VMs, {running} running</p>", status.total);
+    output.push_str(
+        "<table><tr><th>Name</th><th>ID</th><th>Status</th><th>Boot progress</th>\
+         <th>Image</th><th>Resources</th><th>Uptime</th></tr>\n",
+    );
+    for vm in &status.vms {
+        render_row(&mut output, vm);
+    }
+    output.push_str("</table></body></html>\n");
+    output
+}
@@ -14,6 +14,7 @@ import socket
 import http.client
 import urllib.parse
 import ssl
+import time
 import base64
 
 from typing import Optional, Dict, List, Tuple, Union, BinaryIO, Any
@@ -119,6 +120,17 @@ def parse_port_mapping(port_str: str) -> Dict:
         raise argparse.ArgumentTypeError(
             f"Invalid port mapping format: {port_str}")
 
+def parse_nic(nic_str: str) -> Dict:
+    """Parse a NIC string `backend[,key=value...]` into a dictionary"""
+    backend, *opts = nic_str.split(',')
+    nic = {"backend": backend}
+    for opt in opts:
+        key, sep, value = opt.partition('=')
+        if not sep or key not in ("ifname", "mac", "model", "network_group"):
+            raise argparse.ArgumentTypeError(f"Invalid NIC option: {opt}")
+        nic[key] = value
+    return nic
+
 def read_utf8(filepath: str) -> str:
     with open(filepath, 'rb') as f:
         return f.read().decode('utf-8')
@@ -254,9 +266,16 @@ class VmmCLI:
 
         return response
 
-    def list_vms(self, verbose: bool = False, json_output: bool = False) -> None:
+    def list_vms(self, verbose: bool = False, json_output: bool = False,
+                 sort_by: str = '', descending: bool = False,
+                 statuses: Optional[List[str]] = None, selector: str = '') -> None:
         """List all VMs and their status"""
-        response = self.rpc_call('Status')
+        response = self.rpc_call('Status', {
+            'sort_by': sort_by,
+            'descending': descending,
+            'statuses': statuses or [],
+            'selector': selector,
+        })
         vms = response['vms']
 
         if json_output:
@@ -318,15 +337,79 @@ class VmmCLI:
         self.rpc_call('StartVm', {'id': vm_id})
         print(f"Started VM {vm_id}")
 
-    def stop_vm(self, vm_id: str, force: bool = False) -> None:
+    def stop_vm(self, vm_id: str, force: bool = False, timeout: int = 0) -> None:
         """Stop a VM"""
-        if force:
+        if timeout:
+            response = self.rpc_call(
+                'StopVm', {'id': vm_id, 'force': force, 'timeout_secs': timeout})
+            path = response.get('path', '')
+            if path == 'timed_out':
+                print(f"VM {vm_id} did not shut down within {timeout}s, left running: "
+                      f"{response.get('error', '')}")
+            else:
+                print(f"Stopped VM {vm_id} ({path})")
+        elif force:
             self.rpc_call('StopVm', {'id': vm_id})
             print(f"Forcefully stopped VM {vm_id}")
         else:
             self.rpc_call('ShutdownVm', {'id': vm_id})
             print(f"Gracefully shutting down VM {vm_id}")
 
+    def swap_boot_disk(self, vm_id: str, image: str, revert: bool, reboot: bool) -> None:
+        """Boot a VM from another image, or revert to the previous one"""
+        response = self.rpc_call('SwapBootDisk', {
+            'id': vm_id, 'image': image or '', 'revert': revert, 'reboot': reboot})
+        action = "rebooted on" if response.get('rebooted') else "boots next from"
+        print(f"VM {vm_id} {action} image {response.get('image')}, "
+              f"previous image {response.get('previous_image')}")
+
+    def sync_guest_time(self, vm_id: str) -> None:
+        """Set the guest clock of a VM from the host"""
+        response = self.rpc_call('SyncGuestTime', {'id': vm_id})
+        print(f"Synced the clock of VM {vm_id} ({response.get('method')}), "
+              f"it was {response.get('offset_ms', 0)} ms off")
+
+    def reboot_guest(self, vm_id: str, hard: bool, wait: int) -> None:
+        """Reboot the guest OS of a VM in place"""
+        response = self.rpc_call('RebootGuest', {'id': vm_id, 'hard': hard, 'wait_secs': wait})
+        print(f"Rebooting the guest of VM {vm_id} ({response.get('method')})")
+        if response.get('back'):
+            print(f"The guest is back after {response.get('back_after_ms', 0)} ms")
+        elif wait:
+            print(f"The guest is not back after {wait}s")
+
+    def extend_lifetime(self, vm_id: str, extra: int) -> None:
+        """Give a VM more time before it is stopped for exceeding its max lifetime"""
+        response = self.rpc_call('ExtendVmLifetime', {'id': vm_id, 'extra_secs': extra})
+        remaining = int(response.get('expires_at_ms', 0)) // 1000 - int(time.time())
+        print(f"VM {vm_id} is now stopped in {max(remaining, 0)}s, "
+              f"extended by {response.get('extension_secs', 0)}s in total")
+
+    def service_descriptor(self, output: Optional[str]) -> None:
+        """Show the services of the VMM or save their compiled proto definitions"""
+        response = self.rpc_call('GetServiceDescriptor')
+        if output:
+            with open(output, 'wb') as f:
+                f.write(bytes.fromhex(response['file_descriptor_set']))
+            print(f"Saved the FileDescriptorSet to {output} (sha256 {response['sha256']})")
+            return
+        for service in response.get('services', []):
+            print(f"{service['name']}: {len(service.get('methods', []))} methods")
+            for method in service.get('methods', []):
+                print(f"  {method}")
+
+    def launch_digest(self, vm_id: Optional[str], spec_path: Optional[str], as_json: bool) -> None:
+        """Show the launch digest of a VM or of a VM configuration file"""
+        params = {'id': vm_id or ''}
+        if spec_path:
+            with open(spec_path, 'r') as f:
+                params['spec'] = json.load(f)
+        response = self.rpc_call('GetLaunchDigest', params)
+        if as_json:
+            print(json.dumps(response, indent=2))
+        else:
+            print(response.get('digest'))
+
     def remove_vm(self, vm_id: str) -> None:
         """Remove a VM"""
         self.rpc_call('RemoveVm', {'id': vm_id})
@@ -547,6 +630,92 @@ class VmmCLI:
                 "attach_mode": "listed",
                 "gpus": [{"slot": gpu} for gpu in args.gpu or []]
             }
+        if args.pci_device:
+            params["pci_devices"] = args.pci_device
+        if args.dns or args.hostname or args.mac or args.net:
+            params["network"] = {
+                "dns": args.dns or [],
+                "hostname": args.hostname or "",
+                "mac": args.mac or "",
+                "net": args.net or "",
+                "gateway": args.gateway or "",
+                "guest_address": args.guest_address or "",
+            }
+        if args.rng_seed is not None or args.rtc_start:
+            params["test_determinism"] = {"rtc_start": args.rtc_start or ""}
+            if args.rng_seed is not None:
+                params["test_determinism"]["rng_seed"] = args.rng_seed
+        if args.rtc_base or args.rtc_clock:
+            params["rtc"] = {
+                "base": args.rtc_base or "",
+                "clock": args.rtc_clock or "",
+            }
+        if args.display and args.display != "none":
+            params["display"] = {
+                "protocol": args.display,
+                "address": args.display_address or "",
+                "port": args.display_port or 0,
+            }
+        if args.cpu_affinity or args.cpu_shares:
+            params["cpu"] = {
+                "affinity": args.cpu_affinity or "",
+                "shares": args.cpu_shares or 0,
+            }
+        if args.watchdog_timeout:
+            params["watchdog"] = {
+                "timeout_secs": args.watchdog_timeout,
+                "action": args.watchdog_action or "log",
+            }
+        if args.restart_policy:
+            params["restart_policy"] = args.restart_policy
+        if args.success_exit_code or args.failure_exit_code:
+            params["exit_codes"] = {
+                "success": args.success_exit_code or [],
+                "failure": args.failure_exit_code or [],
+            }
+        if args.guest_agent:
+            params["guest_agent"] = True
+        if args.machine_type or args.machine_version:
+            params["machine"] = {
+                "type": args.machine_type or "",
+                "version": args.machine_version or "",
+            }
+        if args.qmp_event or args.restart_on_panic:
+            params["qmp_events"] = {
+                "events": args.qmp_event or [],
+                "restart_on_panic": args.restart_on_panic,
+            }
+        if args.watchdog_device:
+            params["watchdog_device"] = {
+                "model": args.watchdog_device,
+                "action": args.watchdog_device_action or "",
+            }
+        if args.prealloc_memory or args.lock_memory:
+            params["memory_options"] = {
+                "prealloc": args.prealloc_memory,
+                "lock": args.lock_memory,
+            }
+        if args.anti_affinity or args.dedicated_cores or args.require_tee:
+            params["scheduling"] = {
+                "anti_affinity": args.anti_affinity or [],
+                "dedicated_cores": args.dedicated_cores,
+                "require_tee": args.require_tee,
+            }
+        if args.boot_timeout:
+            params["boot_timeout_secs"] = args.boot_timeout
+        if args.max_lifetime:
+            params["max_lifetime_secs"] = args.max_lifetime
+        if args.network_group:
+            params["network_group"] = args.network_group
+        if args.nic:
+            params["nics"] = args.nic
+        if args.pre_start or args.post_start or args.post_stop:
+            params["hooks"] = {
+                "pre_start": args.pre_start or "",
+                "post_start": args.post_start or "",
+                "post_stop": args.post_stop or "",
+                "timeout_secs": args.hook_timeout or 0,
+            }
         if args.kms_url:
             params["kms_urls"] = args.kms_url
         if args.gateway_url:
@@ -627,6 +796,71 @@ class VmmCLI:
 
         print(format_table(rows, headers))
 
+    def reliability_stats(self, window: int, vm_ids: List[str], json_output: bool) -> None:
+        """Show the failures, restarts and crash loops of VMs over a recent window"""
+        response = self.rpc_call('GetReliabilityStats', {'window_secs': window, 'ids': vm_ids})
+
+        if json_output:
+            print(json.dumps(response, indent=2))
+            return
+
+        def row(stats, name):
+            mtbf = stats.get('mtbf_secs')
+            return [
+                name,
+                stats.get('failures', 0),
+                stats.get('restarts', 0),
+                stats.get('crash_loops', 0),
+                stats.get('guest_panics', 0),
+                f"{mtbf}s" if mtbf is not None else '-',
+                'Yes' if stats.get('truncated', False) else 'No'
+            ]
+
+        headers = ['VM', 'Failures', 'Restarts', 'Crash Loops', 'Panics', 'MTBF', 'Truncated']
+        rows = [row(vm, vm.get('id', '-')) for vm in response.get('vms', [])]
+        rows.append(row(response.get('fleet', {}), 'all'))
+        print(f"Over the last {window}s")
+        print(format_table(rows, headers))
+
+    def list_network_devices(self, json_output: bool = False) -> None:
+        """List the host bridges and taps set up for VMs"""
+        response = self.rpc_call('ListManagedNetworkDevices')
+        devices = response.get('devices', [])
+
+        if json_output:
+            print(json.dumps(devices, indent=2))
+            return
+
+        if not devices:
+            print("No network devices found")
+            return
+
+        headers = ['Name', 'Kind', 'Bridge', 'Network Group', 'VMs', 'Orphaned']
+        rows = []
+        for device in devices:
+            rows.append([
+                device.get('name', '-'),
+                device.get('kind', '-'),
+                device.get('bridge') or '-',
+                device.get('network_group') or '-',
+                ', '.join(device.get('vm_ids', [])) or '-',
+                'Yes' if device.get('orphaned', False) else 'No'
+            ])
+
+        print(format_table(rows, headers))
+
+    def cleanup_network_devices(self, dry_run: bool) -> None:
+        """Remove the host bridges and taps set up for VMs that no running VM uses"""
+        response = self.rpc_call('CleanupOrphanedNetworkDevices', {'dry_run': dry_run})
+        action = "Would remove" if dry_run else "Removed"
+        removed = response.get('removed', [])
+        for device in removed:
+            print(f"{action} {device.get('kind')} {device.get('name')}")
+        if not removed:
+            print("No orphaned network devices")
+        for error in response.get('errors', []):
+            print(f"Error: {error}")
+
 
 def format_table(rows, headers):
     """Simple table formatter"""
@@ -859,6 +1093,14 @@ def main():
         '-v', '--verbose', action='store_true', help='Show detailed information')
     lsvm_parser.add_argument(
         '--json', action='store_true', help='Output in JSON format for automation')
+    lsvm_parser.add_argument(
+        '--sort', choices=['created', 'name', 'status'], default='created', help='Sort key')
+    lsvm_parser.add_argument(
+        '--desc', action='store_true', help='Reverse the sort order')
+    lsvm_parser.add_argument(
+        '--status', action='append', help='Only list VMs in this status (can be repeated)')
+    lsvm_parser.add_argument(
+        '--selector', default='', help='Filter like "image=dstack-0.5,status!=stopped"')
 
     # Start command
     start_parser = subparsers.add_parser('start', help='Start a VM')
@@ -868,7 +1110,57 @@ def main():
     stop_parser = subparsers.add_parser('stop', help='Stop a VM')
     stop_parser.add_argument('vm_id', help='VM ID to stop')
     stop_parser.add_argument(
-        '-f', '--force', action='store_true', help='Force stop the VM')
+        '-f', '--force', action='store_true',
+        help='Force stop the VM, with --timeout only if it does not shut down in time')
+    stop_parser.add_argument(
+        '-t', '--timeout', type=int, default=0,
+        help='Seconds to wait for a graceful shutdown before giving up or, with --force, stopping it')
+
+    # Swap boot disk command
+    swap_parser = subparsers.add_parser(
+        'swap-image', help='Boot a VM from another prepared image, keeping its data disk')
+    swap_parser.add_argument('vm_id', help='VM ID')
+    swap_group = swap_parser.add_mutually_exclusive_group(required=True)
+    swap_group.add_argument('--image', help='Image to boot from')
+    swap_group.add_argument('--revert', action='store_true',
+                            help='Swap back to the image of the last swap')
+    swap_parser.add_argument('--no-reboot', action='store_true',
+                             help='Keep a running VM up, the image is used from its next launch')
+
+    # Sync time command
+    sync_time_parser = subparsers.add_parser(
+        'sync-time', help='Set the guest clock of a VM from the host with its guest agent')
+    sync_time_parser.add_argument('vm_id', help='VM ID')
+
+    # Reboot guest command
+    reboot_parser = subparsers.add_parser(
+        'reboot-guest', help='Reboot the guest OS of a VM, keeping its QEMU process')
+    reboot_parser.add_argument('vm_id', help='VM ID')
+    reboot_parser.add_argument('--hard', action='store_true',
+                               help='Reset the machine instead of asking the guest agent')
+    reboot_parser.add_argument('--wait', type=int, default=0,
+                               help='Seconds to wait for the guest to be back')
+
+    # Extend lifetime command
+    extend_lifetime_parser = subparsers.add_parser(
+        'extend-lifetime', help='Give a VM more time before it is stopped at its max lifetime')
+    extend_lifetime_parser.add_argument('vm_id', help='VM ID')
+    extend_lifetime_parser.add_argument('extra', type=int, help='Seconds to add')
+
+    # Service descriptor command
+    descriptor_parser = subparsers.add_parser(
+        'descriptor', help='Show the RPC services of the VMM or save their proto descriptor')
+    descriptor_parser.add_argument('-o', '--output',
+                                   help='File to save the serialized FileDescriptorSet to')
+
+    # Launch digest command
+    digest_parser = subparsers.add_parser(
+        'launch-digest', help='Show the digest of the normalized launch parameters of a VM')
+    digest_group = digest_parser.add_mutually_exclusive_group(required=True)
+    digest_group.add_argument('vm_id', nargs='?', help='VM ID')
+    digest_group.add_argument('--spec', help='VM configuration JSON file, instead of a VM')
+    digest_parser.add_argument('--json', action='store_true',
+                               help='Show the digested inputs as JSON')
 
     # Remove command
     remove_parser = subparsers.add_parser('remove', help='Remove a VM')
@@ -932,6 +1224,89 @@ def main():
                                help='Port mapping in format: protocol[:address]:from:to')
     deploy_parser.add_argument('--gpu', action='append', type=str,
                                help='GPU slot to attach (can be used multiple times)')
+    deploy_parser.add_argument('--pci-device', action='append', type=str,
+                               help='Host PCI device to pass through with VFIO (can be used multiple times)')
+    deploy_parser.add_argument('--dns', action='append', type=str,
+                               help='Guest-visible DNS server address for user-mode networking')
+    deploy_parser.add_argument('--hostname', type=str,
+                               help='Guest hostname handed out by DHCP in user-mode networking')
+    deploy_parser.add_argument('--mac', type=str,
+                               help='MAC address of the guest NIC (default: derived from the VM id)')
+    deploy_parser.add_argument('--net', type=str,
+                               help='Pin the user-mode network of the VM, e.g. 10.0.5.0/24')
+    deploy_parser.add_argument('--gateway', type=str,
+                               help='Host address in the pinned network (default: its .2 address)')
+    deploy_parser.add_argument('--guest-address', type=str,
+                               help='Guest address in the pinned network (default: its .15 address)')
+    deploy_parser.add_argument('--rng-seed', type=int,
+                               help='Fixed QEMU RNG seed, test hosts only (cvm.allow_test_determinism)')
+    deploy_parser.add_argument('--rtc-start', type=str,
+                               help='UTC time the RTC starts at, YYYY-MM-DDTHH:MM:SS, test hosts only')
+    deploy_parser.add_argument('--rtc-base', choices=['utc', 'localtime'],
+                               help='RTC base (default: utc)')
+    deploy_parser.add_argument('--rtc-clock', choices=['host', 'vm', 'rt'],
+                               help='RTC clock source (default: host)')
+    deploy_parser.add_argument('--watchdog-timeout', type=int,
+                               help='Seconds without a guest heartbeat before the watchdog action fires')
+    deploy_parser.add_argument('--watchdog-action', choices=['log', 'event', 'hook', 'restart'],
+                               help='Action on missed heartbeats (default: log)')
+    deploy_parser.add_argument('--restart-policy', type=str,
+                               help='always, on-failure[:max-retries], unless-stopped (default) or no')
+    deploy_parser.add_argument('--success-exit-code', action='append', type=int,
+                               help='QEMU exit code counted as a clean exit by on-failure (default: 0), can be repeated')
+    deploy_parser.add_argument('--failure-exit-code', action='append', type=int,
+                               help='QEMU exit code counted as a failure by on-failure, can be repeated')
+    deploy_parser.add_argument('--machine-type',
+                               help='QEMU machine type (default: q35)')
+    deploy_parser.add_argument('--machine-version',
+                               help='Pin the machine type version, e.g. 8.2 for pc-q35-8.2')
+    deploy_parser.add_argument('--qmp-event', action='append', type=str,
+                               help='QMP event to report as a VM event (can be used multiple times, default: GUEST_PANICKED, BLOCK_IO_ERROR, RESET, SHUTDOWN)')
+    deploy_parser.add_argument('--restart-on-panic', action='store_true',
+                               help='Exit QEMU with a failure on guest panic so the restart policy applies')
+    deploy_parser.add_argument('--watchdog-device', choices=['i6300esb', 'ib700'],
+                               help='Attach an emulated hardware watchdog the guest must pet')
+    deploy_parser.add_argument('--watchdog-device-action', choices=['reset', 'poweroff', 'pause', 'none'],
+                               help='What QEMU does when the watchdog device fires (default: reset)')
+    deploy_parser.add_argument('--prealloc-memory', action='store_true',
+                               help='Allocate all guest memory at launch instead of on first touch')
+    deploy_parser.add_argument('--lock-memory', action='store_true',
+                               help='Lock guest memory in RAM (needs RLIMIT_MEMLOCK above the VM memory)')
+    deploy_parser.add_argument('--guest-agent', action='store_true',
+                               help='Attach a QEMU guest agent channel (requires cvm.guest_agent on the host)')
+    deploy_parser.add_argument('--anti-affinity', action='append', type=str,
+                               help='Scheduling label no other VM on the same host may carry, can be repeated')
+    deploy_parser.add_argument('--dedicated-cores', action='store_true',
+                               help='Scheduling hint: the vCPUs need the --cpu-affinity cores to themselves')
+    deploy_parser.add_argument('--require-tee', action='store_true',
+                               help='Scheduling hint: the VM needs a TDX-capable host')
+    deploy_parser.add_argument('--boot-timeout', type=int,
+                               help='Seconds to wait for the guest to become ready before capturing diagnostics')
+    deploy_parser.add_argument('--max-lifetime', type=int,
+                               help='Seconds the VM may run before it is stopped and left stopped')
+    deploy_parser.add_argument('--network-group', type=str,
+                               help='Network isolation group, VMs of different groups never share a bridge')
+    deploy_parser.add_argument('--nic', action='append', type=parse_nic,
+                               help='Additional NIC: user|tap|bridge[,ifname=..][,mac=..][,model=virtio-net|e1000]'
+                               '[,network_group=..] (can be used multiple times)')
+    deploy_parser.add_argument('--pre-start', type=str,
+                               help='Host command run before the VM is launched, a failure aborts the launch')
+    deploy_parser.add_argument('--post-start', type=str,
+                               help='Host command run after the VM is launched')
+    deploy_parser.add_argument('--post-stop', type=str,
+                               help='Host command run after the VM exits')
+    deploy_parser.add_argument('--hook-timeout', type=int,
+                               help='Seconds each lifecycle hook may run (default: host setting)')
+    deploy_parser.add_argument('--cpu-affinity', type=str,
+                               help='Host cores to pin the vCPUs to, e.g. 0-3,8')
+    deploy_parser.add_argument('--cpu-shares', type=int,
+                               help='Relative CPU weight (1-10000, default: 100)')
+    deploy_parser.add_argument('--display', choices=['none', 'vnc', 'spice'],
+                               help='Display output for debugging (default: none)')
+    deploy_parser.add_argument('--display-address', type=str,
+                               help='Host address the display listens on (default: 127.0.0.1)')
+    deploy_parser.add_argument('--display-port', type=int,
+                               help='Host port of the display (default: auto-allocated)')
     deploy_parser.add_argument('--ppcie', action='store_true',
                                help='Enable PPCIE (Protected PCIe) mode - attach all available GPUs')
     deploy_parser.add_argument('--pin-numa', action='store_true',
@@ -956,6 +1331,25 @@ def main():
     lsgpu_parser.add_argument(
         '--json', action='store_true', help='Output in JSON format for automation')
 
+    # Reliability command
+    reliability_parser = subparsers.add_parser(
+        'reliability', help='Show the failures, restarts and crash loops of VMs')
+    reliability_parser.add_argument('vm_ids', nargs='*', help='VM IDs, all if none')
+    reliability_parser.add_argument('--window', type=int, default=86400,
+                                    help='Seconds back from now to count (default: a day)')
+    reliability_parser.add_argument(
+        '--json', action='store_true', help='Output in JSON format for automation')
+
+    # Network devices commands
+    lsnetdev_parser = subparsers.add_parser(
+        'lsnetdev', help='List the host bridges and taps set up for VMs')
+    lsnetdev_parser.add_argument(
+        '--json', action='store_true', help='Output in JSON format for automation')
+    cleanup_netdev_parser = subparsers.add_parser(
+        'cleanup-netdev', help='Remove the host bridges and taps no running VM uses')
+    cleanup_netdev_parser.add_argument(
+        '--dry-run', action='store_true', help='Only show what would be removed')
+
     # Update environment variables command
     update_env_parser = subparsers.add_parser(
         'update-env', help='Update environment variables for a VM')
@@ -1005,11 +1399,24 @@ def main():
     cli = VmmCLI(args.url, args.auth_user, args.auth_password)
 
     if args.command == 'lsvm':
-        cli.list_vms(args.verbose, args.json)
+        cli.list_vms(args.verbose, args.json, args.sort,
+                     args.desc, args.status, args.selector)
     elif args.command == 'start':
         cli.start_vm(args.vm_id)
     elif args.command == 'stop':
-        cli.stop_vm(args.vm_id, args.force)
+        cli.stop_vm(args.vm_id, args.force, args.timeout)
+    elif args.command == 'swap-image':
+        cli.swap_boot_disk(args.vm_id, args.image, args.revert, not args.no_reboot)
+    elif args.command == 'sync-time':
+        cli.sync_guest_time(args.vm_id)
+    elif args.command == 'reboot-guest':
+        cli.reboot_guest(args.vm_id, args.hard, args.wait)
+    elif args.command == 'extend-lifetime':
+        cli.extend_lifetime(args.vm_id, args.extra)
+    elif args.command == 'descriptor':
+        cli.service_descriptor(args.output)
+    elif args.command == 'launch-digest':
+        cli.launch_digest(args.vm_id, args.spec, args.json)
     elif args.command == 'remove':
         cli.remove_vm(args.vm_id)
     elif args.command == 'logs':
@@ -1022,6 +1429,12 @@ def main():
         cli.list_images(args.json)
     elif args.command == 'lsgpu':
         cli.list_gpus(args.json)
+    elif args.command == 'reliability':
+        cli.reliability_stats(args.window, args.vm_ids, args.json)
+    elif args.command == 'lsnetdev':
+        cli.list_network_devices(args.json)
+    elif args.command == 'cleanup-netdev':
+        cli.cleanup_network_devices(args.dry_run)
     elif args.command == 'update-env':
         cli.update_vm_env(args.vm_id, parse_env_file(
             args.env_file), kms_urls=args.kms_url)
@@ -0,0 +1,138 @@
+// SPDX-FileCopyrightText: © 2025 Phala Network <dstack@phala.network>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Outbound webhooks notified of VM lifecycle events.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use tracing::{debug, error, warn};
+
+use crate::config::{WebhookConfig, WebhookEndpoint};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>` when the endpoint has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Dstack-Signature";
+pub const EVENT_HEADER: &str = "X-Dstack-Event";
+
+pub struct Webhooks {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    delivered: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WebhookStats {
+    pub delivered: u64,
+    /// Deliveries that still failed after all retries
+    pub failed: u64,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            config,
+            client,
+            delivered: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> WebhookStats {
+        WebhookStats {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Post the event to every endpoint subscribed to it. Delivery happens in the background.
+    pub fn emit(self: &Arc<Self>, event: &str, vm_id: Option<&str>, details: Value) {
+        let endpoints = self
+            .config
+            .endpoints
+            .iter()
+            .filter(|e| e.events.is_empty() || e.events.iter().any(|name| name == event))
+            .cloned()
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = json!({
+            "event": event,
+            "vm_id": vm_id,
+            "timestamp": timestamp,
+            "details": details,
+        })
+        .to_string();
+        for endpoint in endpoints {
+            let this = self.clone();
+            let body = body.clone();
+            let event = event.to_string();
+            tokio::spawn(async move { this.deliver(&endpoint, &event, body).await });
+        }
+    }
+
+    async fn deliver(&self, endpoint: &WebhookEndpoint, event: &str, body: String) {
+        let mut delay = Duration::from_secs(1);
+        for attempt in 0..=self.config.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            match self.post(endpoint, event, &body).await {
+                Ok(()) => {
+                    debug!("Delivered {event} to webhook {}", endpoint.url);
+                    self.delivered.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(err) => {
+                    warn!(
+                        "Webhook {} attempt {} for {event} failed: {err:?}",
+                        endpoint.url,
+                        attempt + 1
+                    );
+                }
+            }
+        }
+        error!(
+            "Giving up delivering {event} to webhook {} after {} retries",
+            endpoint.url, self.config.max_retries
+        );
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn post(&self, endpoint: &WebhookEndpoint, event: &str, body: &str) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, event);
+        if !endpoint.secret.is_empty() {
+            let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, endpoint.secret.as_bytes());
+            let signature = ring::hmac::sign(&key, body.as_bytes());
+            request = request.header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", hex::encode(signature.as_ref())),
+            );
+        }
+        let response = request.body(body.to_string()).send().await?;
+        if !response.status().is_success() {
+            bail!("HTTP {}", response.status());
+        }
+        Ok(())
+    }
+}